@@ -16,6 +16,9 @@ pub struct CommandLine<'a> {
     pub num_images: usize,
     /// A list of the images we've been asked to load, in the form `(name, path)`
     pub images: [Option<(&'a str, &'a str)>; MAX_IMAGES],
+    /// The filter the kernel should apply to its log messages (e.g. `info,kernel::scheduler=warn`). This is passed
+    /// straight on to the kernel, which parses it.
+    pub log_filter: Option<&'a str>,
 }
 
 #[derive(Clone, Copy)]
@@ -33,6 +36,7 @@ impl<'a> CommandLine<'a> {
             kernel_heap_size: DEFAULT_KERNEL_HEAP_SIZE,
             num_images: 0,
             images: [None; MAX_IMAGES],
+            log_filter: None,
         };
 
         /*
@@ -124,6 +128,10 @@ impl<'a> CommandLine<'a> {
                     let path = value.expect("You've specified an image without a path!");
                     command_line.add_image(name, path);
                 }
+                "log" => {
+                    command_line.log_filter =
+                        Some(value.expect("'log' parameter must have a log filter as a value"));
+                }
                 _ => warn!("Unsupported kernel command line option with root: '{}'. Ignoring.", root),
             }
         }
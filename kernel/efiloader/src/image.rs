@@ -1,12 +1,13 @@
 use crate::LoaderError;
 use core::{ptr, slice, str};
 use hal::{
-    boot_info::{LoadedImage, Segment, MAX_CAPABILITY_STREAM_LENGTH},
+    boot_info::{KernelSymbols, LoadedImage, Segment, MAX_CAPABILITY_STREAM_LENGTH},
     memory::{Flags, FrameAllocator, FrameSize, Page, PageTable, PhysicalAddress, Size4KiB, VirtualAddress},
 };
 use hal_x86_64::kernel_map;
 use mer::{
     program::{ProgramHeader, SegmentType},
+    section::SectionType,
     Elf,
 };
 use pebble_util::math;
@@ -28,6 +29,10 @@ pub struct KernelInfo {
     /// need to know how much memory the loaded image has taken up. During loading, we calculate the address of
     /// the next available page (this) to use.
     pub next_safe_address: VirtualAddress,
+
+    /// The kernel's symbols, if its image has a symbol table. These are mapped after the loaded image (and so
+    /// before `next_safe_address`).
+    pub symbols: Option<KernelSymbols>,
 }
 
 pub fn load_kernel<A, P>(
@@ -94,10 +99,65 @@ where
         None => panic!("Kernel does not have a '_guard_page' symbol!"),
     };
     assert!(guard_page_address.is_aligned(Size4KiB::SIZE), "Guard page address is not page aligned");
-    page_table.unmap::<Size4KiB>(Page::starts_with(guard_page_address));
+    page_table.unmap::<Size4KiB, _>(Page::starts_with(guard_page_address), allocator);
+
+    let symbols = load_kernel_symbols(boot_services, &elf, &mut next_safe_address, page_table, allocator);
 
     boot_services.free_pool(pool_addr).unwrap_success();
-    Ok(KernelInfo { entry_point, stack_top, next_safe_address })
+    Ok(KernelInfo { entry_point, stack_top, next_safe_address, symbols })
+}
+
+/// Copy the kernel's symbol table, and the string table that holds the names of its symbols, out of the kernel's
+/// ELF, and map them into the kernel's address space at `next_safe_address`. The kernel uses these to symbolicate
+/// backtraces. Returns `None` if the kernel doesn't have a symbol table (e.g. if it's been stripped).
+fn load_kernel_symbols<A, P>(
+    boot_services: &BootServices,
+    elf: &Elf,
+    next_safe_address: &mut VirtualAddress,
+    page_table: &mut P,
+    allocator: &A,
+) -> Option<KernelSymbols>
+where
+    A: FrameAllocator<Size4KiB>,
+    P: PageTable<Size4KiB>,
+{
+    let symbol_table = elf.sections().find(|section| section.name(elf) == Some(".symtab"))?;
+    let string_table = elf.sections().nth(symbol_table.link as usize)?;
+    if string_table.section_type() != SectionType::StrTab {
+        return None;
+    }
+
+    let mut load_table = |data: &[u8]| -> VirtualAddress {
+        let num_frames = Size4KiB::frames_needed(data.len());
+        let physical_address = boot_services
+            .allocate_pages(AllocateType::AnyPages, crate::BOOT_INFO_MEMORY_TYPE, num_frames)
+            .expect_success("Failed to allocate memory for kernel symbols");
+        unsafe {
+            slice::from_raw_parts_mut(physical_address as usize as *mut u8, data.len()).copy_from_slice(data);
+        }
+
+        let virtual_address = *next_safe_address;
+        *next_safe_address += num_frames * Size4KiB::SIZE;
+        page_table
+            .map_area(
+                virtual_address,
+                PhysicalAddress::new(physical_address as usize).unwrap(),
+                num_frames * Size4KiB::SIZE,
+                Flags::default(),
+                allocator,
+            )
+            .unwrap();
+        virtual_address
+    };
+
+    let symbol_data = symbol_table.data(elf)?;
+    let string_data = string_table.data(elf)?;
+    Some(KernelSymbols {
+        symbol_table: load_table(symbol_data),
+        symbol_table_size: symbol_data.len(),
+        string_table: load_table(string_data),
+        string_table_size: string_data.len(),
+    })
 }
 
 pub fn load_image(
@@ -13,7 +13,7 @@ use allocator::BootFrameAllocator;
 use command_line::CommandLine;
 use core::{mem, panic::PanicInfo, ptr, slice};
 use hal::{
-    boot_info::{BootInfo, VideoModeInfo},
+    boot_info::{BootInfo, LogFilterString, VideoModeInfo},
     memory::{Flags, FrameAllocator, FrameSize, Page, PageTable, PhysicalAddress, Size4KiB, VirtualAddress},
 };
 use hal_x86_64::paging::PageTableImpl;
@@ -148,6 +148,10 @@ fn main(image_handle: Handle, system_table: SystemTable<Boot>) -> Result<!, Load
     };
     boot_info.magic = hal::boot_info::BOOT_INFO_MAGIC;
     boot_info.video_mode = video_mode;
+    boot_info.kernel_symbols = kernel_info.symbols;
+    boot_info.log_filter = command_line.log_filter.map(|filter| {
+        LogFilterString::new(filter).expect("Log filter passed to loader is too long")
+    });
 
     /*
      * Find the RSDP address and add it to the boot info.
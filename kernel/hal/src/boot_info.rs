@@ -18,6 +18,56 @@ pub struct BootInfo {
 
     /// The physical address of the RSDP, the first ACPI table.
     pub rsdp_address: Option<PhysicalAddress>,
+
+    /// The kernel's symbol table, if the kernel image has one. This is used to symbolicate backtraces.
+    pub kernel_symbols: Option<KernelSymbols>,
+
+    /// The filter the kernel should apply to its log messages, if one was passed to the loader.
+    pub log_filter: Option<LogFilterString>,
+}
+
+/// Describes where the loader has put the kernel's symbols. These are copied straight from the kernel's ELF image:
+/// `symbol_table` holds the contents of the `.symtab` section, and `string_table` the contents of the string table
+/// it's linked to. Both are mapped into the kernel's address space, in memory marked as `BootInfo`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct KernelSymbols {
+    pub symbol_table: VirtualAddress,
+    /// In bytes.
+    pub symbol_table_size: usize,
+    pub string_table: VirtualAddress,
+    /// In bytes.
+    pub string_table_size: usize,
+}
+
+pub const MAX_LOG_FILTER_LENGTH: usize = 128;
+
+/// A filter for the kernel's log messages, as passed to the loader in its load options (e.g.
+/// `info,kernel::scheduler=warn`). This is passed on to the kernel as a string, and parsed there. Encoded as UTF-8,
+/// and not null-terminated.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct LogFilterString {
+    pub length: u8,
+    pub bytes: [u8; MAX_LOG_FILTER_LENGTH],
+}
+
+impl LogFilterString {
+    /// Create a `LogFilterString` from a string. Returns `None` if the string is longer than
+    /// `MAX_LOG_FILTER_LENGTH` bytes.
+    pub fn new(string: &str) -> Option<LogFilterString> {
+        if string.len() > MAX_LOG_FILTER_LENGTH {
+            return None;
+        }
+
+        let mut bytes = [0; MAX_LOG_FILTER_LENGTH];
+        bytes[0..string.len()].copy_from_slice(string.as_bytes());
+        Some(LogFilterString { length: string.len() as u8, bytes })
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[0..(self.length as usize)]).unwrap()
+    }
 }
 
 pub const MAX_MEMORY_MAP_ENTRIES: usize = 256;
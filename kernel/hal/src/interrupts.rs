@@ -0,0 +1,62 @@
+//! Types for handling interrupts in a way that doesn't depend on the platform's interrupt controller, so that the
+//! kernel can handle interrupts it uses itself (such as the timer's) without knowing how they're delivered.
+//!
+//! Interrupts are identified by a vector, the meaning of which is specific to each platform (e.g. it's an entry in
+//! the IDT on x86_64, and an INTID on a GIC).
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TriggerMode {
+    /// The interrupt fires once, when its line changes state.
+    Edge,
+    /// The interrupt keeps firing for as long as its line is asserted, which is until the device that raised it has
+    /// been serviced.
+    Level,
+}
+
+/// What the CPU was running when it was interrupted.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterruptOrigin {
+    Kernel,
+    Userspace,
+}
+
+/// A handler for an interrupt delivered through a vector. It's called with interrupts disabled, with the vector the
+/// interrupt arrived through, and must pass it to `end_of_interrupt` before it returns, or before it switches to
+/// another task (in which case it won't return until the interrupted task is next scheduled).
+pub type InterruptHandler = fn(vector: u32, origin: InterruptOrigin);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IpiTarget {
+    /// The CPU with the given ID, as returned by `Platform::cpu_id`.
+    Cpu(u32),
+    /// Every CPU except the one sending the IPI.
+    AllOthers,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegisterHandlerError {
+    /// The platform doesn't allow handlers to be registered for this vector, either because it doesn't exist, or
+    /// because it's used for something else (e.g. to deliver interrupts to userspace).
+    InvalidVector,
+    /// Another handler has already been registered for this vector.
+    AlreadyRegistered,
+}
+
+/// The interrupt controller of a platform, which delivers interrupts to the CPU, and lets CPUs interrupt each other
+/// with inter-processor interrupts (IPIs).
+pub trait InterruptController {
+    /// Register a handler to be called when an interrupt arrives through `vector`. The vector must also be
+    /// unmasked before the handler is called.
+    fn register_handler(&self, vector: u32, handler: InterruptHandler) -> Result<(), RegisterHandlerError>;
+
+    /// Mask or unmask the interrupt delivered through `vector`. Interrupts are only delivered while they're
+    /// unmasked.
+    fn set_masked(&self, vector: u32, masked: bool);
+
+    /// Tell the interrupt controller that the kernel has finished handling an interrupt delivered through
+    /// `vector`. Until this is done, interrupts of the same or lower priority won't be delivered.
+    fn end_of_interrupt(&self, vector: u32);
+
+    /// Raise an interrupt through `vector` on another CPU (or CPUs).
+    fn send_ipi(&self, target: IpiTarget, vector: u32);
+}
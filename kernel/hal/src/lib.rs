@@ -5,5 +5,12 @@
 #[macro_use]
 extern crate std;
 
+#[cfg(feature = "test_utils")]
+extern crate alloc;
+
 pub mod boot_info;
+pub mod interrupts;
 pub mod memory;
+#[cfg(feature = "test_utils")]
+pub mod test_utils;
+pub mod timer;
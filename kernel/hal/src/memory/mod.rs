@@ -13,7 +13,7 @@ mod virtual_address;
 
 pub use frame::Frame;
 pub use page::Page;
-pub use paging::{Flags, PageTable, PagingError};
+pub use paging::{CacheType, Flags, Mapping, PageTable, PagingError};
 pub use physical_address::PhysicalAddress;
 pub use virtual_address::VirtualAddress;
 
@@ -57,9 +57,9 @@ macro frame_size($name: ident, $size: expr, $condition: meta) {
     }
 }
 
-frame_size!(Size4KiB, kibibytes(4), cfg(target_arch = "x86_64"));
-frame_size!(Size2MiB, mebibytes(2), cfg(target_arch = "x86_64"));
-frame_size!(Size1GiB, gibibytes(1), cfg(target_arch = "x86_64"));
+frame_size!(Size4KiB, kibibytes(4), cfg(any(target_arch = "x86_64", target_arch = "aarch64")));
+frame_size!(Size2MiB, mebibytes(2), cfg(any(target_arch = "x86_64", target_arch = "aarch64")));
+frame_size!(Size1GiB, gibibytes(1), cfg(any(target_arch = "x86_64", target_arch = "aarch64")));
 
 /// `FrameAllocator` is used to interact with a physical memory manager in a platform-independent way. Methods on
 /// `FrameAllocator` take `&self` and so are expected to use interior-mutability through a type such as `Mutex` to
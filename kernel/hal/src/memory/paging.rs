@@ -1,27 +1,46 @@
 use super::{Frame, FrameAllocator, FrameSize, Page, PhysicalAddress, VirtualAddress};
-use core::ops::{self, Range};
+use core::{
+    cmp,
+    fmt,
+    ops::{self, Range},
+};
 
 /// Defines the permissions for a region of memory. Used both for abstract regions of memory (e.g. entries in a
 /// memory map) and as a architecture-common representation of paging structures.
 ///
 /// The `Add` implementation "coalesces" two sets of `Flags`, giving a set of `Flags` that has the permissions of
 /// both of the sets. For example, if one region is writable and the other is not, the coalesced flags will be
-/// writable. By default, a region is considered to be cached, so coalesced flags will only be cached if both input
-/// regions can safely be cached.
+/// writable. By default, a region is considered to be write-back cached, so coalesced flags use the least
+/// cacheable of the two regions' cache types, as that's safe for both of them.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Flags {
     pub writable: bool,
     pub executable: bool,
     pub user_accessible: bool,
-    pub cached: bool,
+    pub cache_type: CacheType,
 }
 
 impl Default for Flags {
     fn default() -> Self {
-        Flags { writable: false, executable: false, user_accessible: false, cached: true }
+        Flags { writable: false, executable: false, user_accessible: false, cache_type: CacheType::WriteBack }
     }
 }
 
+/// Describes how accesses to a region of memory can be cached. These are ordered from the most cacheable to the
+/// least cacheable.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum CacheType {
+    /// Reads and writes are both cached. This should be used for normal memory.
+    WriteBack,
+    /// Reads are cached, but writes are written through to memory straight away.
+    WriteThrough,
+    /// Reads aren't cached, but writes can be buffered and combined into larger writes. This is useful for memory
+    /// that's written to a lot but not read from, such as framebuffers.
+    WriteCombining,
+    /// Nothing is cached, and accesses are made in program order. This should be used for memory-mapped devices.
+    Uncacheable,
+}
+
 impl ops::Add for Flags {
     type Output = Self;
 
@@ -30,12 +49,52 @@ impl ops::Add for Flags {
             writable: self.writable || other.writable,
             executable: self.executable || other.executable,
             user_accessible: self.user_accessible || other.user_accessible,
-            // If either of the regions should not be cached, we can't cache any of it
-            cached: self.cached && other.cached,
+            // We can only cache the region as much as the least cacheable part of it
+            cache_type: cmp::max(self.cache_type, other.cache_type),
         }
     }
 }
 
+/// A run of virtual memory that's mapped to a contiguous run of physical memory, with pages of the same size and
+/// flags. These are produced by `PageTable::walk`, which coalesces adjacent pages into as few `Mapping`s as it can.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Mapping {
+    pub virtual_start: VirtualAddress,
+    pub physical_start: PhysicalAddress,
+    /// The size of the mapping, in bytes. This is always a multiple of `page_size`.
+    pub size: usize,
+    pub page_size: usize,
+    pub flags: Flags,
+}
+
+impl Mapping {
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.virtual_start && usize::from(address) - usize::from(self.virtual_start) < self.size
+    }
+}
+
+/*
+ * A mapping can end at the very top of the address space, so we print inclusive ranges to avoid overflowing.
+ */
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}..={:#x} -> {:#x}..={:#x} [{}{}{}] {:?}, {} pages of {:#x} bytes",
+            self.virtual_start,
+            usize::from(self.virtual_start) + (self.size - 1),
+            self.physical_start,
+            usize::from(self.physical_start) + (self.size - 1),
+            if self.flags.writable { 'w' } else { '-' },
+            if self.flags.executable { 'x' } else { '-' },
+            if self.flags.user_accessible { 'u' } else { '-' },
+            self.flags.cache_type,
+            self.size / self.page_size,
+            self.page_size,
+        )
+    }
+}
+
 #[derive(Debug)]
 pub enum PagingError {
     /// The virtual memory that is being mapped is already mapped to another part of physical memory.
@@ -106,9 +165,36 @@ where
     where
         A: FrameAllocator<TableSize>;
 
-    fn unmap<S>(&mut self, page: Page<S>) -> Option<Frame<S>>
+    /// Unmap a `Page`, returning the `Frame` it was mapped to, or `None` if it wasn't mapped. If the page is part of
+    /// a larger page, the larger page is split up so the rest of it stays mapped, which can require new page
+    /// tables to be allocated from `allocator`. If the page is mapped with smaller pages instead, nothing is
+    /// unmapped and `None` is returned.
+    fn unmap<S, A>(&mut self, page: Page<S>, allocator: &A) -> Option<Frame<S>>
     where
-        S: FrameSize;
+        S: FrameSize,
+        A: FrameAllocator<TableSize>;
+
+    /// Change the flags of the memory mapped in `range` to `flags`. Parts of the range that aren't mapped are left
+    /// unmapped. If a larger page is only partly inside the range, it's split up so the rest of it keeps its old
+    /// flags, which can require new page tables to be allocated from `allocator`. Both ends of the range must be
+    /// aligned to `TableSize`.
+    fn protect<A>(&mut self, range: Range<VirtualAddress>, flags: Flags, allocator: &A)
+    where
+        A: FrameAllocator<TableSize>;
+
+    /// Call `f` with each run of present mappings in these page tables, in order of virtual address. Adjacent pages
+    /// are coalesced into a single `Mapping` if they're the same size, have the same flags, and are mapped to
+    /// contiguous physical memory.
+    fn walk<F>(&self, f: F)
+    where
+        F: FnMut(Mapping);
+
+    /// Free every page table in this set, including the top-level one, back to `allocator`. The memory mapped by
+    /// the tables isn't freed, and nor are the tables that map the kernel, as they're shared with every set of page
+    /// tables created by `new_with_kernel_mapped`. These page tables can't be used again afterwards.
+    fn free_tables<A>(&mut self, allocator: &A)
+    where
+        A: FrameAllocator<TableSize>;
 }
 
 #[cfg(test)]
@@ -120,20 +206,27 @@ mod tests {
     fn test_flag_coalescing() {
         assert_eq!(Flags::default() + Flags::default(), Flags::default());
         assert_eq!(
-            Flags::default() + Flags { writable: false, executable: true, user_accessible: true, cached: true },
-            Flags { writable: false, executable: true, user_accessible: true, cached: true }
+            Flags::default() + Flags { executable: true, user_accessible: true, ..Default::default() },
+            Flags { executable: true, user_accessible: true, ..Default::default() }
+        );
+        assert_eq!(
+            Flags::default()
+                + Flags { writable: true, executable: true, user_accessible: true, ..Default::default() },
+            Flags { writable: true, executable: true, user_accessible: true, ..Default::default() }
         );
         assert_eq!(
-            Flags::default() + Flags { writable: true, executable: true, user_accessible: true, cached: true },
-            Flags { writable: true, executable: true, user_accessible: true, cached: true }
+            Flags::default() + Flags { cache_type: CacheType::Uncacheable, ..Default::default() },
+            Flags { cache_type: CacheType::Uncacheable, ..Default::default() }
         );
         assert_eq!(
-            Flags::default() + Flags { cached: false, ..Default::default() },
-            Flags { cached: false, ..Default::default() }
+            Flags { cache_type: CacheType::WriteCombining, ..Default::default() }
+                + Flags { cache_type: CacheType::WriteThrough, ..Default::default() },
+            Flags { cache_type: CacheType::WriteCombining, ..Default::default() }
         );
         assert_eq!(
-            Flags { cached: false, ..Default::default() } + Flags { cached: false, ..Default::default() },
-            Flags { cached: false, ..Default::default() }
+            Flags { cache_type: CacheType::WriteCombining, ..Default::default() }
+                + Flags { cache_type: CacheType::Uncacheable, ..Default::default() },
+            Flags { cache_type: CacheType::Uncacheable, ..Default::default() }
         );
     }
 }
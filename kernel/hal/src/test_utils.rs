@@ -0,0 +1,65 @@
+//! Helpers for testing code that manipulates page tables on the host, shared by the tests of the platforms' HALs.
+//! These are only compiled with the `test_utils` feature, which the HALs enable for their tests.
+
+use crate::memory::{Frame, FrameAllocator, FrameSize, PhysicalAddress, Size4KiB, VirtualAddress};
+use alloc::vec::Vec;
+use core::{cell::Cell, ops::Range};
+
+#[repr(C, align(4096))]
+struct PhysicalFrame([u8; Size4KiB::SIZE]);
+
+/// Simulates a small amount of physical memory, starting at physical address `0`, that page tables can be
+/// allocated from. Physical memory is "mapped" at the address of the simulated memory, so the page tables can be
+/// walked as normal.
+pub struct TestMemory {
+    frames: Vec<PhysicalFrame>,
+    next_frame: Cell<usize>,
+    frames_freed: Cell<usize>,
+}
+
+impl TestMemory {
+    pub fn new(num_frames: usize) -> TestMemory {
+        let mut frames = Vec::with_capacity(num_frames);
+        for _ in 0..num_frames {
+            frames.push(PhysicalFrame([0; Size4KiB::SIZE]));
+        }
+        TestMemory { frames, next_frame: Cell::new(0), frames_freed: Cell::new(0) }
+    }
+
+    pub fn physical_base(&self) -> VirtualAddress {
+        VirtualAddress::new(self.frames.as_ptr() as usize)
+    }
+
+    pub fn frames_allocated(&self) -> usize {
+        self.next_frame.get()
+    }
+
+    /// The number of frames that have been freed. Freed frames aren't reused, so this can be compared with
+    /// `frames_allocated` to check that everything has been freed.
+    pub fn frames_freed(&self) -> usize {
+        self.frames_freed.get()
+    }
+}
+
+impl FrameAllocator<Size4KiB> for TestMemory {
+    fn allocate_n(&self, n: usize) -> Range<Frame> {
+        let start = self.next_frame.get();
+        assert!(start + n <= self.frames.len(), "Ran out of test memory");
+        self.next_frame.set(start + n);
+
+        Frame::starts_with(PhysicalAddress::new(start * Size4KiB::SIZE).unwrap())
+            ..Frame::starts_with(PhysicalAddress::new((start + n) * Size4KiB::SIZE).unwrap())
+    }
+
+    fn free_n(&self, _start: Frame, n: usize) {
+        self.frames_freed.set(self.frames_freed.get() + n);
+    }
+}
+
+pub fn virt(address: usize) -> VirtualAddress {
+    VirtualAddress::new(address)
+}
+
+pub fn phys(address: usize) -> PhysicalAddress {
+    PhysicalAddress::new(address).unwrap()
+}
@@ -0,0 +1,22 @@
+use core::time::Duration;
+
+/// A per-CPU timer, which raises an interrupt after a given amount of time, together with the platform's
+/// monotonic clock.
+pub trait Timer {
+    /// The vector the timer's interrupt is delivered through. The kernel must register a handler for this vector
+    /// with the platform's `InterruptController`, and unmask it, before setting the timer.
+    fn vector(&self) -> u32;
+
+    /// Get the current value of the monotonic clock, in nanoseconds. The clock starts at an arbitrary point during
+    /// the kernel's initialization, and never goes backwards.
+    fn monotonic_time(&self) -> u64;
+
+    /// Set the timer to interrupt once, after `duration` has passed. This replaces any previous setting.
+    fn set_one_shot(&self, duration: Duration);
+
+    /// Set the timer to interrupt every `period`, until it's stopped or set again.
+    fn set_periodic(&self, period: Duration);
+
+    /// Stop the timer, so it doesn't interrupt again until it's next set.
+    fn stop(&self);
+}
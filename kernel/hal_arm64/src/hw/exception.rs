@@ -0,0 +1,175 @@
+//! On AArch64, exceptions are taken to a vector table pointed to by `VBAR_EL1`. It has sixteen entries, each
+//! `0x80` bytes long: one for each kind of exception (synchronous, IRQ, FIQ, and SError), taken from each of four
+//! places (the current EL while using `SP_EL0`, the current EL while using `SP_ELx`, and a lower EL running in
+//! AArch64 or AArch32). The cause of a synchronous exception is then described by `ESR_EL1`.
+
+use crate::hw::registers::{instruction_barrier, read_sysreg, write_sysreg};
+use bit_field::BitField;
+use core::fmt;
+use hal::memory::VirtualAddress;
+
+/// The state of the code that was running when an exception was taken. The platform's vector table saves this on
+/// the stack, and restores it (with any changes made by the handler) when it returns with `eret`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    /// `x0` through `x30`. `x29` is the frame pointer, and `x30` is the link register.
+    pub registers: [u64; 31],
+    pub sp_el0: u64,
+    /// The address that `eret` returns to (`ELR_EL1`).
+    pub exception_link_register: u64,
+    /// The `PSTATE` that `eret` restores (`SPSR_EL1`).
+    pub saved_program_status: u64,
+}
+
+impl ExceptionFrame {
+    /// Whether the exception was taken from EL0. The bottom four bits of the saved `PSTATE` hold the EL (and stack
+    /// pointer selection) that was running.
+    pub fn is_from_user_mode(&self) -> bool {
+        self.saved_program_status.get_bits(0..4) == 0b0000
+    }
+}
+
+impl fmt::Debug for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, register) in self.registers.iter().enumerate() {
+            write!(f, "x{:<2} = {:#018x}", i, register)?;
+            f.write_str(if i % 4 == 3 { "\n" } else { "  " })?;
+        }
+        write!(
+            f,
+            "\nsp_el0 = {:#x}, elr = {:#x}, spsr = {:#x}",
+            self.sp_el0, self.exception_link_register, self.saved_program_status
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExceptionOrigin {
+    CurrentElSp0,
+    CurrentElSpx,
+    LowerElAarch64,
+    LowerElAarch32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExceptionKind {
+    Synchronous,
+    Irq,
+    Fiq,
+    SError,
+}
+
+/// Identifies the entry of the vector table that an exception was taken through.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Vector {
+    pub origin: ExceptionOrigin,
+    pub kind: ExceptionKind,
+}
+
+impl Vector {
+    /// Decode the index of an entry in the vector table (`0` to `15`, in the order they appear in the table).
+    pub fn from_index(index: u64) -> Vector {
+        let origin = match index / 4 {
+            0 => ExceptionOrigin::CurrentElSp0,
+            1 => ExceptionOrigin::CurrentElSpx,
+            2 => ExceptionOrigin::LowerElAarch64,
+            3 => ExceptionOrigin::LowerElAarch32,
+            _ => panic!("Invalid exception vector index: {}", index),
+        };
+        let kind = match index % 4 {
+            0 => ExceptionKind::Synchronous,
+            1 => ExceptionKind::Irq,
+            2 => ExceptionKind::Fiq,
+            _ => ExceptionKind::SError,
+        };
+
+        Vector { origin, kind }
+    }
+}
+
+/// The Exception Class of a synchronous exception, from `ESR_EL1.EC`. Classes we don't handle specially are
+/// reported as `Other`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExceptionClass {
+    Unknown,
+    /// An access to the floating-point or SIMD registers, while they're disabled by `CPACR_EL1.FPEN`.
+    FloatingPointAccess,
+    IllegalExecutionState,
+    SupervisorCall,
+    InstructionAbortLowerEl,
+    InstructionAbortSameEl,
+    PcAlignment,
+    DataAbortLowerEl,
+    DataAbortSameEl,
+    SpAlignment,
+    Breakpoint,
+    Other(u8),
+}
+
+/// The Exception Syndrome Register (`ESR_EL1`), which describes the cause of a synchronous exception (or an SError).
+#[derive(Clone, Copy)]
+pub struct Esr(pub u64);
+
+impl Esr {
+    pub fn read() -> Esr {
+        Esr(read_sysreg!(esr_el1))
+    }
+
+    pub fn class(&self) -> ExceptionClass {
+        match self.0.get_bits(26..32) as u8 {
+            0x00 => ExceptionClass::Unknown,
+            0x07 => ExceptionClass::FloatingPointAccess,
+            0x0e => ExceptionClass::IllegalExecutionState,
+            0x15 => ExceptionClass::SupervisorCall,
+            0x20 => ExceptionClass::InstructionAbortLowerEl,
+            0x21 => ExceptionClass::InstructionAbortSameEl,
+            0x22 => ExceptionClass::PcAlignment,
+            0x24 => ExceptionClass::DataAbortLowerEl,
+            0x25 => ExceptionClass::DataAbortSameEl,
+            0x26 => ExceptionClass::SpAlignment,
+            0x3c => ExceptionClass::Breakpoint,
+            other => ExceptionClass::Other(other),
+        }
+    }
+
+    /// The Instruction Specific Syndrome, the meaning of which depends on the exception class.
+    pub fn iss(&self) -> u32 {
+        self.0.get_bits(0..25) as u32
+    }
+
+    /// For data aborts, whether the access that faulted was a write.
+    pub fn is_write(&self) -> bool {
+        self.0.get_bit(6)
+    }
+
+    /// For instruction and data aborts, describe the Fault Status Code, which says why the access faulted.
+    pub fn fault_status(&self) -> &'static str {
+        match self.0.get_bits(0..6) {
+            0b000000..=0b000011 => "address size fault",
+            0b000100..=0b000111 => "translation fault",
+            0b001000..=0b001011 => "access flag fault",
+            0b001100..=0b001111 => "permission fault",
+            0b010000 => "synchronous external abort",
+            0b100001 => "alignment fault",
+            0b110000 => "TLB conflict abort",
+            _ => "unknown fault",
+        }
+    }
+}
+
+impl fmt::Debug for Esr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Esr({:#x}, class = {:?}, iss = {:#x})", self.0, self.class(), self.iss())
+    }
+}
+
+/// Install the vector table at `address`. The table must be aligned to 2KiB.
+///
+/// ### Safety
+/// `address` must point to a valid vector table, which must stay there for as long as it's installed.
+pub unsafe fn install_vector_table(address: VirtualAddress) {
+    assert!(address.is_aligned(0x800));
+    write_sysreg!(vbar_el1, usize::from(address) as u64);
+    instruction_barrier();
+}
@@ -0,0 +1,45 @@
+//! The ARM Generic Interrupt Controller (GIC) comes in several incompatible versions, which we have separate
+//! drivers for. This is the interface they share, so a platform can drive whichever one it finds at runtime.
+
+use hal::interrupts::{IpiTarget, TriggerMode};
+
+/// INTIDs from this one up are special, and are read from the acknowledge registers when there isn't an interrupt
+/// to handle (e.g. `1023` when there's no pending interrupt).
+pub const FIRST_SPECIAL_INTERRUPT: u32 = 1020;
+
+/// Interrupts below this INTID are specific to each CPU (SGIs and PPIs), and those above it are shared between all
+/// of them (SPIs).
+pub const FIRST_SPI: u32 = 32;
+
+/// An interrupt returned by `Gic::acknowledge`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AcknowledgedInterrupt {
+    pub intid: u32,
+    /// The value to pass to `Gic::end_of_interrupt` once the interrupt has been handled. This is the INTID, except
+    /// for SGIs on a GICv2, where it also identifies the CPU that sent the SGI.
+    pub eoi_value: u32,
+}
+
+/// Drivers access the GIC's registers directly, and so must only be used on one CPU at a time (registers that are
+/// banked per-CPU always refer to the CPU the method is called on).
+pub trait Gic {
+    /// The number of interrupts the GIC supports. Valid INTIDs are below this.
+    fn num_interrupts(&self) -> u32;
+
+    /// Acknowledge the highest-priority pending interrupt, and return it. This returns `None` if there isn't one
+    /// (e.g. because the interrupt that caused us to check was withdrawn by the device).
+    fn acknowledge(&self) -> Option<AcknowledgedInterrupt>;
+
+    /// Tell the GIC that the kernel has finished handling an interrupt returned by `acknowledge`, by passing its
+    /// `eoi_value`. Until this is done, interrupts of the same or lower priority won't be delivered.
+    fn end_of_interrupt(&self, eoi_value: u32);
+
+    /// Mask or unmask an interrupt. Interrupts start off masked, and are only delivered while unmasked.
+    fn set_masked(&self, interrupt: u32, masked: bool);
+
+    fn set_trigger_mode(&self, interrupt: u32, trigger_mode: TriggerMode);
+
+    /// Send a Software Generated Interrupt (`interrupt` must be below `16`). CPUs are identified by the affinity
+    /// fields of their `MPIDR_EL1`.
+    fn send_sgi(&self, target: IpiTarget, interrupt: u32);
+}
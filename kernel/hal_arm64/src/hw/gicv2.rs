@@ -0,0 +1,217 @@
+//! Driver for version 2 of the ARM Generic Interrupt Controller (GIC), such as the GIC-400 in the Raspberry Pi 4.
+//! It has two parts: the Distributor, which is shared between all the CPUs and decides where each interrupt is
+//! sent, and a CPU Interface for each CPU, through which interrupts are acknowledged. Both are accessed through
+//! memory-mapped registers, which must be mapped as device memory.
+//!
+//! Interrupts are numbered with an Interrupt ID (INTID):
+//!     - `0..16` are Software Generated Interrupts (SGIs), which CPUs use to interrupt each other
+//!     - `16..32` are Private Peripheral Interrupts (PPIs), which are specific to each CPU (e.g. its timer)
+//!     - `32..1020` are Shared Peripheral Interrupts (SPIs), which are raised by devices
+//!     - `1020..1024` are special, and `1023` is returned when there's no pending interrupt
+
+use crate::hw::gic::{AcknowledgedInterrupt, Gic, FIRST_SPECIAL_INTERRUPT, FIRST_SPI};
+use bit_field::BitField;
+use core::ptr;
+use hal::{
+    interrupts::{IpiTarget, TriggerMode},
+    memory::VirtualAddress,
+};
+
+/*
+ * Distributor registers. Many of these are arrays of registers, with a bit or field for each interrupt.
+ */
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_IGROUPR: usize = 0x080;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_ICPENDR: usize = 0x280;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_ICFGR: usize = 0xc00;
+const GICD_SGIR: usize = 0xf00;
+
+/*
+ * CPU Interface registers.
+ */
+const GICC_CTLR: usize = 0x000;
+const GICC_PMR: usize = 0x004;
+const GICC_BPR: usize = 0x008;
+const GICC_IAR: usize = 0x00c;
+const GICC_EOIR: usize = 0x010;
+
+/// The priority we give every interrupt. We don't use priorities yet, so they're all the same, and in the middle of
+/// the range so they're not masked by the priority mask.
+const DEFAULT_PRIORITY: u8 = 0xa0;
+
+pub struct GicV2 {
+    distributor: VirtualAddress,
+    cpu_interface: VirtualAddress,
+    num_interrupts: u32,
+}
+
+/*
+ * The GIC is only accessed through its registers, so it's fine to move it between threads.
+ */
+unsafe impl Send for GicV2 {}
+unsafe impl Sync for GicV2 {}
+
+impl GicV2 {
+    /// Create a `GicV2` that accesses the Distributor's registers at `distributor`, and the CPU Interface's at
+    /// `cpu_interface`.
+    ///
+    /// ### Safety
+    /// Both addresses must be mapped to the registers of a GICv2, as device memory.
+    pub unsafe fn new(distributor: VirtualAddress, cpu_interface: VirtualAddress) -> GicV2 {
+        let mut gic = GicV2 { distributor, cpu_interface, num_interrupts: 0 };
+
+        /*
+         * `ITLinesNumber` tells us how many SPIs the Distributor supports, in blocks of 32.
+         */
+        gic.num_interrupts = u32::min((gic.read_distributor(GICD_TYPER).get_bits(0..5) + 1) * 32, 1020);
+        gic
+    }
+
+    /// Initialise the Distributor, and the CPU Interface of the calling CPU. All interrupts are masked, and SPIs
+    /// are targetted at the calling CPU.
+    pub unsafe fn init(&self) {
+        self.write_distributor(GICD_CTLR, 0);
+
+        for interrupt in (0..self.num_interrupts).step_by(32) {
+            let index = (interrupt / 32) as usize * 4;
+            self.write_distributor(GICD_ICENABLER + index, 0xffff_ffff);
+            self.write_distributor(GICD_ICPENDR + index, 0xffff_ffff);
+
+            /*
+             * Put all the interrupts in Group 1, which can be signalled to Non-secure EL1. If the firmware has
+             * left us in the Non-secure state, these registers can't be accessed, but it should have done this
+             * for us.
+             */
+            self.write_distributor(GICD_IGROUPR + index, 0xffff_ffff);
+        }
+
+        /*
+         * The target registers of SGIs and PPIs are read-only, as they can only target the CPU they belong to.
+         * The priority and target registers have a byte for each interrupt.
+         */
+        let target = self.current_cpu_target();
+        for interrupt in 0..self.num_interrupts {
+            self.write_distributor_byte(GICD_IPRIORITYR + interrupt as usize, DEFAULT_PRIORITY);
+            if interrupt >= FIRST_SPI {
+                self.write_distributor_byte(GICD_ITARGETSR + interrupt as usize, target);
+            }
+        }
+
+        /*
+         * Enable forwarding of both groups of interrupts to the CPU Interfaces.
+         */
+        self.write_distributor(GICD_CTLR, 0b11);
+
+        /*
+         * Set up the CPU Interface. We allow all priorities through the priority mask, don't group priorities
+         * together for preemption, and then enable signalling of both groups.
+         */
+        self.write_cpu_interface(GICC_PMR, 0xff);
+        self.write_cpu_interface(GICC_BPR, 0);
+        self.write_cpu_interface(GICC_CTLR, 0b11);
+    }
+
+    /// Find the bit that targets the calling CPU in `GICD_ITARGETSR`. The target fields of the first eight
+    /// registers (those for the SGIs and PPIs) always read as the calling CPU's bit.
+    fn current_cpu_target(&self) -> u8 {
+        let targets = unsafe { self.read_distributor(GICD_ITARGETSR) };
+        (0..4).map(|i| targets.get_bits((i * 8)..(i * 8 + 8)) as u8).find(|&target| target != 0).unwrap_or(0x01)
+    }
+
+    unsafe fn read_distributor(&self, offset: usize) -> u32 {
+        ptr::read_volatile((self.distributor + offset).ptr())
+    }
+
+    unsafe fn write_distributor(&self, offset: usize, value: u32) {
+        ptr::write_volatile((self.distributor + offset).mut_ptr(), value);
+    }
+
+    unsafe fn write_distributor_byte(&self, offset: usize, value: u8) {
+        ptr::write_volatile((self.distributor + offset).mut_ptr(), value);
+    }
+
+    unsafe fn read_cpu_interface(&self, offset: usize) -> u32 {
+        ptr::read_volatile((self.cpu_interface + offset).ptr())
+    }
+
+    unsafe fn write_cpu_interface(&self, offset: usize, value: u32) {
+        ptr::write_volatile((self.cpu_interface + offset).mut_ptr(), value);
+    }
+}
+
+impl Gic for GicV2 {
+    fn num_interrupts(&self) -> u32 {
+        self.num_interrupts
+    }
+
+    fn acknowledge(&self) -> Option<AcknowledgedInterrupt> {
+        /*
+         * For SGIs, bits 10..13 of the IAR hold the ID of the CPU that sent it. The EOIR must be written with the
+         * same value, so we keep the whole thing.
+         */
+        let iar = unsafe { self.read_cpu_interface(GICC_IAR) };
+        match iar.get_bits(0..10) {
+            intid if intid >= FIRST_SPECIAL_INTERRUPT => None,
+            intid => Some(AcknowledgedInterrupt { intid, eoi_value: iar.get_bits(0..13) }),
+        }
+    }
+
+    fn end_of_interrupt(&self, eoi_value: u32) {
+        unsafe {
+            self.write_cpu_interface(GICC_EOIR, eoi_value);
+        }
+    }
+
+    fn set_masked(&self, interrupt: u32, masked: bool) {
+        assert!(interrupt < self.num_interrupts);
+        let offset = if masked { GICD_ICENABLER } else { GICD_ISENABLER } + (interrupt / 32) as usize * 4;
+        unsafe {
+            self.write_distributor(offset, 1 << (interrupt % 32));
+        }
+    }
+
+    fn set_trigger_mode(&self, interrupt: u32, trigger_mode: TriggerMode) {
+        /*
+         * Each interrupt has two bits in the configuration registers, the upper of which is set for
+         * edge-triggered interrupts. The configuration of SGIs can't be changed.
+         */
+        assert!(interrupt >= 16 && interrupt < self.num_interrupts);
+        let offset = GICD_ICFGR + (interrupt / 16) as usize * 4;
+        let bit = (interrupt % 16) as usize * 2 + 1;
+        unsafe {
+            let mut config = self.read_distributor(offset);
+            config.set_bit(bit, trigger_mode == TriggerMode::Edge);
+            self.write_distributor(offset, config);
+        }
+    }
+
+    fn send_sgi(&self, target: IpiTarget, interrupt: u32) {
+        assert!(interrupt < 16);
+
+        /*
+         * `GICD_SGIR` either targets a list of CPU Interfaces (one bit for each), or all of them except the
+         * calling CPU's. We assume that each CPU Interface is numbered by the `Aff0` field of its CPU's affinity,
+         * which is true of the single-cluster systems GICv2 is used on.
+         */
+        let mut value = 0u32;
+        value.set_bits(0..4, interrupt);
+        match target {
+            IpiTarget::Cpu(cpu) => {
+                value.set_bits(16..24, 1 << (cpu & 0x7));
+                value.set_bits(24..26, 0b00);
+            }
+            IpiTarget::AllOthers => {
+                value.set_bits(24..26, 0b01);
+            }
+        }
+
+        unsafe {
+            self.write_distributor(GICD_SGIR, value);
+        }
+    }
+}
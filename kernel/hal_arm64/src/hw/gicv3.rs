@@ -0,0 +1,270 @@
+//! Driver for version 3 of the ARM Generic Interrupt Controller, which is used by QEMU's `virt` machine and by
+//! newer ARM platforms. Like GICv2, it has a Distributor for SPIs, but each CPU also has a Redistributor, which
+//! configures its SGIs and PPIs. The CPU Interface is accessed through system registers, rather than memory-mapped
+//! registers. Interrupt IDs are the same as for GICv2 (see `gicv2`).
+//!
+//! We use affinity routing (which is how GICv3 is designed to be used), and put all interrupts in Non-secure
+//! Group 1.
+
+use crate::hw::{
+    gic::{AcknowledgedInterrupt, Gic, FIRST_SPECIAL_INTERRUPT, FIRST_SPI},
+    registers::{instruction_barrier, read_sysreg, write_sysreg},
+};
+use bit_field::BitField;
+use core::ptr;
+use hal::{
+    interrupts::{IpiTarget, TriggerMode},
+    memory::VirtualAddress,
+};
+
+/*
+ * Distributor registers.
+ */
+const GICD_CTLR: usize = 0x0000;
+const GICD_TYPER: usize = 0x0004;
+const GICD_IGROUPR: usize = 0x0080;
+const GICD_ISENABLER: usize = 0x0100;
+const GICD_ICENABLER: usize = 0x0180;
+const GICD_ICPENDR: usize = 0x0280;
+const GICD_IPRIORITYR: usize = 0x0400;
+const GICD_ICFGR: usize = 0x0c00;
+const GICD_IROUTER: usize = 0x6000;
+
+/// `GICD_CTLR.RWP` is set while a write to the `GICD_CTLR` or `GICD_ICENABLER` registers is taking effect.
+const GICD_CTLR_RWP: usize = 31;
+
+/*
+ * Registers in each Redistributor's `RD_base` frame.
+ */
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+
+/*
+ * Registers in each Redistributor's `SGI_base` frame, which follows `RD_base`. These configure the SGIs and PPIs
+ * of the Redistributor's CPU, and have the same layout as the Distributor's registers for the first 32 interrupts.
+ */
+const SGI_BASE: usize = 0x1_0000;
+const GICR_IGROUPR0: usize = SGI_BASE + 0x0080;
+const GICR_ISENABLER0: usize = SGI_BASE + 0x0100;
+const GICR_ICENABLER0: usize = SGI_BASE + 0x0180;
+const GICR_ICPENDR0: usize = SGI_BASE + 0x0280;
+const GICR_IPRIORITYR: usize = SGI_BASE + 0x0400;
+const GICR_ICFGR1: usize = SGI_BASE + 0x0c04;
+
+/// Each Redistributor has two 64KiB frames: `RD_base` and `SGI_base`.
+const REDISTRIBUTOR_STRIDE: usize = 0x2_0000;
+
+const DEFAULT_PRIORITY: u8 = 0xa0;
+
+pub struct GicV3 {
+    distributor: VirtualAddress,
+    /// The `RD_base` of the Redistributor of the CPU that initialised the GIC.
+    redistributor: VirtualAddress,
+    num_interrupts: u32,
+}
+
+/*
+ * The GIC is only accessed through its registers, so it's fine to move it between threads.
+ */
+unsafe impl Send for GicV3 {}
+unsafe impl Sync for GicV3 {}
+
+impl GicV3 {
+    /// Create a `GicV3` that accesses the Distributor's registers at `distributor`, and finds the calling CPU's
+    /// Redistributor in the region starting at `redistributors`. Returns `None` if it can't find it.
+    ///
+    /// ### Safety
+    /// Both addresses must be mapped to the registers of a GICv3, as device memory.
+    pub unsafe fn new(distributor: VirtualAddress, redistributors: VirtualAddress) -> Option<GicV3> {
+        /*
+         * `GICR_TYPER.Affinity_Value` holds the affinity of each Redistributor's CPU, in the same format as
+         * `MPIDR_EL1` (but packed into 32 bits), and `GICR_TYPER.Last` is set for the last one in the region.
+         */
+        let mpidr = read_sysreg!(mpidr_el1);
+        let affinity = (mpidr.get_bits(32..40) << 24) | mpidr.get_bits(0..24);
+
+        let mut redistributor = redistributors;
+        let redistributor = loop {
+            let typer: u64 = ptr::read_volatile((redistributor + GICR_TYPER).ptr());
+            if typer.get_bits(32..64) == affinity {
+                break redistributor;
+            }
+            if typer.get_bit(4) {
+                return None;
+            }
+            redistributor += REDISTRIBUTOR_STRIDE;
+        };
+
+        let mut gic = GicV3 { distributor, redistributor, num_interrupts: 0 };
+        gic.num_interrupts = u32::min((gic.read_distributor(GICD_TYPER).get_bits(0..5) + 1) * 32, 1020);
+        Some(gic)
+    }
+
+    /// Initialise the Distributor, and the Redistributor and CPU Interface of the calling CPU. All interrupts are
+    /// masked, and SPIs are routed to the calling CPU.
+    pub unsafe fn init(&self) {
+        /*
+         * Disable the Distributor, and enable affinity routing (`ARE_NS`).
+         */
+        self.write_distributor(GICD_CTLR, 0);
+        self.wait_for_distributor_write();
+        self.write_distributor(GICD_CTLR, 1 << 4);
+        self.wait_for_distributor_write();
+
+        let mpidr = read_sysreg!(mpidr_el1);
+        let route = (mpidr.get_bits(32..40) << 32) | mpidr.get_bits(0..24);
+        for interrupt in (FIRST_SPI..self.num_interrupts).step_by(32) {
+            let index = (interrupt / 32) as usize * 4;
+            self.write_distributor(GICD_ICENABLER + index, 0xffff_ffff);
+            self.write_distributor(GICD_ICPENDR + index, 0xffff_ffff);
+            self.write_distributor(GICD_IGROUPR + index, 0xffff_ffff);
+        }
+        for interrupt in FIRST_SPI..self.num_interrupts {
+            ptr::write_volatile(
+                (self.distributor + GICD_IPRIORITYR + interrupt as usize).mut_ptr(),
+                DEFAULT_PRIORITY,
+            );
+            ptr::write_volatile((self.distributor + GICD_IROUTER + interrupt as usize * 8).mut_ptr(), route);
+        }
+        self.wait_for_distributor_write();
+
+        /*
+         * Enable affinity routing, and forwarding of Group 1 interrupts.
+         */
+        self.write_distributor(GICD_CTLR, (1 << 4) | (1 << 1));
+        self.wait_for_distributor_write();
+
+        /*
+         * Wake up the Redistributor, by clearing `ProcessorSleep`, and then waiting for `ChildrenAsleep` to clear.
+         */
+        let mut waker = self.read_redistributor(GICR_WAKER);
+        waker.set_bit(1, false);
+        self.write_redistributor(GICR_WAKER, waker);
+        while self.read_redistributor(GICR_WAKER).get_bit(2) {}
+
+        self.write_redistributor(GICR_ICENABLER0, 0xffff_ffff);
+        self.write_redistributor(GICR_ICPENDR0, 0xffff_ffff);
+        self.write_redistributor(GICR_IGROUPR0, 0xffff_ffff);
+        for interrupt in 0..FIRST_SPI {
+            ptr::write_volatile(
+                (self.redistributor + GICR_IPRIORITYR + interrupt as usize).mut_ptr(),
+                DEFAULT_PRIORITY,
+            );
+        }
+
+        /*
+         * Enable the system register interface to the CPU Interface (`ICC_SRE_EL1.SRE`), let all priorities
+         * through the priority mask, don't group priorities for preemption, and enable Group 1 interrupts.
+         */
+        write_sysreg!(icc_sre_el1, read_sysreg!(icc_sre_el1) | 0b1);
+        instruction_barrier();
+        write_sysreg!(icc_pmr_el1, 0xff);
+        write_sysreg!(icc_bpr1_el1, 0);
+        write_sysreg!(icc_igrpen1_el1, 1);
+        instruction_barrier();
+    }
+
+    unsafe fn wait_for_distributor_write(&self) {
+        while self.read_distributor(GICD_CTLR).get_bit(GICD_CTLR_RWP) {}
+    }
+
+    unsafe fn read_distributor(&self, offset: usize) -> u32 {
+        ptr::read_volatile((self.distributor + offset).ptr())
+    }
+
+    unsafe fn write_distributor(&self, offset: usize, value: u32) {
+        ptr::write_volatile((self.distributor + offset).mut_ptr(), value);
+    }
+
+    unsafe fn read_redistributor(&self, offset: usize) -> u32 {
+        ptr::read_volatile((self.redistributor + offset).ptr())
+    }
+
+    unsafe fn write_redistributor(&self, offset: usize, value: u32) {
+        ptr::write_volatile((self.redistributor + offset).mut_ptr(), value);
+    }
+}
+
+impl Gic for GicV3 {
+    fn num_interrupts(&self) -> u32 {
+        self.num_interrupts
+    }
+
+    fn acknowledge(&self) -> Option<AcknowledgedInterrupt> {
+        /*
+         * We don't enable LPIs (which start at INTID `8192`), so anything from `1020` up is a special INTID.
+         */
+        match read_sysreg!(icc_iar1_el1).get_bits(0..24) as u32 {
+            intid if intid >= FIRST_SPECIAL_INTERRUPT => None,
+            intid => Some(AcknowledgedInterrupt { intid, eoi_value: intid }),
+        }
+    }
+
+    fn end_of_interrupt(&self, eoi_value: u32) {
+        unsafe {
+            write_sysreg!(icc_eoir1_el1, eoi_value as u64);
+        }
+    }
+
+    fn set_masked(&self, interrupt: u32, masked: bool) {
+        assert!(interrupt < self.num_interrupts);
+
+        /*
+         * SGIs and PPIs are configured through the Redistributor, and SPIs through the Distributor.
+         */
+        unsafe {
+            if interrupt < FIRST_SPI {
+                let offset = if masked { GICR_ICENABLER0 } else { GICR_ISENABLER0 };
+                self.write_redistributor(offset, 1 << interrupt);
+            } else {
+                let offset = if masked { GICD_ICENABLER } else { GICD_ISENABLER } + (interrupt / 32) as usize * 4;
+                self.write_distributor(offset, 1 << (interrupt % 32));
+            }
+        }
+    }
+
+    fn set_trigger_mode(&self, interrupt: u32, trigger_mode: TriggerMode) {
+        assert!(interrupt >= 16 && interrupt < self.num_interrupts);
+        let bit = (interrupt % 16) as usize * 2 + 1;
+
+        unsafe {
+            if interrupt < FIRST_SPI {
+                let mut config = self.read_redistributor(GICR_ICFGR1);
+                config.set_bit(bit, trigger_mode == TriggerMode::Edge);
+                self.write_redistributor(GICR_ICFGR1, config);
+            } else {
+                let offset = GICD_ICFGR + (interrupt / 16) as usize * 4;
+                let mut config = self.read_distributor(offset);
+                config.set_bit(bit, trigger_mode == TriggerMode::Edge);
+                self.write_distributor(offset, config);
+            }
+        }
+    }
+
+    fn send_sgi(&self, target: IpiTarget, interrupt: u32) {
+        assert!(interrupt < 16);
+
+        /*
+         * `ICC_SGI1R_EL1` either targets a list of CPUs (by their `Aff0`) within a single cluster (identified by
+         * `Aff2.Aff1`), or all CPUs except the calling one (`IRM`).
+         */
+        let mut value = 0u64;
+        value.set_bits(24..28, interrupt as u64);
+        match target {
+            IpiTarget::Cpu(cpu) => {
+                let cpu = cpu as u64;
+                value.set_bits(0..16, 1 << cpu.get_bits(0..4));
+                value.set_bits(16..24, cpu.get_bits(8..16));
+                value.set_bits(32..40, cpu.get_bits(16..24));
+            }
+            IpiTarget::AllOthers => {
+                value.set_bit(40, true);
+            }
+        }
+
+        unsafe {
+            write_sysreg!(icc_sgi1r_el1, value);
+        }
+        instruction_barrier();
+    }
+}
@@ -0,0 +1,8 @@
+pub mod exception;
+pub mod gic;
+pub mod gicv2;
+pub mod gicv3;
+pub mod pl011;
+pub mod registers;
+pub mod timer;
+pub mod tlb;
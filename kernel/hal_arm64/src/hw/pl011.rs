@@ -0,0 +1,112 @@
+//! Driver for the ARM PrimeCell UART (PL011), which is the primary UART on the Raspberry Pi, and on many other ARM
+//! platforms. Its registers are memory-mapped, so it must be accessed through a mapping of its physical address
+//! with a device memory type.
+
+use bit_field::BitField;
+use core::{fmt, ptr};
+use hal::memory::VirtualAddress;
+
+const DATA_REGISTER: usize = 0x00;
+const FLAG_REGISTER: usize = 0x18;
+const INTEGER_BAUD_RATE_DIVISOR: usize = 0x24;
+const FRACTIONAL_BAUD_RATE_DIVISOR: usize = 0x28;
+const LINE_CONTROL_REGISTER: usize = 0x2c;
+const CONTROL_REGISTER: usize = 0x30;
+const INTERRUPT_MASK_REGISTER: usize = 0x38;
+const INTERRUPT_CLEAR_REGISTER: usize = 0x44;
+
+/// Bits of the flag register.
+const RECEIVE_FIFO_EMPTY: usize = 4;
+const TRANSMIT_FIFO_FULL: usize = 5;
+const BUSY: usize = 3;
+
+pub struct Pl011 {
+    base: VirtualAddress,
+}
+
+/*
+ * The UART is only accessed through its registers, so it's fine to move it between threads.
+ */
+unsafe impl Send for Pl011 {}
+
+impl fmt::Write for Pl011 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            unsafe {
+                match byte {
+                    /*
+                     * Serial terminals expect both a carriage return and a line feed for newlines.
+                     */
+                    b'\n' => {
+                        self.write(b'\n');
+                        self.write(b'\r');
+                    }
+
+                    _ => self.write(byte),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Pl011 {
+    /// Create a `Pl011` that accesses the UART's registers at `base`.
+    ///
+    /// ### Safety
+    /// `base` must be mapped to the registers of a PL011, as device memory.
+    pub const unsafe fn new(base: VirtualAddress) -> Pl011 {
+        Pl011 { base }
+    }
+
+    /// Initialise the UART to transmit and receive 8-bit characters, with no parity bit and one stop bit, at
+    /// `baud_rate`. `clock_frequency` is the frequency of the UART's reference clock, in Hz. Interrupts from the
+    /// UART are masked.
+    pub unsafe fn initialise(&mut self, clock_frequency: u32, baud_rate: u32) {
+        /*
+         * Disable the UART, and wait for any character it's transmitting to finish, before we reprogram it.
+         */
+        self.write_register(CONTROL_REGISTER, 0);
+        while self.read_register(FLAG_REGISTER).get_bit(BUSY) {}
+
+        /*
+         * The baud rate divisor is `clock_frequency / (16 * baud_rate)`, with a 16-bit integer part and a 6-bit
+         * fractional part. We calculate it in 64ths to get both parts at once.
+         */
+        let divisor = (clock_frequency as u64 * 4) / baud_rate as u64;
+        self.write_register(INTEGER_BAUD_RATE_DIVISOR, divisor.get_bits(6..22) as u32);
+        self.write_register(FRACTIONAL_BAUD_RATE_DIVISOR, divisor.get_bits(0..6) as u32);
+
+        /*
+         * 8-bit words (`WLEN = 0b11`), with the FIFOs enabled (`FEN`). This must be written after the divisors,
+         * as that's when they're latched.
+         */
+        self.write_register(LINE_CONTROL_REGISTER, (0b11 << 5) | (1 << 4));
+
+        self.write_register(INTERRUPT_MASK_REGISTER, 0);
+        self.write_register(INTERRUPT_CLEAR_REGISTER, 0x7ff);
+
+        /*
+         * Enable the UART (`UARTEN`), and its transmitter (`TXE`) and receiver (`RXE`).
+         */
+        self.write_register(CONTROL_REGISTER, (1 << 0) | (1 << 8) | (1 << 9));
+    }
+
+    pub unsafe fn read(&self) -> u8 {
+        while self.read_register(FLAG_REGISTER).get_bit(RECEIVE_FIFO_EMPTY) {}
+        self.read_register(DATA_REGISTER) as u8
+    }
+
+    pub unsafe fn write(&mut self, value: u8) {
+        while self.read_register(FLAG_REGISTER).get_bit(TRANSMIT_FIFO_FULL) {}
+        self.write_register(DATA_REGISTER, value as u32);
+    }
+
+    unsafe fn read_register(&self, offset: usize) -> u32 {
+        ptr::read_volatile((self.base + offset).ptr())
+    }
+
+    unsafe fn write_register(&mut self, offset: usize, value: u32) {
+        ptr::write_volatile((self.base + offset).mut_ptr(), value);
+    }
+}
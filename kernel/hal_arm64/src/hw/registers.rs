@@ -0,0 +1,59 @@
+//! AArch64 system registers are accessed with the `mrs` and `msr` instructions, which encode the register in the
+//! instruction itself, so these are macros rather than functions that take the register as a parameter.
+//!
+//! System registers only exist on AArch64, but we let code that uses them compile for other architectures, so the
+//! paging code can be tested on the host. They panic if they're actually used there.
+
+pub macro read_sysreg($reg: ident) {{
+    #[cfg(target_arch = "aarch64")]
+    let result: u64 = {
+        let value: u64;
+
+        /*
+         * If this macro is used inside an unsafe block, this causes a warning, which can be unexpected and is
+         * noisy, so we suppress it here.
+         */
+        #[allow(unused_unsafe)]
+        unsafe {
+            asm!(concat!("mrs {}, ", stringify!($reg)), out(reg) value);
+        }
+        value
+    };
+    #[cfg(not(target_arch = "aarch64"))]
+    let result: u64 = read_not_on_aarch64(stringify!($reg));
+
+    result
+}}
+
+pub macro write_sysreg($reg: ident, $value: expr) {
+    /*
+     * This will cause a type-check error if $value isn't a u64.
+     */
+    let value_u64: u64 = $value;
+    #[cfg(target_arch = "aarch64")]
+    asm!(concat!("msr ", stringify!($reg), ", {}"),
+        in(reg) value_u64
+    );
+    #[cfg(not(target_arch = "aarch64"))]
+    write_not_on_aarch64(stringify!($reg), value_u64);
+}
+
+/// An Instruction Synchronization Barrier. Changes to system registers aren't guaranteed to be seen by the
+/// instructions after them until one of these (or an exception entry or return) is executed.
+pub fn instruction_barrier() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("isb");
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+pub fn read_not_on_aarch64(register: &str) -> u64 {
+    panic!("Tried to read system register {} on a non-AArch64 host", register);
+}
+
+/// This is unsafe so that callers of `write_sysreg` need an `unsafe` block on every architecture.
+#[cfg(not(target_arch = "aarch64"))]
+pub unsafe fn write_not_on_aarch64(register: &str, _value: u64) {
+    panic!("Tried to write system register {} on a non-AArch64 host", register);
+}
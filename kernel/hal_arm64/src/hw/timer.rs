@@ -0,0 +1,50 @@
+//! The ARM Generic Timer. Each CPU has a set of timers that count down against the system counter (which we also
+//! use as the monotonic clock), and raise a PPI when they expire. We use the EL1 Physical Timer, which raises
+//! PPI 14 (INTID 30) on most platforms - the device tree says which one it is.
+
+use crate::hw::registers::{instruction_barrier, read_sysreg, write_sysreg};
+use bit_field::BitField;
+use core::time::Duration;
+
+/// The frequency of the system counter, in Hz. This is set by the firmware.
+pub fn frequency() -> u64 {
+    read_sysreg!(cntfrq_el0)
+}
+
+/// Read the system counter.
+pub fn read_counter() -> u64 {
+    /*
+     * Reads of the counter can be speculated, so without the barrier, we could read a value from before earlier
+     * instructions.
+     */
+    instruction_barrier();
+    read_sysreg!(cntpct_el0)
+}
+
+/// Set the timer to fire once, after `duration` has passed. The timer is one-shot, so it must be set again after
+/// it fires to make it periodic.
+pub fn set_timeout(duration: Duration) {
+    let ticks = (duration.as_nanos() * frequency() as u128 / 1_000_000_000) as u64;
+
+    /*
+     * `CNTP_TVAL_EL0` is a signed 32-bit down-counter, so we can't set timeouts longer than this.
+     */
+    let ticks = u64::min(ticks, i32::max_value() as u64);
+
+    let mut control = 0u64;
+    control.set_bit(0, true); // ENABLE
+    control.set_bit(1, false); // IMASK
+    unsafe {
+        write_sysreg!(cntp_tval_el0, ticks);
+        write_sysreg!(cntp_ctl_el0, control);
+    }
+    instruction_barrier();
+}
+
+/// Stop the timer, so it doesn't fire again until it's set with `set_timeout`.
+pub fn disable() {
+    unsafe {
+        write_sysreg!(cntp_ctl_el0, 0);
+    }
+    instruction_barrier();
+}
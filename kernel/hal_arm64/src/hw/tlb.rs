@@ -0,0 +1,48 @@
+//! The TLB caches translations from the page tables, and so must be told when they change. We don't use ASIDs
+//! (Address Space Identifiers) yet, so every userspace address space is tagged with ASID `0`, and their
+//! (non-global) translations must be flushed whenever we switch between them. The kernel's translations are global,
+//! and so survive the switch.
+//!
+//! There's no TLB to maintain when the paging code is tested on the host, so these do nothing there.
+
+use hal::memory::VirtualAddress;
+
+/// Invalidate the translation for `address`, under any ASID, on every core in the Inner Shareable domain.
+pub fn invalidate_page(_address: VirtualAddress) {
+    /*
+     * The operand holds bits 12..56 of the address in its bottom 44 bits, and the bits above those must be zero, so
+     * we have to mask off the top bits of kernel addresses.
+     */
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("dsb ishst
+              tlbi vaae1is, {}
+              dsb ish
+              isb",
+            in(reg) ((usize::from(_address) >> 12) & ((1 << 44) - 1)) as u64
+        );
+    }
+}
+
+/// Flush all of the non-global translations, which belong to the userspace address space that was last installed
+/// in `TTBR0_EL1`.
+pub fn flush_non_global() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("dsb ishst
+              tlbi aside1, xzr
+              dsb nsh
+              isb");
+    }
+}
+
+/// Flush all of the translations cached by this core, including the kernel's global ones.
+pub fn flush() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("dsb ishst
+              tlbi vmalle1
+              dsb nsh
+              isb");
+    }
+}
@@ -0,0 +1,47 @@
+//! This module contains constants that define how the kernel address space is laid out on AArch64. Unlike on
+//! x86_64, the two halves of the address space are translated by separate sets of page tables: the lower half
+//! (virtual addresses `0x0000_0000_0000_0000` through `0x0000_ffff_ffff_ffff`) by the tables in `TTBR0_EL1`, and
+//! the upper half (`0xffff_0000_0000_0000` through `0xffff_ffff_ffff_ffff`) by the tables in `TTBR1_EL1`. The
+//! lower half belongs to userspace, and its tables are switched with the address space. The upper half belongs to
+//! the kernel, and its tables are installed once at boot and never change, so the kernel doesn't need to be
+//! mapped into every address space.
+//!
+//! The bottom of the kernel's half is a mapping of physical memory, which the kernel image is loaded into (the
+//! firmware loads it at a fixed physical address, so it's linked to run from there in the physical mapping). The
+//! top 1TiB is reserved for task kernel stacks, and the 1TiB below that for mapping device memory (the physical
+//! mapping is Normal memory, which mustn't be used to access devices).
+
+use hal::memory::{mebibytes, Bytes, PhysicalAddress, VirtualAddress};
+
+/// Userspace can use the whole of the lower half of the address space.
+pub const USER_ADDRESS_SPACE_END: VirtualAddress = VirtualAddress::new(0x0001_0000_0000_0000);
+
+pub const KERNEL_ADDRESS_SPACE_START: VirtualAddress = VirtualAddress::new(0xffff_0000_0000_0000);
+
+pub const PHYSICAL_MAPPING_BASE: VirtualAddress = KERNEL_ADDRESS_SPACE_START;
+
+/// Access a given physical address through the physical mapping. This cannot be used until the kernel page tables
+/// have been switched to.
+///
+/// # Safety
+/// This itself is safe, because to cause memory unsafety a raw pointer must be created and accessed from the
+/// `VirtualAddress`, which is unsafe.
+pub fn physical_to_virtual(address: PhysicalAddress) -> VirtualAddress {
+    PHYSICAL_MAPPING_BASE + usize::from(address)
+}
+
+/// A page that the kernel maps device memory into while it's accessing it (see `Platform::DEVICE_WINDOW`). This is
+/// the first page of the area reserved for device memory.
+pub const DEVICE_WINDOW: VirtualAddress = VirtualAddress::new(0xffff_fe00_0000_0000);
+
+/// Where the ECAM region of a PCIe root complex is mapped, in the area reserved for device memory. There's room for
+/// the 256MiB an ECAM region needs to cover all 256 buses.
+pub const ECAM_BASE: VirtualAddress = VirtualAddress::new(0xffff_fe01_0000_0000);
+
+pub const KERNEL_STACKS_BASE: VirtualAddress = VirtualAddress::new(0xffff_ff00_0000_0000);
+/*
+ * There is an imposed maximum number of tasks because of the simple way we're allocating task kernel stacks.
+ * This is currently 65536 with a task kernel stack size of 2MiB.
+ */
+pub const STACK_SLOT_SIZE: Bytes = mebibytes(2);
+pub const MAX_TASKS: usize = 65536;
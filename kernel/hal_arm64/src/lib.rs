@@ -1 +1,10 @@
 #![no_std]
+#![feature(asm, decl_macro)]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+pub mod hw;
+pub mod kernel_map;
+pub mod paging;
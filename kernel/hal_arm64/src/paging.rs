@@ -0,0 +1,1364 @@
+//! AArch64 page tables, using a 4KiB translation granule and 48-bit virtual addresses. This gives four levels of
+//! tables, like on x86_64: each level 0 entry covers 512GiB, each level 1 entry 1GiB, each level 2 entry 2MiB, and
+//! each level 3 entry a single 4KiB page. Level 1 and level 2 entries can also map a whole 1GiB or 2MiB block of
+//! memory directly, which is the equivalent of a huge page.
+
+use crate::hw::{
+    registers::{instruction_barrier, read_sysreg, write_sysreg},
+    tlb,
+};
+use bit_field::BitField;
+use bitflags::bitflags;
+use core::{
+    fmt,
+    marker::PhantomData,
+    ops::{Index, IndexMut, Range},
+};
+use hal::memory::{
+    CacheType,
+    Flags,
+    Frame,
+    FrameAllocator,
+    FrameSize,
+    Mapping,
+    Page,
+    PageTable,
+    PagingError,
+    PhysicalAddress,
+    Size1GiB,
+    Size2MiB,
+    Size4KiB,
+    VirtualAddress,
+};
+
+bitflags! {
+    pub struct EntryFlags : u64 {
+        const VALID                     = 1 << 0;
+        /// In level 0 to 2 tables, this marks an entry that points to the next level of table, rather than
+        /// mapping a block. In level 3 tables, it must be set for the entry to map a page.
+        const TABLE                     = 1 << 1;
+        const PAGE                      = Self::TABLE.bits;
+        /// The memory type is selected from `MAIR_EL1` by a three-bit index (`AttrIndx`). These are the indices of
+        /// the types we program into it (see `MAIR`) - write-back is index `0`.
+        const ATTR_WRITE_THROUGH        = 1 << 2;
+        const ATTR_WRITE_COMBINING      = 2 << 2;
+        const ATTR_UNCACHEABLE          = 3 << 2;
+        /// `AP[1]` - the memory can be accessed from EL0.
+        const USER_ACCESSIBLE           = 1 << 6;
+        /// `AP[2]` - the memory can't be written to (at any EL).
+        const READ_ONLY                 = 1 << 7;
+        const INNER_SHAREABLE           = 0b11 << 8;
+        /// The Access Flag. If this isn't set, the first access to the memory faults, which we don't make use of.
+        const ACCESSED                  = 1 << 10;
+        /// Translations for non-global entries are tagged with an ASID in the TLB. We use this for userspace
+        /// mappings, so they can be flushed without affecting the kernel's.
+        const NOT_GLOBAL                = 1 << 11;
+        const PRIVILEGED_EXECUTE_NEVER  = 1 << 53;
+        const USER_EXECUTE_NEVER        = 1 << 54;
+
+        /// This is the set of flags used for all non-terminal page tables (e.g. the ones that contain other page tables,
+        /// not actual page mappings). Table descriptors can restrict the permissions of the memory they map, but we
+        /// don't, so the actual permissions are always simply determined by the flags of the terminal entry.
+        const NON_TERMINAL_FLAGS = Self::VALID.bits | Self::TABLE.bits;
+    }
+}
+
+impl Default for EntryFlags {
+    fn default() -> EntryFlags {
+        EntryFlags::VALID
+    }
+}
+
+impl From<Flags> for EntryFlags {
+    fn from(flags: Flags) -> Self {
+        /*
+         * The kernel should never execute userspace memory, and userspace can't execute kernel memory anyway, so
+         * executable memory is only executable at the EL it's accessible from.
+         */
+        let execute_never = match (flags.executable, flags.user_accessible) {
+            (false, _) => EntryFlags::PRIVILEGED_EXECUTE_NEVER | EntryFlags::USER_EXECUTE_NEVER,
+            (true, true) => EntryFlags::PRIVILEGED_EXECUTE_NEVER,
+            (true, false) => EntryFlags::USER_EXECUTE_NEVER,
+        };
+
+        EntryFlags::VALID
+            | EntryFlags::ACCESSED
+            | EntryFlags::INNER_SHAREABLE
+            | execute_never
+            | if flags.writable { EntryFlags::empty() } else { EntryFlags::READ_ONLY }
+            | if flags.user_accessible { EntryFlags::USER_ACCESSIBLE } else { EntryFlags::empty() }
+            | match flags.cache_type {
+                CacheType::WriteBack => EntryFlags::empty(),
+                CacheType::WriteThrough => EntryFlags::ATTR_WRITE_THROUGH,
+                CacheType::WriteCombining => EntryFlags::ATTR_WRITE_COMBINING,
+                CacheType::Uncacheable => EntryFlags::ATTR_UNCACHEABLE,
+            }
+    }
+}
+
+impl From<EntryFlags> for Flags {
+    fn from(flags: EntryFlags) -> Self {
+        let user_accessible = flags.contains(EntryFlags::USER_ACCESSIBLE);
+        Flags {
+            writable: !flags.contains(EntryFlags::READ_ONLY),
+            executable: if user_accessible {
+                !flags.contains(EntryFlags::USER_EXECUTE_NEVER)
+            } else {
+                !flags.contains(EntryFlags::PRIVILEGED_EXECUTE_NEVER)
+            },
+            user_accessible,
+            cache_type: match flags.bits().get_bits(2..5) {
+                0 => CacheType::WriteBack,
+                1 => CacheType::WriteThrough,
+                2 => CacheType::WriteCombining,
+                _ => CacheType::Uncacheable,
+            },
+        }
+    }
+}
+
+/// The value we program into the Memory Attribute Indirection Register (`MAIR_EL1`). The memory type of a page is
+/// selected from this by the `AttrIndx` field of its entry. We use the first four attributes, one for each
+/// `CacheType`, in the same order as the x86_64 PAT:
+///     - `0` is Normal memory, Write-Back, with read and write allocation
+///     - `1` is Normal memory, Write-Through, with read and write allocation
+///     - `2` is Normal memory, Non-cacheable, which is the closest thing to write-combining
+///     - `3` is Device-nGnRnE memory, which is used for memory-mapped devices
+///
+/// The boot code programs this value itself, before Rust is entered, so the two must be kept in sync.
+pub const MAIR: u64 = {
+    const WRITE_BACK: u64 = 0xff;
+    const WRITE_THROUGH: u64 = 0xbb;
+    const NON_CACHEABLE: u64 = 0x44;
+    const DEVICE_NGNRNE: u64 = 0x00;
+
+    WRITE_BACK | (WRITE_THROUGH << 8) | (NON_CACHEABLE << 16) | (DEVICE_NGNRNE << 24)
+};
+
+/// Bits `12..48` of an entry (or of a `TTBRn_EL1`) hold the physical address of the memory or table it refers to.
+const ADDRESS_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+/// Represents an entry within a page table of any level. Contains a physical address to the next level (or to the
+/// physical memory region), and some flags.
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct Entry(u64);
+
+impl Entry {
+    pub fn unused() -> Entry {
+        Entry(0)
+    }
+
+    pub fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether this entry maps a block (a 2MiB block in a level 2 table, or a 1GiB block in a level 1 table),
+    /// rather than pointing to the next level of page table. This shouldn't be used on level 3 entries, which
+    /// always map pages.
+    pub fn is_block(&self) -> bool {
+        self.flags().contains(EntryFlags::VALID) && !self.flags().contains(EntryFlags::TABLE)
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        EntryFlags::from_bits_truncate(self.0)
+    }
+
+    pub fn address(&self) -> Option<PhysicalAddress> {
+        if self.flags().contains(EntryFlags::VALID) {
+            Some(PhysicalAddress::new((self.0 & ADDRESS_MASK) as usize).unwrap())
+        } else {
+            None
+        }
+    }
+
+    /// Set an entry to have a particular mapping. Passing `None` will set this entry as invalid, whereas passing
+    /// `Some` with a physical address and set of flags will populate an entry.
+    pub fn set(&mut self, entry: Option<(PhysicalAddress, EntryFlags)>) {
+        self.0 = match entry {
+            Some((address, flags)) => (usize::from(address) as u64) | (flags | EntryFlags::VALID).bits(),
+            None => 0,
+        };
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.flags().contains(EntryFlags::VALID) {
+            write!(f, "Invalid")
+        } else {
+            write!(f, "Address: {:#x}, flags: {:?}", self.address().unwrap(), self.flags())
+        }
+    }
+}
+
+pub enum Level0 {}
+pub enum Level1 {}
+pub enum Level2 {}
+pub enum Level3 {}
+
+pub trait TableLevel {}
+impl TableLevel for Level0 {}
+impl TableLevel for Level1 {}
+impl TableLevel for Level2 {}
+impl TableLevel for Level3 {}
+
+/// Tables of levels that implement `HierarchicalLevel` are page tables whose entries are other tables, as opposed
+/// to actual pages (like in level 3 tables). This makes accessing the next level type-safe, as the `next_table`
+/// methods are only implemented for tables that have child tables.
+pub trait HierarchicalLevel: TableLevel {
+    type NextLevel: TableLevel;
+}
+impl HierarchicalLevel for Level0 {
+    type NextLevel = Level1;
+}
+impl HierarchicalLevel for Level1 {
+    type NextLevel = Level2;
+}
+impl HierarchicalLevel for Level2 {
+    type NextLevel = Level3;
+}
+
+const ENTRY_COUNT: usize = 512;
+
+pub struct Table<L>
+where
+    L: TableLevel,
+{
+    entries: [Entry; ENTRY_COUNT],
+    _phantom: PhantomData<L>,
+}
+
+impl<L> Index<usize> for Table<L>
+where
+    L: TableLevel,
+{
+    type Output = Entry;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.entries[index]
+    }
+}
+
+impl<L> IndexMut<usize> for Table<L>
+where
+    L: TableLevel,
+{
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.entries[index]
+    }
+}
+
+impl<L> Table<L>
+where
+    L: TableLevel,
+{
+    pub fn zero(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.set(None);
+        }
+    }
+}
+
+impl<L> Table<L>
+where
+    L: HierarchicalLevel,
+{
+    /// Get a reference to the table at the given `index`, assuming the entirity of the physical address space is
+    /// mapped from `physical_base`. Returns `None` if the entry is empty, or if it maps a block.
+    pub fn next_table(&self, index: usize, physical_base: VirtualAddress) -> Option<&Table<L::NextLevel>> {
+        if self[index].is_block() {
+            return None;
+        }
+
+        self[index]
+            .address()
+            .map(|physical_address| physical_base + usize::from(physical_address))
+            .map(|virtual_address| unsafe { &*(virtual_address.ptr()) })
+    }
+
+    /// Get a mutable reference to the table at the given `index`, assuming the entirity of the physical address
+    /// space is mapped from `physical_base`. Returns `None` if the entry is empty, or if it maps a block.
+    pub fn next_table_mut(
+        &mut self,
+        index: usize,
+        physical_base: VirtualAddress,
+    ) -> Option<&mut Table<L::NextLevel>> {
+        if self[index].is_block() {
+            return None;
+        }
+
+        self[index]
+            .address()
+            .map(|physical_address| physical_base + usize::from(physical_address))
+            .map(|virtual_address| unsafe { &mut *(virtual_address.mut_ptr()) })
+    }
+
+    pub fn next_table_create<A>(
+        &mut self,
+        index: usize,
+        allocator: &A,
+        physical_base: VirtualAddress,
+    ) -> Result<&mut Table<L::NextLevel>, PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        /*
+         * If there's already a block mapped where we need a table, the whole region has already been mapped.
+         */
+        if self[index].is_block() {
+            return Err(PagingError::AlreadyMapped);
+        }
+
+        if self.next_table(index, physical_base).is_none() {
+            /*
+             * This entry is empty, so we create a new page table, zero it, and return that. The table must be
+             * zeroed before it's installed, as the hardware can walk it as soon as it is.
+             */
+            let table_frame = allocator.allocate();
+            let table: &mut Table<L::NextLevel> =
+                unsafe { &mut *((physical_base + usize::from(table_frame.start)).mut_ptr()) };
+            table.zero();
+
+            self.entries[index].set(Some((table_frame.start, EntryFlags::NON_TERMINAL_FLAGS)));
+            Ok(self.next_table_mut(index, physical_base).unwrap())
+        } else {
+            Ok(self.next_table_mut(index, physical_base).unwrap())
+        }
+    }
+
+    /// If the entry at `index` maps a block, replace it with a table of the next level that maps the same memory,
+    /// with the same flags, using pages of `page_size` bytes. This allows part of a block to be unmapped or remapped
+    /// without affecting the rest of it. Does nothing if the entry doesn't map a block. `address` is the virtual
+    /// address of the start of the block.
+    pub fn split_block<A>(
+        &mut self,
+        index: usize,
+        address: VirtualAddress,
+        page_size: usize,
+        allocator: &A,
+        physical_base: VirtualAddress,
+    ) where
+        A: FrameAllocator<Size4KiB>,
+    {
+        if !self[index].is_block() {
+            return;
+        }
+
+        let block_start = self[index].address().unwrap();
+        let flags = if page_size == Size4KiB::SIZE {
+            // In a level 3 table, entries must have this bit set to map a page
+            self[index].flags() | EntryFlags::PAGE
+        } else {
+            self[index].flags()
+        };
+
+        let table_frame = allocator.allocate();
+        let table: &mut Table<L::NextLevel> =
+            unsafe { &mut *((physical_base + usize::from(table_frame.start)).mut_ptr()) };
+        for (i, entry) in table.entries.iter_mut().enumerate() {
+            entry.set(Some((block_start + i * page_size, flags)));
+        }
+
+        /*
+         * The architecture doesn't allow us to replace the block with the table directly, as the TLB could end up
+         * with conflicting translations for the same address. Instead, we have to follow the "break-before-make"
+         * sequence: invalidate the entry, flush any translations of it from the TLB, and only then install the
+         * table. This means the memory is briefly unmapped, so the block can't contain anything we might need to
+         * access in the meantime (e.g. the code doing the splitting).
+         */
+        self.entries[index].set(None);
+        tlb::invalidate_page(address);
+        self.entries[index].set(Some((table_frame.start, EntryFlags::NON_TERMINAL_FLAGS)));
+    }
+}
+
+/// Which half of the address space a set of page tables translates. The lower half belongs to userspace and is
+/// translated by the tables in `TTBR0_EL1`, while the upper half belongs to the kernel and is translated by the
+/// tables in `TTBR1_EL1` (see `kernel_map`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Half {
+    Lower,
+    Upper,
+}
+
+impl Half {
+    /// The virtual address translated by the first entry of the level 0 table.
+    fn base(self) -> usize {
+        match self {
+            Half::Lower => 0x0000_0000_0000_0000,
+            Half::Upper => 0xffff_0000_0000_0000,
+        }
+    }
+}
+
+pub struct PageTableImpl {
+    l0_frame: Frame,
+    /// The virtual address at which physical memory is mapped in the environment that these page tables are being
+    /// constructed in. This is **not** a property of the set of page tables being mapped.
+    physical_base: VirtualAddress,
+    half: Half,
+}
+
+impl PageTableImpl {
+    pub fn new(l0_frame: Frame, physical_base: VirtualAddress, half: Half) -> PageTableImpl {
+        let mut table = PageTableImpl { l0_frame, physical_base, half };
+        Self::l0_mut(&mut table.l0_frame, table.physical_base).zero();
+        table
+    }
+
+    /// Create a `PageTableImpl` from a `Frame` that already contains a level 0 table. This is very unsafe because
+    /// it assumes that the frame contains a valid page table, and that no other `PageTableImpl`s currently exist
+    /// that use this same backing frame (as calling `mapper` on both could lead to two mutable references aliasing
+    /// the same data to exist, which is UB).
+    pub unsafe fn from_frame(l0_frame: Frame, physical_base: VirtualAddress, half: Half) -> PageTableImpl {
+        PageTableImpl { l0_frame, physical_base, half }
+    }
+
+    /// Create a `PageTableImpl` for the tables currently installed for the given half of the address space. This
+    /// has the same safety requirements as `from_frame`.
+    pub unsafe fn current(half: Half, physical_base: VirtualAddress) -> PageTableImpl {
+        let ttbr = match half {
+            Half::Lower => read_sysreg!(ttbr0_el1),
+            Half::Upper => read_sysreg!(ttbr1_el1),
+        };
+        let l0_address = PhysicalAddress::new((ttbr & ADDRESS_MASK) as usize).unwrap();
+        PageTableImpl::from_frame(Frame::starts_with(l0_address), physical_base, half)
+    }
+
+    /// Iterate over the present mappings in these page tables. See `PageTable::walk`.
+    pub fn mappings(&self) -> Mappings<'_> {
+        Mappings { page_table: self, cursor: 0, pending: None }
+    }
+
+    /// Find the page that contains `address`, if it's mapped, as a `Mapping` of that single page. Unlike searching
+    /// through `mappings`, this only walks the tables that translate `address`, like `translate`.
+    pub fn mapping_of(&self, address: VirtualAddress) -> Option<Mapping> {
+        use pebble_util::math::align_down;
+
+        let page = |entry: Entry, page_size: usize| {
+            Some(Mapping {
+                virtual_start: VirtualAddress::new(align_down(usize::from(address), page_size)),
+                physical_start: entry.address()?,
+                size: page_size,
+                page_size,
+                flags: Flags::from(entry.flags()),
+            })
+        };
+
+        let l1 = self.l0().next_table(address.l0_index(), self.physical_base)?;
+
+        let l1_entry = l1[address.l1_index()];
+        if l1_entry.is_block() {
+            return page(l1_entry, Size1GiB::SIZE);
+        }
+
+        let l2 = l1.next_table(address.l1_index(), self.physical_base)?;
+
+        let l2_entry = l2[address.l2_index()];
+        if l2_entry.is_block() {
+            return page(l2_entry, Size2MiB::SIZE);
+        }
+
+        let l3 = l2.next_table(address.l2_index(), self.physical_base)?;
+        page(l3[address.l3_index()], Size4KiB::SIZE)
+    }
+
+    fn l0(&self) -> &Table<Level0> {
+        unsafe { &*((self.physical_base + usize::from(self.l0_frame.start)).mut_ptr()) }
+    }
+
+    /// Get a mutable reference to the level 0 table of this set of page tables. This can't take a `&mut self` like
+    /// you'd normally write this, because then we borrow the entire struct and so can't access `physical_base`
+    /// nicely. Instead, we mutably borrow the level 0 frame to "represent" the borrow.
+    fn l0_mut(frame: &mut Frame, physical_base: VirtualAddress) -> &mut Table<Level0> {
+        unsafe { &mut *((physical_base + usize::from(frame.start)).mut_ptr()) }
+    }
+
+    /// The flags for a terminal entry in these page tables. Userspace's mappings are non-global, so they can be
+    /// flushed from the TLB when we switch address spaces, while the kernel's are kept.
+    fn entry_flags(&self, flags: Flags) -> EntryFlags {
+        match self.half {
+            Half::Lower => EntryFlags::from(flags) | EntryFlags::NOT_GLOBAL,
+            Half::Upper => EntryFlags::from(flags),
+        }
+    }
+}
+
+impl PageTable<Size4KiB> for PageTableImpl {
+    fn new_with_kernel_mapped<A>(_kernel_page_table: &Self, allocator: &A) -> Self
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        /*
+         * The kernel is mapped by its own tables in `TTBR1_EL1`, which are never switched, so an address space
+         * only needs tables for the lower half.
+         */
+        PageTableImpl::new(allocator.allocate(), crate::kernel_map::PHYSICAL_MAPPING_BASE, Half::Lower)
+    }
+
+    fn switch_to(&self) {
+        let ttbr = usize::from(self.l0_frame.start) as u64;
+
+        unsafe {
+            match self.half {
+                Half::Lower => {
+                    write_sysreg!(ttbr0_el1, ttbr);
+                    instruction_barrier();
+                    tlb::flush_non_global();
+                }
+                Half::Upper => {
+                    write_sysreg!(ttbr1_el1, ttbr);
+                    instruction_barrier();
+                    tlb::flush();
+                }
+            }
+        }
+    }
+
+    fn translate(&self, address: VirtualAddress) -> Option<PhysicalAddress> {
+        let l1 = self.l0().next_table(address.l0_index(), self.physical_base)?;
+
+        let l1_entry = l1[address.l1_index()];
+        if l1_entry.is_block() {
+            return Some(l1_entry.address()? + (usize::from(address) % Size1GiB::SIZE));
+        }
+
+        let l2 = l1.next_table(address.l1_index(), self.physical_base)?;
+
+        let l2_entry = l2[address.l2_index()];
+        if l2_entry.is_block() {
+            return Some(l2_entry.address()? + (usize::from(address) % Size2MiB::SIZE));
+        }
+
+        let l3 = l2.next_table(address.l2_index(), self.physical_base)?;
+        Some(l3[address.l3_index()].address()? + (usize::from(address) % Size4KiB::SIZE))
+    }
+
+    fn map<S, A>(&mut self, page: Page<S>, frame: Frame<S>, flags: Flags, allocator: &A) -> Result<(), PagingError>
+    where
+        S: FrameSize,
+        A: FrameAllocator<Size4KiB>,
+    {
+        let entry_flags = self.entry_flags(flags);
+
+        if S::SIZE == Size4KiB::SIZE {
+            let l3 = Self::l0_mut(&mut self.l0_frame, self.physical_base)
+                .next_table_create(page.start.l0_index(), allocator, self.physical_base)?
+                .next_table_create(page.start.l1_index(), allocator, self.physical_base)?
+                .next_table_create(page.start.l2_index(), allocator, self.physical_base)?;
+
+            if !l3[page.start.l3_index()].is_unused() {
+                return Err(PagingError::AlreadyMapped);
+            }
+
+            l3[page.start.l3_index()].set(Some((frame.start, entry_flags | EntryFlags::PAGE)));
+        } else if S::SIZE == Size2MiB::SIZE {
+            let l2 = Self::l0_mut(&mut self.l0_frame, self.physical_base)
+                .next_table_create(page.start.l0_index(), allocator, self.physical_base)?
+                .next_table_create(page.start.l1_index(), allocator, self.physical_base)?;
+
+            if !l2[page.start.l2_index()].is_unused() {
+                return Err(PagingError::AlreadyMapped);
+            }
+
+            l2[page.start.l2_index()].set(Some((frame.start, entry_flags)));
+        } else {
+            assert_eq!(S::SIZE, Size1GiB::SIZE);
+
+            let l1 = Self::l0_mut(&mut self.l0_frame, self.physical_base).next_table_create(
+                page.start.l0_index(),
+                allocator,
+                self.physical_base,
+            )?;
+
+            if !l1[page.start.l1_index()].is_unused() {
+                return Err(PagingError::AlreadyMapped);
+            }
+
+            l1[page.start.l1_index()].set(Some((frame.start, entry_flags)));
+        }
+
+        /*
+         * The TLB doesn't cache invalid entries, but we need a barrier to make sure the new entry is visible to the
+         * table walker before the memory is accessed.
+         */
+        barrier();
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    fn map_area<A>(
+        &mut self,
+        virtual_start: VirtualAddress,
+        physical_start: PhysicalAddress,
+        size: usize,
+        flags: Flags,
+        allocator: &A,
+    ) -> Result<(), PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        use pebble_util::math::{abs_difference, align_down};
+
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(physical_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        /*
+         * If the area is smaller than a single 2MiB block, or if the alignment of the physical and virtual regions
+         * means we'll never be able to use blocks, just map the whole area with 4KiB pages.
+         */
+        let align_mismatch =
+            abs_difference(usize::from(physical_start), usize::from(virtual_start)) % Size2MiB::SIZE != 0;
+        if size < Size2MiB::SIZE || align_mismatch {
+            let pages = Page::starts_with(virtual_start)..Page::starts_with(virtual_start + size);
+            let frames = Frame::starts_with(physical_start)..Frame::starts_with(physical_start + size);
+            return self.map_range::<Size4KiB, A>(pages, frames, flags, allocator);
+        }
+
+        let mut cursor = virtual_start;
+        while cursor < (virtual_start + size) {
+            let cursor_physical = PhysicalAddress::new(
+                usize::from(physical_start) + usize::from(cursor) - usize::from(virtual_start),
+            )
+            .unwrap();
+            let bytes_left = size - (usize::from(cursor) - usize::from(virtual_start));
+
+            if cursor.is_aligned(Size1GiB::SIZE)
+                && cursor_physical.is_aligned(Size1GiB::SIZE)
+                && bytes_left >= Size1GiB::SIZE
+            {
+                /*
+                 * We can fit at least one 1GiB block in, and both virtual and physical cursors have the correct
+                 * alignment. Map as much as we can with 1GiB blocks.
+                 */
+                let bytes_to_map = align_down(bytes_left, Size1GiB::SIZE);
+                let pages = Page::starts_with(cursor)..Page::starts_with(cursor + bytes_to_map);
+                let frames =
+                    Frame::starts_with(cursor_physical)..Frame::starts_with(cursor_physical + bytes_to_map);
+                self.map_range::<Size1GiB, A>(pages, frames, flags, allocator)?;
+                cursor += bytes_to_map;
+            } else if cursor.is_aligned(Size2MiB::SIZE)
+                && cursor_physical.is_aligned(Size2MiB::SIZE)
+                && bytes_left >= Size2MiB::SIZE
+            {
+                /*
+                 * We couldn't use a 1GiB block, but we can use 2MiB blocks. Map as much as we can.
+                 */
+                let bytes_to_map = align_down(bytes_left, Size2MiB::SIZE);
+                let pages = Page::starts_with(cursor)..Page::starts_with(cursor + bytes_to_map);
+                let frames =
+                    Frame::starts_with(cursor_physical)..Frame::starts_with(cursor_physical + bytes_to_map);
+                self.map_range::<Size2MiB, A>(pages, frames, flags, allocator)?;
+                cursor += bytes_to_map;
+            } else {
+                /*
+                 * We can't use any blocks, but we might be able to further in, if the data becomes more aligned. If
+                 * the next 2MiB-aligned address is still inside the range, stop there to have another go.
+                 * NOTE: `cursor` might be 2MiB-aligned at this location, so we start from the next address so we
+                 * don't get stuck here.
+                 */
+                let next_boundary = (cursor + 1).align_up(Size2MiB::SIZE);
+                let bytes_to_map = if next_boundary <= (virtual_start + size) {
+                    bytes_left - (usize::from(virtual_start) + size - usize::from(next_boundary))
+                } else {
+                    bytes_left
+                };
+                let pages = Page::starts_with(cursor)..Page::starts_with(cursor + bytes_to_map);
+                let frames =
+                    Frame::starts_with(cursor_physical)..Frame::starts_with(cursor_physical + bytes_to_map);
+                self.map_range::<Size4KiB, A>(pages, frames, flags, allocator)?;
+                cursor += bytes_to_map;
+            }
+        }
+
+        assert_eq!(cursor, virtual_start + size);
+        Ok(())
+    }
+
+    fn unmap<S, A>(&mut self, page: Page<S>, allocator: &A) -> Option<Frame<S>>
+    where
+        S: FrameSize,
+        A: FrameAllocator<Size4KiB>,
+    {
+        use pebble_util::math::align_down;
+
+        let physical_base = self.physical_base;
+        let address = page.start;
+        let block_start = |size: usize| VirtualAddress::new(align_down(usize::from(address), size));
+        let l1 =
+            Self::l0_mut(&mut self.l0_frame, physical_base).next_table_mut(address.l0_index(), physical_base)?;
+
+        /*
+         * If the page is part of a larger block, we split the block up until the page has its own entry, and then
+         * unmap just that entry. If the page is mapped with smaller pages instead, we don't unmap anything.
+         */
+        let entry = match S::SIZE {
+            Size4KiB::SIZE => {
+                l1.split_block(
+                    address.l1_index(),
+                    block_start(Size1GiB::SIZE),
+                    Size2MiB::SIZE,
+                    allocator,
+                    physical_base,
+                );
+                let l2 = l1.next_table_mut(address.l1_index(), physical_base)?;
+                l2.split_block(
+                    address.l2_index(),
+                    block_start(Size2MiB::SIZE),
+                    Size4KiB::SIZE,
+                    allocator,
+                    physical_base,
+                );
+                let l3 = l2.next_table_mut(address.l2_index(), physical_base)?;
+                &mut l3[address.l3_index()]
+            }
+            Size2MiB::SIZE => {
+                l1.split_block(
+                    address.l1_index(),
+                    block_start(Size1GiB::SIZE),
+                    Size2MiB::SIZE,
+                    allocator,
+                    physical_base,
+                );
+                let l2 = l1.next_table_mut(address.l1_index(), physical_base)?;
+                if !l2[address.l2_index()].is_block() {
+                    return None;
+                }
+                &mut l2[address.l2_index()]
+            }
+            Size1GiB::SIZE => {
+                if !l1[address.l1_index()].is_block() {
+                    return None;
+                }
+                &mut l1[address.l1_index()]
+            }
+
+            _ => panic!("Unimplemented page size!"),
+        };
+
+        let frame = Frame::starts_with(entry.address()?);
+        entry.set(None);
+        tlb::invalidate_page(address);
+
+        Some(frame)
+    }
+
+    fn protect<A>(&mut self, range: Range<VirtualAddress>, flags: Flags, allocator: &A)
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        use pebble_util::math::align_down;
+
+        assert!(range.start.is_aligned(Size4KiB::SIZE));
+        assert!(range.end.is_aligned(Size4KiB::SIZE));
+
+        let physical_base = self.physical_base;
+        let entry_flags = self.entry_flags(flags);
+        let end = usize::from(range.end);
+        let mut cursor = usize::from(range.start);
+
+        /*
+         * We walk the range an entry at a time. Where a whole block is inside the range, we change its entry
+         * directly, but if only part of it is, we split it up and then have another go at the same address. Where
+         * a table is missing, we skip over the whole region it would map.
+         */
+        while cursor < end {
+            let address = VirtualAddress::new(cursor);
+            let whole_page_in_range = |size: usize| cursor % size == 0 && end - cursor >= size;
+            let skip_to_next = |size: usize| align_down(cursor, size).saturating_add(size);
+            let block_start = |size: usize| VirtualAddress::new(align_down(cursor, size));
+
+            let l1 = match Self::l0_mut(&mut self.l0_frame, physical_base)
+                .next_table_mut(address.l0_index(), physical_base)
+            {
+                Some(l1) => l1,
+                None => {
+                    cursor = skip_to_next(Size1GiB::SIZE * ENTRY_COUNT);
+                    continue;
+                }
+            };
+
+            if l1[address.l1_index()].is_block() {
+                if whole_page_in_range(Size1GiB::SIZE) {
+                    let start = l1[address.l1_index()].address().unwrap();
+                    l1[address.l1_index()].set(Some((start, entry_flags)));
+                    tlb::invalidate_page(address);
+                    cursor += Size1GiB::SIZE;
+                    continue;
+                }
+                l1.split_block(
+                    address.l1_index(),
+                    block_start(Size1GiB::SIZE),
+                    Size2MiB::SIZE,
+                    allocator,
+                    physical_base,
+                );
+            }
+
+            let l2 = match l1.next_table_mut(address.l1_index(), physical_base) {
+                Some(l2) => l2,
+                None => {
+                    cursor = skip_to_next(Size1GiB::SIZE);
+                    continue;
+                }
+            };
+
+            if l2[address.l2_index()].is_block() {
+                if whole_page_in_range(Size2MiB::SIZE) {
+                    let start = l2[address.l2_index()].address().unwrap();
+                    l2[address.l2_index()].set(Some((start, entry_flags)));
+                    tlb::invalidate_page(address);
+                    cursor += Size2MiB::SIZE;
+                    continue;
+                }
+                l2.split_block(
+                    address.l2_index(),
+                    block_start(Size2MiB::SIZE),
+                    Size4KiB::SIZE,
+                    allocator,
+                    physical_base,
+                );
+            }
+
+            let l3 = match l2.next_table_mut(address.l2_index(), physical_base) {
+                Some(l3) => l3,
+                None => {
+                    cursor = skip_to_next(Size2MiB::SIZE);
+                    continue;
+                }
+            };
+
+            if let Some(start) = l3[address.l3_index()].address() {
+                l3[address.l3_index()].set(Some((start, entry_flags | EntryFlags::PAGE)));
+                tlb::invalidate_page(address);
+            }
+            cursor += Size4KiB::SIZE;
+        }
+    }
+
+    fn walk<F>(&self, f: F)
+    where
+        F: FnMut(Mapping),
+    {
+        self.mappings().for_each(f);
+    }
+
+    fn free_tables<A>(&mut self, allocator: &A)
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let physical_base = self.physical_base;
+        let free = |entry: Entry| allocator.free_n(Frame::starts_with(entry.address().unwrap()), 1);
+        let l0 = self.l0();
+
+        /*
+         * The kernel has its own tables in `TTBR1_EL1`, so every table reachable from here belongs to this set of
+         * page tables. Entries that map blocks don't point to tables, so `next_table` skips them.
+         */
+        for l0_index in 0..ENTRY_COUNT {
+            if let Some(l1) = l0.next_table(l0_index, physical_base) {
+                for l1_index in 0..ENTRY_COUNT {
+                    if let Some(l2) = l1.next_table(l1_index, physical_base) {
+                        for l2_index in 0..ENTRY_COUNT {
+                            if l2.next_table(l2_index, physical_base).is_some() {
+                                free(l2[l2_index]);
+                            }
+                        }
+                        free(l1[l1_index]);
+                    }
+                }
+                free(l0[l0_index]);
+            }
+        }
+
+        allocator.free_n(self.l0_frame, 1);
+    }
+}
+
+/// Make sure that changes to the page tables are visible to the table walker before we go on to access the memory
+/// they map.
+fn barrier() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!(
+            "dsb ishst
+              isb"
+        );
+    }
+}
+
+/// The size of each half of the address space.
+const HALF_SIZE: usize = 1 << 48;
+
+/// An iterator over the present mappings in a set of page tables, created by `PageTableImpl::mappings`. Adjacent
+/// pages are coalesced in the same way as by `PageTable::walk`.
+pub struct Mappings<'a> {
+    page_table: &'a PageTableImpl,
+    /// The offset into the half of the address space translated by the tables of the next address to look for a
+    /// page at. This is always page-aligned, and we're finished once it reaches `HALF_SIZE`.
+    cursor: usize,
+    /// The mapping we're currently building up, which is returned when we find a page that can't be added to it.
+    pending: Option<Mapping>,
+}
+
+impl<'a> Mappings<'a> {
+    /// Find the next present page, and move the cursor past it. Missing tables are skipped over, like in
+    /// `PageTable::protect`.
+    fn next_page(&mut self) -> Option<Mapping> {
+        use pebble_util::math::align_down;
+
+        let physical_base = self.page_table.physical_base;
+        let half_base = self.page_table.half.base();
+        let page = |cursor: usize, entry: Entry, page_size: usize| Mapping {
+            virtual_start: VirtualAddress::new(half_base + cursor),
+            physical_start: entry.address().unwrap(),
+            size: page_size,
+            page_size,
+            flags: Flags::from(entry.flags()),
+        };
+
+        while self.cursor < HALF_SIZE {
+            let cursor = self.cursor;
+            let address = VirtualAddress::new(cursor);
+            let skip_to_next = |size: usize| align_down(cursor, size) + size;
+
+            let l1 = match self.page_table.l0().next_table(address.l0_index(), physical_base) {
+                Some(l1) => l1,
+                None => {
+                    self.cursor = skip_to_next(Size1GiB::SIZE * ENTRY_COUNT);
+                    continue;
+                }
+            };
+
+            let l1_entry = l1[address.l1_index()];
+            if l1_entry.is_block() {
+                self.cursor = skip_to_next(Size1GiB::SIZE);
+                return Some(page(cursor, l1_entry, Size1GiB::SIZE));
+            }
+
+            let l2 = match l1.next_table(address.l1_index(), physical_base) {
+                Some(l2) => l2,
+                None => {
+                    self.cursor = skip_to_next(Size1GiB::SIZE);
+                    continue;
+                }
+            };
+
+            let l2_entry = l2[address.l2_index()];
+            if l2_entry.is_block() {
+                self.cursor = skip_to_next(Size2MiB::SIZE);
+                return Some(page(cursor, l2_entry, Size2MiB::SIZE));
+            }
+
+            let l3 = match l2.next_table(address.l2_index(), physical_base) {
+                Some(l3) => l3,
+                None => {
+                    self.cursor = skip_to_next(Size2MiB::SIZE);
+                    continue;
+                }
+            };
+
+            self.cursor += Size4KiB::SIZE;
+            let l3_entry = l3[address.l3_index()];
+            if l3_entry.address().is_some() {
+                return Some(page(cursor, l3_entry, Size4KiB::SIZE));
+            }
+        }
+
+        None
+    }
+}
+
+impl<'a> Iterator for Mappings<'a> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        loop {
+            let page = match self.next_page() {
+                Some(page) => page,
+                None => return self.pending.take(),
+            };
+
+            match self.pending {
+                Some(ref mut pending)
+                    if pending.page_size == page.page_size
+                        && pending.flags == page.flags
+                        && usize::from(pending.virtual_start) + pending.size
+                            == usize::from(page.virtual_start)
+                        && usize::from(pending.physical_start) + pending.size
+                            == usize::from(page.physical_start) =>
+                {
+                    pending.size += page.size;
+                }
+                _ => {
+                    if let Some(mapping) = self.pending.replace(page) {
+                        return Some(mapping);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub trait VirtualAddressEx {
+    fn l0_index(self) -> usize;
+    fn l1_index(self) -> usize;
+    fn l2_index(self) -> usize;
+    fn l3_index(self) -> usize;
+}
+
+impl VirtualAddressEx for VirtualAddress {
+    fn l0_index(self) -> usize {
+        usize::from(self).get_bits(39..48)
+    }
+
+    fn l1_index(self) -> usize {
+        usize::from(self).get_bits(30..39)
+    }
+
+    fn l2_index(self) -> usize {
+        usize::from(self).get_bits(21..30)
+    }
+
+    fn l3_index(self) -> usize {
+        usize::from(self).get_bits(12..21)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hal::test_utils::{phys, virt, TestMemory};
+    use std::vec::Vec;
+
+    fn flags() -> Flags {
+        Flags { writable: true, user_accessible: true, ..Default::default() }
+    }
+
+    fn user_page_table(memory: &TestMemory) -> PageTableImpl {
+        PageTableImpl::new(memory.allocate(), memory.physical_base(), Half::Lower)
+    }
+
+    #[test]
+    fn test_map_and_translate() {
+        let memory = TestMemory::new(16);
+        let mut page_table = user_page_table(&memory);
+
+        page_table
+            .map(Page::<Size4KiB>::starts_with(virt(0x1000)), Frame::starts_with(phys(0x5000)), flags(), &memory)
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size2MiB>::starts_with(virt(0x20_0000)),
+                Frame::starts_with(phys(0x60_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size1GiB>::starts_with(virt(0x4000_0000)),
+                Frame::starts_with(phys(0x8000_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        assert_eq!(page_table.translate(virt(0x1234)), Some(phys(0x5234)));
+        assert_eq!(page_table.translate(virt(0x2000)), None);
+        assert_eq!(page_table.translate(virt(0x2f_0123)), Some(phys(0x6f_0123)));
+        assert_eq!(page_table.translate(virt(0x40_0000)), None);
+        assert_eq!(page_table.translate(virt(0x5234_5678)), Some(phys(0x9234_5678)));
+        assert_eq!(page_table.translate(virt(0x8000_0000)), None);
+
+        /*
+         * Memory that's already mapped by a block can't be mapped again with smaller pages.
+         */
+        assert!(matches!(
+            page_table.map(
+                Page::<Size4KiB>::starts_with(virt(0x20_1000)),
+                Frame::starts_with(phys(0x1000)),
+                flags(),
+                &memory
+            ),
+            Err(PagingError::AlreadyMapped)
+        ));
+        assert!(matches!(
+            page_table.map(
+                Page::<Size2MiB>::starts_with(virt(0x4020_0000)),
+                Frame::starts_with(phys(0x20_0000)),
+                flags(),
+                &memory
+            ),
+            Err(PagingError::AlreadyMapped)
+        ));
+    }
+
+    #[test]
+    fn test_entry_formats() {
+        let memory = TestMemory::new(16);
+        let mut page_table = user_page_table(&memory);
+        page_table
+            .map(Page::<Size4KiB>::starts_with(virt(0x1000)), Frame::starts_with(phys(0x5000)), flags(), &memory)
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size2MiB>::starts_with(virt(0x20_0000)),
+                Frame::starts_with(phys(0x60_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        /*
+         * Pages must have the `PAGE` bit set, blocks must not, and both must be non-global in the lower half.
+         */
+        let l2 = page_table
+            .l0()
+            .next_table(0, memory.physical_base())
+            .and_then(|l1| l1.next_table(0, memory.physical_base()))
+            .unwrap();
+        let l3 = l2.next_table(0, memory.physical_base()).unwrap();
+        assert_eq!(l3[1].flags(), EntryFlags::from(flags()) | EntryFlags::PAGE | EntryFlags::NOT_GLOBAL);
+        assert!(l2[1].is_block());
+        assert_eq!(l2[1].flags(), EntryFlags::from(flags()) | EntryFlags::NOT_GLOBAL);
+        assert_eq!(l2[0].flags(), EntryFlags::NON_TERMINAL_FLAGS);
+
+        let kernel_memory = TestMemory::new(16);
+        let mut kernel_page_table =
+            PageTableImpl::new(kernel_memory.allocate(), kernel_memory.physical_base(), Half::Upper);
+        kernel_page_table
+            .map(
+                Page::<Size4KiB>::starts_with(virt(0x1000)),
+                Frame::starts_with(phys(0x5000)),
+                Flags::default(),
+                &kernel_memory,
+            )
+            .unwrap();
+        assert!(!kernel_page_table.mappings().next().unwrap().flags.user_accessible);
+        let l3 = kernel_page_table
+            .l0()
+            .next_table(0, kernel_memory.physical_base())
+            .and_then(|l1| l1.next_table(0, kernel_memory.physical_base()))
+            .and_then(|l2| l2.next_table(0, kernel_memory.physical_base()))
+            .unwrap();
+        assert!(!l3[1].flags().contains(EntryFlags::NOT_GLOBAL));
+    }
+
+    #[test]
+    fn test_flags() {
+        /*
+         * Every combination of `Flags` should survive being turned into an entry and back.
+         */
+        for &writable in &[false, true] {
+            for &executable in &[false, true] {
+                for &user_accessible in &[false, true] {
+                    for &cache_type in &[
+                        CacheType::WriteBack,
+                        CacheType::WriteThrough,
+                        CacheType::WriteCombining,
+                        CacheType::Uncacheable,
+                    ] {
+                        let flags = Flags { writable, executable, user_accessible, cache_type };
+                        assert_eq!(Flags::from(EntryFlags::from(flags)), flags);
+                    }
+                }
+            }
+        }
+
+        /*
+         * The kernel should never be able to execute userspace memory.
+         */
+        let user_code = EntryFlags::from(Flags { executable: true, user_accessible: true, ..Default::default() });
+        assert!(user_code.contains(EntryFlags::PRIVILEGED_EXECUTE_NEVER));
+        assert!(!user_code.contains(EntryFlags::USER_EXECUTE_NEVER));
+    }
+
+    #[test]
+    fn test_cache_types() {
+        /*
+         * Check that each `CacheType` selects an attribute of `MAIR` with the right memory type.
+         */
+        let attribute = |cache_type| {
+            let index = EntryFlags::from(Flags { cache_type, ..Default::default() }).bits().get_bits(2..5);
+            MAIR.get_bits((index as usize * 8)..(index as usize * 8 + 8))
+        };
+
+        assert_eq!(attribute(CacheType::WriteBack), 0xff);
+        assert_eq!(attribute(CacheType::WriteThrough), 0xbb);
+        assert_eq!(attribute(CacheType::WriteCombining), 0x44);
+        assert_eq!(attribute(CacheType::Uncacheable), 0x00);
+    }
+
+    #[test]
+    fn test_split_blocks() {
+        let memory = TestMemory::new(16);
+        let mut page_table = user_page_table(&memory);
+        page_table
+            .map(
+                Page::<Size1GiB>::starts_with(virt(0x4000_0000)),
+                Frame::starts_with(phys(0x8000_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        let frames_before_split = memory.frames_allocated();
+
+        /*
+         * Unmapping a 4KiB page should split the 1GiB block into 2MiB blocks, and then split one of those into
+         * pages.
+         */
+        assert_eq!(
+            page_table.unmap(Page::<Size4KiB>::starts_with(virt(0x4060_0000)), &memory),
+            Some(Frame::starts_with(phys(0x8060_0000)))
+        );
+        assert_eq!(memory.frames_allocated(), frames_before_split + 2);
+        assert_eq!(page_table.translate(virt(0x4060_0000)), None);
+        assert_eq!(page_table.translate(virt(0x4060_1000)), Some(phys(0x8060_1000)));
+        assert_eq!(page_table.translate(virt(0x405f_f000)), Some(phys(0x805f_f000)));
+        assert_eq!(page_table.translate(virt(0x7fff_ffff)), Some(phys(0xbfff_ffff)));
+
+        assert_eq!(
+            page_table.unmap(Page::<Size2MiB>::starts_with(virt(0x4020_0000)), &memory),
+            Some(Frame::starts_with(phys(0x8020_0000)))
+        );
+        assert_eq!(page_table.translate(virt(0x4020_1000)), None);
+
+        let l2 = page_table
+            .l0()
+            .next_table(0, memory.physical_base())
+            .and_then(|l1| l1.next_table(1, memory.physical_base()))
+            .unwrap();
+        assert!(l2[0].is_block());
+        assert!(!l2[3].is_block());
+        let l3 = l2.next_table(3, memory.physical_base()).unwrap();
+        assert_eq!(l3[1].flags(), EntryFlags::from(flags()) | EntryFlags::PAGE | EntryFlags::NOT_GLOBAL);
+    }
+
+    #[test]
+    fn test_protect() {
+        let memory = TestMemory::new(16);
+        let mut page_table = user_page_table(&memory);
+        let read_only = Flags { user_accessible: true, ..Default::default() };
+
+        page_table
+            .map_range(
+                Page::<Size4KiB>::starts_with(virt(0x1000))..Page::starts_with(virt(0x4000)),
+                Frame::starts_with(phys(0x5000))..Frame::starts_with(phys(0x8000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map_range(
+                Page::<Size2MiB>::starts_with(virt(0x20_0000))..Page::starts_with(virt(0x60_0000)),
+                Frame::starts_with(phys(0x60_0000))..Frame::starts_with(phys(0xa0_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        page_table.protect(virt(0x2000)..virt(0x40_2000), read_only, &memory);
+
+        let flags_of =
+            |address| page_table.mappings().find(|mapping| mapping.contains(virt(address))).unwrap().flags;
+        assert_eq!(flags_of(0x1000), flags());
+        assert_eq!(flags_of(0x2000), read_only);
+        assert_eq!(flags_of(0x20_0000), read_only);
+        assert_eq!(flags_of(0x40_1000), read_only);
+        assert_eq!(flags_of(0x40_2000), flags());
+        assert_eq!(page_table.translate(virt(0x40_2234)), Some(phys(0x80_2234)));
+    }
+
+    #[test]
+    fn test_mappings() {
+        let memory = TestMemory::new(16);
+        let mut page_table = user_page_table(&memory);
+        let read_only = Flags { user_accessible: true, ..Default::default() };
+
+        page_table
+            .map_range(
+                Page::<Size4KiB>::starts_with(virt(0x1000))..Page::starts_with(virt(0x4000)),
+                Frame::starts_with(phys(0x5000))..Frame::starts_with(phys(0x8000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(Page::<Size4KiB>::starts_with(virt(0x4000)), Frame::starts_with(phys(0x8000)), read_only, &memory)
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size1GiB>::starts_with(virt(0x4000_0000)),
+                Frame::starts_with(phys(0x8000_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size4KiB>::starts_with(virt(0xffff_ffff_f000)),
+                Frame::starts_with(phys(0x20_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        let mapping = |virtual_start, physical_start, size, page_size, flags| Mapping {
+            virtual_start: virt(virtual_start),
+            physical_start: phys(physical_start),
+            size,
+            page_size,
+            flags,
+        };
+        assert_eq!(
+            page_table.mappings().collect::<Vec<_>>(),
+            vec![
+                mapping(0x1000, 0x5000, 0x3000, Size4KiB::SIZE, flags()),
+                mapping(0x4000, 0x8000, 0x1000, Size4KiB::SIZE, read_only),
+                mapping(0x4000_0000, 0x8000_0000, Size1GiB::SIZE, Size1GiB::SIZE, flags()),
+                mapping(0xffff_ffff_f000, 0x20_0000, 0x1000, Size4KiB::SIZE, flags()),
+            ]
+        );
+
+        assert_eq!(
+            page_table.mapping_of(virt(0x4321)),
+            Some(mapping(0x4000, 0x8000, 0x1000, Size4KiB::SIZE, read_only))
+        );
+        assert_eq!(
+            page_table.mapping_of(virt(0x4abc_0000)),
+            Some(mapping(0x4000_0000, 0x8000_0000, Size1GiB::SIZE, Size1GiB::SIZE, flags()))
+        );
+        assert_eq!(page_table.mapping_of(virt(0x5000)), None);
+    }
+
+    #[test]
+    fn test_free_tables() {
+        let memory = TestMemory::new(16);
+        let mut page_table = user_page_table(&memory);
+        page_table
+            .map(Page::<Size4KiB>::starts_with(virt(0x1000)), Frame::starts_with(phys(0x5000)), flags(), &memory)
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size2MiB>::starts_with(virt(0x20_0000)),
+                Frame::starts_with(phys(0x60_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size1GiB>::starts_with(virt(0x80_0000_0000)),
+                Frame::starts_with(phys(0x8000_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        page_table.free_tables(&memory);
+        assert_eq!(memory.frames_freed(), memory.frames_allocated());
+    }
+}
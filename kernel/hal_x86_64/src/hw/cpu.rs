@@ -8,11 +8,30 @@ use core::str;
 
 pub struct SupportedFeatures {
     pub xsave: bool,
+    /// Whether the TSC runs at a constant rate, regardless of the processor's power state. If it does, it can be
+    /// used as a clock.
+    pub invariant_tsc: bool,
+    /// Supervisor Mode Execution Prevention - stops the kernel executing code in user pages.
+    pub smep: bool,
+    /// Supervisor Mode Access Prevention - stops the kernel accessing user pages, except when it explicitly
+    /// allows it with `stac`.
+    pub smap: bool,
+    /// User-Mode Instruction Prevention - stops userspace using instructions like `sgdt` and `sidt` that leak
+    /// information about the kernel.
+    pub umip: bool,
+    /// Whether TLB entries can be tagged with Process-Context Identifiers, so they don't have to be flushed when
+    /// switching address space.
+    pub pcid: bool,
+    /// Whether the `invpcid` instruction is supported, which can invalidate TLB entries tagged with any PCID.
+    pub invpcid: bool,
+    /// Whether the memory types used by pages can be configured with the Page Attribute Table.
+    pub pat: bool,
 }
 
 /// Describes information we know about the system we're running on.
 pub struct CpuInfo {
     pub max_supported_standard_level: u32,
+    pub max_supported_extended_level: u32,
     pub vendor: Vendor,
     pub model_info: ModelInfo,
     pub supported_features: SupportedFeatures,
@@ -26,13 +45,33 @@ impl CpuInfo {
     pub fn new() -> CpuInfo {
         let processor_cpuid = cpuid(CpuidEntry::ProcessorInfo);
         let vendor_id_cpuid = cpuid(CpuidEntry::VendorId);
+        let max_supported_extended_level = cpuid(CpuidEntry::ExtendedFunctionInfo).a;
         let vendor = decode_vendor(&vendor_id_cpuid);
         let model_info = decode_model_info(processor_cpuid.a);
-        let supported_features = decode_supported_features(processor_cpuid.c, processor_cpuid.d);
+        let power_management_d = if max_supported_extended_level >= CpuidEntry::PowerManagement as u32 {
+            cpuid(CpuidEntry::PowerManagement).d
+        } else {
+            0
+        };
+        let (extended_features_b, extended_features_c) =
+            if vendor_id_cpuid.a >= CpuidEntry::ExtendedFeatures as u32 {
+                let extended_features = cpuid(CpuidEntry::ExtendedFeatures);
+                (extended_features.b, extended_features.c)
+            } else {
+                (0, 0)
+            };
+        let supported_features = decode_supported_features(
+            processor_cpuid.c,
+            processor_cpuid.d,
+            extended_features_b,
+            extended_features_c,
+            power_management_d,
+        );
         let hypervisor_info = decode_hypervisor_info();
 
         CpuInfo {
             max_supported_standard_level: vendor_id_cpuid.a,
+            max_supported_extended_level,
             vendor,
             model_info,
             supported_features,
@@ -100,6 +139,33 @@ impl CpuInfo {
         // running on.
         None
     }
+
+    /// Get the frequency the TSC runs at (in Hz), if we can calculate it. This is only meaningful if the TSC is
+    /// invariant, as otherwise it can change while we're running.
+    pub fn tsc_frequency(&self) -> Option<u64> {
+        /*
+         * If we're running under a hypervisor, it may tell us the frequency of the virtual TSC.
+         */
+        if let Some(ref hypervisor_info) = self.hypervisor_info {
+            if let Some(tsc_freq) = hypervisor_info.tsc_frequency {
+                return Some(tsc_freq);
+            }
+        }
+
+        /*
+         * Otherwise, we can calculate it from the core crystal clock frequency and the ratio between the two, if
+         * they're both enumerated.
+         */
+        if self.max_supported_standard_level >= 0x15 {
+            let tsc_entry = cpuid(CpuidEntry::TscFrequency);
+
+            if tsc_entry.a != 0 && tsc_entry.b != 0 && tsc_entry.c != 0 {
+                return Some(tsc_entry.c as u64 * tsc_entry.b as u64 / tsc_entry.a as u64);
+            }
+        }
+
+        None
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -154,6 +220,7 @@ pub struct HypervisorInfo {
     pub vendor: HypervisorVendor,
     pub max_leaf: u32,
     pub apic_frequency: Option<u32>,
+    pub tsc_frequency: Option<u64>,
 }
 
 /// This is used to reinterpret the bytes of the vendor strings that are spread across the three
@@ -195,6 +262,7 @@ enum CpuidEntry {
     ///
     /// C = feature info (below are for individual bits. 1 = support)
     ///     0 = SSE3
+    ///     17 = PCID
     ///     19 = SSE4.1
     ///     20 = SSE4.2
     ///     21 = x2APIC
@@ -208,9 +276,23 @@ enum CpuidEntry {
     ///     0 = x87 FPU
     ///     4 = RDTSC and CR4.TSC
     ///     15 = CMOV
+    ///     16 = PAT
     ///     19 = CLFLUSH
     ProcessorInfo = 0x01,
 
+    /// Sub-leaf 0:
+    /// A = maximum supported sub-leaf
+    ///
+    /// B = feature info (below are for individual bits. 1 = support)
+    ///     7 = SMEP
+    ///     10 = INVPCID
+    ///     20 = SMAP
+    ///
+    /// C = feature info (below are for individual bits. 1 = support)
+    ///     2 = UMIP
+    /// (this list only includes things we are currently interested in)
+    ExtendedFeatures = 0x07,
+
     /// A = denominator
     /// B = numerator
     /// C = core crystal clock frequency
@@ -222,9 +304,16 @@ enum CpuidEntry {
     /// B,C,D = vendor ID string
     HypervisorVendor = 0x4000_0000,
 
-    /// A = (virtual) TSC frequency
+    /// A = (virtual) TSC frequency in kHz
     /// B = (virtual) bus (local APIC timer) frequency in kHz
     HypervisorFrequencies = 0x4000_0010,
+
+    /// A = maximum supported extended level
+    ExtendedFunctionInfo = 0x8000_0000,
+
+    /// D = power management features (below are for individual bits. 1 = support)
+    ///     8 = invariant TSC
+    PowerManagement = 0x8000_0007,
 }
 
 fn decode_vendor(vendor_id: &CpuidResult) -> Vendor {
@@ -250,8 +339,23 @@ fn decode_model_info(model_info: u32) -> ModelInfo {
     ModelInfo { family, model, stepping, extended_family, extended_model }
 }
 
-fn decode_supported_features(processor_info_c: u32, processor_info_d: u32) -> SupportedFeatures {
-    SupportedFeatures { xsave: processor_info_c.get_bit(26) }
+fn decode_supported_features(
+    processor_info_c: u32,
+    processor_info_d: u32,
+    extended_features_b: u32,
+    extended_features_c: u32,
+    power_management_d: u32,
+) -> SupportedFeatures {
+    SupportedFeatures {
+        xsave: processor_info_c.get_bit(26),
+        invariant_tsc: power_management_d.get_bit(8),
+        smep: extended_features_b.get_bit(7),
+        smap: extended_features_b.get_bit(20),
+        umip: extended_features_c.get_bit(2),
+        pcid: processor_info_c.get_bit(17),
+        invpcid: extended_features_b.get_bit(10),
+        pat: processor_info_d.get_bit(16),
+    }
 }
 
 fn decode_hypervisor_info() -> Option<HypervisorInfo> {
@@ -280,25 +384,39 @@ fn decode_hypervisor_info() -> Option<HypervisorInfo> {
     };
 
     /*
-     * If cpuid has the hypervisor timing leaf, use the bus frequency of that.
-     * NOTE: this is in kHz, so we convert to Hz
+     * If cpuid has the hypervisor timing leaf, use the TSC and bus frequencies from that.
+     * NOTE: these are in kHz, so we convert to Hz
      * NOTE: for this to exist under KVM, the `vmware-cpuid-freq` and `invtsc` cpu flags must be
      * set.
      */
-    let apic_frequency =
-        if max_leaf >= 0x4000_0010 { Some(cpuid(CpuidEntry::HypervisorFrequencies).b * 1000) } else { None };
+    let (apic_frequency, tsc_frequency) = if max_leaf >= 0x4000_0010 {
+        let frequencies = cpuid(CpuidEntry::HypervisorFrequencies);
+        (Some(frequencies.b * 1000), Some(frequencies.a as u64 * 1000))
+    } else {
+        (None, None)
+    };
 
-    Some(HypervisorInfo { vendor, max_leaf, apic_frequency })
+    Some(HypervisorInfo { vendor, max_leaf, apic_frequency, tsc_frequency })
+}
+
+/// Get the initial local APIC ID of the CPU this is called on. This is unique for each CPU, and so can be used to
+/// identify it.
+pub fn current_apic_id() -> u32 {
+    cpuid(CpuidEntry::ProcessorInfo).b.get_bits(24..32)
 }
 
 fn cpuid(entry: CpuidEntry) -> CpuidResult {
     let (a, b, c, d): (u64, u32, u32, u32);
 
+    /*
+     * Some leaves have sub-leaves, which are selected by `ecx`. We only use the first sub-leaf of any leaf, so
+     * always zero it.
+     */
     unsafe {
         asm!("cpuid",
              inlateout("rax") (entry as u64) => a,
              out("ebx") b,
-             out("ecx") c,
+             inlateout("ecx") 0 => c,
              out("edx") d
         );
     }
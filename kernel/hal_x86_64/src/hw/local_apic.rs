@@ -1,5 +1,6 @@
-use core::ptr;
-use hal::memory::VirtualAddress;
+use bit_field::BitField;
+use core::{ptr, time::Duration};
+use hal::{interrupts::IpiTarget, memory::VirtualAddress};
 
 /// Represents a register in the local APIC's configuration area.
 pub struct LocalApicRegister {
@@ -22,6 +23,12 @@ impl LocalApicRegister {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
+}
+
 pub struct LocalApic(VirtualAddress);
 
 impl LocalApic {
@@ -29,6 +36,11 @@ impl LocalApic {
         LocalApic(address)
     }
 
+    /// Get the ID of this local APIC. This is used to target interrupts at the processor it belongs to.
+    pub fn id(&self) -> u8 {
+        unsafe { self.register(0x20).read() }.get_bits(24..32) as u8
+    }
+
     pub unsafe fn enable(&self, spurious_vector: u8) {
         /*
          * - Enable the local APIC by setting bit 8
@@ -37,61 +49,103 @@ impl LocalApic {
         self.register(0xf0).write((1 << 8) | u32::from(spurious_vector));
     }
 
-    /// Set the local APIC timer to interrupt every `duration` ms, and then enable it. The timer
-    /// will signal on the specified vector. The frequency of the local APIC must be passed (in Hz), and
+    /// Set the local APIC timer to interrupt after `duration`, either once or every `duration`, and then enable it.
+    /// The timer will signal on the specified vector. The frequency of the local APIC must be passed (in Hz), and
     /// can sometimes be retrieved from the `CpuInfo`.
-    pub fn enable_timer(&self, duration: u32, apic_frequency: u32, vector: u8) {
+    pub fn set_timer(&self, mode: TimerMode, duration: Duration, apic_frequency: u32, vector: u8) {
+        /*
+         * Calculate the number of ticks in `duration`. We divide the frequency by 16 because we will set the
+         * divider to 16, so the given frequency will be 16 times faster than the count will be decremented by.
+         */
+        let ticks = duration.as_nanos() * u128::from(apic_frequency / 16) / 1_000_000_000;
+        let ticks = u128::min(ticks, u128::from(u32::max_value())) as u32;
+
         /*
-         * Calculate the number of ticks in one millisecond. We also divide by 16 because we will
-         * set the divider to 16, so the given frequency will be 16 times faster than the count
-         * will be decremented by.
+         * An initial count of `0` stops the timer, so a duration shorter than a tick has to wait for a whole one.
          */
-        let ticks_in_1ms = apic_frequency / 1000 / 16;
-        let ticks = duration * ticks_in_1ms;
+        let ticks = u32::max(ticks, 1);
 
         /*
-         * Start the APIC timer in Periodic mode with a divide value of 16, to interrupt every
-         * `duration` ms.
+         * Start the APIC timer with a divide value of 16. Bit 17 of the LVT entry selects Periodic mode.
          *
          * We pick 16 as the divider here because some hardware apparently has issues with using a
          * divider of 1, which would be the simplest.
          */
+        let mut lvt_entry = u32::from(vector);
+        lvt_entry.set_bit(17, mode == TimerMode::Periodic);
         unsafe {
             self.register(0x3e0).write(0x3); // Step 1: Set the divider to 16
-            self.register(0x320).write(u32::from(vector) | 0x20000); // Step 2: enable the timer
+            self.register(0x320).write(lvt_entry); // Step 2: enable the timer
             self.register(0x380).write(ticks); // Step 3: Set the initial count
         }
+    }
+
+    /// Work out the frequency of the local APIC (in Hz), for when it can't be found from the `CpuInfo`. We do this
+    /// by counting how many times the timer is decremented while `wait` waits for `duration`. This stops the timer.
+    pub fn calibrate_frequency<F>(&self, duration: Duration, wait: F) -> u32
+    where
+        F: FnOnce(Duration),
+    {
+        unsafe {
+            /*
+             * Start the timer counting down from its maximum value, with its interrupt masked, and with the same
+             * divider that `set_timer` uses.
+             */
+            self.register(0x3e0).write(0x3);
+            self.register(0x320).write(1 << 16);
+            self.register(0x380).write(u32::max_value());
+
+            wait(duration);
+
+            let ticks = u32::max_value() - self.register(0x390).read();
+            self.stop_timer();
+            (u128::from(ticks) * 16 * 1_000_000_000 / duration.as_nanos()) as u32
+        }
+    }
 
+    /// Stop the local APIC timer. It can be started again with `set_timer`.
+    pub fn stop_timer(&self) {
+        unsafe {
+            self.register(0x380).write(0);
+        }
+    }
+
+    /// Mask or unmask the local APIC timer's interrupt. The timer keeps counting while it's masked.
+    pub fn set_timer_masked(&self, masked: bool) {
+        unsafe {
+            let mut lvt_entry = self.register(0x320).read();
+            lvt_entry.set_bit(16, masked);
+            self.register(0x320).write(lvt_entry);
+        }
+    }
+
+    /// Send a fixed IPI through `vector` to the local APICs of other processors. `IpiTarget::Cpu` takes the ID of
+    /// the target's local APIC.
+    pub fn send_ipi(&self, target: IpiTarget, vector: u8) {
         /*
-         * TODO: we used to calibrate the timer by timing with the PIC. This could still be a good
-         * backup if the cpuid doesn't have the info we need:
+         * The destination goes in the high half of the Interrupt Command Register, and writing to the low half
+         * sends the IPI. The delivery mode (Fixed) and destination mode (Physical) are both represented by zeros,
+         * and a shorthand of `0b11` targets all processors except this one.
          */
-        // unsafe {
-        //     /*
-        //     * Set divide value to 16 and initial counter value to -1. We use 16 because apparently
-        //     * some hardware has issues with other divide values (especially 1, which would be the
-        //     * simplest otherwise). 16 seems to be the most supported.
-        //     */
-        //     Self::register(0x3e0).write(0x3);
-        //     Self::register(0x380).write(0xffff_ffff);
-
-        //     /*
-        //      * Sleep for 10ms with the PIT and then stop the APIC timer
-        //      */
-        //     ::pit::PIT.do_sleep(10);
-        //     Self::register(0x320).write(0x10000);
-
-        //     let ticks_in_10ms = 0xffff_ffff - Self::register(0x390).read() as usize;
-        //     trace!("Timing of local APIC bus frequency complete");
-
-        //     /*
-        //     * Start the APIC timer in Periodic mode with a divide value of 16 again, to interrupt
-        //     * every 10 ms on the given vector.
-        //     */
-        //     Self::register(0x320).write(u32::from(vector) | 0x20000);
-        //     Self::register(0x3e0).write(0x3);
-        //     Self::register(0x380).write(((ticks_in_10ms / 10) * duration) as u32);
-        // }
+        let mut command = u32::from(vector);
+        command.set_bit(14, true); // Assert
+        let destination = match target {
+            IpiTarget::Cpu(apic_id) => apic_id as u8,
+            IpiTarget::AllOthers => {
+                command.set_bits(18..20, 0b11);
+                0
+            }
+        };
+
+        unsafe {
+            self.register(0x310).write(u32::from(destination) << 24);
+            self.register(0x300).write(command);
+
+            /*
+             * Wait for the IPI to be sent (`Delivery Status` is set while it's pending).
+             */
+            while self.register(0x300).read().get_bit(12) {}
+        }
     }
 
     pub unsafe fn register(&self, offset: usize) -> LocalApicRegister {
@@ -4,6 +4,7 @@ pub mod i8259_pic;
 pub mod idt;
 pub mod io_apic;
 pub mod local_apic;
+pub mod pit;
 pub mod port;
 pub mod registers;
 pub mod serial;
@@ -0,0 +1,54 @@
+//! The Programmable Interval Timer (PIT) is an old, but almost universally available, timer with a known frequency.
+//! We don't use it to raise interrupts, but it's useful for calibrating timers whose frequency we can't find out any
+//! other way (like the local APIC timer and the TSC on some processors).
+
+use super::port::Port;
+use core::time::Duration;
+
+/// The frequency the PIT's counters are decremented at, in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// The longest duration `busy_wait` can wait for. The PIT's counters are 16 bits wide, so this is about 54ms.
+pub const MAX_WAIT: Duration = Duration::from_nanos(0xffff * 1_000_000_000 / PIT_FREQUENCY);
+
+pub struct Pit {
+    channel_2_data: Port<u8>,
+    mode_command: Port<u8>,
+    /// Port `0x61` controls the gate of channel 2 (bit 0), and lets us read its output (bit 5). Bit 1 connects the
+    /// output to the PC speaker, which we keep turned off.
+    channel_2_control: Port<u8>,
+}
+
+impl Pit {
+    pub const unsafe fn new() -> Pit {
+        Pit { channel_2_data: Port::new(0x42), mode_command: Port::new(0x43), channel_2_control: Port::new(0x61) }
+    }
+
+    /// Spin until `duration` has passed, using channel 2 of the PIT. `duration` must not be longer than
+    /// `MAX_WAIT`. This doesn't use interrupts, so can be used before they're set up.
+    pub fn busy_wait(&mut self, duration: Duration) {
+        assert!(duration <= MAX_WAIT);
+        let count = (duration.as_nanos() * u128::from(PIT_FREQUENCY) / 1_000_000_000) as u16;
+
+        unsafe {
+            /*
+             * Disable the gate (which stops the counter) and the speaker, and then program channel 2 to count down
+             * from `count` in mode 0 (Interrupt On Terminal Count), with the count written as a low and then a high
+             * byte. In mode 0, the channel's output goes high when the count reaches zero.
+             */
+            let control = self.channel_2_control.read() & !0b11;
+            self.channel_2_control.write(control);
+            self.mode_command.write(0b1011_0000);
+            self.channel_2_data.write(count as u8);
+            self.channel_2_data.write((count >> 8) as u8);
+
+            /*
+             * Enable the gate to start counting down, and wait for the output to go high.
+             */
+            self.channel_2_control.write(control | 0b1);
+            while self.channel_2_control.read() & (1 << 5) == 0 {}
+
+            self.channel_2_control.write(control);
+        }
+    }
+}
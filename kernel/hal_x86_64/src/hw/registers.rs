@@ -106,7 +106,15 @@ impl fmt::Debug for CpuFlags {
 pub const CR4_RESTRICT_RDTSC: usize = 2;
 pub const CR4_ENABLE_PAE: usize = 5;
 pub const CR4_ENABLE_GLOBAL_PAGES: usize = 7;
+/// If this is set, instructions like `sgdt` and `sidt` can only be used in Ring 0.
+pub const CR4_ENABLE_UMIP: usize = 11;
+/// If this is set, the bottom 12 bits of `CR3` hold the PCID of the current address space.
+pub const CR4_ENABLE_PCID: usize = 17;
 pub const CR4_XSAVE_ENABLE_BIT: usize = 18;
+/// If this is set, the kernel can't execute code in user-accessible pages.
+pub const CR4_ENABLE_SMEP: usize = 20;
+/// If this is set, the kernel can't access user-accessible pages, unless `RFLAGS.AC` is set (with `stac`).
+pub const CR4_ENABLE_SMAP: usize = 21;
 
 /// Read a control register. The name of the control register should be passed as any of: `CR0`,
 /// `CR1`, `CR2`, `CR3`, `CR4`, `CR8`.
@@ -162,6 +170,14 @@ pub const IA32_FMASK: u32 = 0xc000_0084;
 /// A virtual address can be stored in this MSR, and acts as the base of the GS segment.
 pub const IA32_GS_BASE: u32 = 0xc000_0101;
 
+/// The Page Attribute Table. This holds eight memory types, and the `PAT`, `PCD`, and `PWT` bits of a page-table
+/// entry select which of them the page uses.
+pub const IA32_PAT: u32 = 0x277;
+
+/// Describes the state of the processor after a machine-check exception has occured. Bit 0 is set if it's possible
+/// to restart execution after the exception, and bit 2 is set if a machine-check was in progress.
+pub const IA32_MCG_STATUS: u32 = 0x17a;
+
 /// Read from a model-specific register.
 pub fn read_msr(reg: u32) -> u64 {
     let (high, low): (u32, u32);
@@ -184,3 +200,16 @@ pub unsafe fn write_msr(reg: u32, value: u64) {
         in("edx") value.get_bits(32..64) as u32
     );
 }
+
+/// Read the value of the timestamp counter. Only use this as a clock if the processor supports an invariant TSC
+/// (see `SupportedFeatures::invariant_tsc`), as otherwise its rate can change with the processor's power state.
+pub fn read_tsc() -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!("rdtsc",
+            out("eax") low,
+            out("edx") high
+        );
+    }
+    (high as u64) << 32 | (low as u64)
+}
@@ -2,6 +2,7 @@ use super::port::Port;
 use core::fmt;
 
 pub const COM1: u16 = 0x3f8;
+pub const COM2: u16 = 0x2f8;
 
 pub struct SerialPort {
     data_register: Port<u8>,
@@ -65,7 +66,6 @@ impl SerialPort {
         self.modem_control_register.write(0x0B);
     }
 
-    #[allow(unused)]
     pub unsafe fn read(&self) -> u8 {
         while (self.line_status_register.read() & 1) == 0 {
             // XXX: Required to stop loop from being optimized away
@@ -1,6 +1,34 @@
+//! The TLB caches translations from the page tables, and so must be told when they change. If the processor
+//! supports PCIDs (Process-Context Identifiers), translations are tagged with the PCID of the address space they
+//! belong to, so we can switch between address spaces without flushing the whole TLB. This means the TLB may hold
+//! translations for address spaces other than the current one, so changes to them have to be invalidated too.
+
 use super::registers::{read_control_reg, write_control_reg};
+use core::sync::atomic::{AtomicBool, Ordering};
 use hal::memory::VirtualAddress;
 
+/// A Process-Context Identifier. Only the bottom 12 bits are used, and PCID `0` is used for the kernel's page tables
+/// (and for any address spaces that we couldn't allocate a PCID for).
+pub type Pcid = u16;
+
+pub const MAX_PCID: Pcid = 4095;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static INVPCID_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Record that PCIDs have been enabled (by setting `CR4.PCIDE`), and whether the `invpcid` instruction can be used.
+/// This must be done before any page tables are created for userspace, as PCIDs are allocated when they're created.
+pub fn set_pcid_enabled(invpcid_supported: bool) {
+    INVPCID_SUPPORTED.store(invpcid_supported, Ordering::Relaxed);
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Invalidate the translation for `address` in the current address space, and any global translation for it.
+#[cfg(not(test))]
 #[rustfmt::skip]
 pub fn invalidate_page(address: VirtualAddress) {
     unsafe {
@@ -8,7 +36,33 @@ pub fn invalidate_page(address: VirtualAddress) {
     }
 }
 
+/// `invlpg` can only be used in Ring 0, so this does nothing when the paging code is tested on the host.
+#[cfg(test)]
+pub fn invalidate_page(_address: VirtualAddress) {}
+
+/// Invalidate the translation for `address` in the address space with the given PCID, which doesn't have to be the
+/// current one. Returns `false` if the processor doesn't support `invpcid`, in which case nothing is invalidated.
+pub fn invalidate_page_in(pcid: Pcid, address: VirtualAddress) -> bool {
+    if !INVPCID_SUPPORTED.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    let descriptor: [u64; 2] = [pcid as u64, usize::from(address) as u64];
+    unsafe {
+        /*
+         * Type `0` invalidates a single address, in a single PCID.
+         */
+        asm!("invpcid {}, [{}]", in(reg) 0u64, in(reg) &descriptor);
+    }
+    true
+}
+
+/// Flush all of the non-global translations for the current address space.
 pub fn flush() {
+    /*
+     * Bit 63 (which tells the processor not to flush when PCIDs are enabled) is never set when `cr3` is read, so we
+     * can just write back the value we read.
+     */
     let current_cr3 = read_control_reg!(cr3);
     unsafe {
         write_control_reg!(cr3, current_cr3);
@@ -8,13 +8,18 @@
 //! P3 is used to map the entirety of physical memory into the kernel address space, and for task kernel stacks.
 //!
 //! Directly below the base of the kernel, we reserve 128GiB for task kernel stacks, which gives us a maximum of
-//! 65536 tasks if each one has the default stack size.
+//! 65536 tasks if each one has the default stack size. Below those, we reserve 1GiB for mapping device memory, which
+//! needs a different memory type to the physical mapping.
 //!
-//! This leaves us 382GiB for the physical memory map, which should be sufficient for any system I can imagine us
+//! This leaves us 381GiB for the physical memory map, which should be sufficient for any system I can imagine us
 //! running on (famous last words).
 
 use hal::memory::{mebibytes, Bytes, PhysicalAddress, VirtualAddress};
 
+/// Userspace is restricted to the canonical lower half of the address space, even though only the last P4 entry
+/// is actually used by the kernel.
+pub const USER_ADDRESS_SPACE_END: VirtualAddress = VirtualAddress::new(0x0000_8000_0000_0000);
+
 pub const KERNEL_P4_ENTRY: usize = 511;
 pub const KERNEL_ADDRESS_SPACE_START: VirtualAddress = VirtualAddress::new(0xffff_ff80_0000_0000);
 
@@ -30,6 +35,10 @@ pub fn physical_to_virtual(address: PhysicalAddress) -> VirtualAddress {
     PHYSICAL_MAPPING_BASE + usize::from(address)
 }
 
+/// A page that the kernel maps device memory into while it's accessing it (see `Platform::DEVICE_WINDOW`). This is
+/// the first page of the area reserved for device memory.
+pub const DEVICE_WINDOW: VirtualAddress = VirtualAddress::new(0xffff_ffdf_4000_0000);
+
 pub const KERNEL_STACKS_BASE: VirtualAddress = VirtualAddress::new(0xffff_ffdf_8000_0000);
 /*
  * There is an imposed maximum number of tasks because of the simple way we're allocating task kernel stacks.
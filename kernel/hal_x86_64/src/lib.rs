@@ -1,6 +1,10 @@
 #![no_std]
 #![feature(asm, decl_macro, const_fn, global_asm, naked_functions, type_ascription)]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 pub mod hw;
 pub mod kernel_map;
 pub mod paging;
@@ -1,16 +1,22 @@
-use crate::hw::{registers::write_control_reg, tlb};
+use crate::hw::{
+    registers::{read_control_reg, write_control_reg},
+    tlb::{self, Pcid, MAX_PCID},
+};
 use bit_field::BitField;
 use bitflags::bitflags;
 use core::{
     fmt,
     marker::PhantomData,
-    ops::{Index, IndexMut},
+    ops::{Index, IndexMut, Range},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 use hal::memory::{
+    CacheType,
     Flags,
     Frame,
     FrameAllocator,
     FrameSize,
+    Mapping,
     Page,
     PageTable,
     PagingError,
@@ -20,6 +26,18 @@ use hal::memory::{
     Size4KiB,
     VirtualAddress,
 };
+use spin::Mutex;
+
+/// If this bit is set when `cr3` is written to, translations cached under the new PCID are kept, rather than being
+/// flushed.
+const CR3_NO_FLUSH: usize = 63;
+
+/// This is incremented whenever a mapping in the kernel's part of the address space is changed. The kernel is
+/// mapped into every address space, so its translations can be cached under any PCID - an address space that
+/// hasn't been flushed since the last change must flush its PCID when it's next switched to.
+static KERNEL_MAPPINGS_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+static PCID_ALLOCATOR: Mutex<PcidAllocator> = Mutex::new(PcidAllocator::new());
 
 bitflags! {
     pub struct EntryFlags : u64 {
@@ -54,10 +72,48 @@ impl From<Flags> for EntryFlags {
             | if flags.writable { EntryFlags::WRITABLE } else { EntryFlags::empty() }
             | if flags.executable { EntryFlags::empty() } else { EntryFlags::NO_EXECUTE }
             | if flags.user_accessible { EntryFlags::USER_ACCESSIBLE } else { EntryFlags::empty() }
-            | if flags.cached { EntryFlags::empty() } else { EntryFlags::NO_CACHE }
+            | match flags.cache_type {
+                CacheType::WriteBack => EntryFlags::empty(),
+                CacheType::WriteThrough => EntryFlags::WRITE_THROUGH,
+                CacheType::WriteCombining => EntryFlags::NO_CACHE,
+                CacheType::Uncacheable => EntryFlags::NO_CACHE | EntryFlags::WRITE_THROUGH,
+            }
     }
 }
 
+impl From<EntryFlags> for Flags {
+    fn from(flags: EntryFlags) -> Self {
+        Flags {
+            writable: flags.contains(EntryFlags::WRITABLE),
+            executable: !flags.contains(EntryFlags::NO_EXECUTE),
+            user_accessible: flags.contains(EntryFlags::USER_ACCESSIBLE),
+            cache_type: match (flags.contains(EntryFlags::NO_CACHE), flags.contains(EntryFlags::WRITE_THROUGH)) {
+                (false, false) => CacheType::WriteBack,
+                (false, true) => CacheType::WriteThrough,
+                (true, false) => CacheType::WriteCombining,
+                (true, true) => CacheType::Uncacheable,
+            },
+        }
+    }
+}
+
+/// The value we program into the Page Attribute Table (`IA32_PAT`). The memory type of a page is selected from this
+/// table by the `PCD` (`NO_CACHE`) and `PWT` (`WRITE_THROUGH`) bits of its entry, as well as the `PAT` bit, which we
+/// don't use because it's in a different place in huge page entries. The first four entries are therefore enough
+/// for all of the `CacheType`s, and the last four just repeat them.
+///
+/// This is the same as the table the processor starts with, except that entry 2 is write-combining instead of
+/// "uncached minus", so the `CacheType`s still map to sensible memory types if the table hasn't been programmed.
+pub const PAT: u64 = {
+    const WRITE_BACK: u64 = 0x06;
+    const WRITE_THROUGH: u64 = 0x04;
+    const WRITE_COMBINING: u64 = 0x01;
+    const UNCACHEABLE: u64 = 0x00;
+
+    let types = WRITE_BACK | (WRITE_THROUGH << 8) | (WRITE_COMBINING << 16) | (UNCACHEABLE << 24);
+    types | (types << 32)
+};
+
 /// Represents an entry within a page table of any level. Contains a physical address to the next level (or to the
 /// physical memory region), and some flags.
 #[repr(transparent)]
@@ -73,6 +129,12 @@ impl Entry {
         self.0 == 0
     }
 
+    /// Whether this entry maps a huge page (a 2MiB page in a P2, or a 1GiB page in a P3), rather than pointing to
+    /// the next level of page table.
+    pub fn is_huge(&self) -> bool {
+        self.flags().contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE)
+    }
+
     pub fn flags(&self) -> EntryFlags {
         EntryFlags::from_bits_truncate(self.0)
     }
@@ -192,8 +254,13 @@ where
     L: HierarchicalLevel,
 {
     /// Get a reference to the table at the given `index`, assuming the entirity of
-    /// the physical address space is mapped from `physical_base`.
+    /// the physical address space is mapped from `physical_base`. Returns `None` if the entry is empty, or if it
+    /// maps a huge page.
     pub fn next_table(&self, index: usize, physical_base: VirtualAddress) -> Option<&Table<L::NextLevel>> {
+        if self[index].is_huge() {
+            return None;
+        }
+
         self[index]
             .address()
             .map(|physical_address| physical_base + usize::from(physical_address))
@@ -201,12 +268,17 @@ where
     }
 
     /// Get a mutable reference to the table at the given `index`, assuming the entirity of
-    /// the physical address space is mapped from `physical_base`.
+    /// the physical address space is mapped from `physical_base`. Returns `None` if the entry is empty, or if it
+    /// maps a huge page.
     pub fn next_table_mut(
         &mut self,
         index: usize,
         physical_base: VirtualAddress,
     ) -> Option<&mut Table<L::NextLevel>> {
+        if self[index].is_huge() {
+            return None;
+        }
+
         self[index]
             .address()
             .map(|physical_address| physical_base + usize::from(physical_address))
@@ -222,6 +294,15 @@ where
     where
         A: FrameAllocator<Size4KiB>,
     {
+        /*
+         * When we're seeing if we need to create a parent table in order to map into lower tables (e.g. creating a
+         * P2 to create a P1 for 4KiB mappings), there might already be a huge page mapped into the parent table.
+         * If this occurs, we error because the whole region has already been mapped.
+         */
+        if self[index].is_huge() {
+            return Err(PagingError::AlreadyMapped);
+        }
+
         if self.next_table(index, physical_base).is_none() {
             /*
              * This entry is empty, so we create a new page table, zero it, and return that.
@@ -231,20 +312,48 @@ where
             table.zero();
             Ok(table)
         } else {
-            /*
-             * This entry already exists, so we don't need to create another one. However, we do need to detect a
-             * special case here: when we're seeing if we need to create a parent table in order to map into lower
-             * tables (e.g. creating a P2 to create a P1 for 4KiB mappings), there might already be a huge page
-             * mapped into the parent table. If this occurs, we error because the whole region has already been
-             * mapped.
-             */
-            if self[index].flags().contains(EntryFlags::HUGE_PAGE) {
-                return Err(PagingError::AlreadyMapped);
-            }
-
             Ok(self.next_table_mut(index, physical_base).unwrap())
         }
     }
+
+    /// If the entry at `index` maps a huge page, replace it with a table of the next level that maps the same
+    /// memory, with the same flags, using pages of `page_size` bytes. This allows part of a huge page to be
+    /// unmapped or remapped without affecting the rest of it. Does nothing if the entry doesn't map a huge page.
+    pub fn split_huge_page<A>(
+        &mut self,
+        index: usize,
+        page_size: usize,
+        allocator: &A,
+        physical_base: VirtualAddress,
+    ) where
+        A: FrameAllocator<Size4KiB>,
+    {
+        if !self[index].is_huge() {
+            return;
+        }
+
+        let huge_page_start = self[index].address().unwrap();
+        let flags = if page_size == Size4KiB::SIZE {
+            // In a P1, this bit means something else, and all the entries map 4KiB pages anyway
+            self[index].flags() - EntryFlags::HUGE_PAGE
+        } else {
+            self[index].flags()
+        };
+
+        /*
+         * We fill in the new table before we install it, so the memory stays mapped the whole time. The TLB may
+         * still hold the translation for the huge page, but that's fine - it's the same as the new mappings, and
+         * it's invalidated when any of them are changed.
+         */
+        let table_frame = allocator.allocate();
+        let table: &mut Table<L::NextLevel> =
+            unsafe { &mut *((physical_base + usize::from(table_frame.start)).mut_ptr()) };
+        for (i, entry) in table.entries.iter_mut().enumerate() {
+            entry.set(Some((huge_page_start + i * page_size, flags)));
+        }
+
+        self.entries[index].set(Some((table_frame.start, EntryFlags::NON_TERMINAL_FLAGS)));
+    }
 }
 
 pub struct PageTableImpl {
@@ -257,21 +366,82 @@ pub struct PageTableImpl {
     /// tables would have a `physical_base` in the higher half in the kernel, after we switch to
     /// the kernel's set of page tables.
     physical_base: VirtualAddress,
+    /// The PCID that translations from these page tables are cached under. This is `None` if PCIDs aren't enabled,
+    /// or if we've run out of them, in which case the tables use PCID `0` (like the kernel's) and the TLB is
+    /// flushed every time we switch to them.
+    pcid: Option<Pcid>,
+    /// Set when a mapping has been changed while translations for it might be cached under `pcid`, but we
+    /// couldn't invalidate them directly. The whole PCID is flushed the next time we switch to these tables.
+    needs_flush: AtomicBool,
+    /// The value of `KERNEL_MAPPINGS_GENERATION` the last time `pcid` was flushed.
+    kernel_mappings_generation: AtomicUsize,
 }
 
 impl PageTableImpl {
     pub fn new(p4_frame: Frame, physical_base: VirtualAddress) -> PageTableImpl {
-        let mut table = PageTableImpl { p4_frame, physical_base };
+        let mut table = PageTableImpl::with_pcid(p4_frame, physical_base, None);
         Self::p4_mut(&mut table.p4_frame, table.physical_base).zero();
         table
     }
 
+    fn with_pcid(p4_frame: Frame, physical_base: VirtualAddress, pcid: Option<Pcid>) -> PageTableImpl {
+        /*
+         * A PCID may have been used by another address space before, so we always flush it the first time we
+         * switch to the new tables.
+         */
+        PageTableImpl {
+            p4_frame,
+            physical_base,
+            pcid,
+            needs_flush: AtomicBool::new(true),
+            kernel_mappings_generation: AtomicUsize::new(0),
+        }
+    }
+
     /// Create a `PageTableImpl` from a `Frame` that already contains a P4. This is very unsafe because
     /// it assumes that the frame contains a valid page table, and that no other `PageTableImpl`s
     /// currently exist that use this same backing frame (as calling `mapper` on both could lead to
     /// two mutable references aliasing the same data to exist, which is UB).
     pub unsafe fn from_frame(p4_frame: Frame, physical_base: VirtualAddress) -> PageTableImpl {
-        PageTableImpl { p4_frame, physical_base }
+        PageTableImpl::with_pcid(p4_frame, physical_base, None)
+    }
+
+    /// Iterate over the present mappings in these page tables. See `PageTable::walk`.
+    pub fn mappings(&self) -> Mappings<'_> {
+        Mappings { page_table: self, cursor: 0, pending: None }
+    }
+
+    /// Find the page that contains `address`, if it's mapped, as a `Mapping` of that single page. Unlike searching
+    /// through `mappings`, this only walks the tables that translate `address`, like `translate`.
+    pub fn mapping_of(&self, address: VirtualAddress) -> Option<Mapping> {
+        use pebble_util::math::align_down;
+
+        let page = |entry: Entry, page_size: usize| {
+            Some(Mapping {
+                virtual_start: VirtualAddress::new(align_down(usize::from(address), page_size)),
+                physical_start: entry.address()?,
+                size: page_size,
+                page_size,
+                flags: Flags::from(entry.flags()),
+            })
+        };
+
+        let p3 = self.p4().next_table(address.p4_index(), self.physical_base)?;
+
+        let p3_entry = p3[address.p3_index()];
+        if p3_entry.is_huge() {
+            return page(p3_entry, Size1GiB::SIZE);
+        }
+
+        let p2 = p3.next_table(address.p3_index(), self.physical_base)?;
+
+        let p2_entry = p2[address.p2_index()];
+        if p2_entry.is_huge() {
+            return page(p2_entry, Size2MiB::SIZE);
+        }
+
+        let p1 = p2.next_table(address.p2_index(), self.physical_base)?;
+        page(p1[address.p1_index()], Size4KiB::SIZE)
     }
 
     fn p4(&self) -> &Table<Level4> {
@@ -284,6 +454,37 @@ impl PageTableImpl {
     fn p4_mut(frame: &mut Frame, physical_base: VirtualAddress) -> &mut Table<Level4> {
         unsafe { &mut *((physical_base + usize::from(frame.start)).mut_ptr()) }
     }
+
+    /// Invalidate any cached translations for `address`, after its mapping in these page tables has been changed
+    /// or removed. This isn't needed when a new mapping is created, as the TLB doesn't cache missing mappings.
+    fn invalidate_page(&self, address: VirtualAddress) {
+        tlb::invalidate_page(address);
+        if !tlb::pcid_enabled() {
+            return;
+        }
+
+        if address.p4_index() == crate::kernel_map::KERNEL_P4_ENTRY {
+            KERNEL_MAPPINGS_GENERATION.fetch_add(1, Ordering::SeqCst);
+        } else if let Some(pcid) = self.pcid {
+            /*
+             * `invlpg` only invalidates the translation in the current address space, so if these page tables
+             * aren't current, we have to target their PCID instead.
+             */
+            let is_current =
+                read_control_reg!(cr3) as usize & 0x000f_ffff_ffff_f000 == usize::from(self.p4_frame.start);
+            if !is_current && !tlb::invalidate_page_in(pcid, address) {
+                self.needs_flush.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl Drop for PageTableImpl {
+    fn drop(&mut self) {
+        if let Some(pcid) = self.pcid {
+            PCID_ALLOCATOR.lock().free(pcid);
+        }
+    }
 }
 
 impl PageTable<Size4KiB> for PageTableImpl {
@@ -291,7 +492,10 @@ impl PageTable<Size4KiB> for PageTableImpl {
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let mut page_table = PageTableImpl::new(allocator.allocate(), crate::kernel_map::PHYSICAL_MAPPING_BASE);
+        let pcid = if tlb::pcid_enabled() { PCID_ALLOCATOR.lock().allocate() } else { None };
+        let mut page_table =
+            PageTableImpl::with_pcid(allocator.allocate(), crate::kernel_map::PHYSICAL_MAPPING_BASE, pcid);
+        Self::p4_mut(&mut page_table.p4_frame, page_table.physical_base).zero();
 
         /*
          * Install the address of the kernel's P3 in every address space, so that the kernel is always mapped.
@@ -306,19 +510,38 @@ impl PageTable<Size4KiB> for PageTableImpl {
     }
 
     fn switch_to(&self) {
+        let mut cr3 = usize::from(self.p4_frame.start) as u64;
+
+        /*
+         * If we have a PCID, the translations cached under it the last time these page tables were active are
+         * still correct (unless we've been told otherwise), so we don't need to flush them.
+         */
+        if let Some(pcid) = self.pcid {
+            let generation = KERNEL_MAPPINGS_GENERATION.load(Ordering::SeqCst);
+            let needs_flush = self.needs_flush.swap(false, Ordering::SeqCst)
+                | (self.kernel_mappings_generation.swap(generation, Ordering::SeqCst) != generation);
+
+            cr3 |= pcid as u64;
+            cr3.set_bit(CR3_NO_FLUSH, !needs_flush);
+        }
+
         unsafe {
-            write_control_reg!(cr3, usize::from(self.p4_frame.start) as u64);
+            write_control_reg!(cr3, cr3);
         }
     }
 
     fn translate(&self, address: VirtualAddress) -> Option<PhysicalAddress> {
-        let p2 = self
-            .p4()
-            .next_table(address.p4_index(), self.physical_base)
-            .and_then(|p3| p3.next_table(address.p3_index(), self.physical_base))?;
+        let p3 = self.p4().next_table(address.p4_index(), self.physical_base)?;
+
+        let p3_entry = p3[address.p3_index()];
+        if p3_entry.is_huge() {
+            return Some(p3_entry.address()? + (usize::from(address) % Size1GiB::SIZE));
+        }
+
+        let p2 = p3.next_table(address.p3_index(), self.physical_base)?;
 
         let p2_entry = p2[address.p2_index()];
-        if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+        if p2_entry.is_huge() {
             return Some(p2_entry.address()? + (usize::from(address) % Size2MiB::SIZE));
         }
 
@@ -468,30 +691,311 @@ impl PageTable<Size4KiB> for PageTableImpl {
         Ok(())
     }
 
-    fn unmap<S>(&mut self, page: Page<S>) -> Option<Frame<S>>
+    fn unmap<S, A>(&mut self, page: Page<S>, allocator: &A) -> Option<Frame<S>>
     where
         S: FrameSize,
+        A: FrameAllocator<Size4KiB>,
     {
-        match S::SIZE {
+        let physical_base = self.physical_base;
+        let p3 = Self::p4_mut(&mut self.p4_frame, physical_base)
+            .next_table_mut(page.start.p4_index(), physical_base)?;
+
+        /*
+         * If the page is part of a larger huge page, we split the huge page up until the page has its own entry,
+         * and then unmap just that entry. If the page is mapped with smaller pages instead, we don't unmap
+         * anything.
+         */
+        let entry = match S::SIZE {
             Size4KiB::SIZE => {
-                let p1 = Self::p4_mut(&mut self.p4_frame, self.physical_base)
-                    .next_table_mut(page.start.p4_index(), self.physical_base)?
-                    .next_table_mut(page.start.p3_index(), self.physical_base)?
-                    .next_table_mut(page.start.p2_index(), self.physical_base)?;
-                let frame = Frame::starts_with(p1[page.start.p1_index()].address()?);
-                p1[page.start.p1_index()].set(None);
-                tlb::invalidate_page(page.start);
-
-                Some(frame)
+                p3.split_huge_page(page.start.p3_index(), Size2MiB::SIZE, allocator, physical_base);
+                let p2 = p3.next_table_mut(page.start.p3_index(), physical_base)?;
+                p2.split_huge_page(page.start.p2_index(), Size4KiB::SIZE, allocator, physical_base);
+                let p1 = p2.next_table_mut(page.start.p2_index(), physical_base)?;
+                &mut p1[page.start.p1_index()]
+            }
+            Size2MiB::SIZE => {
+                p3.split_huge_page(page.start.p3_index(), Size2MiB::SIZE, allocator, physical_base);
+                let p2 = p3.next_table_mut(page.start.p3_index(), physical_base)?;
+                if !p2[page.start.p2_index()].is_huge() {
+                    return None;
+                }
+                &mut p2[page.start.p2_index()]
+            }
+            Size1GiB::SIZE => {
+                if !p3[page.start.p3_index()].is_huge() {
+                    return None;
+                }
+                &mut p3[page.start.p3_index()]
             }
-            Size2MiB::SIZE => unimplemented!(),
-            Size1GiB::SIZE => unimplemented!(),
 
             _ => panic!("Unimplemented page size!"),
+        };
+
+        let frame = Frame::starts_with(entry.address()?);
+        entry.set(None);
+        self.invalidate_page(page.start);
+
+        Some(frame)
+    }
+
+    fn protect<A>(&mut self, range: Range<VirtualAddress>, flags: Flags, allocator: &A)
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        use pebble_util::math::align_down;
+
+        assert!(range.start.is_aligned(Size4KiB::SIZE));
+        assert!(range.end.is_aligned(Size4KiB::SIZE));
+
+        let physical_base = self.physical_base;
+        let end = usize::from(range.end);
+        let mut cursor = usize::from(range.start);
+
+        /*
+         * We walk the range an entry at a time. Where a whole huge page is inside the range, we change its entry
+         * directly, but if only part of it is, we split it up and then have another go at the same address. Where
+         * a table is missing, we skip over the whole region it would map.
+         */
+        while cursor < end {
+            let address = VirtualAddress::new(cursor);
+            let whole_page_in_range = |size: usize| cursor % size == 0 && end - cursor >= size;
+            let skip_to_next = |size: usize| align_down(cursor, size).saturating_add(size);
+
+            let p3 = match Self::p4_mut(&mut self.p4_frame, physical_base)
+                .next_table_mut(address.p4_index(), physical_base)
+            {
+                Some(p3) => p3,
+                None => {
+                    cursor = skip_to_next(Size1GiB::SIZE * ENTRY_COUNT);
+                    continue;
+                }
+            };
+
+            if p3[address.p3_index()].is_huge() {
+                if whole_page_in_range(Size1GiB::SIZE) {
+                    let start = p3[address.p3_index()].address().unwrap();
+                    p3[address.p3_index()].set(Some((start, EntryFlags::from(flags) | EntryFlags::HUGE_PAGE)));
+                    self.invalidate_page(address);
+                    cursor += Size1GiB::SIZE;
+                    continue;
+                }
+                p3.split_huge_page(address.p3_index(), Size2MiB::SIZE, allocator, physical_base);
+            }
+
+            let p2 = match p3.next_table_mut(address.p3_index(), physical_base) {
+                Some(p2) => p2,
+                None => {
+                    cursor = skip_to_next(Size1GiB::SIZE);
+                    continue;
+                }
+            };
+
+            if p2[address.p2_index()].is_huge() {
+                if whole_page_in_range(Size2MiB::SIZE) {
+                    let start = p2[address.p2_index()].address().unwrap();
+                    p2[address.p2_index()].set(Some((start, EntryFlags::from(flags) | EntryFlags::HUGE_PAGE)));
+                    self.invalidate_page(address);
+                    cursor += Size2MiB::SIZE;
+                    continue;
+                }
+                p2.split_huge_page(address.p2_index(), Size4KiB::SIZE, allocator, physical_base);
+            }
+
+            let p1 = match p2.next_table_mut(address.p2_index(), physical_base) {
+                Some(p1) => p1,
+                None => {
+                    cursor = skip_to_next(Size2MiB::SIZE);
+                    continue;
+                }
+            };
+
+            if let Some(start) = p1[address.p1_index()].address() {
+                p1[address.p1_index()].set(Some((start, EntryFlags::from(flags))));
+                self.invalidate_page(address);
+            }
+            cursor += Size4KiB::SIZE;
+        }
+    }
+
+    fn walk<F>(&self, f: F)
+    where
+        F: FnMut(Mapping),
+    {
+        self.mappings().for_each(f);
+    }
+
+    fn free_tables<A>(&mut self, allocator: &A)
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let physical_base = self.physical_base;
+        let free = |entry: Entry| allocator.free_n(Frame::starts_with(entry.address().unwrap()), 1);
+        let p4 = self.p4();
+
+        /*
+         * The kernel's P3 is shared by every address space, so we leave it (and the tables below it) alone. Entries
+         * that map huge pages don't point to tables, so `next_table` skips them.
+         */
+        for p4_index in (0..ENTRY_COUNT).filter(|&index| index != crate::kernel_map::KERNEL_P4_ENTRY) {
+            if let Some(p3) = p4.next_table(p4_index, physical_base) {
+                for p3_index in 0..ENTRY_COUNT {
+                    if let Some(p2) = p3.next_table(p3_index, physical_base) {
+                        for p2_index in 0..ENTRY_COUNT {
+                            if p2.next_table(p2_index, physical_base).is_some() {
+                                free(p2[p2_index]);
+                            }
+                        }
+                        free(p3[p3_index]);
+                    }
+                }
+                free(p4[p4_index]);
+            }
+        }
+
+        allocator.free_n(self.p4_frame, 1);
+    }
+}
+
+/// The size of the virtual address space, before it's sign-extended to make canonical addresses.
+const ADDRESS_SPACE_SIZE: usize = 1 << 48;
+
+/// An iterator over the present mappings in a set of page tables, created by `PageTableImpl::mappings`. Adjacent
+/// pages are coalesced in the same way as by `PageTable::walk`.
+pub struct Mappings<'a> {
+    page_table: &'a PageTableImpl,
+    /// The next address to look for a page at, before it's sign-extended. This is always page-aligned, and we're
+    /// finished once it reaches `ADDRESS_SPACE_SIZE`. Walking the un-extended addresses means we don't have to
+    /// handle the hole in the middle of the address space specially.
+    cursor: usize,
+    /// The mapping we're currently building up, which is returned when we find a page that can't be added to it.
+    pending: Option<Mapping>,
+}
+
+impl<'a> Mappings<'a> {
+    /// Find the next present page, and move the cursor past it. Missing tables are skipped over, like in
+    /// `PageTable::protect`.
+    fn next_page(&mut self) -> Option<Mapping> {
+        use pebble_util::math::align_down;
+
+        let physical_base = self.page_table.physical_base;
+        let page = |address: VirtualAddress, entry: Entry, page_size: usize| Mapping {
+            virtual_start: address,
+            physical_start: entry.address().unwrap(),
+            size: page_size,
+            page_size,
+            flags: Flags::from(entry.flags()),
+        };
+
+        while self.cursor < ADDRESS_SPACE_SIZE {
+            let cursor = self.cursor;
+            let address = VirtualAddress::new(cursor);
+            let skip_to_next = |size: usize| align_down(cursor, size) + size;
+
+            let p3 = match self.page_table.p4().next_table(address.p4_index(), physical_base) {
+                Some(p3) => p3,
+                None => {
+                    self.cursor = skip_to_next(Size1GiB::SIZE * ENTRY_COUNT);
+                    continue;
+                }
+            };
+
+            let p3_entry = p3[address.p3_index()];
+            if p3_entry.is_huge() {
+                self.cursor = skip_to_next(Size1GiB::SIZE);
+                return Some(page(address, p3_entry, Size1GiB::SIZE));
+            }
+
+            let p2 = match p3.next_table(address.p3_index(), physical_base) {
+                Some(p2) => p2,
+                None => {
+                    self.cursor = skip_to_next(Size1GiB::SIZE);
+                    continue;
+                }
+            };
+
+            let p2_entry = p2[address.p2_index()];
+            if p2_entry.is_huge() {
+                self.cursor = skip_to_next(Size2MiB::SIZE);
+                return Some(page(address, p2_entry, Size2MiB::SIZE));
+            }
+
+            let p1 = match p2.next_table(address.p2_index(), physical_base) {
+                Some(p1) => p1,
+                None => {
+                    self.cursor = skip_to_next(Size2MiB::SIZE);
+                    continue;
+                }
+            };
+
+            self.cursor += Size4KiB::SIZE;
+            let p1_entry = p1[address.p1_index()];
+            if p1_entry.address().is_some() {
+                return Some(page(address, p1_entry, Size4KiB::SIZE));
+            }
+        }
+
+        None
+    }
+}
+
+impl<'a> Iterator for Mappings<'a> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        loop {
+            let page = match self.next_page() {
+                Some(page) => page,
+                None => return self.pending.take(),
+            };
+
+            /*
+             * We compare the raw addresses, so that a run that ends at the top of the lower half isn't joined up
+             * with one that starts at the bottom of the higher half.
+             */
+            match self.pending {
+                Some(ref mut pending)
+                    if pending.page_size == page.page_size
+                        && pending.flags == page.flags
+                        && usize::from(pending.virtual_start) + pending.size
+                            == usize::from(page.virtual_start)
+                        && usize::from(pending.physical_start) + pending.size
+                            == usize::from(page.physical_start) =>
+                {
+                    pending.size += page.size;
+                }
+                _ => {
+                    if let Some(mapping) = self.pending.replace(page) {
+                        return Some(mapping);
+                    }
+                }
+            }
         }
     }
 }
 
+/// Allocates PCIDs to address spaces. PCID `0` is never allocated, as it's used by the kernel's page tables.
+struct PcidAllocator {
+    /// A bitmap of the PCIDs that are in use.
+    used: [u64; (MAX_PCID as usize + 1) / 64],
+}
+
+impl PcidAllocator {
+    const fn new() -> PcidAllocator {
+        PcidAllocator { used: [0; (MAX_PCID as usize + 1) / 64] }
+    }
+
+    fn allocate(&mut self) -> Option<Pcid> {
+        let pcid = (1..=MAX_PCID).find(|&pcid| !self.used[pcid as usize / 64].get_bit(pcid as usize % 64))?;
+        self.used[pcid as usize / 64].set_bit(pcid as usize % 64, true);
+        Some(pcid)
+    }
+
+    fn free(&mut self, pcid: Pcid) {
+        assert!(self.used[pcid as usize / 64].get_bit(pcid as usize % 64), "Tried to free unallocated PCID");
+        self.used[pcid as usize / 64].set_bit(pcid as usize % 64, false);
+    }
+}
+
 pub trait VirtualAddressEx {
     fn p4_index(self) -> usize;
     fn p3_index(self) -> usize;
@@ -516,3 +1020,445 @@ impl VirtualAddressEx for VirtualAddress {
         usize::from(self).get_bits(12..21)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hal::test_utils::{phys, virt, TestMemory};
+    use std::vec::Vec;
+
+    fn flags() -> Flags {
+        Flags { writable: true, ..Default::default() }
+    }
+
+    #[test]
+    fn test_map_and_translate() {
+        let memory = TestMemory::new(16);
+        let mut page_table = PageTableImpl::new(memory.allocate(), memory.physical_base());
+
+        page_table
+            .map(Page::<Size4KiB>::starts_with(virt(0x1000)), Frame::starts_with(phys(0x5000)), flags(), &memory)
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size2MiB>::starts_with(virt(0x20_0000)),
+                Frame::starts_with(phys(0x60_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size1GiB>::starts_with(virt(0x4000_0000)),
+                Frame::starts_with(phys(0x8000_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        assert_eq!(page_table.translate(virt(0x1234)), Some(phys(0x5234)));
+        assert_eq!(page_table.translate(virt(0x2000)), None);
+        assert_eq!(page_table.translate(virt(0x2f_0123)), Some(phys(0x6f_0123)));
+        assert_eq!(page_table.translate(virt(0x40_0000)), None);
+        assert_eq!(page_table.translate(virt(0x5234_5678)), Some(phys(0x9234_5678)));
+        assert_eq!(page_table.translate(virt(0x8000_0000)), None);
+
+        /*
+         * Memory that's already mapped by a huge page can't be mapped again with smaller pages.
+         */
+        assert!(matches!(
+            page_table.map(
+                Page::<Size4KiB>::starts_with(virt(0x20_1000)),
+                Frame::starts_with(phys(0x1000)),
+                flags(),
+                &memory
+            ),
+            Err(PagingError::AlreadyMapped)
+        ));
+        assert!(matches!(
+            page_table.map(
+                Page::<Size2MiB>::starts_with(virt(0x4020_0000)),
+                Frame::starts_with(phys(0x20_0000)),
+                flags(),
+                &memory
+            ),
+            Err(PagingError::AlreadyMapped)
+        ));
+    }
+
+    #[test]
+    fn test_unmap_huge_pages() {
+        let memory = TestMemory::new(16);
+        let mut page_table = PageTableImpl::new(memory.allocate(), memory.physical_base());
+
+        page_table
+            .map(
+                Page::<Size2MiB>::starts_with(virt(0x20_0000)),
+                Frame::starts_with(phys(0x60_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size1GiB>::starts_with(virt(0x4000_0000)),
+                Frame::starts_with(phys(0x8000_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size4KiB>::starts_with(virt(0x40_0000)),
+                Frame::starts_with(phys(0x5000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        assert_eq!(
+            page_table.unmap(Page::<Size2MiB>::starts_with(virt(0x20_0000)), &memory),
+            Some(Frame::starts_with(phys(0x60_0000)))
+        );
+        assert_eq!(page_table.translate(virt(0x20_1000)), None);
+        assert_eq!(page_table.unmap(Page::<Size2MiB>::starts_with(virt(0x20_0000)), &memory), None);
+
+        assert_eq!(
+            page_table.unmap(Page::<Size1GiB>::starts_with(virt(0x4000_0000)), &memory),
+            Some(Frame::starts_with(phys(0x8000_0000)))
+        );
+        assert_eq!(page_table.translate(virt(0x4000_0000)), None);
+
+        /*
+         * Unmapping a huge page that's actually mapped with smaller pages shouldn't unmap anything.
+         */
+        assert_eq!(page_table.unmap(Page::<Size2MiB>::starts_with(virt(0x40_0000)), &memory), None);
+        assert_eq!(page_table.translate(virt(0x40_0000)), Some(phys(0x5000)));
+    }
+
+    #[test]
+    fn test_split_2mib_page() {
+        let memory = TestMemory::new(16);
+        let mut page_table = PageTableImpl::new(memory.allocate(), memory.physical_base());
+        page_table
+            .map(
+                Page::<Size2MiB>::starts_with(virt(0x20_0000)),
+                Frame::starts_with(phys(0x60_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        let frames_before_split = memory.frames_allocated();
+
+        assert_eq!(
+            page_table.unmap(Page::<Size4KiB>::starts_with(virt(0x20_3000)), &memory),
+            Some(Frame::starts_with(phys(0x60_3000)))
+        );
+        assert_eq!(memory.frames_allocated(), frames_before_split + 1);
+
+        assert_eq!(page_table.translate(virt(0x20_3000)), None);
+        assert_eq!(page_table.translate(virt(0x20_2fff)), Some(phys(0x60_2fff)));
+        assert_eq!(page_table.translate(virt(0x20_4000)), Some(phys(0x60_4000)));
+        assert_eq!(page_table.translate(virt(0x3f_f123)), Some(phys(0x7f_f123)));
+
+        /*
+         * The rest of the huge page should be mapped by 4KiB pages with the same flags.
+         */
+        let p1 = page_table
+            .p4()
+            .next_table(0, memory.physical_base())
+            .and_then(|p3| p3.next_table(0, memory.physical_base()))
+            .and_then(|p2| p2.next_table(1, memory.physical_base()))
+            .unwrap();
+        assert!(!p1[4].flags().contains(EntryFlags::HUGE_PAGE));
+        assert_eq!(p1[4].flags(), EntryFlags::from(flags()));
+    }
+
+    #[test]
+    fn test_split_1gib_page() {
+        let memory = TestMemory::new(16);
+        let mut page_table = PageTableImpl::new(memory.allocate(), memory.physical_base());
+        page_table
+            .map(
+                Page::<Size1GiB>::starts_with(virt(0x4000_0000)),
+                Frame::starts_with(phys(0x8000_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        /*
+         * Unmapping a 2MiB page should split the 1GiB page into 2MiB pages, and unmapping a 4KiB page from one of
+         * those should split it again.
+         */
+        assert_eq!(
+            page_table.unmap(Page::<Size2MiB>::starts_with(virt(0x4020_0000)), &memory),
+            Some(Frame::starts_with(phys(0x8020_0000)))
+        );
+        assert_eq!(page_table.translate(virt(0x4020_1000)), None);
+        assert_eq!(page_table.translate(virt(0x4000_1000)), Some(phys(0x8000_1000)));
+        assert_eq!(page_table.translate(virt(0x7fff_ffff)), Some(phys(0xbfff_ffff)));
+
+        assert_eq!(
+            page_table.unmap(Page::<Size4KiB>::starts_with(virt(0x4060_0000)), &memory),
+            Some(Frame::starts_with(phys(0x8060_0000)))
+        );
+        assert_eq!(page_table.translate(virt(0x4060_0000)), None);
+        assert_eq!(page_table.translate(virt(0x4060_1000)), Some(phys(0x8060_1000)));
+        assert_eq!(page_table.translate(virt(0x405f_f000)), Some(phys(0x805f_f000)));
+
+        let p2 = page_table
+            .p4()
+            .next_table(0, memory.physical_base())
+            .and_then(|p3| p3.next_table(1, memory.physical_base()))
+            .unwrap();
+        assert!(p2[0].is_huge());
+        assert!(!p2[3].is_huge());
+    }
+
+    #[test]
+    fn test_cache_types() {
+        /*
+         * Check that each `CacheType` selects an entry of the PAT with the right memory type.
+         */
+        let memory_type = |cache_type| {
+            let flags = EntryFlags::from(Flags { cache_type, ..Default::default() });
+            let index = (flags.contains(EntryFlags::NO_CACHE) as u64) << 1
+                | (flags.contains(EntryFlags::WRITE_THROUGH) as u64);
+            PAT.get_bits((index as usize * 8)..(index as usize * 8 + 8))
+        };
+
+        assert_eq!(memory_type(CacheType::WriteBack), 0x06);
+        assert_eq!(memory_type(CacheType::WriteThrough), 0x04);
+        assert_eq!(memory_type(CacheType::WriteCombining), 0x01);
+        assert_eq!(memory_type(CacheType::Uncacheable), 0x00);
+    }
+
+    #[test]
+    fn test_pcid_allocator() {
+        let mut allocator = PcidAllocator::new();
+        assert_eq!(allocator.allocate(), Some(1));
+        assert_eq!(allocator.allocate(), Some(2));
+        assert_eq!(allocator.allocate(), Some(3));
+
+        allocator.free(2);
+        assert_eq!(allocator.allocate(), Some(2));
+
+        for pcid in 4..=MAX_PCID {
+            assert_eq!(allocator.allocate(), Some(pcid));
+        }
+        assert_eq!(allocator.allocate(), None);
+    }
+
+    #[test]
+    fn test_protect() {
+        let memory = TestMemory::new(16);
+        let mut page_table = PageTableImpl::new(memory.allocate(), memory.physical_base());
+        let read_only = Flags::default();
+
+        page_table
+            .map_range(
+                Page::<Size4KiB>::starts_with(virt(0x1000))..Page::starts_with(virt(0x4000)),
+                Frame::starts_with(phys(0x5000))..Frame::starts_with(phys(0x8000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size2MiB>::starts_with(virt(0x20_0000)),
+                Frame::starts_with(phys(0x60_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size2MiB>::starts_with(virt(0x40_0000)),
+                Frame::starts_with(phys(0x80_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        /*
+         * This range covers some of the 4KiB pages, all of the first 2MiB page, the start of the second 2MiB
+         * page, and some memory that isn't mapped at all.
+         */
+        page_table.protect(virt(0x2000)..virt(0x40_2000), read_only, &memory);
+
+        let entry_flags = |page_table: &PageTableImpl, address: usize| {
+            let address = virt(address);
+            let p2 = page_table
+                .p4()
+                .next_table(address.p4_index(), memory.physical_base())
+                .and_then(|p3| p3.next_table(address.p3_index(), memory.physical_base()))
+                .unwrap();
+            match p2.next_table(address.p2_index(), memory.physical_base()) {
+                Some(p1) => p1[address.p1_index()].flags(),
+                None => p2[address.p2_index()].flags() - EntryFlags::HUGE_PAGE,
+            }
+        };
+
+        assert_eq!(entry_flags(&page_table, 0x1000), EntryFlags::from(flags()));
+        assert_eq!(entry_flags(&page_table, 0x2000), EntryFlags::from(read_only));
+        assert_eq!(entry_flags(&page_table, 0x3000), EntryFlags::from(read_only));
+        assert_eq!(entry_flags(&page_table, 0x20_0000), EntryFlags::from(read_only));
+        assert_eq!(entry_flags(&page_table, 0x40_1000), EntryFlags::from(read_only));
+        assert_eq!(entry_flags(&page_table, 0x40_2000), EntryFlags::from(flags()));
+        assert_eq!(entry_flags(&page_table, 0x5f_f000), EntryFlags::from(flags()));
+
+        /*
+         * Only the second 2MiB page should have been split, and unmapped memory should stay unmapped.
+         */
+        let p2 = page_table
+            .p4()
+            .next_table(0, memory.physical_base())
+            .and_then(|p3| p3.next_table(0, memory.physical_base()))
+            .unwrap();
+        assert!(p2[1].is_huge());
+        assert!(!p2[2].is_huge());
+        assert_eq!(page_table.translate(virt(0x4000)), None);
+        assert_eq!(page_table.translate(virt(0x40_1234)), Some(phys(0x80_1234)));
+        assert_eq!(page_table.translate(virt(0x40_2234)), Some(phys(0x80_2234)));
+    }
+
+    #[test]
+    fn test_mappings() {
+        let memory = TestMemory::new(16);
+        let mut page_table = PageTableImpl::new(memory.allocate(), memory.physical_base());
+        let read_only = Flags::default();
+
+        page_table
+            .map_range(
+                Page::<Size4KiB>::starts_with(virt(0x1000))..Page::starts_with(virt(0x4000)),
+                Frame::starts_with(phys(0x5000))..Frame::starts_with(phys(0x8000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(Page::<Size4KiB>::starts_with(virt(0x4000)), Frame::starts_with(phys(0x10000)), flags(), &memory)
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size4KiB>::starts_with(virt(0x5000)),
+                Frame::starts_with(phys(0x11000)),
+                read_only,
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map_range(
+                Page::<Size2MiB>::starts_with(virt(0x20_0000))..Page::starts_with(virt(0x60_0000)),
+                Frame::starts_with(phys(0x60_0000))..Frame::starts_with(phys(0xa0_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size1GiB>::starts_with(virt(0x4000_0000)),
+                Frame::starts_with(phys(0x8000_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        /*
+         * These are contiguous in physical memory, and would be in virtual memory if it wasn't for the hole in the
+         * middle of the address space, so they shouldn't be coalesced.
+         */
+        page_table
+            .map(
+                Page::<Size4KiB>::starts_with(virt(0x7fff_ffff_f000)),
+                Frame::starts_with(phys(0x20_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size4KiB>::starts_with(virt(0xffff_8000_0000_0000)),
+                Frame::starts_with(phys(0x20_1000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        let mapping = |virtual_start, physical_start, size, page_size, flags| Mapping {
+            virtual_start: virt(virtual_start),
+            physical_start: phys(physical_start),
+            size,
+            page_size,
+            flags,
+        };
+        assert_eq!(
+            page_table.mappings().collect::<Vec<_>>(),
+            vec![
+                mapping(0x1000, 0x5000, 0x3000, Size4KiB::SIZE, flags()),
+                mapping(0x4000, 0x10000, 0x1000, Size4KiB::SIZE, flags()),
+                mapping(0x5000, 0x11000, 0x1000, Size4KiB::SIZE, read_only),
+                mapping(0x20_0000, 0x60_0000, 0x40_0000, Size2MiB::SIZE, flags()),
+                mapping(0x4000_0000, 0x8000_0000, Size1GiB::SIZE, Size1GiB::SIZE, flags()),
+                mapping(0x7fff_ffff_f000, 0x20_0000, 0x1000, Size4KiB::SIZE, flags()),
+                mapping(0xffff_8000_0000_0000, 0x20_1000, 0x1000, Size4KiB::SIZE, flags()),
+            ]
+        );
+
+        let mut num_mappings = 0;
+        page_table.walk(|_| num_mappings += 1);
+        assert_eq!(num_mappings, 7);
+
+        assert_eq!(
+            page_table.mapping_of(virt(0x2abc)),
+            Some(mapping(0x2000, 0x6000, 0x1000, Size4KiB::SIZE, flags()))
+        );
+        assert_eq!(
+            page_table.mapping_of(virt(0x30_1234)),
+            Some(mapping(0x20_0000, 0x60_0000, Size2MiB::SIZE, Size2MiB::SIZE, flags()))
+        );
+        assert_eq!(page_table.mapping_of(virt(0x6000)), None);
+    }
+
+    #[test]
+    fn test_free_tables() {
+        let memory = TestMemory::new(16);
+        let mut page_table = PageTableImpl::new(memory.allocate(), memory.physical_base());
+        page_table
+            .map(Page::<Size4KiB>::starts_with(virt(0x1000)), Frame::starts_with(phys(0x5000)), flags(), &memory)
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size2MiB>::starts_with(virt(0x20_0000)),
+                Frame::starts_with(phys(0x60_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        page_table
+            .map(
+                Page::<Size1GiB>::starts_with(virt(0x80_0000_0000)),
+                Frame::starts_with(phys(0x8000_0000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+        let user_frames = memory.frames_allocated();
+
+        /*
+         * The tables under the kernel's P4 entry are shared, so they shouldn't be freed.
+         */
+        page_table
+            .map(
+                Page::<Size4KiB>::starts_with(virt(0xffff_ff80_0000_0000)),
+                Frame::starts_with(phys(0x6000)),
+                flags(),
+                &memory,
+            )
+            .unwrap();
+
+        page_table.free_tables(&memory);
+        assert_eq!(memory.frames_freed(), user_frames);
+    }
+}
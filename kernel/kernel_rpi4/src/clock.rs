@@ -0,0 +1,40 @@
+//! This module provides the kernel's monotonic clock, using the ARM Generic Timer's physical counter
+//! (`CNTPCT_EL0`). The counter is always present, runs at a constant frequency (given by `CNTFRQ_EL0`, which the
+//! firmware sets up), and is synchronised between cores, so it makes a good clock.
+
+use hal_arm64::hw::timer::{self, read_counter};
+use log::info;
+use pebble_util::InitGuard;
+
+static CLOCK: InitGuard<Clock> = InitGuard::uninit();
+
+struct Clock {
+    start: u64,
+    frequency: u64,
+}
+
+pub fn init() {
+    let frequency = timer::frequency();
+    info!("Using the generic timer's counter as monotonic clock (frequency = {}Hz)", frequency);
+    CLOCK.initialize(Clock { start: read_counter(), frequency });
+}
+
+/// Get the number of nanoseconds since the clock was initialised, or `None` if it hasn't been initialised yet.
+pub fn try_now() -> Option<u64> {
+    if CLOCK.try_get().is_some() {
+        Some(now())
+    } else {
+        None
+    }
+}
+
+/// Get the number of nanoseconds since the clock was initialised.
+pub fn now() -> u64 {
+    let clock = CLOCK.get();
+
+    /*
+     * We do this calculation in 128 bits, as multiplying by 10^9 first would otherwise overflow after only a few
+     * minutes.
+     */
+    ((read_counter() - clock.start) as u128 * 1_000_000_000 / clock.frequency as u128) as u64
+}
@@ -0,0 +1,184 @@
+//! This module handles exceptions taken through the vector table in `exception.s`. Like on x86_64, exceptions
+//! caused by userspace are never fatal to the kernel - the task that caused them is killed instead. System calls
+//! are also made with an exception (`svc`), so they're dispatched from here too.
+
+use crate::{interrupts, user_access, PlatformImpl};
+use hal::{interrupts::InterruptOrigin, memory::VirtualAddress};
+use hal_arm64::{
+    hw::{
+        exception::{self, Esr, ExceptionClass, ExceptionFrame, ExceptionKind, ExceptionOrigin, Vector},
+        registers::read_sysreg,
+    },
+    kernel_map,
+    paging::{Half, PageTableImpl},
+};
+use libpebble::syscall::{FaultKind, FaultReport};
+use log::{error, info, log, Level};
+
+global_asm!(include_str!("exception.s"));
+extern "C" {
+    /*
+     * This isn't really a static - it's the label of the vector table, and we only use its address.
+     */
+    static exception_vector_table: u8;
+}
+
+pub fn install_vector_table() {
+    unsafe {
+        exception::install_vector_table(VirtualAddress::new(&exception_vector_table as *const u8 as usize));
+    }
+}
+
+/// Called by the vector table with the index of the vector the exception was taken through, and the saved state of
+/// the interrupted code. Any changes made to `frame` are restored when the handler returns.
+#[no_mangle]
+extern "C" fn rust_exception_entry(vector: u64, frame: &mut ExceptionFrame) {
+    let vector = Vector::from_index(vector);
+
+    match (vector.origin, vector.kind) {
+        (ExceptionOrigin::LowerElAarch64, ExceptionKind::Synchronous) => handle_user_synchronous(frame),
+        (ExceptionOrigin::CurrentElSpx, ExceptionKind::Synchronous) => handle_kernel_synchronous(frame),
+
+        (ExceptionOrigin::LowerElAarch64, ExceptionKind::Irq) => {
+            interrupts::handle_irq(InterruptOrigin::Userspace)
+        }
+        (ExceptionOrigin::CurrentElSpx, ExceptionKind::Irq) => interrupts::handle_irq(InterruptOrigin::Kernel),
+
+        /*
+         * We put all interrupts in Group 1, which are signalled as IRQs, so we shouldn't ever take an FIQ.
+         */
+        (_, ExceptionKind::Fiq) => panic!("Took an FIQ through {:?}, but we don't use them!", vector),
+        (_, ExceptionKind::SError) => {
+            error!("SERROR: {:?}\n{:#x?}", Esr::read(), frame);
+            panic!("Unrecoverable SError");
+        }
+
+        /*
+         * The kernel never uses `SP_EL0`, and we don't support running AArch32 tasks.
+         */
+        _ => panic!("Took exception through unexpected vector: {:?}", vector),
+    }
+}
+
+fn handle_user_synchronous(frame: &mut ExceptionFrame) {
+    let esr = Esr::read();
+    let elr = frame.exception_link_register;
+
+    match esr.class() {
+        ExceptionClass::SupervisorCall => {
+            /*
+             * Interrupts are masked on exception entry, but system calls can take a long time, so we unmask them
+             * while we handle it.
+             */
+            let [number, a, b, c, d, e] = {
+                let r = &frame.registers;
+                [r[0], r[1], r[2], r[3], r[4], r[5]]
+            };
+            unsafe {
+                asm!("msr daifclr, #2");
+            }
+            let result = kernel::syscall::handle_syscall::<PlatformImpl>(
+                number as usize,
+                a as usize,
+                b as usize,
+                c as usize,
+                d as usize,
+                e as usize,
+            );
+            unsafe {
+                asm!("msr daifset, #2");
+            }
+            frame.registers[0] = result as u64;
+        }
+
+        ExceptionClass::InstructionAbortLowerEl | ExceptionClass::DataAbortLowerEl => {
+            // FAR_EL1 holds the address that caused the abort
+            let far = read_sysreg!(far_el1);
+            log_mapping_of(Level::Warn, far);
+            kill_faulting_task(FaultKind::PageFault, esr.0, elr, far);
+        }
+        ExceptionClass::Unknown | ExceptionClass::FloatingPointAccess | ExceptionClass::IllegalExecutionState => {
+            kill_faulting_task(FaultKind::InvalidOpcode, esr.0, elr, 0)
+        }
+        ExceptionClass::PcAlignment | ExceptionClass::SpAlignment => {
+            kill_faulting_task(FaultKind::AlignmentCheck, esr.0, elr, 0)
+        }
+        ExceptionClass::Breakpoint => kill_faulting_task(FaultKind::Debug, esr.0, elr, 0),
+        _ => kill_faulting_task(FaultKind::GeneralProtectionFault, esr.0, elr, 0),
+    }
+}
+
+fn handle_kernel_synchronous(frame: &mut ExceptionFrame) {
+    let esr = Esr::read();
+
+    match esr.class() {
+        ExceptionClass::DataAbortSameEl => {
+            /*
+             * If the kernel faulted while copying to or from userspace, it's because userspace gave us a bad
+             * pointer, so we make the copy fail instead of panicking.
+             */
+            if user_access::fixup_fault(&mut frame.exception_link_register) {
+                return;
+            }
+
+            let far = read_sysreg!(far_el1);
+            error!(
+                "DATA ABORT: kernel {} {:#x} at {:#x}: {}",
+                if esr.is_write() { "wrote to" } else { "read from" },
+                far,
+                frame.exception_link_register,
+                esr.fault_status()
+            );
+            log_mapping_of(Level::Error, far);
+            error!("{:#x?}", frame);
+            log_backtrace(Level::Error, frame);
+            panic!("Unrecoverable fault");
+        }
+
+        ExceptionClass::Breakpoint => {
+            info!("BREAKPOINT: {:#x?}", frame);
+            log_backtrace(Level::Info, frame);
+
+            /*
+             * Unlike `int3`, `brk` leaves `ELR_EL1` pointing at itself, so we skip over it.
+             */
+            frame.exception_link_register += 4;
+        }
+
+        class => {
+            error!("EXCEPTION: {:?} at {:#x} ({:?})", class, frame.exception_link_register, esr);
+            error!("{:#x?}", frame);
+            log_backtrace(Level::Error, frame);
+            panic!("Unrecoverable fault");
+        }
+    }
+}
+
+/// Log a backtrace of the code that was interrupted by an exception. This is only safe to call for exceptions that
+/// occured in the kernel, as the frame pointer of userspace code can't be trusted.
+fn log_backtrace(level: Level, frame: &ExceptionFrame) {
+    unsafe {
+        kernel::backtrace::log_backtrace(
+            level,
+            Some(frame.exception_link_register as usize),
+            frame.registers[29] as usize,
+        );
+    }
+}
+
+/// Log the page that contains `address` in the page tables that are currently installed for its half of the
+/// address space (the running task's, for the lower half), to help work out why it caused an abort.
+fn log_mapping_of(level: Level, address: u64) {
+    let address = VirtualAddress::new(address as usize);
+    let half = if address >= kernel_map::KERNEL_ADDRESS_SPACE_START { Half::Upper } else { Half::Lower };
+    let page_table = unsafe { PageTableImpl::current(half, kernel_map::PHYSICAL_MAPPING_BASE) };
+
+    match page_table.mapping_of(address) {
+        Some(mapping) => log!(level, "Faulting address {:#x} is mapped: {}", address, mapping),
+        None => log!(level, "Faulting address {:#x} is not mapped", address),
+    }
+}
+
+fn kill_faulting_task(kind: FaultKind, error_code: u64, instruction_pointer: u64, address: u64) -> ! {
+    kernel::handle_user_fault::<PlatformImpl>(FaultReport { kind, error_code, instruction_pointer, address })
+}
@@ -0,0 +1,88 @@
+/*
+ * The exception vector table. Each entry saves `x0` and `x1`, puts the index of the entry in `x0`, and then jumps
+ * to the common code, which saves the rest of the state of the interrupted code as an `ExceptionFrame` (see
+ * `hal_arm64::hw::exception`), and calls `rust_exception_entry` with it. When that returns, the (possibly
+ * modified) state is restored, and we return to the interrupted code with `eret`.
+ *
+ * Exceptions taken from the kernel use the kernel stack that was already in use, and exceptions taken from
+ * userspace use the task's kernel stack, as `SP_EL1` always holds it while userspace is running.
+ */
+
+.equ EXCEPTION_FRAME_SIZE, 272
+
+.macro EXCEPTION_VECTOR index
+.balign 0x80
+    sub sp, sp, #EXCEPTION_FRAME_SIZE
+    stp x0, x1, [sp, #0]
+    mov x0, #\index
+    b exception_common
+.endm
+
+.section .text
+.balign 0x800
+.global exception_vector_table
+exception_vector_table:
+    EXCEPTION_VECTOR 0
+    EXCEPTION_VECTOR 1
+    EXCEPTION_VECTOR 2
+    EXCEPTION_VECTOR 3
+    EXCEPTION_VECTOR 4
+    EXCEPTION_VECTOR 5
+    EXCEPTION_VECTOR 6
+    EXCEPTION_VECTOR 7
+    EXCEPTION_VECTOR 8
+    EXCEPTION_VECTOR 9
+    EXCEPTION_VECTOR 10
+    EXCEPTION_VECTOR 11
+    EXCEPTION_VECTOR 12
+    EXCEPTION_VECTOR 13
+    EXCEPTION_VECTOR 14
+    EXCEPTION_VECTOR 15
+
+exception_common:
+    stp x2, x3, [sp, #16]
+    stp x4, x5, [sp, #32]
+    stp x6, x7, [sp, #48]
+    stp x8, x9, [sp, #64]
+    stp x10, x11, [sp, #80]
+    stp x12, x13, [sp, #96]
+    stp x14, x15, [sp, #112]
+    stp x16, x17, [sp, #128]
+    stp x18, x19, [sp, #144]
+    stp x20, x21, [sp, #160]
+    stp x22, x23, [sp, #176]
+    stp x24, x25, [sp, #192]
+    stp x26, x27, [sp, #208]
+    stp x28, x29, [sp, #224]
+    mrs x1, sp_el0
+    stp x30, x1, [sp, #240]
+    mrs x1, elr_el1
+    mrs x2, spsr_el1
+    stp x1, x2, [sp, #256]
+
+    // Call into Rust with the vector index in `x0`, and a pointer to the frame in `x1`
+    mov x1, sp
+    bl rust_exception_entry
+
+    ldp x1, x2, [sp, #256]
+    msr elr_el1, x1
+    msr spsr_el1, x2
+    ldp x30, x1, [sp, #240]
+    msr sp_el0, x1
+    ldp x28, x29, [sp, #224]
+    ldp x26, x27, [sp, #208]
+    ldp x24, x25, [sp, #192]
+    ldp x22, x23, [sp, #176]
+    ldp x20, x21, [sp, #160]
+    ldp x18, x19, [sp, #144]
+    ldp x16, x17, [sp, #128]
+    ldp x14, x15, [sp, #112]
+    ldp x12, x13, [sp, #96]
+    ldp x10, x11, [sp, #80]
+    ldp x8, x9, [sp, #64]
+    ldp x6, x7, [sp, #48]
+    ldp x4, x5, [sp, #32]
+    ldp x2, x3, [sp, #16]
+    ldp x0, x1, [sp, #0]
+    add sp, sp, #EXCEPTION_FRAME_SIZE
+    eret
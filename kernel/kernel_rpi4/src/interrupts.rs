@@ -0,0 +1,394 @@
+//! This module drives the interrupt controller, which is a GIC described by the device tree (a GIC-400 on the
+//! Raspberry Pi 4), and the generic timer.
+//!
+//! The vectors of `InterruptController` are INTIDs. The kernel can register handlers for SGIs and PPIs, which are
+//! specific to each CPU. On this platform, a GSI is the INTID of an SPI. SPIs are the only interrupts devices can
+//! raise, so they're the only ones that can be routed to an `Interrupt`.
+
+use crate::clock;
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use fdt::{Fdt, Node};
+use hal::{
+    interrupts::{InterruptHandler, InterruptOrigin, IpiTarget, RegisterHandlerError, TriggerMode},
+    memory::PhysicalAddress,
+};
+use hal_arm64::{
+    hw::{
+        gic::{AcknowledgedInterrupt, Gic, FIRST_SPI},
+        gicv2::GicV2,
+        gicv3::GicV3,
+        timer,
+    },
+    kernel_map,
+};
+use kernel::{
+    object::{
+        event::Event,
+        interrupt::{InterruptRoutingError, INTERRUPT_SIGNAL},
+    },
+    pci::MsiMessage,
+};
+use log::{info, warn};
+use pebble_util::InitGuard;
+use spin::Mutex;
+
+pub static INTERRUPT_CONTROLLER: InitGuard<InterruptController> = InitGuard::uninit();
+pub static TIMER: InitGuard<GenericTimer> = InitGuard::uninit();
+static IRQ_ROUTING: InitGuard<Mutex<IrqRouting>> = InitGuard::uninit();
+
+/// The non-secure EL1 physical timer is PPI 14 on every platform we know of, but we check the device tree anyway.
+const DEFAULT_TIMER_INTERRUPT: u32 = 30;
+const FIRST_PPI: u32 = 16;
+
+pub struct InterruptController {
+    gic: Box<dyn Gic + Send + Sync>,
+    /// The handlers the kernel has registered for SGIs and PPIs, indexed by INTID.
+    handlers: Mutex<[Option<InterruptHandler>; FIRST_SPI as usize]>,
+}
+
+impl hal::interrupts::InterruptController for InterruptController {
+    fn register_handler(&self, vector: u32, handler: InterruptHandler) -> Result<(), RegisterHandlerError> {
+        if vector >= FIRST_SPI {
+            return Err(RegisterHandlerError::InvalidVector);
+        }
+
+        without_interrupts(|| {
+            let mut handlers = self.handlers.lock();
+            if handlers[vector as usize].is_some() {
+                return Err(RegisterHandlerError::AlreadyRegistered);
+            }
+            handlers[vector as usize] = Some(handler);
+            Ok(())
+        })
+    }
+
+    fn set_masked(&self, vector: u32, masked: bool) {
+        self.gic.set_masked(vector, masked);
+    }
+
+    fn end_of_interrupt(&self, vector: u32) {
+        self.gic.end_of_interrupt(vector);
+    }
+
+    fn send_ipi(&self, target: IpiTarget, vector: u32) {
+        self.gic.send_sgi(target, vector);
+    }
+}
+
+/// The EL1 physical timer of the generic timer. It only supports one-shot timeouts, so we make it periodic by
+/// setting it again each time it fires.
+pub struct GenericTimer {
+    interrupt: u32,
+    /// The period of the timer in nanoseconds, if it's periodic, or `0` if it's one-shot.
+    period: AtomicU64,
+}
+
+impl GenericTimer {
+    /// Called when the timer's interrupt arrives, before the kernel's handler.
+    fn on_interrupt(&self) {
+        /*
+         * The timer's interrupt is level-triggered, and stays asserted until it's set again or disabled.
+         */
+        match self.period.load(Ordering::Relaxed) {
+            0 => timer::disable(),
+            period => timer::set_timeout(Duration::from_nanos(period)),
+        }
+    }
+}
+
+impl hal::timer::Timer for GenericTimer {
+    fn vector(&self) -> u32 {
+        self.interrupt
+    }
+
+    fn monotonic_time(&self) -> u64 {
+        clock::now()
+    }
+
+    fn set_one_shot(&self, duration: Duration) {
+        self.period.store(0, Ordering::Relaxed);
+        timer::set_timeout(duration);
+    }
+
+    fn set_periodic(&self, period: Duration) {
+        self.period.store(period.as_nanos() as u64, Ordering::Relaxed);
+        timer::set_timeout(period);
+    }
+
+    fn stop(&self) {
+        self.period.store(0, Ordering::Relaxed);
+        timer::disable();
+    }
+}
+
+struct IrqRoute {
+    trigger_mode: TriggerMode,
+    /// The events to signal when the interrupt fires. Level-triggered SPIs can be shared by several devices, so can
+    /// have more than one.
+    events: Vec<Arc<Event>>,
+}
+
+impl IrqRoute {
+    /// Whether any of the events the interrupt has been delivered to haven't acknowledged it yet.
+    fn is_pending(&self) -> bool {
+        self.events.iter().any(|event| event.signals() & INTERRUPT_SIGNAL != 0)
+    }
+}
+
+struct IrqRouting {
+    /// The trigger modes of the SPIs described by the device tree.
+    interrupt_modes: BTreeMap<u32, TriggerMode>,
+    /// Maps the INTIDs of SPIs to the events they've been routed to.
+    routes: BTreeMap<u32, IrqRoute>,
+}
+
+impl IrqRouting {
+    fn trigger_mode(&self, gsi: u32) -> TriggerMode {
+        /*
+         * If the device tree doesn't tell us otherwise, we assume interrupts are level-triggered, as most
+         * peripherals' are.
+         */
+        self.interrupt_modes.get(&gsi).copied().unwrap_or(TriggerMode::Level)
+    }
+}
+
+/// Find and initialise the GIC described by the device tree, and find the timer's interrupt. The timer isn't
+/// started until the kernel sets it.
+pub fn init(fdt: &Fdt) {
+    let (gic, gic_node) = find_interrupt_controller(fdt).expect("No supported interrupt controller found");
+
+    /*
+     * The timer's `interrupts` property lists its secure, non-secure, virtual, and hypervisor PPIs, in that order.
+     * We use the non-secure one.
+     */
+    let timer_interrupt = fdt
+        .find_compatible(&["arm,armv8-timer", "arm,armv7-timer"])
+        .and_then(|node| node.property("interrupts"))
+        .and_then(|property| property.cells().skip(3).nth(1))
+        .map(|ppi| FIRST_PPI + ppi)
+        .unwrap_or(DEFAULT_TIMER_INTERRUPT);
+    gic.set_trigger_mode(timer_interrupt, TriggerMode::Level);
+    info!("Using the generic timer through INTID {}", timer_interrupt);
+
+    INTERRUPT_CONTROLLER.initialize(InterruptController { gic, handlers: Mutex::new([None; FIRST_SPI as usize]) });
+    TIMER.initialize(GenericTimer { interrupt: timer_interrupt, period: AtomicU64::new(0) });
+    IRQ_ROUTING.initialize(Mutex::new(IrqRouting {
+        interrupt_modes: interrupt_modes(fdt, gic_node),
+        routes: BTreeMap::new(),
+    }));
+}
+
+/// Find the first GIC we have a driver for, create a driver for it, and initialise it. Returns the driver, and the
+/// GIC's node in the device tree.
+fn find_interrupt_controller<'a>(fdt: &Fdt<'a>) -> Option<(Box<dyn Gic + Send + Sync>, Node<'a>)> {
+    let map_region = |node: &Node, index: usize| {
+        let region = node.mmio_regions().nth(index)?;
+        Some(kernel_map::physical_to_virtual(PhysicalAddress::new(region.address as usize)?))
+    };
+
+    if let Some(node) = fdt.find_compatible(&["arm,gic-400", "arm,cortex-a15-gic"]) {
+        let gic = unsafe { GicV2::new(map_region(&node, 0)?, map_region(&node, 1)?) };
+        unsafe {
+            gic.init();
+        }
+        info!("Found GICv2 at {}, with {} interrupts", node.name(), gic.num_interrupts());
+        return Some((Box::new(gic), node));
+    }
+
+    if let Some(node) = fdt.find_compatible(&["arm,gic-v3"]) {
+        let gic = unsafe { GicV3::new(map_region(&node, 0)?, map_region(&node, 1)?)? };
+        unsafe {
+            gic.init();
+        }
+        info!("Found GICv3 at {}, with {} interrupts", node.name(), gic.num_interrupts());
+        return Some((Box::new(gic), node));
+    }
+
+    None
+}
+
+/// Find the trigger modes of the SPIs the devices in the device tree deliver to `gic`. Interrupts are described by
+/// three cells: the type (`0` for SPIs), the number within that type, and flags, the bottom four of which give
+/// the trigger mode.
+fn interrupt_modes(fdt: &Fdt, gic: Node) -> BTreeMap<u32, TriggerMode> {
+    let gic_phandle = gic.property("phandle").and_then(|property| property.as_u32());
+    let mut modes = BTreeMap::new();
+
+    for node in fdt.all_nodes().filter(|node| node.is_enabled()) {
+        let interrupts = match node.property("interrupts") {
+            Some(interrupts) => interrupts,
+            None => continue,
+        };
+        if interrupt_parent(node) != gic_phandle {
+            continue;
+        }
+
+        let mut cells = interrupts.cells();
+        while let (Some(kind), Some(number), Some(flags)) = (cells.next(), cells.next(), cells.next()) {
+            if kind != 0 {
+                continue;
+            }
+            let trigger_mode = if flags & 0b0011 != 0 { TriggerMode::Edge } else { TriggerMode::Level };
+            modes.insert(FIRST_SPI + number, trigger_mode);
+        }
+    }
+
+    modes
+}
+
+/// Find the phandle of the controller a node's interrupts are delivered to. It's inherited from the node's parents
+/// if it doesn't have an `interrupt-parent` property itself.
+fn interrupt_parent(node: Node) -> Option<u32> {
+    let mut node = Some(node);
+    while let Some(current) = node {
+        if let Some(phandle) = current.property("interrupt-parent").and_then(|property| property.as_u32()) {
+            return Some(phandle);
+        }
+        node = current.parent();
+    }
+    None
+}
+
+/// Called when an IRQ exception is taken. We handle every interrupt that's pending, as the GIC only raises the
+/// exception again when a new one arrives.
+pub fn handle_irq(origin: InterruptOrigin) {
+    let controller = INTERRUPT_CONTROLLER.get();
+
+    while let Some(AcknowledgedInterrupt { intid: interrupt, eoi_value }) = controller.gic.acknowledge() {
+        /*
+         * SGIs and PPIs are handled by the kernel. The handler is responsible for signalling the end of the
+         * interrupt, and could switch to another task, so we mustn't hold any locks when we call it. It's passed
+         * the value the end of the interrupt must be signalled with as its vector, which is the INTID except for
+         * SGIs on a GICv2, where it also identifies the CPU that sent the SGI.
+         */
+        if interrupt < FIRST_SPI {
+            if interrupt == TIMER.get().interrupt {
+                TIMER.get().on_interrupt();
+            }
+
+            let handler = controller.handlers.lock()[interrupt as usize];
+            match handler {
+                Some(handler) => handler(eoi_value, origin),
+                None => {
+                    warn!("Interrupt with INTID {} has no handler. Masking it.", interrupt);
+                    controller.gic.set_masked(interrupt, true);
+                    controller.gic.end_of_interrupt(eoi_value);
+                }
+            }
+            continue;
+        }
+
+        let routing = IRQ_ROUTING.get().lock();
+        if let Some(route) = routing.routes.get(&interrupt) {
+            for event in &route.events {
+                event.signal(INTERRUPT_SIGNAL);
+            }
+
+            /*
+             * Level-triggered interrupts keep firing until the device that raised them has been serviced, so we mask
+             * them until the tasks handling them acknowledge them. Every task sharing the interrupt is signalled, and
+             * each one has to check its own device.
+             */
+            if route.trigger_mode == TriggerMode::Level {
+                controller.gic.set_masked(interrupt, true);
+            }
+        } else {
+            warn!("Unexpected interrupt with INTID {}. Masking it.", interrupt);
+            controller.gic.set_masked(interrupt, true);
+        }
+
+        drop(routing);
+        controller.gic.end_of_interrupt(eoi_value);
+    }
+}
+
+/// Route the SPI with the given GSI (its INTID), so that `INTERRUPT_SIGNAL` is set on `event` when it fires.
+/// Level-triggered SPIs can be shared, in which case `event` is added to the events already signalled by it.
+pub fn route_interrupt(gsi: u32, event: Arc<Event>) -> Result<(), InterruptRoutingError> {
+    without_interrupts(|| {
+        let gic = &INTERRUPT_CONTROLLER.get().gic;
+        let mut routing = IRQ_ROUTING.get().lock();
+
+        if gsi < FIRST_SPI || gsi >= gic.num_interrupts() {
+            return Err(InterruptRoutingError::InvalidGsi);
+        }
+        if let Some(route) = routing.routes.get_mut(&gsi) {
+            /*
+             * Edge-triggered interrupts can't be shared reliably, because an edge from a second device could be
+             * lost while the line is still asserted by the first.
+             */
+            if route.trigger_mode != TriggerMode::Level {
+                return Err(InterruptRoutingError::AlreadyRouted);
+            }
+            route.events.push(event);
+            return Ok(());
+        }
+
+        let trigger_mode = routing.trigger_mode(gsi);
+        routing.routes.insert(gsi, IrqRoute { trigger_mode, events: vec![event] });
+        gic.set_trigger_mode(gsi, trigger_mode);
+        gic.set_masked(gsi, false);
+        Ok(())
+    })
+}
+
+/// Unmask an interrupt that has been handled, once every event it was delivered to has acknowledged it. Only
+/// level-triggered interrupts are masked when they fire, but we unmask all interrupts here for simplicity.
+pub fn acknowledge_interrupt(gsi: u32) {
+    without_interrupts(|| {
+        if IRQ_ROUTING.get().lock().routes.get(&gsi).map_or(false, |route| !route.is_pending()) {
+            INTERRUPT_CONTROLLER.get().gic.set_masked(gsi, false);
+        }
+    })
+}
+
+/// Stop signalling `event` when an interrupt fires. If no other events share the interrupt, it's masked.
+pub fn unroute_interrupt(gsi: u32, event: &Arc<Event>) {
+    without_interrupts(|| {
+        let gic = &INTERRUPT_CONTROLLER.get().gic;
+        let mut routing = IRQ_ROUTING.get().lock();
+
+        if let Some(route) = routing.routes.get_mut(&gsi) {
+            route.events.retain(|routed| !Arc::ptr_eq(routed, event));
+
+            if route.events.is_empty() {
+                routing.routes.remove(&gsi);
+                gic.set_masked(gsi, true);
+            } else if !route.is_pending() {
+                /*
+                 * If the interrupt was waiting to be acknowledged by the event we've just removed, nothing else
+                 * will unmask it.
+                 */
+                gic.set_masked(gsi, false);
+            }
+        }
+    })
+}
+
+/*
+ * TODO: MSIs on the Raspberry Pi 4 are handled by a Broadcom-specific controller in the PCIe root complex, which
+ * we don't have a driver for yet. Other platforms would use a GICv2m frame or a GICv3 ITS.
+ */
+pub fn route_msi(_event: Arc<Event>) -> Result<MsiMessage, InterruptRoutingError> {
+    Err(InterruptRoutingError::NoFreeVectors)
+}
+
+/// Run `f` with IRQs masked on this CPU. The routing state and handlers are also locked by `handle_irq`, so we
+/// mustn't be interrupted while we hold their locks (system calls are handled with IRQs unmasked).
+fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let daif: u64;
+    unsafe {
+        asm!("mrs {}, daif; msr daifset, #2", out(reg) daif);
+    }
+
+    let result = f();
+
+    unsafe {
+        asm!("msr daif, {}", in(reg) daif);
+    }
+    result
+}
@@ -0,0 +1,63 @@
+use crate::clock;
+use hal::memory::{PhysicalAddress, VirtualAddress};
+use hal_arm64::{hw::pl011::Pl011, kernel_map};
+use kernel::{klog, log_filter, object::SENTINEL_KERNEL_ID};
+use log::{Log, Metadata, Record};
+use spin::Mutex;
+
+/// The UART we log to. This defaults to the Raspberry Pi 4's primary UART (`UART0`), which is a PL011 at physical
+/// address `0xfe201000`, but can be changed to the one the device tree describes by `init`. We access it through
+/// the physical mapping, which the boot code maps the peripherals into as Device memory.
+static UART: Mutex<Pl011> = Mutex::new(unsafe { Pl011::new(VirtualAddress::new(0xffff_0000_fe20_1000)) });
+
+/// The firmware sets the UART's reference clock to 48MHz.
+const UART_CLOCK_FREQUENCY: u32 = 48_000_000;
+const UART_BAUD_RATE: u32 = 115_200;
+
+/// Initialise the UART at `address`, or the default one if it's `None`. The UART must be within the first 4GiB of
+/// the physical address space, which is the only part the boot page tables map.
+pub fn init(address: Option<PhysicalAddress>) {
+    let mut uart = UART.lock();
+    unsafe {
+        if let Some(address) = address {
+            *uart = Pl011::new(kernel_map::physical_to_virtual(address));
+        }
+        uart.initialise(UART_CLOCK_FREQUENCY, UART_BAUD_RATE);
+    }
+}
+
+/// This handles calls to the log macros throughout the kernel, and writes logging to the UART. Messages are also
+/// added to the kernel log (see `kernel::klog`). Which messages are enabled is controlled by the log filter (see
+/// `kernel::log_filter`).
+pub struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        /*
+         * Messages from tasks have already been filtered by the task that logged them.
+         */
+        metadata.target() == klog::TASK_LOG_TARGET || metadata.level() <= log_filter::level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        use core::fmt::Write;
+
+        if self.enabled(record.metadata()) {
+            if record.target() != klog::TASK_LOG_TARGET {
+                klog::record(
+                    record.level(),
+                    SENTINEL_KERNEL_ID,
+                    clock::try_now().unwrap_or(0),
+                    crate::cpu_id(),
+                    *record.args(),
+                );
+            }
+
+            UART.lock()
+                .write_fmt(format_args!("[{}][{}] {}\n", record.level(), record.target(), record.args()))
+                .unwrap();
+        }
+    }
+
+    fn flush(&self) {}
+}
@@ -1,35 +1,380 @@
 #![no_std]
 #![no_main]
-#![feature(asm, global_asm)]
+#![feature(asm, global_asm, decl_macro)]
+
+extern crate alloc;
+extern crate rlibc;
+
+mod clock;
+mod exception;
+mod interrupts;
+mod logger;
+mod memory_map;
+mod pci;
+mod per_cpu;
+mod task;
+mod user_access;
 
 global_asm!(include_str!("start.s"));
 
-use core::panic::PanicInfo;
+use alloc::{boxed::Box, string::String, sync::Arc};
+use core::{
+    ops::Range,
+    panic::PanicInfo,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use fdt::{Fdt, Node};
+use hal::memory::{PhysicalAddress, VirtualAddress};
+use hal_arm64::{
+    hw::registers::read_sysreg,
+    kernel_map,
+    paging::{Half, PageTableImpl},
+};
+use kernel::{
+    memory::{KernelStackAllocator, PhysicalMemoryManager},
+    object::{event::Event, interrupt::InterruptRoutingError, task::Task, SENTINEL_KERNEL_ID},
+    pci::MsiMessage,
+    scheduler::Scheduler,
+    Platform,
+};
+use libpebble::caps::Capability;
+use log::{error, info, trace, warn};
+use pebble_util::InitGuard;
+use spin::{Mutex, MutexGuard};
+
+static KERNEL_PAGE_TABLE: InitGuard<Mutex<PageTableImpl>> = InitGuard::uninit();
+static KERNEL_STACK_ALLOCATOR: InitGuard<KernelStackAllocator<PlatformImpl>> = InitGuard::uninit();
+/// The device tree passed to us by the firmware, which describes the hardware of the platform. It's accessed
+/// through the physical mapping, and the memory it's in is never freed.
+pub static DEVICE_TREE: InitGuard<Fdt<'static>> = InitGuard::uninit();
 
 extern "C" {
-    static __bss_start: usize;
-    static __bss_end: usize;
+    /*
+     * These aren't really statics - they're defined by the linker script at the start and end of the kernel image,
+     * and we only use their addresses.
+     */
+    static _kernel_start: u8;
+    static _kernel_end: u8;
 }
 
-#[no_mangle]
-pub fn kmain() -> ! {
-    // Zero BSS
-    // TODO: do this in assembly before we reach Rust
-    let mut ptr = unsafe { &__bss_start as *const _ as *mut usize };
-    let end = unsafe { &__bss_end as *const _ as *mut usize };
+/// The size of the kernel heap, which is placed directly after the kernel image.
+const HEAP_SIZE: usize = hal::memory::mebibytes(8);
+
+/// The initial task is loaded from the boot medium alongside the kernel, so, like the images the loader gives us on
+/// x86_64, it's trusted with any capabilities it asks for.
+const INITIAL_TASK_CAPABILITIES: &[Capability] = &[
+    Capability::GetFramebuffer,
+    Capability::EarlyLogging,
+    Capability::ServiceProvider,
+    Capability::ServiceUser,
+    Capability::PciBusDriver,
+    Capability::CreateTask,
+    Capability::HandleInterrupts,
+    Capability::Supervisor,
+    Capability::ReadKernelLog,
+    Capability::DumpAddressSpace,
+];
+
+pub struct PlatformImpl;
+
+impl Platform for PlatformImpl {
+    type PageTableSize = hal::memory::Size4KiB;
+    type PageTable = PageTableImpl;
+    type PerCpu = per_cpu::PerCpuImpl;
+    type InterruptController = interrupts::InterruptController;
+    type Timer = interrupts::GenericTimer;
+
+    const USER_ADDRESS_SPACE_END: VirtualAddress = kernel_map::USER_ADDRESS_SPACE_END;
+    const DEVICE_WINDOW: VirtualAddress = kernel_map::DEVICE_WINDOW;
+
+    fn kernel_page_table<'a>() -> MutexGuard<'a, Self::PageTable> {
+        KERNEL_PAGE_TABLE.get().lock()
+    }
+
+    fn kernel_stack_allocator<'a>() -> &'a KernelStackAllocator<Self> {
+        KERNEL_STACK_ALLOCATOR.get()
+    }
+
+    fn physical_to_virtual(address: PhysicalAddress) -> VirtualAddress {
+        kernel_map::physical_to_virtual(address)
+    }
+
+    fn interrupt_controller<'a>() -> &'a Self::InterruptController {
+        interrupts::INTERRUPT_CONTROLLER.get()
+    }
+
+    fn timer<'a>() -> &'a Self::Timer {
+        interrupts::TIMER.get()
+    }
+
+    fn cpu_id() -> u32 {
+        cpu_id()
+    }
 
-    while ptr < end {
+    unsafe fn copy_user(destination: *mut u8, source: *const u8, length: usize) -> Result<(), ()> {
+        user_access::copy_user(destination, source, length)
+    }
+
+    fn route_interrupt(gsi: u32, event: Arc<Event>) -> Result<(), InterruptRoutingError> {
+        interrupts::route_interrupt(gsi, event)
+    }
+
+    fn acknowledge_interrupt(gsi: u32) {
+        interrupts::acknowledge_interrupt(gsi)
+    }
+
+    fn unroute_interrupt(gsi: u32, event: &Arc<Event>) {
+        interrupts::unroute_interrupt(gsi, event)
+    }
+
+    fn route_msi(event: Arc<Event>) -> Result<MsiMessage, InterruptRoutingError> {
+        interrupts::route_msi(event)
+    }
+
+    fn unroute_msi(_message: MsiMessage) {}
+
+    fn idle() {
+        /*
+         * If an interrupt is pending when we unmask them, it's taken before the `wfi`, and so we don't miss it. If
+         * it arrives later, it wakes us from the `wfi`, and is taken straight after.
+         */
         unsafe {
-            core::ptr::write_volatile(ptr, 0x0);
-            ptr = ptr.offset(1);
+            asm!("msr daifclr, #2; wfi; msr daifset, #2");
         }
     }
 
-    panic!("Did stuff!");
+    fn per_cpu<'a>() -> Pin<&'a mut Self::PerCpu> {
+        unsafe { per_cpu::get_per_cpu_data() }
+    }
+
+    unsafe fn initialize_task_kernel_stack(
+        kernel_stack_top: &mut VirtualAddress,
+        task_entry_point: VirtualAddress,
+        task_argument: usize,
+        user_stack_top: &mut VirtualAddress,
+    ) {
+        task::initialize_kernel_stack(kernel_stack_top, task_entry_point, task_argument, user_stack_top);
+    }
+
+    unsafe fn context_switch(current_kernel_stack: *mut VirtualAddress, new_kernel_stack: VirtualAddress) {
+        task::context_switch(current_kernel_stack, new_kernel_stack)
+    }
+
+    unsafe fn drop_into_userspace(kernel_stack_pointer: VirtualAddress) -> ! {
+        task::drop_into_userspace(kernel_stack_pointer)
+    }
 }
 
+/// The affinity fields of `MPIDR_EL1` identify the running core.
+pub fn cpu_id() -> u32 {
+    (read_sysreg!(mpidr_el1) & 0xff_ffff) as u32
+}
+
+/// Called by `start.s` once it has enabled the MMU, and moved us to the upper half of the address space.
+/// `device_tree_address` is the physical address of the device tree the firmware passed us.
+#[no_mangle]
+pub extern "C" fn kmain(device_tree_address: usize) -> ! {
+    /*
+     * Parse the device tree first, so we can find the UART we should log to. If we don't have one, we carry on
+     * with the default UART, so we can at least report the problem.
+     */
+    let fdt = PhysicalAddress::new(device_tree_address)
+        .ok_or(fdt::FdtError::IncorrectMagic)
+        .and_then(|address| unsafe { Fdt::from_ptr(kernel_map::physical_to_virtual(address).ptr()) });
+    let uart_address = fdt.as_ref().ok().and_then(find_uart);
+
+    logger::init(uart_address);
+    log::set_logger(&logger::KernelLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("Pebble kernel is running");
+
+    /*
+     * Install the exception handlers. Unlike on x86_64, we can do this straight away, as the vector table doesn't
+     * need anything else to be set up first.
+     */
+    exception::install_vector_table();
+
+    let fdt = match fdt {
+        Ok(fdt) => fdt,
+        Err(err) => panic!("Failed to parse device tree at {:#x}: {:?}", device_tree_address, err),
+    };
+    if let Some(model) = fdt.root().property("model").and_then(|property| property.as_str()) {
+        info!("Running on: {}", model);
+    }
+    for node in fdt.all_nodes().filter(|node| node.is_enabled()) {
+        if let (Some(compatible), Some(region)) = (node.compatible().next(), node.mmio_regions().next()) {
+            trace!("Found device {} ({}) at {:#x}", node.name(), compatible, region.address);
+        }
+    }
+
+    /*
+     * There's no loader on this platform to set up a heap for us, so we put it directly after the kernel image,
+     * which is already mapped by the boot page tables.
+     */
+    let heap_start = VirtualAddress::new(unsafe { &_kernel_end as *const u8 as usize }).align_up(0x1000);
+    unsafe {
+        kernel::ALLOCATOR.lock().init(heap_start, HEAP_SIZE);
+    }
+
+    /*
+     * Build the memory map from the device tree, making sure we don't use the memory the kernel image, heap, device
+     * tree, and initrd (if there is one) are already in.
+     */
+    let initrd = find_initrd(&fdt);
+    let virtual_to_physical =
+        |address: VirtualAddress| (usize::from(address) - usize::from(kernel_map::PHYSICAL_MAPPING_BASE)) as u64;
+    let kernel_start = VirtualAddress::new(unsafe { &_kernel_start as *const u8 as usize });
+    let memory_map = memory_map::build(
+        &fdt,
+        &[
+            virtual_to_physical(kernel_start)..virtual_to_physical(heap_start + HEAP_SIZE),
+            (device_tree_address as u64)..(device_tree_address + fdt.total_size()) as u64,
+            initrd.clone().unwrap_or(0..0),
+        ],
+    );
+    kernel::PHYSICAL_MEMORY_MANAGER.initialize(PhysicalMemoryManager::new(&memory_map));
+    DEVICE_TREE.initialize(fdt);
+
+    /*
+     * The boot code has built the kernel's page tables, with the physical mapping at the right place, so we can
+     * just take over the ones that are installed.
+     */
+    KERNEL_PAGE_TABLE
+        .initialize(Mutex::new(unsafe { PageTableImpl::current(Half::Upper, kernel_map::PHYSICAL_MAPPING_BASE) }));
+
+    KERNEL_STACK_ALLOCATOR.initialize(KernelStackAllocator::new(
+        kernel_map::KERNEL_STACKS_BASE,
+        kernel_map::KERNEL_STACKS_BASE + kernel_map::STACK_SLOT_SIZE * kernel_map::MAX_TASKS,
+        hal::memory::mebibytes(2),
+    ));
+
+    clock::init();
+    interrupts::init(DEVICE_TREE.get());
+
+    /*
+     * Create and install the per-CPU data for the boot core. The other cores are left parked by the boot code. As
+     * `kmain` never returns, this lives forever.
+     */
+    let mut per_cpu = per_cpu::PerCpuImpl::new(Scheduler::new());
+    per_cpu.as_mut().install();
+    kernel::scheduler::start_ticking::<PlatformImpl>();
+
+    /*
+     * If the device tree describes a PCIe root complex we can use, find the devices behind it.
+     */
+    match pci::EcamAccess::from_fdt(DEVICE_TREE.get()) {
+        Some(pci_access) => {
+            *kernel::PCI_INFO.write() = Some(pci::resolve(&pci_access));
+            kernel::PCI_ACCESS.initialize(Some(Mutex::new(Box::new(pci_access))));
+        }
+        None => {
+            info!("No supported PCIe root complex found");
+            kernel::PCI_ACCESS.initialize(None);
+        }
+    }
+
+    /*
+     * There's no loader on this platform, so the initial task is the ELF image the firmware loads for us as the
+     * initrd. Without one, there's nothing to drop into userspace with.
+     */
+    let initrd = match initrd {
+        Some(initrd) => initrd,
+        None => {
+            warn!("No initrd to load the initial task from. Idling.");
+            loop {
+                PlatformImpl::idle();
+            }
+        }
+    };
+    let elf_bytes = unsafe {
+        core::slice::from_raw_parts(
+            kernel_map::physical_to_virtual(PhysicalAddress::new(initrd.start as usize).unwrap()).ptr(),
+            (initrd.end - initrd.start) as usize,
+        )
+    };
+    let task = match Task::from_elf(
+        SENTINEL_KERNEL_ID,
+        String::from("init"),
+        elf_bytes,
+        INITIAL_TASK_CAPABILITIES,
+        &kernel::PHYSICAL_MEMORY_MANAGER.get(),
+    ) {
+        Ok(task) => task,
+        Err(err) => panic!("Failed to load initial task from initrd: {:?}", err),
+    };
+    PlatformImpl::per_cpu().scheduler().add_task(task);
+
+    info!("Dropping into usermode");
+    PlatformImpl::per_cpu().scheduler().drop_to_userspace()
+}
+
+/// Find the initrd the firmware has loaded for us, which is described by the `linux,initrd-start` and
+/// `linux,initrd-end` properties of `/chosen`. We can only access it if it's in the first 4GiB of the physical
+/// address space, like the UART.
+fn find_initrd(fdt: &Fdt) -> Option<Range<u64>> {
+    let chosen = fdt.chosen()?;
+    let start = chosen.property("linux,initrd-start")?.as_u64()?;
+    let end = chosen.property("linux,initrd-end")?.as_u64()?;
+
+    if start >= end || end > 0x1_0000_0000 {
+        warn!("Ignoring initrd at {:#x}..{:#x}, as we can't access it", start, end);
+        return None;
+    }
+    Some(start..end)
+}
+
+/// Find the UART to log to. This is the one `/chosen/stdout-path` refers to, if there is one we can use, or
+/// otherwise the first enabled PL011. We only have a driver for the PL011, and can only access UARTs in the first
+/// 4GiB of the physical address space (the only part the boot page tables map), so if we can't find one we can
+/// use, `None` is returned and the default UART is kept.
+fn find_uart(fdt: &Fdt) -> Option<PhysicalAddress> {
+    let is_usable =
+        |node: &Node| node.is_enabled() && node.compatible().any(|compatible| compatible == "arm,pl011");
+
+    /*
+     * `stdout-path` is either the path of a node or the name of an alias of one, optionally followed by a `:` and
+     * the settings to use (e.g. `serial0:115200n8`), which we ignore.
+     */
+    let stdout = fdt
+        .chosen()
+        .and_then(|chosen| chosen.property("stdout-path")?.as_str())
+        .and_then(|stdout_path| {
+            let path = stdout_path.split(':').next().unwrap();
+            if path.starts_with('/') {
+                fdt.find_node(path)
+            } else {
+                fdt.find_node(fdt.find_node("/aliases")?.property(path)?.as_str()?)
+            }
+        })
+        .filter(|node| is_usable(node));
+
+    let node = stdout.or_else(|| fdt.all_nodes().find(|node| is_usable(node)))?;
+    let address = node.mmio_regions().next()?.address;
+    if address >= 0x1_0000_0000 {
+        return None;
+    }
+    PhysicalAddress::new(address as usize)
+}
+
+#[cfg(not(test))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    error!("KERNEL PANIC: {}", info);
+
+    /*
+     * If we panic while producing a backtrace (e.g. because we fault walking a corrupted stack), we don't try to
+     * produce another one.
+     */
+    static PANICKING: AtomicBool = AtomicBool::new(false);
+    if !PANICKING.swap(true, Ordering::SeqCst) {
+        let fp: usize;
+        unsafe {
+            asm!("mov {}, x29", out(reg) fp);
+            kernel::backtrace::log_backtrace(log::Level::Error, None, fp);
+        }
+    }
+
     loop {
         unsafe {
             asm!("wfe");
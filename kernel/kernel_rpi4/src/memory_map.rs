@@ -0,0 +1,79 @@
+//! There's no loader on this platform to give us a memory map, so we build one from the device tree. The `/memory`
+//! nodes describe the RAM the ARM cores can use, and we remove the parts that are already in use: areas reserved
+//! by the firmware (which it describes in the memory reservation block, or the `/reserved-memory` node), the
+//! kernel image and heap, the initrd, and the device tree itself.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use fdt::Fdt;
+use hal::{
+    boot_info::{MemoryMap, MemoryMapEntry, MemoryType},
+    memory::PhysicalAddress,
+};
+use log::{info, warn};
+
+/// The boot page tables only map the first 4GiB of physical memory, so we can't use memory above that yet.
+const MAPPED_MEMORY_END: u64 = 0x1_0000_0000;
+
+/// Build a memory map of the conventional memory described by `fdt`. `in_use` lists extra areas of memory that
+/// shouldn't be used, in addition to those the device tree reserves.
+pub fn build(fdt: &Fdt, in_use: &[Range<u64>]) -> MemoryMap {
+    let mut reserved: Vec<Range<u64>> = in_use.to_vec();
+    reserved.extend(fdt.memory_reservations().map(|(address, size)| address..(address + size)));
+    if let Some(reserved_memory) = fdt.find_node("/reserved-memory") {
+        for node in reserved_memory.children() {
+            reserved.extend(
+                node.mmio_regions().map(|region| region.address..(region.address + region.size.unwrap_or(0))),
+            );
+        }
+    }
+    reserved.sort_by_key(|range| range.start);
+
+    let mut memory_map = MemoryMap::default();
+    for region in fdt.memory_nodes().flat_map(|node| node.mmio_regions()) {
+        let mut start = region.address;
+        let end = u64::min(region.address + region.size.unwrap_or(0), MAPPED_MEMORY_END);
+        if region.address + region.size.unwrap_or(0) > MAPPED_MEMORY_END {
+            warn!("Ignoring memory above {:#x}, as it isn't mapped", MAPPED_MEMORY_END);
+        }
+
+        /*
+         * Walk the reserved areas in order, adding the gaps between them as conventional memory.
+         */
+        for area in reserved.iter().filter(|area| area.end > start && area.start < end) {
+            if area.start > start {
+                add_conventional(&mut memory_map, start..area.start);
+            }
+            start = u64::max(start, area.end);
+        }
+        if start < end {
+            add_conventional(&mut memory_map, start..end);
+        }
+    }
+
+    memory_map
+}
+
+fn add_conventional(memory_map: &mut MemoryMap, range: Range<u64>) {
+    /*
+     * The physical memory manager deals in whole frames, so we can only use the frames that are entirely within
+     * the range.
+     */
+    let start = (range.start + 0xfff) & !0xfff;
+    let end = range.end & !0xfff;
+    if start >= end {
+        return;
+    }
+
+    info!("Conventional memory: {:#x}..{:#x}", start, end);
+    if memory_map
+        .add_entry(MemoryMapEntry {
+            start: PhysicalAddress::new(start as usize).unwrap(),
+            size: (end - start) as usize,
+            memory_type: MemoryType::Conventional,
+        })
+        .is_err()
+    {
+        warn!("Memory map is full. Ignoring memory at {:#x}..{:#x}", start, end);
+    }
+}
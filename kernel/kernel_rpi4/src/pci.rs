@@ -0,0 +1,167 @@
+//! Platforms that describe their hardware with a device tree describe a PCIe root complex with a node. If it's
+//! compatible with `pci-host-ecam-generic`, its configuration space is accessed through a standard ECAM region,
+//! which we can use to enumerate the devices behind it.
+//!
+//! The Raspberry Pi 4's own root complex isn't ECAM-compatible (configuration space is accessed indirectly, one
+//! function at a time, through registers of the controller), so it isn't supported yet.
+
+use crate::PlatformImpl;
+use alloc::collections::BTreeMap;
+use bit_field::BitField;
+use core::{ops::RangeInclusive, ptr};
+use fdt::Fdt;
+use hal::memory::{
+    mebibytes,
+    CacheType,
+    Flags,
+    Frame,
+    Page,
+    PageTable,
+    PhysicalAddress,
+    Size4KiB,
+    VirtualAddress,
+};
+use hal_arm64::kernel_map;
+use kernel::{
+    pci::{read_capabilities, PciDevice, PciInfo},
+    Platform,
+};
+use log::{info, warn};
+use pci_types::{ConfigRegionAccess, PciAddress, PciHeader};
+
+#[derive(Clone)]
+pub struct EcamAccess {
+    base: VirtualAddress,
+    segment: u16,
+    bus_range: RangeInclusive<u8>,
+}
+
+impl EcamAccess {
+    /// Find the first ECAM-compatible root complex described by the device tree.
+    pub fn from_fdt(fdt: &Fdt) -> Option<EcamAccess> {
+        let node = fdt.find_compatible(&["pci-host-ecam-generic"]).filter(|node| node.is_enabled())?;
+        let region = node.mmio_regions().next()?;
+
+        /*
+         * If the node doesn't say which buses the region covers, it covers all of them.
+         */
+        let bus_range = match node.property("bus-range") {
+            Some(property) => {
+                let mut cells = property.cells();
+                (cells.next()? as u8)..=(cells.next()? as u8)
+            }
+            None => 0..=255,
+        };
+        let segment = node.property("linux,pci-domain").and_then(|property| property.as_u32()).unwrap_or(0) as u16;
+
+        /*
+         * Each bus has 1MiB of configuration space, and the region must be aligned to that.
+         */
+        let size = (*bus_range.end() as usize - *bus_range.start() as usize + 1) * mebibytes(1);
+        if region.size.map_or(true, |region_size| region_size < size as u64)
+            || region.address % mebibytes(1) as u64 != 0
+        {
+            warn!("ECAM region of {} doesn't cover buses {:?}. Ignoring it.", node.name(), bus_range);
+            return None;
+        }
+        let physical_base = PhysicalAddress::new(region.address as usize)?;
+
+        info!(
+            "Found PCIe root complex {} with ECAM region at {:#x} (segment {}, buses {:?})",
+            node.name(),
+            region.address,
+            segment,
+            bus_range
+        );
+
+        /*
+         * Configuration space must be accessed as Device memory, so we can't use the physical mapping (which is
+         * Normal memory above the first 4GiB). Instead, we map the region into the area reserved for device memory.
+         */
+        PlatformImpl::kernel_page_table()
+            .map_range(
+                Page::<Size4KiB>::starts_with(kernel_map::ECAM_BASE)
+                    ..Page::starts_with(kernel_map::ECAM_BASE + size),
+                Frame::starts_with(physical_base)..Frame::starts_with(physical_base + size),
+                Flags { writable: true, cache_type: CacheType::Uncacheable, ..Default::default() },
+                kernel::PHYSICAL_MEMORY_MANAGER.get(),
+            )
+            .expect("ECAM region is already mapped");
+
+        Some(EcamAccess { base: kernel_map::ECAM_BASE, segment, bus_range })
+    }
+
+    fn function_address(&self, address: PciAddress) -> Option<VirtualAddress> {
+        if address.segment() != self.segment || !self.bus_range.contains(&address.bus()) {
+            return None;
+        }
+
+        let mut offset = 0;
+        offset.set_bits(20..28, (address.bus() - self.bus_range.start()) as usize);
+        offset.set_bits(15..20, address.device() as usize);
+        offset.set_bits(12..15, address.function() as usize);
+        Some(self.base + offset)
+    }
+}
+
+impl ConfigRegionAccess for EcamAccess {
+    fn function_exists(&self, address: PciAddress) -> bool {
+        self.function_address(address).is_some()
+    }
+
+    unsafe fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        let ptr = (self.function_address(address).unwrap() + offset as usize).ptr();
+        ptr::read_volatile(ptr)
+    }
+
+    unsafe fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        let ptr = (self.function_address(address).unwrap() + offset as usize).mut_ptr();
+        ptr::write_volatile(ptr, value)
+    }
+}
+
+/// Find all the PCI devices behind the root complex.
+pub fn resolve(access: &EcamAccess) -> PciInfo {
+    let mut info = PciInfo { devices: BTreeMap::new() };
+
+    for bus in access.bus_range.clone() {
+        for device in 0..32 {
+            let address = PciAddress::new(access.segment, bus, device, 0);
+            if !PciHeader::new(address).has_multiple_functions(access) {
+                check_function(access, &mut info, address);
+            } else {
+                for function in 0..8 {
+                    check_function(access, &mut info, PciAddress::new(access.segment, bus, device, function));
+                }
+            }
+        }
+    }
+
+    info
+}
+
+fn check_function(access: &EcamAccess, info: &mut PciInfo, address: PciAddress) {
+    let header = PciHeader::new(address);
+    let (vendor_id, device_id) = header.id(access);
+    let (revision, class, sub_class, interface) = header.revision_and_class(access);
+
+    if vendor_id == 0xffff {
+        return;
+    }
+
+    info!("Found PCI device at {}: (vendor = {:#x}, device = {:#x})", address, vendor_id, device_id);
+
+    /*
+     * TODO: legacy interrupt pins are routed to the GIC by the root complex's `interrupt-map` property, which we
+     * don't parse yet, so we can't give these devices a GSI.
+     */
+    if unsafe { access.read(address, 0x3c) }.get_bits(8..16) != 0 {
+        warn!("PCI function at {} uses an interrupt pin, which can't be routed yet", address);
+    }
+
+    let capabilities = read_capabilities(access, address);
+    info.devices.insert(
+        address,
+        PciDevice { vendor_id, device_id, revision, class, sub_class, interface, capabilities, gsi: None },
+    );
+}
@@ -0,0 +1,88 @@
+use alloc::boxed::Box;
+use core::{marker::PhantomPinned, mem, pin::Pin};
+use hal::memory::VirtualAddress;
+use hal_arm64::hw::registers::{read_sysreg, write_sysreg};
+use kernel::{per_cpu::PerCpu, scheduler::Scheduler};
+use pebble_util::unsafe_pinned;
+
+/// Get a mutable reference to the per-CPU data of the running CPU. This is unsafe because it is the caller's
+/// responsibility to ensure that only one mutable reference to the per-CPU data exists at any one time. It is also
+/// unsafe to call this before the per-CPU data has been installed.
+pub unsafe fn get_per_cpu_data<'a>() -> Pin<&'a mut PerCpuImpl> {
+    let ptr = read_sysreg!(tpidr_el1);
+    Pin::new_unchecked(&mut *(ptr as *mut PerCpuImpl))
+}
+
+#[repr(C)]
+pub struct PerCpuImpl {
+    /// The first field of the per-cpu structure must be a pointer to itself. `TPIDR_EL1` holds this pointer. This
+    /// means the structure must be pinned, as it is self-referential.
+    _self_pointer: *const PerCpuImpl,
+    _pin: PhantomPinned,
+
+    scheduler: Scheduler<crate::PlatformImpl>,
+}
+
+impl PerCpuImpl {
+    unsafe_pinned!(pub scheduler: Scheduler<crate::PlatformImpl>);
+
+    pub fn new(scheduler: Scheduler<crate::PlatformImpl>) -> Pin<Box<PerCpuImpl>> {
+        let mut per_cpu = Box::pin(PerCpuImpl {
+            _self_pointer: 0x0 as *const PerCpuImpl,
+            _pin: PhantomPinned,
+
+            scheduler,
+        });
+
+        let address: *mut PerCpuImpl = unsafe { mem::transmute(per_cpu.as_ref()) };
+        unsafe {
+            Pin::get_unchecked_mut(per_cpu.as_mut())._self_pointer = address;
+        }
+
+        per_cpu
+    }
+
+    pub fn install(self: Pin<&mut Self>) {
+        unsafe {
+            write_sysreg!(tpidr_el1, self.as_ref()._self_pointer as usize as u64);
+        }
+    }
+}
+
+impl PerCpu<crate::PlatformImpl> for PerCpuImpl {
+    fn scheduler(self: Pin<&mut Self>) -> Pin<&mut Scheduler<crate::PlatformImpl>> {
+        self.scheduler()
+    }
+
+    /*
+     * Exceptions from EL0 are taken on `SP_EL1`, which is left where it was when we last returned to userspace. A
+     * task's kernel stack is empty whenever it's in userspace, so that's always the top of it, and we don't need
+     * to install it anywhere.
+     */
+    fn set_kernel_stack_pointer(self: Pin<&mut Self>, _kernel_stack_top: VirtualAddress) {}
+
+    /*
+     * Userspace's stack pointer lives in `SP_EL0`, which the kernel doesn't use (it always runs on `SP_EL1`), and
+     * which is saved and restored by the exception vectors. We can therefore access it directly, rather than
+     * keeping a copy here like we do on x86_64.
+     */
+    fn get_user_stack_pointer(self: Pin<&mut Self>) -> VirtualAddress {
+        VirtualAddress::new(read_sysreg!(sp_el0) as usize)
+    }
+
+    fn set_user_stack_pointer(self: Pin<&mut Self>, stack_pointer: VirtualAddress) {
+        unsafe {
+            write_sysreg!(sp_el0, usize::from(stack_pointer) as u64);
+        }
+    }
+}
+
+/*
+ * Accidently dropping the per-CPU data after it's been installed leads to some really weird behaviour that I've
+ * found difficult to debug in the past, so this guards against that.
+ */
+impl Drop for PerCpuImpl {
+    fn drop(&mut self) {
+        panic!("Per-CPU data should not be dropped!");
+    }
+}
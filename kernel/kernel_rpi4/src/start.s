@@ -1,15 +1,191 @@
-.section ".text.entry"
+/*
+ * The firmware starts us at `_start` in EL2 (or EL1, depending on its configuration), with the MMU off, and the
+ * physical address of the device tree in `x0`. Only the boot core jumps to us - the others are parked by the
+ * firmware until they're released through the spin table, but we check anyway.
+ *
+ * We're running at our physical address, not the one we were linked at, until we've enabled the MMU, so we must
+ * only use PC-relative addressing (`adr` and `adrp`) until then.
+ */
+
+/*
+ * These must be kept in sync with `hal_arm64::paging::MAIR` and `hal_arm64::paging::EntryFlags`.
+ */
+.equ MAIR_VALUE,                0x0044bbff
+/*
+ * 4KiB granules and 48-bit virtual addresses for both halves, with Inner Shareable, Write-Back table walks. The
+ * physical address size (`IPS`) is filled in from `ID_AA64MMFR0_EL1`.
+ */
+.equ TCR_VALUE,                 0xb5103510
+/*
+ * `SCTLR_EL1` with the MMU (`M`), data cache (`C`), and instruction cache (`I`) enabled, and its reserved bits set.
+ */
+.equ SCTLR_VALUE,               0x30d01805
+/*
+ * A table descriptor, a Write-Back block that only the kernel can execute (`VALID | INNER_SHAREABLE | ACCESSED |
+ * USER_EXECUTE_NEVER`), and a Device-nGnRnE block that can't be executed at all.
+ */
+.equ TABLE_DESCRIPTOR,          0x3
+.equ NORMAL_BLOCK,              (0x701 | (1 << 54))
+.equ DEVICE_BLOCK,              (0x40d | (3 << 53))
+/*
+ * The Raspberry Pi 4's peripherals start at this address (in low peripheral mode), up to the end of the first 4GiB.
+ */
+.equ PERIPHERALS_BASE,          0xfc000000
+
+.section .text.entry
 .global _start
 _start:
     mrs x1, mpidr_el1
     and x1, x1, #3
-    # TODO: can't we just jump past the jump to kmain if we're an AP instead of 2 labels?
     cbz x1, 2f
 1:
     wfe
     b 1b
 2:
-    ldr x1, =_start
-    mov sp, x1
+    // Keep the address of the device tree safe, until we pass it to `kmain`
+    mov x19, x0
+
+    /*
+     * If we've been started in EL2, drop down to EL1. We don't use the hypervisor, so we just allow EL1 to use
+     * AArch64 and access the timer, and then "return" to the next instruction in EL1.
+     */
+    mrs x0, CurrentEL
+    lsr x0, x0, #2
+    cmp x0, #2
+    b.ne 3f
+
+    mov x0, #(1 << 31)          // HCR_EL2.RW: EL1 uses AArch64
+    msr hcr_el2, x0
+    mrs x0, cnthctl_el2
+    orr x0, x0, #3              // EL1PCTEN | EL1PCEN: don't trap accesses to the physical timer and counter
+    msr cnthctl_el2, x0
+    msr cntvoff_el2, xzr
+    mov x0, #0x33ff             // CPTR_EL2: don't trap accesses to the floating-point registers to EL2
+    msr cptr_el2, x0
+    msr hstr_el2, xzr
+    mov x0, #0x3c5              // EL1h, with all exceptions masked
+    msr spsr_el2, x0
+    adr x0, 3f
+    msr elr_el2, x0
+    eret
+3:
+    /*
+     * Trap accesses to the floating-point and SIMD registers from both EL0 and EL1. The kernel doesn't use them,
+     * and doesn't save them when it switches tasks, so userspace can't either yet.
+     */
+    msr cpacr_el1, xzr
+
+    /*
+     * Build the boot page tables. `TTBR0_EL1` identity-maps the first 1GiB (which we're running in), so we can keep
+     * going after we enable the MMU. `TTBR1_EL1` maps the first 4GiB of physical memory at the bottom of the upper
+     * half, which includes the kernel image and the peripherals. This is the start of the physical mapping, and
+     * becomes the kernel's page tables.
+     */
+    adrp x0, _boot_page_tables_start
+    adrp x1, _boot_page_tables_end
+4:
+    stp xzr, xzr, [x0], #16
+    cmp x0, x1
+    b.lo 4b
+
+    // TTBR0: L0[0] -> L1, L1[0] = 1GiB block at 0
+    adrp x0, boot_ttbr0_l0
+    adrp x1, boot_ttbr0_l1
+    orr x2, x1, #TABLE_DESCRIPTOR
+    str x2, [x0]
+    ldr x2, =NORMAL_BLOCK
+    str x2, [x1]
+
+    // TTBR1: L0[0] -> L1, with 1GiB blocks for the first 3GiB
+    adrp x0, boot_ttbr1_l0
+    adrp x1, boot_ttbr1_l1
+    orr x3, x1, #TABLE_DESCRIPTOR
+    str x3, [x0]
+    mov x4, #0x40000000
+    str x2, [x1, #0]
+    add x2, x2, x4
+    str x2, [x1, #8]
+    add x2, x2, x4
+    str x2, [x1, #16]
+
+    // L1[3] -> L2, with 2MiB blocks for the last 1GiB, which are device memory from the start of the peripherals
+    adrp x5, boot_ttbr1_l2
+    orr x3, x5, #TABLE_DESCRIPTOR
+    str x3, [x1, #24]
+    mov x6, #0xc0000000
+    mov x7, #0
+    ldr x8, =PERIPHERALS_BASE
+    ldr x9, =NORMAL_BLOCK
+    ldr x10, =DEVICE_BLOCK
+5:
+    cmp x6, x8
+    csel x11, x9, x10, lo
+    orr x11, x11, x6
+    str x11, [x5, x7, lsl #3]
+    add x6, x6, #0x200000
+    add x7, x7, #1
+    cmp x7, #512
+    b.lo 5b
+
+    // Configure and enable the MMU
+    ldr x0, =MAIR_VALUE
+    msr mair_el1, x0
+    ldr x0, =TCR_VALUE
+    mrs x1, id_aa64mmfr0_el1
+    and x1, x1, #0x7
+    orr x0, x0, x1, lsl #32
+    msr tcr_el1, x0
+    adrp x0, boot_ttbr0_l0
+    msr ttbr0_el1, x0
+    adrp x0, boot_ttbr1_l0
+    msr ttbr1_el1, x0
+    isb
+    tlbi vmalle1
+    dsb nsh
+    ldr x0, =SCTLR_VALUE
+    msr sctlr_el1, x0
+    isb
+
+    // Jump to our virtual address in the upper half
+    ldr x0, =6f
+    br x0
+6:
+    // Remove the identity mapping, which userspace's tables will replace
+    ldr x0, =boot_ttbr0_l0
+    str xzr, [x0]
+    dsb ishst
+    tlbi vmalle1
+    dsb nsh
+    isb
+
+    // Zero the BSS
+    ldr x0, =__bss_start
+    ldr x1, =__bss_end
+7:
+    stp xzr, xzr, [x0], #16
+    cmp x0, x1
+    b.lo 7b
+
+    ldr x0, =_stack_top
+    mov sp, x0
+    mov x29, xzr
+    mov x30, xzr
+
+    mov x0, x19
     bl kmain
     b 1b
+
+.section .boot_page_tables, "aw", @nobits
+.balign 4096
+_boot_page_tables_start:
+boot_ttbr0_l0:
+    .space 4096
+boot_ttbr0_l1:
+    .space 4096
+boot_ttbr1_l0:
+    .space 4096
+boot_ttbr1_l1:
+    .space 4096
+boot_ttbr1_l2:
+    .space 4096
+_boot_page_tables_end:
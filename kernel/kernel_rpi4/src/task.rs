@@ -0,0 +1,78 @@
+use core::mem;
+use hal::memory::VirtualAddress;
+
+global_asm!(include_str!("task.s"));
+extern "C" {
+    fn task_entry_trampoline() -> !;
+
+    fn do_drop_to_usermode(kernel_sp: VirtualAddress) -> !;
+
+    /// Do the actual context switch: save the callee-saved registers of the old task on its kernel stack, switch
+    /// to the new task's kernel stack, restore its registers and return. As on x86_64, tasks that have never been
+    /// run before have a frame that "returns" to `task_entry_trampoline`, which enters userspace for the first
+    /// time.
+    fn do_context_switch(current_kernel_sp: *mut VirtualAddress, new_kernel_sp: VirtualAddress);
+}
+
+/// This is the layout of the stack that we expect to be present when we switch to a task. It is
+/// created both in preparation for initial task entry, and when we're switching away from a task.
+/// We use the C ABI here because we access this structure from assembly.
+#[derive(Default, Debug)]
+#[repr(C)]
+pub struct ContextSwitchFrame {
+    pub x19: u64,
+    pub x20: u64,
+    pub x21: u64,
+    pub x22: u64,
+    pub x23: u64,
+    pub x24: u64,
+    pub x25: u64,
+    pub x26: u64,
+    pub x27: u64,
+    pub x28: u64,
+    /// The frame pointer.
+    pub x29: u64,
+    /// The link register. When we construct an initial stack frame, we set this to the address of the kernel-space
+    /// trampoline that enters userspace. On normal context switches, this is the real return address that leads
+    /// back up the kernel call-stack to the exception handler.
+    pub x30: u64,
+}
+
+pub unsafe fn initialize_kernel_stack(
+    kernel_stack_top: &mut VirtualAddress,
+    task_entry_point: VirtualAddress,
+    task_argument: usize,
+    user_stack_top: &mut VirtualAddress,
+) {
+    /*
+     * The stack pointer must always be 16-byte aligned on AArch64 - misaligned accesses through it fault.
+     */
+    const REQUIRED_STACK_ALIGNMENT: usize = 16;
+    *kernel_stack_top = kernel_stack_top.align_down(REQUIRED_STACK_ALIGNMENT);
+    *user_stack_top = user_stack_top.align_down(REQUIRED_STACK_ALIGNMENT);
+
+    /*
+     * Construct the context-switch frame that is used when a task is switched to for the first time. The
+     * trampoline expects the entry point in `x19`, and the task's argument in `x20`. The frame pointer is zeroed to
+     * terminate backtraces at task entry. The size of the frame is a multiple of 16, so the stack stays aligned.
+     */
+    *kernel_stack_top -= mem::size_of::<ContextSwitchFrame>();
+    *(kernel_stack_top.mut_ptr() as *mut ContextSwitchFrame) = ContextSwitchFrame {
+        x19: usize::from(task_entry_point) as u64,
+        x20: task_argument as u64,
+        x29: 0x0,
+        x30: task_entry_trampoline as u64,
+        ..Default::default()
+    };
+}
+
+pub unsafe fn context_switch(current_kernel_stack: *mut VirtualAddress, new_kernel_stack: VirtualAddress) {
+    do_context_switch(current_kernel_stack, new_kernel_stack);
+}
+
+pub unsafe fn drop_into_userspace(kernel_stack_pointer: VirtualAddress) -> ! {
+    /*
+     * Like on x86_64, we use the context we install into the task's kernel stack to drop into usermode.
+     */
+    do_drop_to_usermode(kernel_stack_pointer);
+}
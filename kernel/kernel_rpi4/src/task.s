@@ -0,0 +1,103 @@
+/*
+ * Used to enter a task for the first time. The context switch frame built by `initialize_kernel_stack` puts the
+ * task's entry point in `x19`, and its argument in `x20`.
+ *
+ * We enter userspace with `eret`, which needs:
+ *     - `ELR_EL1` to contain the address to enter userspace at
+ *     - `SPSR_EL1` to contain the state to enter with. We use EL0, with all exceptions unmasked (all zeros).
+ *
+ * The task's user stack pointer has already been installed in `SP_EL0` by the scheduler.
+ */
+.global task_entry_trampoline
+task_entry_trampoline:
+    // Mask exceptions while we're messing around with stacks. They're unmasked by the `eret`.
+    msr daifset, #0xf
+
+    msr elr_el1, x19
+    msr spsr_el1, xzr
+    mov x0, x20
+
+    // Zero all the other registers, to avoid leaking kernel data into userspace
+    mov x1, xzr
+    mov x2, xzr
+    mov x3, xzr
+    mov x4, xzr
+    mov x5, xzr
+    mov x6, xzr
+    mov x7, xzr
+    mov x8, xzr
+    mov x9, xzr
+    mov x10, xzr
+    mov x11, xzr
+    mov x12, xzr
+    mov x13, xzr
+    mov x14, xzr
+    mov x15, xzr
+    mov x16, xzr
+    mov x17, xzr
+    mov x18, xzr
+    mov x19, xzr
+    mov x20, xzr
+    mov x21, xzr
+    mov x22, xzr
+    mov x23, xzr
+    mov x24, xzr
+    mov x25, xzr
+    mov x26, xzr
+    mov x27, xzr
+    mov x28, xzr
+    mov x29, xzr
+    mov x30, xzr
+
+    // Leap of faith!
+    eret
+
+// fn do_drop_to_usermode(kernel_sp: VirtualAddress) -> !
+.global do_drop_to_usermode
+do_drop_to_usermode:
+    msr daifset, #0xf
+
+    // Switch to the task's kernel stack, and pop its initial context switch frame
+    mov sp, x0
+    ldp x19, x20, [sp, #0]
+    ldp x21, x22, [sp, #16]
+    ldp x23, x24, [sp, #32]
+    ldp x25, x26, [sp, #48]
+    ldp x27, x28, [sp, #64]
+    ldp x29, x30, [sp, #80]
+    add sp, sp, #96
+
+    // We're now in the same state as if we'd context switched to the task, so we can enter it the same way
+    b task_entry_trampoline
+
+// fn do_context_switch(current_kernel_sp: *mut VirtualAddress, new_kernel_sp: VirtualAddress)
+.global do_context_switch
+do_context_switch:
+    // Save current task's context
+    sub sp, sp, #96
+    stp x19, x20, [sp, #0]
+    stp x21, x22, [sp, #16]
+    stp x23, x24, [sp, #32]
+    stp x25, x26, [sp, #48]
+    stp x27, x28, [sp, #64]
+    stp x29, x30, [sp, #80]
+
+    // Change kernel stacks
+    mov x9, sp
+    str x9, [x0]
+    mov sp, x1
+
+    // Restore state of new task
+    ldp x19, x20, [sp, #0]
+    ldp x21, x22, [sp, #16]
+    ldp x23, x24, [sp, #32]
+    ldp x25, x26, [sp, #48]
+    ldp x27, x28, [sp, #64]
+    ldp x29, x30, [sp, #80]
+    add sp, sp, #96
+
+    /*
+     * Return, either back up through the kernel and to the exception handler, or in the case of a task that
+     * hasn't been run before, into the kernel-space usermode trampoline
+     */
+    ret
@@ -0,0 +1,51 @@
+//! Copies to and from userspace that can recover from faults. The kernel validates the memory that system calls
+//! access, but userspace can still change its mappings from another thread after the memory has been validated,
+//! so the copies themselves must be able to fail gracefully.
+//!
+//! Unlike on x86_64, the kernel doesn't yet prevent itself from accessing userspace memory outside of these copies
+//! (which would need `PSTATE.PAN`).
+
+global_asm!(include_str!("user_access.s"));
+extern "C" {
+    fn copy_user_bytes(destination: *mut u8, source: *const u8, length: usize) -> u64;
+
+    /*
+     * These aren't really statics - they're labels in `copy_user_bytes`, and we only use their addresses.
+     */
+    static copy_user_bytes_access_start: u8;
+    static copy_user_bytes_access_end: u8;
+    static copy_user_bytes_fixup: u8;
+}
+
+/// Copy `length` bytes from `source` to `destination`. If the copy faults, `Err(())` is returned.
+///
+/// ### Safety
+/// Both buffers must be valid for `length` bytes, except for memory in userspace, which may fault. Kernel memory
+/// must not fault.
+pub unsafe fn copy_user(destination: *mut u8, source: *const u8, length: usize) -> Result<(), ()> {
+    if copy_user_bytes(destination, source, length) == 0 {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// Called by exception handlers when the kernel faults. If the fault was caused by `copy_user`,
+/// `exception_link_register` (the address the handler will return to) is moved to the copy's fixup code, so the copy
+/// fails instead, and `true` is returned.
+pub fn fixup_fault(exception_link_register: &mut u64) -> bool {
+    let (access_start, access_end, fixup) = unsafe {
+        (
+            &copy_user_bytes_access_start as *const u8 as u64,
+            &copy_user_bytes_access_end as *const u8 as u64,
+            &copy_user_bytes_fixup as *const u8 as u64,
+        )
+    };
+
+    if (access_start..access_end).contains(exception_link_register) {
+        *exception_link_register = fixup;
+        true
+    } else {
+        false
+    }
+}
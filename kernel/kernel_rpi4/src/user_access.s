@@ -0,0 +1,28 @@
+/*
+ * Copy `x2` bytes from `x1` to `x0`, where one of the buffers is in userspace. Returns `0` if the copy succeeded.
+ *
+ * Memory is only accessed by the instructions between `copy_user_bytes_access_start` and
+ * `copy_user_bytes_access_end`. If one of them causes a fault (e.g. because the memory has been unmapped by
+ * another thread since it was validated), the exception handler resumes execution at `copy_user_bytes_fixup`
+ * instead, which returns `1`.
+ */
+.global copy_user_bytes
+copy_user_bytes:
+    cbz x2, 2f
+.global copy_user_bytes_access_start
+copy_user_bytes_access_start:
+1:
+    ldrb w3, [x1], #1
+    strb w3, [x0], #1
+    subs x2, x2, #1
+    b.ne 1b
+.global copy_user_bytes_access_end
+copy_user_bytes_access_end:
+2:
+    mov x0, #0
+    ret
+
+.global copy_user_bytes_fixup
+copy_user_bytes_fixup:
+    mov x0, #1
+    ret
@@ -0,0 +1,80 @@
+//! This module provides the kernel's monotonic clock. If the processor has an invariant TSC, we use it to provide a
+//! precise clock, calibrating it against the PIT if we can't find its frequency from `cpuid`. Otherwise, we fall
+//! back to counting ticks of the local APIC timer, which is much coarser (it only advances once every timer period,
+//! and only while the timer is periodic).
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use hal_x86_64::hw::{cpu::CpuInfo, pit::Pit, registers::read_tsc};
+use log::{info, warn};
+use pebble_util::InitGuard;
+
+static CLOCK: InitGuard<Clock> = InitGuard::uninit();
+
+/// How long we time other timers against the PIT for, when we need to calibrate them. Longer periods give more
+/// accurate results, but this must not be longer than `pit::MAX_WAIT`.
+pub const CALIBRATION_PERIOD: Duration = Duration::from_millis(10);
+
+/// The number of nanoseconds counted by ticks of the local APIC timer since it was enabled. This is only used as the
+/// clock if we can't use the TSC.
+static APIC_TIMER_NANOS: AtomicU64 = AtomicU64::new(0);
+
+enum Clock {
+    Tsc { start: u64, frequency: u64 },
+    ApicTicks,
+}
+
+/// Initialise the monotonic clock.
+pub fn init(cpu_info: &CpuInfo) {
+    let clock = if cpu_info.supported_features.invariant_tsc {
+        let frequency = cpu_info.tsc_frequency().unwrap_or_else(calibrate_tsc);
+        info!("Using invariant TSC as monotonic clock (frequency = {}Hz)", frequency);
+        Clock::Tsc { start: read_tsc(), frequency }
+    } else {
+        warn!("Can't use the TSC as a monotonic clock. Falling back to the local APIC timer!");
+        Clock::ApicTicks
+    };
+
+    CLOCK.initialize(clock);
+}
+
+/// Work out the frequency of the TSC (in Hz) by counting how much it advances while we wait on the PIT.
+fn calibrate_tsc() -> u64 {
+    let start = read_tsc();
+    unsafe { Pit::new() }.busy_wait(CALIBRATION_PERIOD);
+    let ticks = read_tsc() - start;
+
+    let frequency = (u128::from(ticks) * 1_000_000_000 / CALIBRATION_PERIOD.as_nanos()) as u64;
+    info!("Calibrated TSC against the PIT (frequency = {}Hz)", frequency);
+    frequency
+}
+
+/// Called every time the local APIC timer ticks, while it's ticking periodically with the given `period`.
+pub fn apic_tick(period: Duration) {
+    APIC_TIMER_NANOS.fetch_add(period.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Get the number of nanoseconds since the clock was initialised, or `None` if it hasn't been initialised yet.
+pub fn try_now() -> Option<u64> {
+    if CLOCK.try_get().is_some() {
+        Some(now())
+    } else {
+        None
+    }
+}
+
+/// Get the number of nanoseconds since the clock was initialised.
+pub fn now() -> u64 {
+    match CLOCK.get() {
+        Clock::Tsc { start, frequency } => {
+            /*
+             * We do this calculation in 128 bits, as multiplying by 10^9 first would otherwise overflow after
+             * only a few seconds.
+             */
+            ((read_tsc() - start) as u128 * 1_000_000_000 / *frequency as u128) as u64
+        }
+        Clock::ApicTicks => APIC_TIMER_NANOS.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,327 @@
+//! Platform support for the kernel's GDB stub (see `kernel::gdb`). The stub talks to GDB over the COM2 serial port,
+//! and is entered on breakpoints, single-steps, NMIs, and panics, if the kernel is built with the `gdb_stub`
+//! feature.
+
+use bit_field::BitField;
+use core::{mem, ptr};
+use hal::memory::{Frame, PageTable, PhysicalAddress, VirtualAddress};
+use hal_x86_64::{
+    hw::{
+        idt::InterruptStackFrame,
+        registers::{read_control_reg, CpuFlags},
+        serial::{SerialPort, COM2},
+    },
+    kernel_map,
+    paging::PageTableImpl,
+};
+use kernel::gdb::{Connection, GdbStub, StopReason, Target};
+use log::info;
+use spin::Mutex;
+
+static GDB_STUB: Mutex<GdbStub<SerialConnection>> =
+    Mutex::new(GdbStub::new(SerialConnection(unsafe { SerialPort::new(COM2) })));
+
+/// The addresses of the hardware breakpoints installed in each of `DR0` through `DR3`.
+static HARDWARE_BREAKPOINTS: Mutex<[Option<usize>; 4]> = Mutex::new([None; 4]);
+
+struct SerialConnection(SerialPort);
+
+impl Connection for SerialConnection {
+    fn read(&mut self) -> u8 {
+        unsafe { self.0.read() }
+    }
+
+    fn write(&mut self, byte: u8) {
+        unsafe { self.0.write(byte) }
+    }
+}
+
+/// Initialise the serial port used to talk to GDB, and then stop, so GDB can attach before the rest of the kernel
+/// runs.
+pub fn init() {
+    unsafe {
+        SerialPort::new(COM2).initialise();
+    }
+
+    info!("Waiting for GDB to attach on COM2");
+    unsafe {
+        asm!("int3");
+    }
+}
+
+/// Enter the GDB stub from an exception handler. `stack_frame` is the state of the interrupted code, which is
+/// restored (with any changes made by GDB) when the handler returns.
+pub fn enter(stack_frame: &mut InterruptStackFrame, reason: StopReason) {
+    /*
+     * If we're already in the stub (e.g. because we panicked while handling a packet), we can't enter it again.
+     */
+    let mut stub = match GDB_STUB.try_lock() {
+        Some(stub) => stub,
+        None => return,
+    };
+    stub.handle_stop(&mut X86Target { stack_frame }, reason);
+
+    /*
+     * Set the Resume flag, so that if we stopped on a hardware breakpoint, we don't hit it again immediately when
+     * we return to the instruction it's on.
+     */
+    let mut flags = u64::from(stack_frame.cpu_flags);
+    flags.set_bit(CpuFlags::RESUME_FLAG as usize, true);
+    stack_frame.cpu_flags = CpuFlags::new(flags);
+}
+
+/// Enter the GDB stub from the panic handler. As we don't have the state of any interrupted code, GDB is shown the
+/// state of the panic handler itself.
+pub fn enter_on_panic() {
+    let (rip, rsp, rbp): (u64, u64, u64);
+    unsafe {
+        asm!("lea {}, [rip]
+              mov {}, rsp
+              mov {}, rbp",
+            out(reg) rip,
+            out(reg) rsp,
+            out(reg) rbp
+        );
+    }
+
+    /*
+     * We don't know the values of the other registers, so we just leave them zeroed.
+     */
+    let mut stack_frame: InterruptStackFrame = unsafe { mem::zeroed() };
+    stack_frame.rbp = rbp;
+    stack_frame.instruction_pointer = VirtualAddress::new(rip as usize);
+    stack_frame.code_segment = read_segment!(cs);
+    stack_frame.cpu_flags = CpuFlags::read();
+    stack_frame.stack_pointer = VirtualAddress::new(rsp as usize);
+    stack_frame.stack_segment = read_segment!(ss);
+
+    enter(&mut stack_frame, StopReason::Panic);
+}
+
+macro read_segment($segment: ident) {{
+    let value: u64;
+    unsafe {
+        asm!(concat!("mov {:x}, ", stringify!($segment)), out(reg) value);
+    }
+    value
+}}
+
+struct X86Target<'a> {
+    stack_frame: &'a mut InterruptStackFrame,
+}
+
+impl<'a> X86Target<'a> {
+    /// Translate an address using the page tables that are currently installed (which will be the address space of
+    /// the running task, if there is one).
+    fn translate(address: usize) -> Option<PhysicalAddress> {
+        let p4_address = PhysicalAddress::new(read_control_reg!(cr3) as usize & !0xfff)?;
+        let page_table = unsafe {
+            PageTableImpl::from_frame(Frame::starts_with(p4_address), kernel_map::PHYSICAL_MAPPING_BASE)
+        };
+        page_table.translate(VirtualAddress::new(address))
+    }
+
+    /*
+     * We access memory through the physical mapping, so that we don't fault on unmapped memory, and so that we can
+     * write to read-only memory (e.g. to insert software breakpoints in code).
+     */
+    fn physical_mapping(address: usize) -> Option<*mut u8> {
+        Some(kernel_map::physical_to_virtual(Self::translate(address)?).mut_ptr())
+    }
+
+    /// The registers in the order GDB expects for `x86_64` (in the `g` packet, and when numbering them), up to and
+    /// including `rip`. These are all 8 bytes wide.
+    fn general_register(&mut self, index: usize) -> Option<&mut u64> {
+        let frame = &mut *self.stack_frame;
+        Some(match index {
+            0 => &mut frame.rax,
+            1 => &mut frame.rbx,
+            2 => &mut frame.rcx,
+            3 => &mut frame.rdx,
+            4 => &mut frame.rsi,
+            5 => &mut frame.rdi,
+            6 => &mut frame.rbp,
+            8 => &mut frame.r8,
+            9 => &mut frame.r9,
+            10 => &mut frame.r10,
+            11 => &mut frame.r11,
+            12 => &mut frame.r12,
+            13 => &mut frame.r13,
+            14 => &mut frame.r14,
+            15 => &mut frame.r15,
+            // `rsp` (7) and `rip` (16) are stored as `VirtualAddress`es, so are handled separately
+            _ => return None,
+        })
+    }
+}
+
+/// Index of `rsp` in GDB's numbering of the `x86_64` registers.
+const RSP: usize = 7;
+/// Index of `rip` in GDB's numbering of the `x86_64` registers.
+const RIP: usize = 16;
+/// Index of `eflags` in GDB's numbering of the `x86_64` registers.
+const EFLAGS: usize = 17;
+
+impl<'a> Target for X86Target<'a> {
+    const BREAKPOINT_INSTRUCTION: &'static [u8] = &[0xcc]; // int3
+
+    fn read_registers(&self, write: &mut dyn FnMut(&[u8])) {
+        let frame = &*self.stack_frame;
+        for &value in &[
+            frame.rax,
+            frame.rbx,
+            frame.rcx,
+            frame.rdx,
+            frame.rsi,
+            frame.rdi,
+            frame.rbp,
+            usize::from(frame.stack_pointer) as u64,
+            frame.r8,
+            frame.r9,
+            frame.r10,
+            frame.r11,
+            frame.r12,
+            frame.r13,
+            frame.r14,
+            frame.r15,
+            usize::from(frame.instruction_pointer) as u64,
+        ] {
+            write(&value.to_le_bytes());
+        }
+
+        /*
+         * Then `eflags`, and the segment registers (`cs`, `ss`, `ds`, `es`, `fs`, and `gs`), which are 4 bytes
+         * wide. We don't report the floating-point or SSE state, as the kernel doesn't use it - GDB treats
+         * registers missing from the end of the packet as unavailable.
+         */
+        for &value in &[u64::from(frame.cpu_flags) as u32, frame.code_segment as u32, frame.stack_segment as u32] {
+            write(&value.to_le_bytes());
+        }
+        for _ in 0..4 {
+            write(&0u32.to_le_bytes());
+        }
+    }
+
+    fn write_register(&mut self, index: usize, value: &[u8]) -> bool {
+        match index {
+            RSP | RIP if value.len() == 8 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(value);
+                let address = VirtualAddress::new(u64::from_le_bytes(bytes) as usize);
+
+                if index == RSP {
+                    self.stack_frame.stack_pointer = address;
+                } else {
+                    self.stack_frame.instruction_pointer = address;
+                }
+                true
+            }
+
+            EFLAGS if value.len() == 4 => {
+                let mut bytes = [0u8; 4];
+                bytes.copy_from_slice(value);
+                self.stack_frame.cpu_flags = CpuFlags::new(u64::from(u32::from_le_bytes(bytes)));
+                true
+            }
+
+            _ if value.len() == 8 => match self.general_register(index) {
+                Some(register) => {
+                    let mut bytes = [0u8; 8];
+                    bytes.copy_from_slice(value);
+                    *register = u64::from_le_bytes(bytes);
+                    true
+                }
+                None => false,
+            },
+
+            _ => false,
+        }
+    }
+
+    fn read_memory(&self, address: usize, buffer: &mut [u8]) -> bool {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            match Self::physical_mapping(address + i) {
+                Some(ptr) => *byte = unsafe { ptr::read_volatile(ptr) },
+                None => return false,
+            }
+        }
+        true
+    }
+
+    fn write_memory(&mut self, address: usize, bytes: &[u8]) -> bool {
+        /*
+         * Make sure all of the memory is mapped before we write any of it.
+         */
+        if (0..bytes.len()).any(|i| Self::translate(address + i).is_none()) {
+            return false;
+        }
+
+        for (i, &byte) in bytes.iter().enumerate() {
+            unsafe {
+                ptr::write_volatile(Self::physical_mapping(address + i).unwrap(), byte);
+            }
+        }
+        true
+    }
+
+    fn set_instruction_pointer(&mut self, address: usize) {
+        self.stack_frame.instruction_pointer = VirtualAddress::new(address);
+    }
+
+    fn set_single_step(&mut self, enabled: bool) {
+        let mut flags = u64::from(self.stack_frame.cpu_flags);
+        flags.set_bit(CpuFlags::TRAP_FLAG as usize, enabled);
+        self.stack_frame.cpu_flags = CpuFlags::new(flags);
+    }
+
+    fn insert_hardware_breakpoint(&mut self, address: usize) -> bool {
+        let mut breakpoints = HARDWARE_BREAKPOINTS.lock();
+        let index = match breakpoints.iter().position(|breakpoint| breakpoint.is_none()) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        breakpoints[index] = Some(address);
+        unsafe {
+            set_debug_address_register(index, address as u64);
+        }
+        update_dr7(&breakpoints);
+        true
+    }
+
+    fn remove_hardware_breakpoint(&mut self, address: usize) -> bool {
+        let mut breakpoints = HARDWARE_BREAKPOINTS.lock();
+        let index = match breakpoints.iter().position(|&breakpoint| breakpoint == Some(address)) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        breakpoints[index] = None;
+        update_dr7(&breakpoints);
+        true
+    }
+}
+
+unsafe fn set_debug_address_register(index: usize, address: u64) {
+    match index {
+        0 => asm!("mov dr0, {}", in(reg) address),
+        1 => asm!("mov dr1, {}", in(reg) address),
+        2 => asm!("mov dr2, {}", in(reg) address),
+        3 => asm!("mov dr3, {}", in(reg) address),
+        _ => panic!("Invalid debug address register: {}", index),
+    }
+}
+
+/// Enable the hardware breakpoints that are in use in `DR7`. Each breakpoint has a local enable bit (bit `2n`),
+/// and a condition and length (bits `16+4n..20+4n`). We use a condition and length of `0`, which means the
+/// breakpoint is hit when the instruction at its address is executed.
+fn update_dr7(breakpoints: &[Option<usize>; 4]) {
+    let mut dr7 = 0u64;
+    for (i, breakpoint) in breakpoints.iter().enumerate() {
+        dr7.set_bit(i * 2, breakpoint.is_some());
+    }
+
+    unsafe {
+        asm!("mov dr7, {}", in(reg) dr7);
+    }
+}
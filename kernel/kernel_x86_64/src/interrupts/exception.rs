@@ -1,55 +1,191 @@
 //! This module contains all the interrupt handlers used to handle CPU exceptions. Some of these
 //! exceptions are handled and recovered from, while some are fatal errors and lead to kernel
-//! panics.
+//! panics. Exceptions caused by userspace are never fatal to the kernel - the task that caused them
+//! is killed instead.
 
+use crate::{user_access, PlatformImpl};
 use bit_field::BitField;
-use hal_x86_64::hw::{
-    idt::{ExceptionWithErrorStackFrame, InterruptStackFrame},
-    registers::read_control_reg,
+use hal::memory::{Frame, PhysicalAddress, VirtualAddress};
+use hal_x86_64::{
+    hw::{
+        idt::{ExceptionWithErrorStackFrame, InterruptStackFrame},
+        registers::{read_control_reg, read_msr, IA32_MCG_STATUS},
+    },
+    kernel_map,
+    paging::PageTableImpl,
 };
-use log::{error, info};
+use kernel::gdb::StopReason;
+use libpebble::syscall::{FaultKind, FaultReport};
+use log::{error, info, log, Level};
 use pebble_util::BinaryPrettyPrint;
 
-pub extern "C" fn nmi_handler(_: &InterruptStackFrame) {
-    info!("NMI occured!");
+/// Whether an exception was caused by code running in userspace. The CPU pushes the `cs` selector of the code that
+/// was running when the exception occured, so we can check its RPL (the bottom two bits).
+fn is_user_mode(code_segment: u64) -> bool {
+    code_segment.get_bits(0..2) == 3
 }
 
-pub extern "C" fn breakpoint_handler(stack_frame: &InterruptStackFrame) {
-    info!("BREAKPOINT: {:#x?}", stack_frame);
+/// Log a backtrace of the code that was interrupted by an exception. This is only safe to call for exceptions that
+/// occured in the kernel, as the frame pointer of userspace code can't be trusted.
+fn log_backtrace(level: Level, instruction_pointer: VirtualAddress, rbp: u64) {
+    unsafe {
+        kernel::backtrace::log_backtrace(level, Some(usize::from(instruction_pointer)), rbp as usize);
+    }
+}
+
+/// Log the page that contains `address` in the page tables that are currently installed (which belong to the
+/// running task, if there is one), to help work out why it caused a page fault.
+fn log_mapping_of(level: Level, address: u64) {
+    let p4_address = match PhysicalAddress::new(read_control_reg!(cr3) as usize & !0xfff) {
+        Some(p4_address) => p4_address,
+        None => return,
+    };
+    let page_table =
+        unsafe { PageTableImpl::from_frame(Frame::starts_with(p4_address), kernel_map::PHYSICAL_MAPPING_BASE) };
+
+    let address = VirtualAddress::new(address as usize);
+    match page_table.mapping_of(address) {
+        Some(mapping) => log!(level, "Faulting address {:#x} is mapped: {}", address, mapping),
+        None => log!(level, "Faulting address {:#x} is not mapped", address),
+    }
+}
+
+fn kill_faulting_task(kind: FaultKind, error_code: u64, instruction_pointer: u64, address: u64) -> ! {
+    kernel::handle_user_fault::<PlatformImpl>(FaultReport { kind, error_code, instruction_pointer, address })
+}
+
+/// Handle an exception that we can't recover from if it was caused by the kernel. If it was caused by userspace,
+/// the faulting task is killed instead.
+fn unrecoverable_exception(name: &str, kind: FaultKind, stack_frame: &InterruptStackFrame) -> ! {
+    if is_user_mode(stack_frame.code_segment) {
+        kill_faulting_task(kind, 0, usize::from(stack_frame.instruction_pointer) as u64, 0);
+    }
+
+    error!("EXCEPTION: {} at {:#x}\n{:#x?}", name, stack_frame.instruction_pointer, stack_frame);
+    log_backtrace(Level::Error, stack_frame.instruction_pointer, stack_frame.rbp);
+    panic!("Unrecoverable fault");
+}
+
+/// Like `unrecoverable_exception`, but for exceptions that push an error code. Many of these push a selector error
+/// code, so we also decode the error code as one if `selector_error_code` is set.
+fn unrecoverable_exception_with_error(
+    name: &str,
+    kind: FaultKind,
+    stack_frame: &ExceptionWithErrorStackFrame,
+    selector_error_code: bool,
+) -> ! {
+    if is_user_mode(stack_frame.code_segment) {
+        kill_faulting_task(kind, stack_frame.error_code, usize::from(stack_frame.instruction_pointer) as u64, 0);
+    }
+
+    error!(
+        "EXCEPTION: {} at {:#x} (error code = {:#x})",
+        name, stack_frame.instruction_pointer, stack_frame.error_code
+    );
+    if selector_error_code {
+        log_selector_error_code(stack_frame.error_code);
+    }
+    error!("{:#x?}", stack_frame);
+    log_backtrace(Level::Error, stack_frame.instruction_pointer, stack_frame.rbp);
+    panic!("Unrecoverable fault");
+}
+
+/// Decode an error code that refers to a segment selector. An error code of `0` means the exception wasn't caused
+/// by a particular selector.
+fn log_selector_error_code(error_code: u64) {
+    if error_code == 0 {
+        return;
+    }
 
+    error!(
+        "Caused by selector: index = {:#x} in {}{}",
+        error_code.get_bits(3..16),
+        match error_code.get_bits(1..3) {
+            0b00 => "GDT",
+            0b01 | 0b11 => "IDT",
+            0b10 => "LDT",
+            _ => unreachable!(),
+        },
+        if error_code.get_bit(0) { " (caused by an event external to the program)" } else { "" }
+    );
+}
+
+pub extern "C" fn nmi_handler(stack_frame: &mut InterruptStackFrame) {
+    info!("NMI occured!");
+
+    if cfg!(feature = "gdb_stub") {
+        crate::gdb::enter(stack_frame, StopReason::Interrupt);
+    }
+}
+
+pub extern "C" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
     /*
-     * TEMP: Do a stacktrace.
+     * Userspace can't be debugged through the GDB stub, and we can't walk its frame pointer, so a breakpoint in
+     * userspace is treated like any other fault (this matches how AArch64 reports `brk`).
      */
-    let mut rbp = stack_frame.rbp as usize;
-    info!("Starting stacktrace. First frame is at: {:#x}", rbp);
-    if rbp != 0 {
-        for i in 0..16 {
-            let next_rbp = unsafe { *(rbp as *const usize) };
-            let return_address = unsafe { *((rbp + 8) as *const usize) };
-
-            info!("     {}: return address: {:#x}. next frame is at: {:#x}", i, return_address, next_rbp);
-
-            if next_rbp == 0x0 {
-                break;
-            } else {
-                rbp = next_rbp;
-            }
-        }
+    if is_user_mode(stack_frame.code_segment) {
+        kill_faulting_task(FaultKind::Debug, 0, usize::from(stack_frame.instruction_pointer) as u64, 0);
     }
+
+    if cfg!(feature = "gdb_stub") {
+        crate::gdb::enter(stack_frame, StopReason::Breakpoint);
+        return;
+    }
+
+    info!("BREAKPOINT: {:#x?}", stack_frame);
+    log_backtrace(Level::Info, stack_frame.instruction_pointer, stack_frame.rbp);
 }
 
 pub extern "C" fn invalid_opcode_handler(stack_frame: &InterruptStackFrame) {
+    if is_user_mode(stack_frame.code_segment) {
+        kill_faulting_task(FaultKind::InvalidOpcode, 0, usize::from(stack_frame.instruction_pointer) as u64, 0);
+    }
+
     error!("INVALID OPCODE AT: {:#x}", stack_frame.instruction_pointer);
+    log_backtrace(Level::Error, stack_frame.instruction_pointer, stack_frame.rbp);
     panic!("Unrecoverable fault");
 }
 
-pub extern "C" fn general_protection_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
+pub extern "C" fn general_protection_fault_handler(stack_frame: &mut ExceptionWithErrorStackFrame) {
+    if is_user_mode(stack_frame.code_segment) {
+        kill_faulting_task(
+            FaultKind::GeneralProtectionFault,
+            stack_frame.error_code,
+            usize::from(stack_frame.instruction_pointer) as u64,
+            0,
+        );
+    }
+
+    if user_access::fixup_fault(&mut stack_frame.instruction_pointer) {
+        return;
+    }
+
     error!("General protection fault (error code = {:#x}). Interrupt stack frame: ", stack_frame.error_code);
     error!("{:#x?}", stack_frame);
+    log_backtrace(Level::Error, stack_frame.instruction_pointer, stack_frame.rbp);
     panic!("Unrecoverable fault");
 }
 
-pub extern "C" fn page_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
+pub extern "C" fn page_fault_handler(stack_frame: &mut ExceptionWithErrorStackFrame) {
+    if is_user_mode(stack_frame.code_segment) {
+        // CR2 holds the address of the page that caused the #PF
+        log_mapping_of(Level::Warn, read_control_reg!(cr2));
+        kill_faulting_task(
+            FaultKind::PageFault,
+            stack_frame.error_code,
+            usize::from(stack_frame.instruction_pointer) as u64,
+            read_control_reg!(cr2),
+        );
+    }
+
+    /*
+     * If the kernel faulted while copying to or from userspace, it's because userspace gave us a bad pointer, so
+     * we make the copy fail instead of panicking.
+     */
+    if user_access::fixup_fault(&mut stack_frame.instruction_pointer) {
+        return;
+    }
+
     error!(
         "PAGE_FAULT: {} ({:#x})",
         match (
@@ -80,14 +216,13 @@ pub extern "C" fn page_fault_handler(stack_frame: &ExceptionWithErrorStackFrame)
     );
 
     error!("Error code: {}", BinaryPrettyPrint(stack_frame.error_code));
+    log_mapping_of(Level::Error, read_control_reg!(cr2));
     error!("{:#x?}", stack_frame);
+    log_backtrace(Level::Error, stack_frame.instruction_pointer, stack_frame.rbp);
 
     /*
-     * Page-faults can be recovered from and so are faults, but we never will so just give up.
-     */
-    /*
-     * In the future, page faults can be used for demand paging and so are recoverable. At the moment, they're
-     * always bad, so we panic here.
+     * In the future, page faults can be used for demand paging and so are recoverable. At the moment, page faults
+     * caused by the kernel are always bad, so we panic here.
      */
     panic!("Unrecoverable fault");
 }
@@ -96,3 +231,106 @@ pub extern "C" fn double_fault_handler(stack_frame: &ExceptionWithErrorStackFram
     error!("EXCEPTION: DOUBLE FAULT   (Error code: {})\n{:#?}", stack_frame.error_code, stack_frame);
     panic!("Unrecoverable fault");
 }
+
+pub extern "C" fn divide_error_handler(stack_frame: &InterruptStackFrame) {
+    unrecoverable_exception("DIVIDE ERROR", FaultKind::DivideError, stack_frame);
+}
+
+pub extern "C" fn debug_handler(stack_frame: &mut InterruptStackFrame) {
+    if is_user_mode(stack_frame.code_segment) {
+        kill_faulting_task(FaultKind::Debug, 0, usize::from(stack_frame.instruction_pointer) as u64, 0);
+    }
+
+    /*
+     * DR6 tells us what caused the exception. The CPU never clears it, so we do, so that it only reflects the
+     * cause of the next debug exception.
+     */
+    let dr6: u64;
+    unsafe {
+        asm!("mov {}, dr6", out(reg) dr6);
+        asm!("mov dr6, {}", in(reg) 0u64);
+    }
+
+    /*
+     * Debug exceptions are either faults or traps depending on their cause. The only things that cause faults are
+     * hardware instruction breakpoints, which are only set by the GDB stub, which makes sure we don't hit them
+     * again when we return. Otherwise, we can just return after logging what happened.
+     */
+    if cfg!(feature = "gdb_stub") {
+        crate::gdb::enter(stack_frame, StopReason::Breakpoint);
+    } else {
+        info!("DEBUG EXCEPTION at {:#x} (DR6 = {:#x})", stack_frame.instruction_pointer, dr6);
+    }
+}
+
+pub extern "C" fn overflow_handler(stack_frame: &InterruptStackFrame) {
+    unrecoverable_exception("OVERFLOW", FaultKind::Overflow, stack_frame);
+}
+
+pub extern "C" fn bound_range_exceeded_handler(stack_frame: &InterruptStackFrame) {
+    unrecoverable_exception("BOUND RANGE EXCEEDED", FaultKind::BoundRangeExceeded, stack_frame);
+}
+
+pub extern "C" fn device_not_available_handler(stack_frame: &InterruptStackFrame) {
+    unrecoverable_exception("DEVICE NOT AVAILABLE", FaultKind::DeviceNotAvailable, stack_frame);
+}
+
+pub extern "C" fn invalid_tss_handler(stack_frame: &ExceptionWithErrorStackFrame) {
+    unrecoverable_exception_with_error("INVALID TSS", FaultKind::InvalidTss, stack_frame, true);
+}
+
+pub extern "C" fn segment_not_present_handler(stack_frame: &ExceptionWithErrorStackFrame) {
+    unrecoverable_exception_with_error("SEGMENT NOT PRESENT", FaultKind::SegmentNotPresent, stack_frame, true);
+}
+
+pub extern "C" fn stack_segment_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
+    unrecoverable_exception_with_error("STACK-SEGMENT FAULT", FaultKind::StackSegmentFault, stack_frame, true);
+}
+
+pub extern "C" fn x87_fault_handler(stack_frame: &InterruptStackFrame) {
+    unrecoverable_exception("x87 FLOATING-POINT EXCEPTION", FaultKind::X87FloatingPoint, stack_frame);
+}
+
+pub extern "C" fn alignment_check_handler(stack_frame: &ExceptionWithErrorStackFrame) {
+    unrecoverable_exception_with_error("ALIGNMENT CHECK", FaultKind::AlignmentCheck, stack_frame, false);
+}
+
+pub extern "C" fn machine_check_handler(stack_frame: &InterruptStackFrame) {
+    /*
+     * Machine checks are caused by hardware errors, so we can't recover from them, even if they occured while
+     * userspace was running.
+     */
+    error!(
+        "EXCEPTION: MACHINE CHECK at {:#x} (IA32_MCG_STATUS = {:#x})\n{:#x?}",
+        stack_frame.instruction_pointer,
+        read_msr(IA32_MCG_STATUS),
+        stack_frame
+    );
+    panic!("Unrecoverable machine check");
+}
+
+pub extern "C" fn simd_exception_handler(stack_frame: &InterruptStackFrame) {
+    if is_user_mode(stack_frame.code_segment) {
+        kill_faulting_task(
+            FaultKind::SimdFloatingPoint,
+            0,
+            usize::from(stack_frame.instruction_pointer) as u64,
+            0,
+        );
+    }
+
+    /*
+     * MXCSR tells us which SIMD floating-point exception occured.
+     */
+    let mut mxcsr: u32 = 0;
+    unsafe {
+        asm!("stmxcsr [{}]", in(reg) &mut mxcsr);
+    }
+    error!("SIMD floating-point exception (MXCSR = {:#x})", mxcsr);
+
+    unrecoverable_exception("SIMD FLOATING-POINT EXCEPTION", FaultKind::SimdFloatingPoint, stack_frame);
+}
+
+pub extern "C" fn virtualization_exception_handler(stack_frame: &InterruptStackFrame) {
+    unrecoverable_exception("VIRTUALIZATION EXCEPTION", FaultKind::Virtualization, stack_frame);
+}
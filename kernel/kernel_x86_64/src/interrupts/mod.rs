@@ -1,21 +1,43 @@
 mod exception;
 
-use acpi::InterruptModel;
+use acpi::{
+    platform::interrupt::{Polarity, TriggerMode as AcpiTriggerMode},
+    InterruptModel,
+};
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
 use aml::{value::Args as AmlArgs, AmlContext, AmlName, AmlValue};
-use core::time::Duration;
-use hal::memory::PhysicalAddress;
+use bit_field::BitField;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use hal::{
+    interrupts::{InterruptHandler, InterruptOrigin, IpiTarget, RegisterHandlerError},
+    memory::PhysicalAddress,
+};
 use hal_x86_64::{
     hw::{
         cpu::CpuInfo,
         gdt::KERNEL_CODE_SELECTOR,
         i8259_pic::Pic,
         idt::{wrap_handler, wrap_handler_with_error_code, Idt, InterruptStackFrame},
-        local_apic::LocalApic,
+        io_apic::{DeliveryMode, IoApic, PinPolarity, TriggerMode},
+        local_apic::{LocalApic, TimerMode},
+        pit::Pit,
+        registers::CpuFlags,
     },
     kernel_map,
 };
-use log::warn;
+use kernel::{
+    object::{
+        event::Event,
+        interrupt::{InterruptRoutingError, INTERRUPT_SIGNAL},
+    },
+    pci::MsiMessage,
+};
+use log::info;
 use pebble_util::InitGuard;
+use spin::Mutex;
 
 /// This should only be accessed directly by the bootstrap processor.
 ///
@@ -25,14 +47,18 @@ use pebble_util::InitGuard;
 /// |------------------|-----------------------------|
 /// |       00-1f      | Intel Reserved (Exceptions) |
 /// |       20-2f      | i8259 PIC Interrupts        |
-/// |       30-??      | IOAPIC Interrupts           |
+/// |       30-4f      | IOAPIC Interrupts and MSIs  |
 /// |        ..        |                             |
+/// |       f0-fd      | Kernel handlers (e.g. IPIs) |
 /// |        fe        | Local APIC timer            |
 /// |        ff        | APIC spurious interrupt     |
 /// |------------------|-----------------------------|
 static mut IDT: Idt = Idt::empty();
 
 static LOCAL_APIC: InitGuard<LocalApic> = InitGuard::uninit();
+static IRQ_ROUTING: InitGuard<Mutex<IrqRouting>> = InitGuard::uninit();
+pub static INTERRUPT_CONTROLLER: InitGuard<InterruptController> = InitGuard::uninit();
+pub static TIMER: InitGuard<LocalApicTimer> = InitGuard::uninit();
 
 /*
  * These constants define the IDT's layout. Refer to the documentation of the `IDT` static for
@@ -40,34 +66,68 @@ static LOCAL_APIC: InitGuard<LocalApic> = InitGuard::uninit();
  */
 const LEGACY_PIC_VECTOR: u8 = 0x20;
 const FREE_VECTORS_START: u8 = 0x30;
+const NUM_IRQ_VECTORS: u8 = 0x20;
+const KERNEL_VECTORS_START: u8 = 0xf0;
 const APIC_TIMER_VECTOR: u8 = 0xfe;
 const APIC_SPURIOUS_VECTOR: u8 = 0xff;
 
-pub struct InterruptController {}
+pub struct InterruptController {
+    /// The handlers the kernel has registered for the vectors from `KERNEL_VECTORS_START` up to and including
+    /// `APIC_TIMER_VECTOR`, indexed by vector.
+    handlers: Mutex<[Option<InterruptHandler>; 256]>,
+}
 
 impl InterruptController {
     /// Install handlers for exceptions, and load the IDT. This is done early in initialization to catch issues
     /// like page faults and kernel stack overflows nicely.
     pub fn install_exception_handlers() {
         unsafe {
+            IDT.divide_error().set_handler(wrap_handler!(exception::divide_error_handler), KERNEL_CODE_SELECTOR);
+            IDT.debug_exception().set_handler(wrap_handler!(exception::debug_handler), KERNEL_CODE_SELECTOR);
             IDT.nmi().set_handler(wrap_handler!(exception::nmi_handler), KERNEL_CODE_SELECTOR);
             IDT.breakpoint().set_handler(wrap_handler!(exception::breakpoint_handler), KERNEL_CODE_SELECTOR);
+            IDT.overflow().set_handler(wrap_handler!(exception::overflow_handler), KERNEL_CODE_SELECTOR);
+            IDT.bound_range_exceeded()
+                .set_handler(wrap_handler!(exception::bound_range_exceeded_handler), KERNEL_CODE_SELECTOR);
             IDT.invalid_opcode()
                 .set_handler(wrap_handler!(exception::invalid_opcode_handler), KERNEL_CODE_SELECTOR);
+            IDT.device_not_available()
+                .set_handler(wrap_handler!(exception::device_not_available_handler), KERNEL_CODE_SELECTOR);
+            IDT.double_fault()
+                .set_handler(wrap_handler_with_error_code!(exception::double_fault_handler), KERNEL_CODE_SELECTOR);
+            IDT.invalid_tss()
+                .set_handler(wrap_handler_with_error_code!(exception::invalid_tss_handler), KERNEL_CODE_SELECTOR);
+            IDT.segment_not_present().set_handler(
+                wrap_handler_with_error_code!(exception::segment_not_present_handler),
+                KERNEL_CODE_SELECTOR,
+            );
+            IDT.stack_segment_fault().set_handler(
+                wrap_handler_with_error_code!(exception::stack_segment_fault_handler),
+                KERNEL_CODE_SELECTOR,
+            );
             IDT.general_protection_fault().set_handler(
                 wrap_handler_with_error_code!(exception::general_protection_fault_handler),
                 KERNEL_CODE_SELECTOR,
             );
             IDT.page_fault()
                 .set_handler(wrap_handler_with_error_code!(exception::page_fault_handler), KERNEL_CODE_SELECTOR);
-            IDT.double_fault()
-                .set_handler(wrap_handler_with_error_code!(exception::double_fault_handler), KERNEL_CODE_SELECTOR);
+            IDT.x87_fault().set_handler(wrap_handler!(exception::x87_fault_handler), KERNEL_CODE_SELECTOR);
+            IDT.alignment_check().set_handler(
+                wrap_handler_with_error_code!(exception::alignment_check_handler),
+                KERNEL_CODE_SELECTOR,
+            );
+            IDT.machine_check().set_handler(wrap_handler!(exception::machine_check_handler), KERNEL_CODE_SELECTOR);
+            IDT.simd_exception()
+                .set_handler(wrap_handler!(exception::simd_exception_handler), KERNEL_CODE_SELECTOR);
+            IDT.virtualization_exception()
+                .set_handler(wrap_handler!(exception::virtualization_exception_handler), KERNEL_CODE_SELECTOR);
 
             IDT.load();
         }
     }
 
-    pub fn init(interrupt_model: &InterruptModel, aml_context: &mut AmlContext) -> InterruptController {
+    /// Initialise the interrupt controller, and install it as `INTERRUPT_CONTROLLER`.
+    pub fn init(interrupt_model: &InterruptModel, aml_context: &mut AmlContext) {
         match interrupt_model {
             InterruptModel::Apic(info) => {
                 if info.also_has_legacy_pics {
@@ -97,44 +157,442 @@ impl InterruptController {
                     .expect("Failed to invoke \\_PIC method");
 
                 /*
-                 * Install handlers for the spurious interrupt and local APIC timer, and then
-                 * enable the local APIC.
-                 * Install a spurious interrupt handler and enable the local APIC.
+                 * Install handlers for the spurious interrupt, the local APIC timer, and the vectors the kernel
+                 * can register its own handlers for, and then enable the local APIC.
                  */
                 unsafe {
                     IDT[APIC_TIMER_VECTOR]
                         .set_handler(wrap_handler!(local_apic_timer_handler), KERNEL_CODE_SELECTOR);
                     IDT[APIC_SPURIOUS_VECTOR].set_handler(wrap_handler!(spurious_handler), KERNEL_CODE_SELECTOR);
+                    install_irq_handlers!(
+                        handle_kernel_interrupt;
+                        0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd
+                    );
                     LOCAL_APIC.get().enable(APIC_SPURIOUS_VECTOR);
                 }
 
-                InterruptController {}
+                /*
+                 * Find the IOAPICs, and mask all of their interrupts. Interrupts are only unmasked when they're
+                 * routed to a task that can handle them.
+                 */
+                let io_apics = info
+                    .io_apics
+                    .iter()
+                    .map(|io_apic_info| {
+                        let mut io_apic = unsafe {
+                            IoApic::new(
+                                kernel_map::physical_to_virtual(
+                                    PhysicalAddress::new(io_apic_info.address as usize).unwrap(),
+                                ),
+                                io_apic_info.global_system_interrupt_base,
+                            )
+                        };
+
+                        for i in 0..io_apic.num_redirection_entries() {
+                            io_apic.set_irq_mask(i, true);
+                        }
+
+                        io_apic
+                    })
+                    .collect();
+
+                let interrupt_modes = info
+                    .interrupt_source_overrides
+                    .iter()
+                    .map(|isa_override| {
+                        let polarity = match isa_override.polarity {
+                            Polarity::SameAsBus | Polarity::ActiveHigh => PinPolarity::High,
+                            Polarity::ActiveLow => PinPolarity::Low,
+                        };
+                        let trigger_mode = match isa_override.trigger_mode {
+                            AcpiTriggerMode::SameAsBus | AcpiTriggerMode::Edge => TriggerMode::Edge,
+                            AcpiTriggerMode::Level => TriggerMode::Level,
+                        };
+                        (isa_override.global_system_interrupt, (polarity, trigger_mode))
+                    })
+                    .collect();
+
+                IRQ_ROUTING.initialize(Mutex::new(IrqRouting {
+                    io_apics,
+                    interrupt_modes,
+                    routes: BTreeMap::new(),
+                    destination: LOCAL_APIC.get().id(),
+                }));
+                unsafe {
+                    install_irq_handlers!(
+                        handle_irq;
+                        0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e,
+                        0x3f, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d,
+                        0x4e, 0x4f
+                    );
+                }
+
+                INTERRUPT_CONTROLLER.initialize(InterruptController { handlers: Mutex::new([None; 256]) });
             }
 
             _ => panic!("Unsupported interrupt model!"),
         }
     }
+}
+
+impl hal::interrupts::InterruptController for InterruptController {
+    fn register_handler(&self, vector: u32, handler: InterruptHandler) -> Result<(), RegisterHandlerError> {
+        without_interrupts(|| {
+            if vector < u32::from(KERNEL_VECTORS_START) || vector > u32::from(APIC_TIMER_VECTOR) {
+                return Err(RegisterHandlerError::InvalidVector);
+            }
+
+            let mut handlers = self.handlers.lock();
+            if handlers[vector as usize].is_some() {
+                return Err(RegisterHandlerError::AlreadyRegistered);
+            }
+            handlers[vector as usize] = Some(handler);
+            Ok(())
+        })
+    }
+
+    fn set_masked(&self, vector: u32, masked: bool) {
+        /*
+         * Only the timer can be masked at the local APIC. IOAPIC interrupts are masked when they're routed, and
+         * IPIs can't be masked at all.
+         */
+        if vector == u32::from(APIC_TIMER_VECTOR) {
+            LOCAL_APIC.get().set_timer_masked(masked);
+        }
+    }
+
+    fn end_of_interrupt(&self, _vector: u32) {
+        unsafe {
+            LOCAL_APIC.get().send_eoi();
+        }
+    }
+
+    fn send_ipi(&self, target: IpiTarget, vector: u32) {
+        LOCAL_APIC.get().send_ipi(target, vector as u8);
+    }
+}
+
+/// The local APIC's timer.
+pub struct LocalApicTimer {
+    /// The frequency of the local APIC, in Hz.
+    apic_frequency: u32,
+    /// The period of the timer in nanoseconds, if it's periodic, or `0` if it's one-shot. This is used by the
+    /// monotonic clock if it can't use the TSC.
+    period: AtomicU64,
+}
+
+impl LocalApicTimer {
+    fn set(&self, mode: TimerMode, duration: Duration) {
+        LOCAL_APIC.get().set_timer(mode, duration, self.apic_frequency, APIC_TIMER_VECTOR);
+    }
+}
+
+impl hal::timer::Timer for LocalApicTimer {
+    fn vector(&self) -> u32 {
+        u32::from(APIC_TIMER_VECTOR)
+    }
+
+    fn monotonic_time(&self) -> u64 {
+        crate::clock::now()
+    }
+
+    fn set_one_shot(&self, duration: Duration) {
+        self.period.store(0, Ordering::Relaxed);
+        self.set(TimerMode::OneShot, duration);
+    }
+
+    fn set_periodic(&self, period: Duration) {
+        self.period.store(period.as_nanos() as u64, Ordering::Relaxed);
+        self.set(TimerMode::Periodic, period);
+    }
+
+    fn stop(&self) {
+        self.period.store(0, Ordering::Relaxed);
+        LOCAL_APIC.get().stop_timer();
+    }
+}
+
+/// Create the `TIMER`. This must be done after the interrupt controller has been initialised. If we can't find
+/// the frequency of the local APIC from the `CpuInfo`, we calibrate it against the PIT.
+pub fn init_timer(cpu_info: &CpuInfo) {
+    let apic_frequency = match cpu_info.apic_frequency() {
+        Some(apic_frequency) => apic_frequency,
+        None => {
+            let apic_frequency =
+                LOCAL_APIC.get().calibrate_frequency(crate::clock::CALIBRATION_PERIOD, |duration| {
+                    unsafe { Pit::new() }.busy_wait(duration)
+                });
+            info!("Calibrated local APIC against the PIT (frequency = {}Hz)", apic_frequency);
+            apic_frequency
+        }
+    };
+    assert!(apic_frequency != 0, "Failed to find the frequency of the local APIC");
+
+    TIMER.initialize(LocalApicTimer { apic_frequency, period: AtomicU64::new(0) });
+}
+
+extern "C" fn local_apic_timer_handler(frame: &InterruptStackFrame) {
+    /*
+     * The monotonic clock counts ticks of the timer if it can't use the TSC, which only works while it's periodic.
+     */
+    let period = TIMER.get().period.load(Ordering::Relaxed);
+    if period != 0 {
+        crate::clock::apic_tick(Duration::from_nanos(period));
+    }
+
+    handle_kernel_interrupt(APIC_TIMER_VECTOR, frame);
+}
+
+extern "C" fn spurious_handler(_: &InterruptStackFrame) {}
+
+struct IrqRoute {
+    /// The GSI of the interrupt, or `None` if it's a message-signalled interrupt.
+    gsi: Option<u32>,
+    trigger_mode: TriggerMode,
+    /// The events to signal when the interrupt fires. Level-triggered GSIs can be shared by several devices (this
+    /// is normal for legacy PCI interrupts), so can have more than one.
+    events: Vec<Arc<Event>>,
+}
+
+impl IrqRoute {
+    /// Whether any of the events the interrupt has been delivered to haven't acknowledged it yet.
+    fn is_pending(&self) -> bool {
+        self.events.iter().any(|event| event.signals() & INTERRUPT_SIGNAL != 0)
+    }
+}
+
+/// Tracks the IOAPICs, and which interrupts are routed through which of the vectors we deliver IOAPIC interrupts
+/// and MSIs through.
+struct IrqRouting {
+    io_apics: Vec<IoApic>,
+    /// The polarity and trigger mode of GSIs that we've been told about, either by the MADT (for ISA interrupts that
+    /// differ from the defaults), or by the AML (for PCI interrupts).
+    interrupt_modes: BTreeMap<u32, (PinPolarity, TriggerMode)>,
+    /// Maps vectors to the interrupts routed through them.
+    routes: BTreeMap<u8, IrqRoute>,
+    /// The ID of the local APIC to deliver interrupts to.
+    destination: u8,
+}
+
+impl IrqRouting {
+    fn io_apic_for_gsi(&mut self, gsi: u32) -> Option<&mut IoApic> {
+        self.io_apics.iter_mut().find(|io_apic| {
+            gsi >= io_apic.global_interrupt_base
+                && gsi < io_apic.global_interrupt_base + io_apic.num_redirection_entries()
+        })
+    }
+
+    fn vector_for_gsi(&self, gsi: u32) -> Option<u8> {
+        self.routes.iter().find(|(_, route)| route.gsi == Some(gsi)).map(|(&vector, _)| vector)
+    }
+
+    fn route_for_gsi(&mut self, gsi: u32) -> Option<&mut IrqRoute> {
+        self.routes.values_mut().find(|route| route.gsi == Some(gsi))
+    }
+
+    fn alloc_vector(&self) -> Option<u8> {
+        (FREE_VECTORS_START..(FREE_VECTORS_START + NUM_IRQ_VECTORS))
+            .find(|vector| !self.routes.contains_key(vector))
+    }
 
-    /// Enable the per-CPU timer on the local APIC, so that it ticks every `period` ms. Cannot be
-    /// called before interrupt handlers are installed, because this borrows `self`.
-    pub fn enable_local_timer(&mut self, cpu_info: &CpuInfo, period: Duration) {
+    fn polarity_and_trigger_mode(&self, gsi: u32) -> (PinPolarity, TriggerMode) {
         /*
-         * TODO: currently, this relies upon being able to get the frequency from the
-         * CpuInfo. We should probably build a backup to calibrate it using another timer.
+         * If we haven't been told otherwise, the first 16 GSIs are the ISA interrupts, which are active-high and
+         * edge-triggered. We assume the rest are PCI interrupts, which are active-low and level-triggered.
          */
-        match cpu_info.apic_frequency() {
-            Some(apic_frequency) => {
-                LOCAL_APIC.get().enable_timer(period.as_millis() as u32, apic_frequency, APIC_TIMER_VECTOR);
+        match self.interrupt_modes.get(&gsi) {
+            Some(&(polarity, trigger_mode)) => (polarity, trigger_mode),
+            None if gsi < 16 => (PinPolarity::High, TriggerMode::Edge),
+            None => (PinPolarity::Low, TriggerMode::Level),
+        }
+    }
+
+    fn set_mask(&mut self, gsi: u32, masked: bool) {
+        let io_apic = self.io_apic_for_gsi(gsi).unwrap();
+        let irq = gsi - io_apic.global_interrupt_base;
+        io_apic.set_irq_mask(irq, masked);
+    }
+}
+
+/// Set the polarity and trigger mode to use for the interrupt with the given GSI, when it's routed. This is used
+/// to set up PCI interrupts with the information the AML gives us.
+pub fn set_interrupt_mode(gsi: u32, polarity: PinPolarity, trigger_mode: TriggerMode) {
+    without_interrupts(|| {
+        IRQ_ROUTING.get().lock().interrupt_modes.insert(gsi, (polarity, trigger_mode));
+    })
+}
+
+/// Route the interrupt with the given GSI through one of the free vectors, so that `INTERRUPT_SIGNAL` is set on
+/// `event` when it fires. Level-triggered interrupts can be shared, in which case `event` is added to the events
+/// already signalled through the GSI's vector.
+pub fn route_interrupt(gsi: u32, event: Arc<Event>) -> Result<(), InterruptRoutingError> {
+    without_interrupts(|| {
+        let mut routing = IRQ_ROUTING.get().lock();
+
+        if routing.io_apic_for_gsi(gsi).is_none() {
+            return Err(InterruptRoutingError::InvalidGsi);
+        }
+        if let Some(route) = routing.route_for_gsi(gsi) {
+            /*
+             * Edge-triggered interrupts can't be shared reliably, because an edge from a second device could be lost
+             * while the line is still asserted by the first.
+             */
+            if let TriggerMode::Edge = route.trigger_mode {
+                return Err(InterruptRoutingError::AlreadyRouted);
             }
-            None => warn!("Couldn't find frequency of APIC from cpuid. Local APIC timer not enabled!"),
+            route.events.push(event);
+            return Ok(());
+        }
+
+        let vector = routing.alloc_vector().ok_or(InterruptRoutingError::NoFreeVectors)?;
+        let (polarity, trigger_mode) = routing.polarity_and_trigger_mode(gsi);
+        let destination = routing.destination;
+
+        routing.routes.insert(vector, IrqRoute { gsi: Some(gsi), trigger_mode, events: vec![event] });
+        let io_apic = routing.io_apic_for_gsi(gsi).unwrap();
+        let irq = gsi - io_apic.global_interrupt_base;
+        io_apic.write_entry(irq, vector, DeliveryMode::Fixed, polarity, trigger_mode, false, destination);
+
+        Ok(())
+    })
+}
+
+/// Unmask an interrupt that has been handled, once every event it was delivered to has acknowledged it. Only
+/// level-triggered interrupts are masked when they fire, but we unmask all interrupts here for simplicity.
+pub fn acknowledge_interrupt(gsi: u32) {
+    without_interrupts(|| {
+        let mut routing = IRQ_ROUTING.get().lock();
+
+        if routing.route_for_gsi(gsi).map_or(false, |route| !route.is_pending()) {
+            routing.set_mask(gsi, false);
+        }
+    })
+}
+
+/// Stop signalling `event` when an interrupt fires. If no other events share the interrupt, it's masked, and the
+/// vector it was routed through is freed.
+pub fn unroute_interrupt(gsi: u32, event: &Arc<Event>) {
+    without_interrupts(|| {
+        let mut routing = IRQ_ROUTING.get().lock();
+
+        if let Some(vector) = routing.vector_for_gsi(gsi) {
+            let route = routing.routes.get_mut(&vector).unwrap();
+            route.events.retain(|routed| !Arc::ptr_eq(routed, event));
+
+            if route.events.is_empty() {
+                routing.set_mask(gsi, true);
+                routing.routes.remove(&vector);
+            } else if !route.is_pending() {
+                /*
+                 * If the interrupt was waiting to be acknowledged by the event we've just removed, nothing else will
+                 * unmask it.
+                 */
+                routing.set_mask(gsi, false);
+            }
+        }
+    })
+}
+
+/// Allocate a vector for a message-signalled interrupt, so that `INTERRUPT_SIGNAL` is set on `event` when a
+/// device writes the returned message.
+pub fn route_msi(event: Arc<Event>) -> Result<MsiMessage, InterruptRoutingError> {
+    without_interrupts(|| {
+        let mut routing = IRQ_ROUTING.get().lock();
+        let vector = routing.alloc_vector().ok_or(InterruptRoutingError::NoFreeVectors)?;
+        routing
+            .routes
+            .insert(vector, IrqRoute { gsi: None, trigger_mode: TriggerMode::Edge, events: vec![event] });
+
+        /*
+         * The message is written to the local APIC's address range, with the ID of the target local APIC in bits
+         * 12..20. The data holds the vector, along with a delivery mode of Fixed and an edge trigger mode (both
+         * represented by zeros).
+         */
+        let mut address = 0xfee0_0000;
+        address.set_bits(12..20, u64::from(routing.destination));
+        Ok(MsiMessage { address, data: u32::from(vector) })
+    })
+}
+
+/// Free the vector of a message allocated with `route_msi`.
+pub fn unroute_msi(message: MsiMessage) {
+    without_interrupts(|| {
+        IRQ_ROUTING.get().lock().routes.remove(&(message.data.get_bits(0..8) as u8));
+    })
+}
+
+/// Run `f` with interrupts disabled on this CPU. The routing state and handlers are also locked by the IRQ handlers,
+/// so we mustn't be interrupted while we hold their locks (system calls are handled with interrupts enabled).
+fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = CpuFlags::read().interrupts_enabled();
+    unsafe {
+        asm!("cli");
+    }
+
+    let result = f();
+
+    if enabled {
+        unsafe {
+            asm!("sti");
         }
     }
+    result
 }
 
-extern "C" fn local_apic_timer_handler(_: &InterruptStackFrame) {
+/// Install a handler for each of the given vectors, which calls `$handler` with the vector and the interrupt's stack
+/// frame. We can't tell which vector we're handling from inside a handler, so we need to generate a separate one
+/// for each vector.
+macro install_irq_handlers($handler: ident; $($vector: literal),*) {
+    $(
+        {
+            extern "C" fn irq_handler(frame: &InterruptStackFrame) {
+                $handler($vector, frame);
+            }
+
+            IDT[$vector].set_handler(wrap_handler!(irq_handler), KERNEL_CODE_SELECTOR);
+        }
+    )*
+}
+
+fn handle_irq(vector: u8, _: &InterruptStackFrame) {
+    let mut routing = IRQ_ROUTING.get().lock();
+
+    if let Some(route) = routing.routes.get(&vector) {
+        let gsi = route.gsi;
+        let trigger_mode = route.trigger_mode;
+        for event in &route.events {
+            event.signal(INTERRUPT_SIGNAL);
+        }
+
+        /*
+         * Level-triggered interrupts keep firing until the device that raised them has been serviced, so we mask
+         * them until the tasks handling them acknowledge them. We can't tell which of the devices sharing a GSI
+         * raised it, so every task is signalled, and each one has to check its own device.
+         */
+        if let (Some(gsi), TriggerMode::Level) = (gsi, trigger_mode) {
+            routing.set_mask(gsi, true);
+        }
+    }
+
     unsafe {
         LOCAL_APIC.get().send_eoi();
     }
 }
 
-extern "C" fn spurious_handler(_: &InterruptStackFrame) {}
+/// Call the handler the kernel has registered for `vector`.
+fn handle_kernel_interrupt(vector: u8, frame: &InterruptStackFrame) {
+    let origin =
+        if frame.code_segment.get_bits(0..2) == 3 { InterruptOrigin::Userspace } else { InterruptOrigin::Kernel };
+
+    /*
+     * The handler is responsible for sending the EOI, and could switch to another task, so we mustn't hold the lock
+     * when we call it.
+     */
+    let handler = INTERRUPT_CONTROLLER.get().handlers.lock()[vector as usize];
+    match handler {
+        Some(handler) => handler(u32::from(vector), origin),
+        None => unsafe {
+            LOCAL_APIC.get().send_eoi();
+        },
+    }
+}
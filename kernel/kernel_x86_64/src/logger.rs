@@ -1,4 +1,6 @@
-use hal_x86_64::hw::serial::SerialPort;
+use crate::clock;
+use hal_x86_64::hw::{cpu::current_apic_id, serial::SerialPort};
+use kernel::{klog, log_filter, object::SENTINEL_KERNEL_ID};
 use log::{Log, Metadata, Record};
 use spin::Mutex;
 
@@ -8,18 +10,32 @@ use spin::Mutex;
 static COM1: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::new(hal_x86_64::hw::serial::COM1) });
 
 /// This handles calls to the log macros throughout the kernel, and writes logging to the COM1
-/// serial port.
+/// serial port. Messages are also added to the kernel log (see `kernel::klog`). Which messages are enabled is
+/// controlled by the log filter passed to the loader (see `kernel::log_filter`).
 pub struct KernelLogger;
 
 impl Log for KernelLogger {
-    fn enabled(&self, _: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        /*
+         * Messages from tasks have already been filtered by the task that logged them.
+         */
+        metadata.target() == klog::TASK_LOG_TARGET || metadata.level() <= log_filter::level_for(metadata.target())
     }
 
     fn log(&self, record: &Record) {
         use core::fmt::Write;
 
         if self.enabled(record.metadata()) {
+            if record.target() != klog::TASK_LOG_TARGET {
+                klog::record(
+                    record.level(),
+                    SENTINEL_KERNEL_ID,
+                    clock::try_now().unwrap_or(0),
+                    current_apic_id(),
+                    *record.args(),
+                );
+            }
+
             COM1.lock()
                 .write_fmt(format_args!("[{}][{}] {}\n", record.level(), record.target(), record.args()))
                 .unwrap();
@@ -6,18 +6,25 @@ extern crate alloc;
 extern crate rlibc;
 
 mod acpi_handler;
+mod clock;
+mod gdb;
 mod interrupts;
 mod logger;
 mod pci;
 mod per_cpu;
 mod task;
 mod topo;
+mod user_access;
 
 use acpi::{AcpiTables, PciConfigRegions};
 use acpi_handler::{AmlHandler, PebbleAcpiHandler};
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc};
 use aml::AmlContext;
-use core::{panic::PanicInfo, pin::Pin, time::Duration};
+use core::{
+    panic::PanicInfo,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use hal::{
     boot_info::BootInfo,
     memory::{Frame, PhysicalAddress, VirtualAddress},
@@ -26,15 +33,20 @@ use hal_x86_64::{hw::registers::read_control_reg, kernel_map, paging::PageTableI
 use interrupts::InterruptController;
 use kernel::{
     memory::{KernelStackAllocator, PhysicalMemoryManager},
+    object::{event::Event, interrupt::InterruptRoutingError},
+    pci::MsiMessage,
     Platform,
 };
 use log::{error, info};
 use pci::PciResolver;
-use spin::Mutex;
+use pebble_util::InitGuard;
+use spin::{Mutex, MutexGuard};
 use topo::Topology;
 
+static KERNEL_PAGE_TABLE: InitGuard<Mutex<PageTableImpl>> = InitGuard::uninit();
+static KERNEL_STACK_ALLOCATOR: InitGuard<KernelStackAllocator<PlatformImpl>> = InitGuard::uninit();
+
 pub struct PlatformImpl {
-    kernel_page_table: PageTableImpl,
     topology: Topology,
 }
 
@@ -42,9 +54,68 @@ impl Platform for PlatformImpl {
     type PageTableSize = hal::memory::Size4KiB;
     type PageTable = PageTableImpl;
     type PerCpu = per_cpu::PerCpuImpl;
+    type InterruptController = InterruptController;
+    type Timer = interrupts::LocalApicTimer;
+
+    const USER_ADDRESS_SPACE_END: VirtualAddress = kernel_map::USER_ADDRESS_SPACE_END;
+    const DEVICE_WINDOW: VirtualAddress = kernel_map::DEVICE_WINDOW;
+
+    fn kernel_page_table<'a>() -> MutexGuard<'a, Self::PageTable> {
+        KERNEL_PAGE_TABLE.get().lock()
+    }
+
+    fn kernel_stack_allocator<'a>() -> &'a KernelStackAllocator<Self> {
+        KERNEL_STACK_ALLOCATOR.get()
+    }
+
+    fn physical_to_virtual(address: PhysicalAddress) -> VirtualAddress {
+        kernel_map::physical_to_virtual(address)
+    }
+
+    fn interrupt_controller<'a>() -> &'a Self::InterruptController {
+        interrupts::INTERRUPT_CONTROLLER.get()
+    }
+
+    fn timer<'a>() -> &'a Self::Timer {
+        interrupts::TIMER.get()
+    }
+
+    fn cpu_id() -> u32 {
+        hal_x86_64::hw::cpu::current_apic_id()
+    }
 
-    fn kernel_page_table(&mut self) -> &mut Self::PageTable {
-        &mut self.kernel_page_table
+    unsafe fn copy_user(destination: *mut u8, source: *const u8, length: usize) -> Result<(), ()> {
+        user_access::copy_user(destination, source, length)
+    }
+
+    fn route_interrupt(gsi: u32, event: Arc<Event>) -> Result<(), InterruptRoutingError> {
+        interrupts::route_interrupt(gsi, event)
+    }
+
+    fn acknowledge_interrupt(gsi: u32) {
+        interrupts::acknowledge_interrupt(gsi);
+    }
+
+    fn unroute_interrupt(gsi: u32, event: &Arc<Event>) {
+        interrupts::unroute_interrupt(gsi, event);
+    }
+
+    fn route_msi(event: Arc<Event>) -> Result<MsiMessage, InterruptRoutingError> {
+        interrupts::route_msi(event)
+    }
+
+    fn unroute_msi(message: MsiMessage) {
+        interrupts::unroute_msi(message);
+    }
+
+    fn idle() {
+        /*
+         * `sti` only takes effect after the next instruction, so an interrupt can't arrive between enabling
+         * interrupts and halting (which would make us miss it, and potentially halt forever).
+         */
+        unsafe {
+            asm!("sti; hlt; cli");
+        }
     }
 
     fn per_cpu<'a>() -> Pin<&'a mut Self::PerCpu> {
@@ -54,17 +125,18 @@ impl Platform for PlatformImpl {
     unsafe fn initialize_task_kernel_stack(
         kernel_stack_top: &mut VirtualAddress,
         task_entry_point: VirtualAddress,
+        task_argument: usize,
         user_stack_top: &mut VirtualAddress,
     ) {
-        task::initialize_kernel_stack(kernel_stack_top, task_entry_point, user_stack_top);
+        task::initialize_kernel_stack(kernel_stack_top, task_entry_point, task_argument, user_stack_top);
     }
 
     unsafe fn context_switch(current_kernel_stack: *mut VirtualAddress, new_kernel_stack: VirtualAddress) {
         task::context_switch(current_kernel_stack, new_kernel_stack)
     }
 
-    unsafe fn drop_into_userspace() -> ! {
-        task::drop_into_userspace()
+    unsafe fn drop_into_userspace(kernel_stack_pointer: VirtualAddress) -> ! {
+        task::drop_into_userspace(kernel_stack_pointer)
     }
 }
 
@@ -78,6 +150,17 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
         panic!("Boot info magic is not correct!");
     }
 
+    if let Some(log_filter) = boot_info.log_filter {
+        info!("Filtering log messages with: '{}'", log_filter.as_str());
+        kernel::log_filter::init(log_filter);
+    }
+
+    if let Some(ref symbols) = boot_info.kernel_symbols {
+        unsafe {
+            kernel::backtrace::init(symbols);
+        }
+    }
+
     use gfxconsole::{Bgr32, Format, Framebuffer, Pixel};
     assert_eq!(boot_info.video_mode.as_ref().unwrap().pixel_format, hal::boot_info::PixelFormat::BGR32);
     let framebuffer = Framebuffer {
@@ -100,25 +183,25 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
         kernel::ALLOCATOR.lock().init(boot_info.heap_address, boot_info.heap_size);
     }
 
-    kernel::PHYSICAL_MEMORY_MANAGER.initialize(PhysicalMemoryManager::new(boot_info));
+    kernel::PHYSICAL_MEMORY_MANAGER.initialize(PhysicalMemoryManager::new(&boot_info.memory_map));
 
     /*
      * Create our version of the kernel page table. This assumes that the loader has correctly installed a
      * set of page tables, including a full physical mapping at the correct location. Strange things will happen
      * if this is not the case, so this is a tad unsafe.
      */
-    let kernel_page_table = unsafe {
+    KERNEL_PAGE_TABLE.initialize(Mutex::new(unsafe {
         PageTableImpl::from_frame(
             Frame::starts_with(PhysicalAddress::new(read_control_reg!(cr3) as usize).unwrap()),
             kernel_map::PHYSICAL_MAPPING_BASE,
         )
-    };
+    }));
 
-    let mut kernel_stack_allocator = KernelStackAllocator::<PlatformImpl>::new(
+    KERNEL_STACK_ALLOCATOR.initialize(KernelStackAllocator::new(
         kernel_map::KERNEL_STACKS_BASE,
         kernel_map::KERNEL_STACKS_BASE + kernel_map::STACK_SLOT_SIZE * kernel_map::MAX_TASKS,
         hal::memory::mebibytes(2),
-    );
+    ));
 
     /*
      * Install the exception handlers. Where we do this is a compromise between as-early-as-possible (we don't
@@ -127,6 +210,13 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
      */
     InterruptController::install_exception_handlers();
 
+    /*
+     * If we've been built with the GDB stub, we stop here to give GDB a chance to attach.
+     */
+    if cfg!(feature = "gdb_stub") {
+        gdb::init();
+    }
+
     /*
      * Parse the static ACPI tables.
      */
@@ -168,15 +258,6 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
         // info!("----- Finished AML namespace -----");
     }
 
-    /*
-     * Resolve all the PCI info.
-     * XXX: not sure this is the right place to do this just yet.
-     */
-    // TODO: this whole situation is a bit gross and needs more thought I think
-    *kernel::PCI_INFO.write() = Some(PciResolver::resolve(pci_access.clone()));
-    kernel::PCI_ACCESS.initialize(Some(Mutex::new(Box::new(pci_access))));
-
-
     /*
      * Initialize devices defined in AML.
      * TODO: We should probably call `_REG` on all the op-regions we allow access to at this point before this.
@@ -184,28 +265,33 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
     aml_context.initialize_objects().expect("Failed to initialize AML objects");
 
     /*
-     * Initialise the interrupt controller, which enables interrupts, and start the per-cpu timer.
+     * Initialise the interrupt controller, which enables interrupts, and the monotonic clock, and then start the
+     * timer ticking (the clock may rely on the timer ticking if it can't use the TSC).
      */
-    let mut interrupt_controller =
-        InterruptController::init(&acpi_platform_info.interrupt_model, &mut aml_context);
-    interrupt_controller.enable_local_timer(&topology.cpu_info, Duration::from_millis(10));
+    InterruptController::init(&acpi_platform_info.interrupt_model, &mut aml_context);
+    interrupts::init_timer(&topology.cpu_info);
+    clock::init(&topology.cpu_info);
+    kernel::scheduler::start_ticking::<PlatformImpl>();
+
+    /*
+     * Resolve all the PCI info. This needs to happen after the interrupt controller has been initialised, as the
+     * AML gives us different interrupt routing information once we've told it we're using the APIC.
+     * XXX: not sure this is the right place to do this just yet.
+     */
+    // TODO: this whole situation is a bit gross and needs more thought I think
+    *kernel::PCI_INFO.write() = Some(PciResolver::resolve(pci_access.clone(), &mut aml_context));
+    kernel::PCI_ACCESS.initialize(Some(Mutex::new(Box::new(pci_access))));
 
     task::install_syscall_handler();
 
-    let mut platform = PlatformImpl { kernel_page_table, topology };
+    let _platform = PlatformImpl { topology };
 
     /*
      * Create kernel objects from loaded images and schedule them.
      */
     info!("Loading {} initial tasks to the ready queue", boot_info.loaded_images.num_images);
     for image in boot_info.loaded_images.images() {
-        kernel::load_task(
-            &mut PlatformImpl::per_cpu().scheduler(),
-            image,
-            platform.kernel_page_table(),
-            &kernel::PHYSICAL_MEMORY_MANAGER.get(),
-            &mut kernel_stack_allocator,
-        );
+        kernel::load_task(&mut PlatformImpl::per_cpu().scheduler(), image, &kernel::PHYSICAL_MEMORY_MANAGER.get());
     }
     if let Some(ref video_info) = boot_info.video_mode {
         kernel::create_framebuffer(video_info);
@@ -223,6 +309,23 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
 fn panic(info: &PanicInfo) -> ! {
     error!("KERNEL PANIC: {}", info);
 
+    /*
+     * If we panic while producing a backtrace (e.g. because we fault walking a corrupted stack), we don't try to
+     * produce another one.
+     */
+    static PANICKING: AtomicBool = AtomicBool::new(false);
+    if !PANICKING.swap(true, Ordering::SeqCst) {
+        let rbp: usize;
+        unsafe {
+            asm!("mov {}, rbp", out(reg) rbp);
+            kernel::backtrace::log_backtrace(log::Level::Error, None, rbp);
+        }
+    }
+
+    if cfg!(feature = "gdb_stub") {
+        gdb::enter_on_panic();
+    }
+
     /*
      * If the `qemu_exit` feature is set, we use the debug port to exit.
      */
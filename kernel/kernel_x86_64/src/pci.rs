@@ -1,13 +1,30 @@
+use crate::interrupts;
 use acpi::PciConfigRegions;
 use alloc::collections::BTreeMap;
-use aml::{pci_routing::PciRoutingTable, AmlContext, AmlName};
+use aml::{
+    pci_routing::{PciRoutingTable, Pin},
+    resource::{InterruptPolarity, InterruptTrigger},
+    value::Args as AmlArgs,
+    AmlContext,
+    AmlName,
+    AmlValue,
+};
+use bit_field::BitField;
 use core::ptr;
 use hal::memory::PhysicalAddress;
-use hal_x86_64::kernel_map;
-use kernel::pci::{PciDevice, PciInfo};
-use log::info;
+use hal_x86_64::{
+    hw::io_apic::{PinPolarity, TriggerMode},
+    kernel_map,
+};
+use kernel::pci::{read_capabilities, PciDevice, PciInfo};
+use log::{info, warn};
 use pci_types::{ConfigRegionAccess, PciAddress, PciHeader};
 
+/// The hardware IDs of PCI and PCI Express root bridges. These can either be given as strings, or as integers
+/// encoded with the ASL `EisaId` macro.
+const ROOT_BRIDGE_IDS: [&str; 2] = ["PNP0A03", "PNP0A08"];
+const ROOT_BRIDGE_EISA_IDS: [u64; 2] = [0x030a_d041, 0x080a_d041];
+
 #[derive(Clone)]
 pub struct EcamAccess(PciConfigRegions);
 
@@ -45,20 +62,48 @@ impl ConfigRegionAccess for EcamAccess {
     }
 }
 
-pub struct PciResolver<A>
+pub struct PciResolver<'a, A>
 where
     A: ConfigRegionAccess,
 {
     access: A,
     info: PciInfo,
+    aml_context: &'a mut AmlContext,
+    /// The routing table of the root bus, used to work out which GSIs devices' interrupt pins are routed to. This
+    /// is `None` if we couldn't find or parse the `_PRT` object.
+    routing_table: Option<PciRoutingTable>,
 }
 
-impl<A> PciResolver<A>
+impl<'a, A> PciResolver<'a, A>
 where
     A: ConfigRegionAccess,
 {
-    pub fn resolve(access: A) -> PciInfo {
-        let mut resolver = Self { access, info: PciInfo { devices: BTreeMap::new() } };
+    /// Find all the PCI devices, and resolve information about them. The `_PIC` method must have been used to tell
+    /// the firmware that we're using the APIC before this is called, as the interrupt routing information the AML
+    /// gives us depends on it.
+    pub fn resolve(access: A, aml_context: &'a mut AmlContext) -> PciInfo {
+        let routing_table = match find_root_bridge(aml_context) {
+            Some(root_bridge) => {
+                info!("Found PCI root bridge in AML namespace at {:?}", root_bridge);
+                let prt_path = AmlName::from_str("_PRT").unwrap().resolve(&root_bridge).unwrap();
+
+                match PciRoutingTable::from_prt_path(&prt_path, aml_context) {
+                    Ok(routing_table) => Some(routing_table),
+                    Err(err) => {
+                        warn!(
+                            "Couldn't get PCI routing table: {:?}. Legacy PCI interrupts will not be routed!",
+                            err
+                        );
+                        None
+                    }
+                }
+            }
+            None => {
+                warn!("Couldn't find PCI root bridge in AML namespace. Legacy PCI interrupts will not be routed!");
+                None
+            }
+        };
+        let mut resolver = Self { access, info: PciInfo { devices: BTreeMap::new() }, aml_context, routing_table };
 
         /*
          * If the device at 0:0:0:0 has multiple functions, there are multiple PCI host controllers, so we need to
@@ -117,9 +162,97 @@ where
                 bus, device, function, vendor_id, device_id
             );
 
-            self.info
-                .devices
-                .insert(address, PciDevice { vendor_id, device_id, revision, class, sub_class, interface });
+            let capabilities = read_capabilities(&self.access, address);
+            let gsi = self.resolve_gsi(address);
+
+            self.info.devices.insert(
+                address,
+                PciDevice { vendor_id, device_id, revision, class, sub_class, interface, capabilities, gsi },
+            );
+        }
+    }
+
+    /// Work out which GSI a function's legacy interrupt pin is routed to, using the AML's `_PRT` object. The
+    /// IOAPIC entry for the GSI is set up with the trigger mode and polarity the AML gives us.
+    fn resolve_gsi(&mut self, address: PciAddress) -> Option<u32> {
+        /*
+         * The Interrupt Pin register is bits 8..16 of the dword at `0x3c`. A value of `0` means the function
+         * doesn't use an interrupt pin.
+         * TODO: functions behind PCI-to-PCI bridges need their pins swizzled, and routed using the bridge's `_PRT`
+         */
+        let pin = match unsafe { self.access.read(address, 0x3c) }.get_bits(8..16) {
+            0 => return None,
+            1 => Pin::IntA,
+            2 => Pin::IntB,
+            3 => Pin::IntC,
+            4 => Pin::IntD,
+            _ => {
+                warn!("PCI function at {} has an invalid interrupt pin", address);
+                return None;
+            }
+        };
+
+        let routing_table = self.routing_table.as_ref()?;
+        match routing_table.route(address.device() as u16, address.function() as u16, pin, self.aml_context) {
+            Ok(irq) => {
+                let polarity = match irq.polarity {
+                    InterruptPolarity::ActiveHigh => PinPolarity::High,
+                    InterruptPolarity::ActiveLow => PinPolarity::Low,
+                };
+                let trigger_mode = match irq.trigger {
+                    InterruptTrigger::Edge => TriggerMode::Edge,
+                    InterruptTrigger::Level => TriggerMode::Level,
+                };
+                interrupts::set_interrupt_mode(irq.irq, polarity, trigger_mode);
+
+                info!("PCI function at {} has interrupt pin {:?}, routed to GSI {}", address, pin, irq.irq);
+                Some(irq.irq)
+            }
+            Err(err) => {
+                warn!("Failed to route interrupt of PCI function at {}: {:?}", address, err);
+                None
+            }
+        }
+    }
+}
+
+/// Find the PCI root bridge in the AML namespace. It isn't always called `PCI0`, so we look for a device that has a
+/// hardware ID (`_HID`) or compatible ID (`_CID`) of a PCI or PCI Express root bridge.
+/// TODO: some systems have more than one root bridge, each with its own `_PRT`. We only use the first one we find.
+fn find_root_bridge(aml_context: &mut AmlContext) -> Option<AmlName> {
+    let mut root_bridge = None;
+
+    /*
+     * We can't invoke methods on the context while we're traversing its namespace, so we traverse a copy of it.
+     * Objects that don't exist (e.g. because a level isn't a device) just fail to match, so we can ignore errors.
+     */
+    let _ = aml_context.namespace.clone().traverse(|path, _level| {
+        if root_bridge.is_some() {
+            return Ok(false);
+        }
+
+        let is_root_bridge = ["_HID", "_CID"].iter().any(|id_name| {
+            AmlName::from_str(id_name)
+                .and_then(|name| name.resolve(path))
+                .and_then(|id_path| aml_context.invoke_method(&id_path, AmlArgs::default()))
+                .map_or(false, |id| is_root_bridge_id(&id))
+        });
+
+        if is_root_bridge {
+            root_bridge = Some(path.clone());
         }
+        Ok(root_bridge.is_none())
+    });
+
+    root_bridge
+}
+
+/// `_HID` objects contain a single ID, but `_CID` objects can contain a package of them.
+fn is_root_bridge_id(id: &AmlValue) -> bool {
+    match id {
+        AmlValue::Integer(eisa_id) => ROOT_BRIDGE_EISA_IDS.contains(eisa_id),
+        AmlValue::String(id) => ROOT_BRIDGE_IDS.contains(&id.as_str()),
+        AmlValue::Package(ids) => ids.iter().any(is_root_bridge_id),
+        _ => false,
     }
 }
@@ -23,8 +23,8 @@ pub struct PerCpuImpl {
     _self_pointer: *const PerCpuImpl,
     _pin: PhantomPinned,
 
-    /// The next field must then be the current task's kernel stack pointer. We access this manually from assembly
-    /// with `gs:0x8`, so it must remain at a fixed offset within this struct.
+    /// The next field must then be the stack pointer to use when the current task enters the kernel. We access
+    /// this manually from assembly with `gs:0x8`, so it must remain at a fixed offset within this struct.
     current_task_kernel_rsp: VirtualAddress,
     /// This field must remain at `gs:0x10`, and so cannot be moved.
     current_task_user_rsp: VirtualAddress,
@@ -84,7 +84,12 @@ impl PerCpu<crate::PlatformImpl> for PerCpuImpl {
         self.scheduler()
     }
 
-    fn set_kernel_stack_pointer(mut self: Pin<&mut Self>, stack_pointer: VirtualAddress) {
+    fn set_kernel_stack_pointer(mut self: Pin<&mut Self>, kernel_stack_top: VirtualAddress) {
+        /*
+         * The System V ABI needs the stack to be 16-byte aligned. The CPU aligns the stack like this itself when it
+         * takes an interrupt, but the `syscall` handler loads it straight from `gs:0x8`.
+         */
+        let stack_pointer = kernel_stack_top.align_down(16);
         *self.as_mut().current_task_kernel_rsp() = stack_pointer;
         self.as_mut().tss().set_kernel_stack(stack_pointer);
     }
@@ -59,11 +59,12 @@ syscall_handler:
     pop r11
     pop rcx
 
-    // Disable interrupts again while we mess around with the stacks
+    /*
+     * Disable interrupts again while we mess around with the stacks. We've popped everything we pushed, so the
+     * kernel stack is back at the top the scheduler installed in the per-CPU data, and doesn't need saving.
+     */
     cli
 
-    // Save the kernel's stack back into per-cpu data
-    mov gs:0x8, rsp
     // Move back to the task's user stack
     mov rsp, gs:0x10
 
@@ -7,7 +7,7 @@ global_asm!(include_str!("syscall.s"));
 extern "C" {
     fn task_entry_trampoline() -> !;
 
-    fn do_drop_to_usermode() -> !;
+    fn do_drop_to_usermode(kernel_rsp: VirtualAddress) -> !;
 
     /// Do the actual context switch: save the context of the old task on its kernel stack, switch
     /// to the new task's kernel stack, restore its context and return. The only non-trivial part
@@ -54,6 +54,7 @@ pub struct ContextSwitchFrame {
 pub unsafe fn initialize_kernel_stack(
     kernel_stack_top: &mut VirtualAddress,
     task_entry_point: VirtualAddress,
+    task_argument: usize,
     user_stack_top: &mut VirtualAddress,
 ) {
     /*
@@ -80,13 +81,14 @@ pub unsafe fn initialize_kernel_stack(
     /*
      * Next, we construct the context-switch frame that is used when a task is switched to for
      * the first time. This initializes registers to sensible values, and then jumps to a
-     * kernel-space trampoline that enters userspace.
+     * kernel-space trampoline that enters userspace. The task's argument is passed in `r13`, and moved into
+     * `rdi` (the first argument in the Sys-V ABI) by the trampoline.
      */
     *kernel_stack_top -= mem::size_of::<ContextSwitchFrame>();
     *(kernel_stack_top.mut_ptr() as *mut ContextSwitchFrame) = ContextSwitchFrame {
         r15: usize::from(task_entry_point) as u64,
         r14: INITIAL_RFLAGS.into(),
-        r13: 0x0,
+        r13: task_argument as u64,
         r12: 0x0,
         rbp: 0x0,
         rbx: 0x0,
@@ -98,13 +100,11 @@ pub unsafe fn context_switch(current_kernel_stack: *mut VirtualAddress, new_kern
     do_context_switch(current_kernel_stack, new_kernel_stack);
 }
 
-pub unsafe fn drop_into_userspace() -> ! {
+pub unsafe fn drop_into_userspace(kernel_stack_pointer: VirtualAddress) -> ! {
     /*
-     * On x86_64, we use the context we install into the task's kernel stack to drop into usermode. We don't
-     * need the kernel stack pointer as it has already been installed into the per-cpu info, so we can just
-     * load it from there.
+     * On x86_64, we use the context we install into the task's kernel stack to drop into usermode.
      */
-    do_drop_to_usermode();
+    do_drop_to_usermode(kernel_stack_pointer);
 }
 
 /// We use the `syscall` instruction to make system calls, as it's always present on supported systems. We need
@@ -161,7 +161,7 @@ pub fn install_syscall_handler() {
      * doing.
      *
      * Importantly, we disable interrupts because they're not safe until we've stopped messing about with
-     * stacks.
+     * stacks. We also clear the Alignment Check flag, because it disables SMAP while it's set.
      */
     let flags_mask = CpuFlags::STATUS_MASK
         | CpuFlags::TRAP_FLAG
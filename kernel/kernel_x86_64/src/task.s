@@ -12,41 +12,44 @@
  *     - `r15` is moved into `rcx`
  *     - `r14` is moved into `r11`
  *
- * We also need to switch to the task's user stack, which we access through the per-CPU data.
+ * The task's argument is also passed in `r13`, and is moved into `rdi`.
+ *
+ * We also need to switch to the task's user stack, which we access through the per-CPU data. The kernel stack
+ * we'll enter the kernel on has already been installed into the per-CPU data by the scheduler.
  */
 .global task_entry_trampoline
 task_entry_trampoline:
     // Disable interrupts while we're messing around with stacks. Re-enabled on `sysretq`.
     cli
 
-    mov gs:0x8, rsp     // Save the task's kernel stack
     mov rsp, gs:0x10
 
     mov rcx, r15
     xor r15, r15
     mov r11, r14
     xor r14, r14
+    mov rdi, r13
+    xor r13, r13
 
     // Zero all registers not zerod as part of the context load, to avoid leaking kernel data into userspace
-    // XXX: leave `rcx` and `r11` alone as they're needed for `sysret`
+    // XXX: leave `rcx` and `r11` alone as they're needed for `sysret`, and `rdi` as it holds the task's argument
     xor rax, rax
     xor rdx, rdx
     xor rsi, rsi
-    xor rdi, rdi
     xor r8, r8
     xor r9, r9
     xor r10, r10
 
     sysretq
 
-// fn do_drop_into_usermode() -> !
+// fn do_drop_to_usermode(kernel_rsp: VirtualAddress) -> !
 .global do_drop_to_usermode
 do_drop_to_usermode:
     // Disable interrupts while we're messing around with stacks. Re-enabled on `sysretq`.
     cli
 
-    // Switch to the task's kernel stack
-    mov rsp, gs:0x8
+    // Switch to the task's kernel stack, where its initial context switch frame is
+    mov rsp, rdi
 
     // Pop the context-saved registers. We pop `r14` into `r11` and `r15` into `rcx` because that's where we want
     // them for the `sysretq` anyways.
@@ -60,6 +63,10 @@ do_drop_to_usermode:
     // Switch to the task's user stack
     mov rsp, gs:0x10
 
+    // Move the task's argument into the first argument register
+    mov rdi, r13
+    xor r13, r13
+
     /*
      * Zero all registers that weren't zerod as part of the context load, except rcx and r11, as they're needed by
      * `sysret`. We also zero `r14` and `r15`, which would normally be loaded from the saved context but weren't
@@ -68,7 +75,6 @@ do_drop_to_usermode:
     xor rax, rax
     xor rdx, rdx
     xor rsi, rsi
-    xor rdi, rdi
     xor r8, r8
     xor r9, r9
     xor r10, r10
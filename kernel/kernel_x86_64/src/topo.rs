@@ -1,4 +1,4 @@
-use crate::per_cpu::PerCpuImpl;
+use crate::{per_cpu::PerCpuImpl, user_access};
 use acpi::platform::ProcessorState;
 use alloc::{boxed::Box, vec::Vec};
 use core::{fmt, pin::Pin};
@@ -109,11 +109,16 @@ fn check_support_and_enable_features(cpu_info: &CpuInfo) {
         write_control_reg,
         write_msr,
         CR4_ENABLE_GLOBAL_PAGES,
+        CR4_ENABLE_PCID,
+        CR4_ENABLE_SMAP,
+        CR4_ENABLE_SMEP,
+        CR4_ENABLE_UMIP,
         CR4_RESTRICT_RDTSC,
         CR4_XSAVE_ENABLE_BIT,
         EFER,
         EFER_ENABLE_NX_BIT,
         EFER_ENABLE_SYSCALL,
+        IA32_PAT,
     };
 
     if !cpu_info.supported_features.xsave {
@@ -124,9 +129,47 @@ fn check_support_and_enable_features(cpu_info: &CpuInfo) {
     cr4.set_bit(CR4_XSAVE_ENABLE_BIT, true);
     cr4.set_bit(CR4_ENABLE_GLOBAL_PAGES, true);
     cr4.set_bit(CR4_RESTRICT_RDTSC, true);
+
+    /*
+     * These stop the kernel from executing or accessing userspace memory by accident. They're not supported by
+     * older processors, so we just do without them there. Once SMAP is enabled, userspace memory can only be
+     * accessed inside a user-access window (see `user_access`).
+     */
+    let features = &cpu_info.supported_features;
+    info!(
+        "Protection features supported: SMEP = {}, SMAP = {}, UMIP = {}",
+        features.smep, features.smap, features.umip
+    );
+    cr4.set_bit(CR4_ENABLE_SMEP, features.smep);
+    cr4.set_bit(CR4_ENABLE_SMAP, features.smap);
+    cr4.set_bit(CR4_ENABLE_UMIP, features.umip);
+
+    /*
+     * PCIDs let us switch address space without flushing the TLB. They can only be enabled while the current PCID
+     * is `0`, which is true here because the kernel's page tables don't use one.
+     */
+    info!("PCIDs supported: {} (INVPCID supported: {})", features.pcid, features.invpcid);
+    cr4.set_bit(CR4_ENABLE_PCID, features.pcid);
+
     unsafe {
         write_control_reg!(CR4, cr4);
     }
+    user_access::set_smap_enabled(features.smap);
+    if features.pcid {
+        hal_x86_64::hw::tlb::set_pcid_enabled(features.invpcid);
+    }
+
+    /*
+     * Program the Page Attribute Table, so we can use all of the `CacheType`s. Nothing is mapped with a memory
+     * type that this changes yet, so we don't need to flush any caches.
+     */
+    if features.pat {
+        unsafe {
+            write_msr(IA32_PAT, hal_x86_64::paging::PAT);
+        }
+    } else {
+        warn!("Processor does not support PAT. Write-combining memory will be uncached instead.");
+    }
 
     let mut efer = read_msr(EFER);
     efer.set_bit(EFER_ENABLE_SYSCALL, true);
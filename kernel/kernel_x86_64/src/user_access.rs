@@ -0,0 +1,95 @@
+//! Copies to and from userspace that can recover from faults. The kernel validates the memory that system calls
+//! access, but userspace can still change its mappings from another thread after the memory has been validated,
+//! so the copies themselves must be able to fail gracefully.
+//!
+//! If the processor supports SMAP, the kernel can't touch userspace memory at all, except inside a user-access
+//! window, which is only opened around the copies made here. This means every intentional access to userspace
+//! memory goes through the kernel's validation of system call arguments, and any other access faults.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use hal::memory::VirtualAddress;
+
+/// Whether SMAP has been enabled. `stac` and `clac` are invalid instructions on processors that don't support it,
+/// so we can only use them once we know it's there.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+global_asm!(include_str!("user_access.s"));
+extern "C" {
+    fn copy_user_bytes(destination: *mut u8, source: *const u8, length: usize) -> u64;
+
+    /*
+     * These aren't really statics - they're labels in `copy_user_bytes`, and we only use their addresses.
+     */
+    static copy_user_bytes_access: u8;
+    static copy_user_bytes_fixup: u8;
+}
+
+/// Copy `length` bytes from `source` to `destination`. If the copy faults, `Err(())` is returned.
+///
+/// ### Safety
+/// Both buffers must be valid for `length` bytes, except for memory in userspace, which may fault. Kernel memory
+/// must not fault.
+pub unsafe fn copy_user(destination: *mut u8, source: *const u8, length: usize) -> Result<(), ()> {
+    let result = {
+        let _window = UserAccessWindow::open();
+        copy_user_bytes(destination, source, length)
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// Called by exception handlers when the kernel faults. If the fault was caused by `copy_user`, `instruction_pointer`
+/// (the address the handler will return to) is moved to the copy's fixup code, so the copy fails instead, and
+/// `true` is returned.
+pub fn fixup_fault(instruction_pointer: &mut VirtualAddress) -> bool {
+    let (access, fixup) =
+        unsafe { (&copy_user_bytes_access as *const u8 as usize, &copy_user_bytes_fixup as *const u8 as usize) };
+
+    if usize::from(*instruction_pointer) == access {
+        *instruction_pointer = VirtualAddress::new(fixup);
+        true
+    } else {
+        false
+    }
+}
+
+pub fn set_smap_enabled(enabled: bool) {
+    SMAP_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// While this exists, the kernel is allowed to access userspace memory, even if SMAP is enabled. It sets
+/// `RFLAGS.AC` when it's opened, and clears it again when it's dropped, so it should be kept open for as short a
+/// time as possible.
+///
+/// Userspace can't leave `AC` set when it enters the kernel through a system call, because it's masked out by
+/// `syscall` (see `task::install_syscall_handler`).
+struct UserAccessWindow {
+    smap_enabled: bool,
+}
+
+impl UserAccessWindow {
+    fn open() -> UserAccessWindow {
+        let smap_enabled = SMAP_ENABLED.load(Ordering::Relaxed);
+        if smap_enabled {
+            unsafe {
+                asm!("stac");
+            }
+        }
+
+        UserAccessWindow { smap_enabled }
+    }
+}
+
+impl Drop for UserAccessWindow {
+    fn drop(&mut self) {
+        if self.smap_enabled {
+            unsafe {
+                asm!("clac");
+            }
+        }
+    }
+}
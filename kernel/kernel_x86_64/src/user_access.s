@@ -0,0 +1,23 @@
+.intel_syntax noprefix
+.code64
+
+/*
+ * Copy `rdx` bytes from `rsi` to `rdi`, where one of the buffers is in userspace. Returns `0` if the copy succeeded.
+ *
+ * Userspace memory is only accessed by the `rep movsb` at `copy_user_bytes_access`. If it causes a fault (e.g.
+ * because the memory has been unmapped by another thread since it was validated), the exception handler resumes
+ * execution at `copy_user_bytes_fixup` instead, which returns `1`.
+ */
+.global copy_user_bytes
+copy_user_bytes:
+    mov rcx, rdx
+.global copy_user_bytes_access
+copy_user_bytes_access:
+    rep movsb
+    xor rax, rax
+    ret
+
+.global copy_user_bytes_fixup
+copy_user_bytes_fixup:
+    mov rax, 1
+    ret
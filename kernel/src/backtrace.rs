@@ -0,0 +1,256 @@
+//! This module walks the kernel's stack to produce backtraces, and uses the kernel's symbol table (if the loader
+//! passed it to us) to resolve the addresses in them to function names. Backtraces are logged when the kernel
+//! panics, and when it takes an exception it can't recover from.
+
+use bit_field::BitField;
+use core::{fmt, mem, ptr, slice};
+use hal::boot_info::KernelSymbols;
+use log::{log, Level};
+use mer::symbol::iterate_symbol_table;
+use pebble_util::InitGuard;
+
+static KERNEL_SYMBOLS: InitGuard<SymbolTable> = InitGuard::uninit();
+
+/// The maximum number of frames we'll walk. This stops us walking forever if the frame chain is corrupted in a way
+/// that creates a loop.
+const MAX_FRAMES: usize = 64;
+
+/// The value of the type field of a symbol's `info` that marks it as a function.
+const SYMBOL_TYPE_FUNC: u8 = 2;
+
+struct SymbolTable {
+    symbols: &'static [u8],
+    strings: &'static [u8],
+}
+
+/// Make the kernel's symbols available for symbolicating backtraces. Until this is called, backtraces only contain
+/// raw addresses.
+///
+/// ### Safety
+/// `symbols` must describe a valid symbol table and string table, which must stay mapped for the rest of the
+/// kernel's lifetime.
+pub unsafe fn init(symbols: &KernelSymbols) {
+    KERNEL_SYMBOLS.initialize(SymbolTable {
+        symbols: slice::from_raw_parts(symbols.symbol_table.ptr(), symbols.symbol_table_size),
+        strings: slice::from_raw_parts(symbols.string_table.ptr(), symbols.string_table_size),
+    });
+}
+
+/// Log a backtrace at the given level, starting with the frame pointed to by `frame_pointer`. If
+/// `instruction_pointer` is provided, it is logged as the first entry (this is useful for exceptions, where it's
+/// the address of the faulting instruction).
+///
+/// ### Safety
+/// `frame_pointer` must either be `0`, or point to a valid frame on the current kernel stack.
+pub unsafe fn log_backtrace(level: Level, instruction_pointer: Option<usize>, frame_pointer: usize) {
+    log!(level, "Backtrace:");
+
+    /*
+     * Return addresses point to the instruction after the call, which can be the first instruction of the next
+     * function if the call was to a diverging function. We look up the address of the call instruction instead.
+     */
+    let addresses = instruction_pointer
+        .map(|address| (address, address))
+        .into_iter()
+        .chain(Frames { frame_pointer, depth: 0 }.map(|address| (address, address - 1)));
+
+    for (i, (address, lookup_address)) in addresses.enumerate() {
+        match symbolicate(lookup_address) {
+            Some((name, start)) => {
+                log!(level, "    {:>2}: {:#018x} - {}+{:#x}", i, address, Demangle(name), address - start)
+            }
+            None => log!(level, "    {:>2}: {:#018x} - <unknown>", i, address),
+        }
+    }
+}
+
+/// Find the function that contains `address`. Returns its (mangled) name and start address.
+fn symbolicate(address: usize) -> Option<(&'static str, usize)> {
+    let table = KERNEL_SYMBOLS.try_get()?;
+    let address = address as u64;
+
+    /*
+     * We don't use `Symbol::symbol_type`, as it panics on types it doesn't know about, and we don't want to panic
+     * while we're already panicking.
+     */
+    let symbol = iterate_symbol_table(table.symbols).find(|symbol| {
+        symbol.info.get_bits(0..4) == SYMBOL_TYPE_FUNC
+            && address >= symbol.value
+            && address < symbol.value + symbol.size
+    })?;
+
+    Some((symbol.name_in(table.strings)?, symbol.value as usize))
+}
+
+/// Iterates over the return addresses of the frames in a chain of frame pointers. This relies on the kernel being
+/// compiled with frame pointers. On both x86_64 and AArch64, each frame starts with the caller's frame pointer,
+/// followed by the return address.
+struct Frames {
+    frame_pointer: usize,
+    depth: usize,
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.frame_pointer == 0
+            || self.frame_pointer % mem::align_of::<usize>() != 0
+            || self.depth >= MAX_FRAMES
+        {
+            return None;
+        }
+
+        let (next_frame_pointer, return_address) = unsafe {
+            let frame = self.frame_pointer as *const usize;
+            (ptr::read(frame), ptr::read(frame.add(1)))
+        };
+
+        /*
+         * The stack grows downwards, so each caller's frame must be at a higher address than the frame we're in.
+         * If it isn't, we've either reached the end of the chain, or it's been corrupted, so we stop.
+         */
+        self.frame_pointer = if next_frame_pointer > self.frame_pointer { next_frame_pointer } else { 0 };
+        self.depth += 1;
+
+        if return_address == 0 {
+            return None;
+        }
+        Some(return_address)
+    }
+}
+
+/// Displays a symbol name mangled with Rust's legacy mangling scheme in a more readable form. Names that aren't
+/// mangled like this are displayed unchanged.
+///
+/// Legacy-mangled names look like `_ZN4core3fmt5write17h0123456789abcdefE`: a list of length-prefixed path
+/// components between `_ZN` and `E`, the last of which is a hash. Characters that can't appear in symbols are
+/// escaped with `$...$` sequences, and `.` is used in place of `:`.
+struct Demangle<'a>(&'a str);
+
+impl Demangle<'_> {
+    /// Split the mangled path into its components. Returns `None` if the name isn't mangled with the legacy
+    /// scheme.
+    fn components(&self) -> Option<impl Iterator<Item = &str> + Clone> {
+        if !self.0.starts_with("_ZN") || !self.0.ends_with('E') {
+            return None;
+        }
+        let path = &self.0[3..(self.0.len() - 1)];
+
+        /*
+         * Make sure the whole path is well-formed before we start displaying any of it.
+         */
+        let mut rest = path;
+        while !rest.is_empty() {
+            let (length, after) = Self::split_length(rest)?;
+            rest = after.get(length..)?;
+        }
+
+        let mut rest = path;
+        Some(core::iter::from_fn(move || {
+            if rest.is_empty() {
+                return None;
+            }
+            let (length, after) = Self::split_length(rest).unwrap();
+            rest = &after[length..];
+            Some(&after[..length])
+        }))
+    }
+
+    fn split_length(bytes: &str) -> Option<(usize, &str)> {
+        let digits = bytes.bytes().take_while(u8::is_ascii_digit).count();
+        let length = bytes[..digits].parse().ok()?;
+        Some((length, &bytes[digits..]))
+    }
+
+    fn is_hash(component: &str) -> bool {
+        component.len() == 17
+            && component.starts_with('h')
+            && component[1..].bytes().all(|byte| byte.is_ascii_hexdigit())
+    }
+}
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let components = match self.components() {
+            Some(components) => components,
+            None => return f.write_str(self.0),
+        };
+        let num_components = components.clone().count();
+
+        for (i, component) in components.enumerate() {
+            if i == num_components - 1 && Self::is_hash(component) {
+                break;
+            }
+            if i != 0 {
+                f.write_str("::")?;
+            }
+
+            /*
+             * Components that would start with `$` are prefixed with an `_`, which we strip off.
+             */
+            let mut rest = if component.starts_with("_$") { &component[1..] } else { component };
+            while !rest.is_empty() {
+                if rest.starts_with("..") {
+                    f.write_str("::")?;
+                    rest = &rest[2..];
+                } else if rest.starts_with('$') {
+                    let end = match rest[1..].find('$') {
+                        Some(end) => end + 2,
+                        None => return f.write_str(rest),
+                    };
+                    f.write_str(match &rest[..end] {
+                        "$SP$" => "@",
+                        "$BP$" => "*",
+                        "$RF$" => "&",
+                        "$LT$" => "<",
+                        "$GT$" => ">",
+                        "$LP$" => "(",
+                        "$RP$" => ")",
+                        "$C$" => ",",
+                        "$u20$" => " ",
+                        "$u27$" => "'",
+                        "$u5b$" => "[",
+                        "$u5d$" => "]",
+                        "$u7b$" => "{",
+                        "$u7d$" => "}",
+                        "$u7e$" => "~",
+                        escape => escape,
+                    })?;
+                    rest = &rest[end..];
+                } else {
+                    let end = rest.find(|c| c == '$' || c == '.').unwrap_or(rest.len());
+                    let end = if end == 0 { 1 } else { end };
+                    f.write_str(&rest[..end])?;
+                    rest = &rest[end..];
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demangle() {
+        macro test($mangled: expr, $demangled: expr) {
+            assert_eq!(format!("{}", Demangle($mangled)), $demangled);
+        }
+
+        test!("_ZN4core3fmt5write17h0123456789abcdefE", "core::fmt::write");
+        test!("_ZN6kernel9scheduler9Scheduler4tick17hfedcba9876543210E", "kernel::scheduler::Scheduler::tick");
+        test!(
+            "_ZN63_$LT$kernel..object..task..Task$u20$as$u20$core..fmt..Debug$GT$3fmt17h0123456789abcdefE",
+            "<kernel::object::task::Task as core::fmt::Debug>::fmt"
+        );
+        test!("_ZN4core3ptr13drop_in_place17h0123456789abcdefE", "core::ptr::drop_in_place");
+        test!("kentry", "kentry");
+        test!("_ZN4core3fmtE", "core::fmt");
+        test!("_ZN4core3fmt", "_ZN4core3fmt");
+        test!("_ZN4core9fmtE", "_ZN4core9fmtE");
+    }
+}
@@ -0,0 +1,639 @@
+//! This module implements a stub for the GDB Remote Serial Protocol, which allows the kernel to be debugged by GDB
+//! without any help from an emulator (e.g. on real hardware). The protocol itself is architecture-independent, so
+//! it lives here - platforms provide a `Connection` to talk to GDB over (usually a serial port), and a `Target` that
+//! provides access to the state of the stopped CPU.
+//!
+//! The stub supports reading and writing registers and memory, software and hardware breakpoints, and
+//! single-stepping. It's entered when the platform decides the kernel should stop (e.g. on a breakpoint), reports
+//! the stop to GDB, and then handles packets until GDB tells it to resume.
+
+use bit_field::BitField;
+use core::{mem, str};
+
+/// The maximum size of a packet's data. This is reported to GDB, which won't send us anything larger.
+const PACKET_SIZE: usize = 4096;
+
+/// The number of software breakpoints that can be inserted at the same time.
+const MAX_SOFTWARE_BREAKPOINTS: usize = 32;
+
+/// The maximum length of a `Target`'s breakpoint instruction.
+const MAX_BREAKPOINT_LENGTH: usize = 4;
+
+/// A transport that the stub can talk to GDB over.
+pub trait Connection {
+    /// Read a byte from GDB. This should block until one is available.
+    fn read(&mut self) -> u8;
+    fn write(&mut self, byte: u8);
+}
+
+/// Provides access to the state of the CPU that's stopped in the stub. The registers are those of the code that
+/// was running when the stub was entered, and any changes must be applied when it resumes.
+pub trait Target {
+    /// The instruction used to implement software breakpoints. Executing it must cause the platform to enter the
+    /// stub with `StopReason::Breakpoint`.
+    const BREAKPOINT_INSTRUCTION: &'static [u8];
+
+    /// Produce the contents of the registers, in the order and format that GDB expects for the architecture. They
+    /// can be passed to `write` in any number of pieces.
+    fn read_registers(&self, write: &mut dyn FnMut(&[u8]));
+
+    /// Set the value of the register with the given index (in GDB's numbering). Returns `false` if the register
+    /// doesn't exist or can't be written.
+    fn write_register(&mut self, index: usize, value: &[u8]) -> bool;
+
+    /// Read memory at `address` into `buffer`. Returns `false` if any of it isn't mapped.
+    fn read_memory(&self, address: usize, buffer: &mut [u8]) -> bool;
+
+    /// Write `bytes` to memory at `address`. This should work even if the memory is mapped as read-only, so that
+    /// software breakpoints can be inserted into code. Returns `false` if any of it isn't mapped.
+    fn write_memory(&mut self, address: usize, bytes: &[u8]) -> bool;
+
+    fn set_instruction_pointer(&mut self, address: usize);
+
+    /// Set whether the CPU should stop (entering the stub with `StopReason::Breakpoint`) after executing a single
+    /// instruction, once it's resumed.
+    fn set_single_step(&mut self, enabled: bool);
+
+    /// Insert a hardware breakpoint at `address`. Returns `false` if there aren't any free hardware breakpoints.
+    fn insert_hardware_breakpoint(&mut self, address: usize) -> bool;
+
+    /// Remove a hardware breakpoint inserted with `insert_hardware_breakpoint`. Returns `false` if there isn't one
+    /// at `address`.
+    fn remove_hardware_breakpoint(&mut self, address: usize) -> bool;
+}
+
+/// Why the stub was entered. This is reported to GDB as a POSIX signal number.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StopReason {
+    /// A breakpoint was hit, or a single step has completed.
+    Breakpoint,
+    /// The kernel was interrupted from outside (e.g. by an NMI).
+    Interrupt,
+    /// The kernel panicked. It can't be resumed.
+    Panic,
+}
+
+impl StopReason {
+    fn signal(self) -> u8 {
+        match self {
+            StopReason::Breakpoint => 5, // SIGTRAP
+            StopReason::Interrupt => 2,  // SIGINT
+            StopReason::Panic => 6,      // SIGABRT
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SoftwareBreakpoint {
+    address: usize,
+    original: [u8; MAX_BREAKPOINT_LENGTH],
+}
+
+pub struct GdbStub<C>
+where
+    C: Connection,
+{
+    connection: C,
+    software_breakpoints: [Option<SoftwareBreakpoint>; MAX_SOFTWARE_BREAKPOINTS],
+    response: Response,
+}
+
+impl<C> GdbStub<C>
+where
+    C: Connection,
+{
+    pub const fn new(connection: C) -> GdbStub<C> {
+        GdbStub {
+            connection,
+            software_breakpoints: [None; MAX_SOFTWARE_BREAKPOINTS],
+            response: Response { buffer: [0; PACKET_SIZE], length: 0 },
+        }
+    }
+
+    /// Report that the kernel has stopped, and then handle packets from GDB until it tells us to resume (or
+    /// detaches). Any changes GDB makes are applied to `target`.
+    pub fn handle_stop<T>(&mut self, target: &mut T, reason: StopReason)
+    where
+        T: Target,
+    {
+        self.response.clear();
+        self.response.push_stop_reply(reason);
+        self.send_response();
+
+        let mut packet = [0u8; PACKET_SIZE];
+        loop {
+            let length = self.receive_packet(&mut packet);
+            self.response.clear();
+
+            match self.handle_packet(target, reason, &packet[..length]) {
+                PacketResult::Respond => self.send_response(),
+                PacketResult::RespondAndResume => {
+                    self.send_response();
+                    return;
+                }
+                PacketResult::Resume => return,
+            }
+        }
+    }
+
+    fn handle_packet<T>(&mut self, target: &mut T, reason: StopReason, packet: &[u8]) -> PacketResult
+    where
+        T: Target,
+    {
+        let (&command, arguments) = match packet.split_first() {
+            Some(split) => split,
+            None => return PacketResult::Respond,
+        };
+
+        match command {
+            b'?' => self.response.push_stop_reply(reason),
+
+            b'g' => {
+                let response = &mut self.response;
+                target.read_registers(&mut |bytes| response.push_hex(bytes));
+            }
+
+            b'P' => {
+                let result = split_once(arguments, b'=').and_then(|(index, value)| {
+                    let index = parse_hex(index)?;
+                    let mut buffer = [0u8; 16];
+                    let value = decode_hex(value, &mut buffer)?;
+                    Some(target.write_register(index, value))
+                });
+                self.response.push_result(result == Some(true));
+            }
+
+            b'm' => match parse_address_and_length(arguments) {
+                Some((address, length)) => {
+                    let mut buffer = [0u8; PACKET_SIZE / 2];
+                    let length = usize::min(length, buffer.len());
+                    if target.read_memory(address, &mut buffer[..length]) {
+                        self.response.push_hex(&buffer[..length]);
+                    } else {
+                        self.response.push_error(0x01);
+                    }
+                }
+                None => self.response.push_error(0x00),
+            },
+
+            b'M' => {
+                let result = split_once(arguments, b':').and_then(|(header, data)| {
+                    let (address, length) = parse_address_and_length(header)?;
+                    let mut buffer = [0u8; PACKET_SIZE / 2];
+                    let bytes = decode_hex(data, &mut buffer)?;
+                    if bytes.len() != length {
+                        return None;
+                    }
+                    Some(target.write_memory(address, bytes))
+                });
+                self.response.push_result(result == Some(true));
+            }
+
+            b'c' | b's' => {
+                /*
+                 * We can't resume from a panic, so we just report it again.
+                 */
+                if reason == StopReason::Panic {
+                    self.response.push_stop_reply(reason);
+                    return PacketResult::Respond;
+                }
+
+                if !arguments.is_empty() {
+                    match parse_hex(arguments) {
+                        Some(address) => target.set_instruction_pointer(address),
+                        None => {
+                            self.response.push_error(0x00);
+                            return PacketResult::Respond;
+                        }
+                    }
+                }
+
+                target.set_single_step(command == b's');
+
+                /*
+                 * We don't respond to a resume until the target stops again, when we send a new stop reply.
+                 */
+                return PacketResult::Resume;
+            }
+
+            b'Z' | b'z' => {
+                let insert = command == b'Z';
+                let result = split_once(arguments, b',').and_then(|(kind, rest)| {
+                    let (address, _) = parse_address_and_length(rest)?;
+                    match kind {
+                        b"0" if insert => Some(self.insert_software_breakpoint(target, address)),
+                        b"0" => Some(self.remove_software_breakpoint(target, address)),
+                        b"1" if insert => Some(target.insert_hardware_breakpoint(address)),
+                        b"1" => Some(target.remove_hardware_breakpoint(address)),
+                        _ => None,
+                    }
+                });
+
+                /*
+                 * An empty response tells GDB that we don't support this type of breakpoint.
+                 */
+                if let Some(success) = result {
+                    self.response.push_result(success);
+                }
+            }
+
+            b'D' => {
+                self.remove_all_software_breakpoints(target);
+                target.set_single_step(false);
+                self.response.push_str("OK");
+                return PacketResult::RespondAndResume;
+            }
+
+            b'q' => {
+                if arguments.starts_with(b"Supported") {
+                    self.response.push_str("PacketSize=");
+                    self.response.push_hex_number(PACKET_SIZE);
+                } else if arguments.starts_with(b"Attached") {
+                    // Tell GDB it attached to an existing process, so it detaches instead of killing it on exit
+                    self.response.push_str("1");
+                } else if arguments == b"C" {
+                    self.response.push_str("QC1");
+                }
+            }
+
+            b'H' => self.response.push_str("OK"),
+
+            /*
+             * For anything we don't support, we send an empty response.
+             */
+            _ => (),
+        }
+
+        PacketResult::Respond
+    }
+
+    fn insert_software_breakpoint<T>(&mut self, target: &mut T, address: usize) -> bool
+    where
+        T: Target,
+    {
+        let length = T::BREAKPOINT_INSTRUCTION.len();
+        if self.software_breakpoints.iter().flatten().any(|breakpoint| breakpoint.address == address) {
+            return true;
+        }
+
+        let slot = match self.software_breakpoints.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        let mut original = [0u8; MAX_BREAKPOINT_LENGTH];
+        if !target.read_memory(address, &mut original[..length])
+            || !target.write_memory(address, T::BREAKPOINT_INSTRUCTION)
+        {
+            return false;
+        }
+
+        *slot = Some(SoftwareBreakpoint { address, original });
+        true
+    }
+
+    fn remove_software_breakpoint<T>(&mut self, target: &mut T, address: usize) -> bool
+    where
+        T: Target,
+    {
+        let slot = match self
+            .software_breakpoints
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(breakpoint) if breakpoint.address == address))
+        {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        let breakpoint = slot.take().unwrap();
+        target.write_memory(address, &breakpoint.original[..T::BREAKPOINT_INSTRUCTION.len()])
+    }
+
+    fn remove_all_software_breakpoints<T>(&mut self, target: &mut T)
+    where
+        T: Target,
+    {
+        for slot in self.software_breakpoints.iter_mut() {
+            if let Some(breakpoint) = slot.take() {
+                target.write_memory(breakpoint.address, &breakpoint.original[..T::BREAKPOINT_INSTRUCTION.len()]);
+            }
+        }
+    }
+
+    /// Receive a packet from GDB into `buffer`, returning its length. Packets look like `$data#checksum`,
+    /// where the checksum is the sum of the bytes of the data, modulo 256, as two hex digits. We acknowledge each
+    /// packet with a `+`, or ask for it to be resent with a `-` if the checksum is wrong.
+    fn receive_packet(&mut self, buffer: &mut [u8]) -> usize {
+        loop {
+            /*
+             * Skip anything before the start of the packet, including acknowledgements and interrupt requests (which
+             * don't mean anything while we're stopped).
+             */
+            while self.connection.read() != b'$' {}
+
+            let mut length = 0;
+            let mut checksum = 0u8;
+            loop {
+                let byte = self.connection.read();
+                if byte == b'#' {
+                    break;
+                }
+
+                /*
+                 * If a packet is too long, we drop the rest of it. This shouldn't happen, as we tell GDB how long
+                 * packets can be.
+                 */
+                if length < buffer.len() {
+                    buffer[length] = byte;
+                    length += 1;
+                }
+                checksum = checksum.wrapping_add(byte);
+            }
+
+            let expected = [self.connection.read(), self.connection.read()];
+            if parse_hex(&expected) == Some(checksum as usize) {
+                self.connection.write(b'+');
+                return length;
+            } else {
+                self.connection.write(b'-');
+            }
+        }
+    }
+
+    /// Send `self.response` to GDB, resending it until GDB acknowledges it.
+    fn send_response(&mut self) {
+        loop {
+            let data = &self.response.buffer[..self.response.length];
+            let checksum = data.iter().fold(0u8, |checksum, &byte| checksum.wrapping_add(byte));
+
+            self.connection.write(b'$');
+            for &byte in data {
+                self.connection.write(byte);
+            }
+            self.connection.write(b'#');
+            self.connection.write(HEX_DIGITS[checksum.get_bits(4..8) as usize]);
+            self.connection.write(HEX_DIGITS[checksum.get_bits(0..4) as usize]);
+
+            match self.connection.read() {
+                b'-' => continue,
+                _ => return,
+            }
+        }
+    }
+}
+
+enum PacketResult {
+    Respond,
+    RespondAndResume,
+    Resume,
+}
+
+struct Response {
+    buffer: [u8; PACKET_SIZE],
+    length: usize,
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+impl Response {
+    fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        /*
+         * GDB never asks for anything that can't fit in a packet, so we can just drop anything that won't fit.
+         */
+        if self.length < self.buffer.len() {
+            self.buffer[self.length] = byte;
+            self.length += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(HEX_DIGITS[byte.get_bits(4..8) as usize]);
+            self.push(HEX_DIGITS[byte.get_bits(0..4) as usize]);
+        }
+    }
+
+    fn push_hex_number(&mut self, value: usize) {
+        let mut digits = (0..(mem::size_of::<usize>() * 2))
+            .rev()
+            .map(|i| value.get_bits((i * 4)..(i * 4 + 4)))
+            .skip_while(|&digit| digit == 0)
+            .peekable();
+
+        if digits.peek().is_none() {
+            self.push(b'0');
+        }
+        for digit in digits {
+            self.push(HEX_DIGITS[digit]);
+        }
+    }
+
+    fn push_stop_reply(&mut self, reason: StopReason) {
+        self.push(b'S');
+        self.push_hex(&[reason.signal()]);
+    }
+
+    fn push_error(&mut self, error: u8) {
+        self.push(b'E');
+        self.push_hex(&[error]);
+    }
+
+    fn push_result(&mut self, success: bool) {
+        if success {
+            self.push_str("OK");
+        } else {
+            self.push_error(0x01);
+        }
+    }
+}
+
+fn split_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..index], &bytes[(index + 1)..]))
+}
+
+/// Parse a big-endian hex number, like those used for addresses and lengths in packets.
+fn parse_hex(bytes: &[u8]) -> Option<usize> {
+    if bytes.is_empty() {
+        return None;
+    }
+    usize::from_str_radix(str::from_utf8(bytes).ok()?, 16).ok()
+}
+
+/// Parse arguments of the form `address,length`.
+fn parse_address_and_length(bytes: &[u8]) -> Option<(usize, usize)> {
+    let (address, length) = split_once(bytes, b',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+/// Decode a string of hex-encoded bytes into `buffer`, returning the decoded bytes.
+fn decode_hex<'a>(bytes: &[u8], buffer: &'a mut [u8]) -> Option<&'a [u8]> {
+    if bytes.len() % 2 != 0 || bytes.len() / 2 > buffer.len() {
+        return None;
+    }
+
+    for (i, pair) in bytes.chunks(2).enumerate() {
+        buffer[i] = parse_hex(pair)? as u8;
+    }
+    Some(&buffer[..(bytes.len() / 2)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{collections::VecDeque, vec::Vec};
+
+    struct TestConnection {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Connection for TestConnection {
+        fn read(&mut self) -> u8 {
+            self.input.pop_front().expect("Stub tried to read past the end of the test input")
+        }
+
+        fn write(&mut self, byte: u8) {
+            self.output.push(byte);
+        }
+    }
+
+    /// A target with 16 bytes of memory at `0x1000`, and a single 8-byte register.
+    struct TestTarget {
+        memory: [u8; 16],
+        register: u64,
+        single_step: bool,
+    }
+
+    impl Target for TestTarget {
+        const BREAKPOINT_INSTRUCTION: &'static [u8] = &[0xcc];
+
+        fn read_registers(&self, write: &mut dyn FnMut(&[u8])) {
+            write(&self.register.to_le_bytes());
+        }
+
+        fn write_register(&mut self, index: usize, value: &[u8]) -> bool {
+            if index != 0 || value.len() != 8 {
+                return false;
+            }
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(value);
+            self.register = u64::from_le_bytes(bytes);
+            true
+        }
+
+        fn read_memory(&self, address: usize, buffer: &mut [u8]) -> bool {
+            match address.checked_sub(0x1000).and_then(|offset| self.memory.get(offset..(offset + buffer.len()))) {
+                Some(memory) => {
+                    buffer.copy_from_slice(memory);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn write_memory(&mut self, address: usize, bytes: &[u8]) -> bool {
+            match address
+                .checked_sub(0x1000)
+                .and_then(|offset| self.memory.get_mut(offset..(offset + bytes.len())))
+            {
+                Some(memory) => {
+                    memory.copy_from_slice(bytes);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn set_instruction_pointer(&mut self, _address: usize) {}
+
+        fn set_single_step(&mut self, enabled: bool) {
+            self.single_step = enabled;
+        }
+
+        fn insert_hardware_breakpoint(&mut self, _address: usize) -> bool {
+            false
+        }
+
+        fn remove_hardware_breakpoint(&mut self, _address: usize) -> bool {
+            false
+        }
+    }
+
+    fn packet(data: &str) -> Vec<u8> {
+        let checksum = data.bytes().fold(0u8, |checksum, byte| checksum.wrapping_add(byte));
+        format!("${}#{:02x}", data, checksum).into_bytes()
+    }
+
+    /// Run the stub with the given packets from GDB (each of which is acknowledged by the stub, and each response
+    /// acknowledged by GDB), and return the responses sent by the stub.
+    fn run(target: &mut TestTarget, packets: &[&str]) -> Vec<u8> {
+        let mut input = VecDeque::new();
+        input.push_back(b'+');
+        for data in packets {
+            input.extend(packet(data));
+            input.push_back(b'+');
+        }
+
+        let mut stub = GdbStub::new(TestConnection { input, output: Vec::new() });
+        stub.handle_stop(target, StopReason::Breakpoint);
+        stub.connection.output
+    }
+
+    fn expected(responses: &[&str]) -> Vec<u8> {
+        let mut output = packet("S05");
+        for response in responses {
+            output.push(b'+');
+            output.extend(packet(response));
+        }
+        output
+    }
+
+    #[test]
+    fn test_memory_and_registers() {
+        let mut target = TestTarget { memory: [0; 16], register: 0x1234, single_step: false };
+        target.memory[0..4].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+
+        let output = run(&mut target, &["g", "m1000,4", "m2000,4", "M1004,2:cafe", "P0=efcdab8967452301", "c"]);
+        let mut expected = expected(&["3412000000000000", "deadbeef", "E01", "OK", "OK"]);
+        expected.push(b'+');
+        assert_eq!(output, expected);
+        assert_eq!(&target.memory[4..6], &[0xca, 0xfe]);
+        assert_eq!(target.register, 0x0123456789abcdef);
+        assert!(!target.single_step);
+    }
+
+    #[test]
+    fn test_software_breakpoints() {
+        let mut target = TestTarget { memory: [0x90; 16], register: 0, single_step: false };
+
+        let output = run(&mut target, &["Z0,1002,1", "m1000,4", "Z1,1004,1", "Z0,2000,1", "D"]);
+        assert_eq!(output, expected(&["OK", "9090cc90", "E01", "E01", "OK"]));
+
+        /*
+         * Detaching should have removed the breakpoint.
+         */
+        assert_eq!(target.memory, [0x90; 16]);
+    }
+
+    #[test]
+    fn test_single_step() {
+        let mut target = TestTarget { memory: [0; 16], register: 0, single_step: false };
+
+        let output = run(&mut target, &["?", "s"]);
+        let mut expected = expected(&["S05"]);
+        expected.push(b'+');
+        assert_eq!(output, expected);
+        assert!(target.single_step);
+    }
+}
@@ -0,0 +1,177 @@
+//! The kernel log keeps the most recent messages logged by the kernel (and by tasks, through `early_log`) as
+//! structured records in a fixed-size ring buffer, so they can be read back from userspace with the
+//! `read_kernel_log` system call. The buffer is allocated statically, so it also holds messages logged before the
+//! heap is set up, which allows a userspace logger to replay the kernel's boot messages like `dmesg`.
+
+use crate::object::KernelObjectId;
+use core::{
+    cmp,
+    fmt::{self, Write},
+};
+use libpebble::syscall::{KernelLogRecord, KERNEL_LOG_MESSAGE_MAX_LENGTH};
+use log::Level;
+use spin::Mutex;
+
+/// The number of records the kernel log can hold. Once it's full, the oldest records are overwritten.
+pub const KERNEL_LOG_NUM_RECORDS: usize = 512;
+
+/// Messages logged by tasks are logged to the kernel's logger with this target. They are added to the kernel log
+/// by the `early_log` system call (so that the task is recorded as their source), so the platform's logger should
+/// not add them again.
+pub const TASK_LOG_TARGET: &str = "task";
+
+static KERNEL_LOG: Mutex<KernelLog> = Mutex::new(KernelLog::new());
+
+/// Add a record to the kernel log. `source` is the ID of the task that logged the message, or
+/// `SENTINEL_KERNEL_ID` if it was logged by the kernel itself. Messages that don't fit in a record are truncated.
+pub fn record(level: Level, source: KernelObjectId, timestamp: u64, cpu: u32, message: fmt::Arguments) {
+    let mut record = KernelLogRecord {
+        sequence: 0,
+        timestamp,
+        source: u64::from(source),
+        cpu,
+        level: level as u16,
+        ..KernelLogRecord::EMPTY
+    };
+
+    /*
+     * We format the message before taking the lock, so that a panic while formatting it can't leave the log
+     * locked (we log from the panic handler too).
+     */
+    let mut writer = MessageWriter { buffer: &mut record.message, length: 0 };
+    let _ = writer.write_fmt(message);
+    record.message_length = writer.length as u16;
+
+    KERNEL_LOG.lock().push(record);
+}
+
+/// Copy records from the kernel log into `buffer`, oldest first, starting with the record with sequence number
+/// `first_sequence` (or the oldest record we have, if it's already been overwritten). Returns the number of
+/// records copied.
+pub fn read(first_sequence: u64, buffer: &mut [KernelLogRecord]) -> usize {
+    KERNEL_LOG.lock().read(first_sequence, buffer)
+}
+
+struct KernelLog {
+    records: [KernelLogRecord; KERNEL_LOG_NUM_RECORDS],
+    /// The sequence number that will be given to the next record. The record with sequence number `n` is stored
+    /// at `records[n % KERNEL_LOG_NUM_RECORDS]`.
+    next_sequence: u64,
+}
+
+impl KernelLog {
+    const fn new() -> KernelLog {
+        KernelLog { records: [KernelLogRecord::EMPTY; KERNEL_LOG_NUM_RECORDS], next_sequence: 0 }
+    }
+
+    fn push(&mut self, mut record: KernelLogRecord) {
+        record.sequence = self.next_sequence;
+        self.records[(self.next_sequence % KERNEL_LOG_NUM_RECORDS as u64) as usize] = record;
+        self.next_sequence += 1;
+    }
+
+    fn read(&self, first_sequence: u64, buffer: &mut [KernelLogRecord]) -> usize {
+        let oldest_sequence = self.next_sequence.saturating_sub(KERNEL_LOG_NUM_RECORDS as u64);
+        let first_sequence = cmp::max(first_sequence, oldest_sequence);
+        let num_records =
+            cmp::min(self.next_sequence.saturating_sub(first_sequence), buffer.len() as u64) as usize;
+
+        for (i, entry) in buffer.iter_mut().take(num_records).enumerate() {
+            *entry = self.records[((first_sequence + i as u64) % KERNEL_LOG_NUM_RECORDS as u64) as usize];
+        }
+
+        num_records
+    }
+}
+
+/// Formats a message into a record's message buffer, silently truncating it (on a character boundary, so it
+/// stays valid UTF-8) if it doesn't fit.
+struct MessageWriter<'a> {
+    buffer: &'a mut [u8; KERNEL_LOG_MESSAGE_MAX_LENGTH],
+    length: usize,
+}
+
+impl<'a> Write for MessageWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let space = KERNEL_LOG_MESSAGE_MAX_LENGTH - self.length;
+        let mut length = cmp::min(s.len(), space);
+        while !s.is_char_boundary(length) {
+            length -= 1;
+        }
+
+        self.buffer[self.length..(self.length + length)].copy_from_slice(&s.as_bytes()[..length]);
+        self.length += length;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::SENTINEL_KERNEL_ID;
+    use alloc::boxed::Box;
+
+    fn make_record(message: fmt::Arguments) -> KernelLogRecord {
+        let mut record = KernelLogRecord::EMPTY;
+        let mut writer = MessageWriter { buffer: &mut record.message, length: 0 };
+        writer.write_fmt(message).unwrap();
+        record.message_length = writer.length as u16;
+        record.source = u64::from(SENTINEL_KERNEL_ID);
+        record
+    }
+
+    #[test]
+    fn test_truncation() {
+        let record = make_record(format_args!("Hello, {}!", "World"));
+        assert_eq!(record.message(), Some("Hello, World!"));
+
+        let long = "a".repeat(KERNEL_LOG_MESSAGE_MAX_LENGTH + 10);
+        let record = make_record(format_args!("{}", long));
+        assert_eq!(record.message(), Some(&long[..KERNEL_LOG_MESSAGE_MAX_LENGTH]));
+
+        /*
+         * `é` is two bytes, so the second half of the one that would straddle the end must be dropped.
+         */
+        let long = format!("{}é", "a".repeat(KERNEL_LOG_MESSAGE_MAX_LENGTH - 1));
+        let record = make_record(format_args!("{}", long));
+        assert_eq!(record.message(), Some(&long[..(KERNEL_LOG_MESSAGE_MAX_LENGTH - 1)]));
+    }
+
+    #[test]
+    fn test_read() {
+        let mut log = Box::new(KernelLog::new());
+        let mut buffer = [KernelLogRecord::EMPTY; 4];
+        assert_eq!(log.read(0, &mut buffer), 0);
+
+        for i in 0..3 {
+            log.push(make_record(format_args!("{}", i)));
+        }
+        assert_eq!(log.read(0, &mut buffer), 3);
+        assert_eq!(buffer[0].sequence, 0);
+        assert_eq!(buffer[2].message(), Some("2"));
+        assert_eq!(log.read(2, &mut buffer), 1);
+        assert_eq!(buffer[0].sequence, 2);
+        assert_eq!(log.read(3, &mut buffer), 0);
+    }
+
+    #[test]
+    fn test_wrap_around() {
+        let mut log = Box::new(KernelLog::new());
+        for i in 0..(KERNEL_LOG_NUM_RECORDS + 10) {
+            log.push(make_record(format_args!("{}", i)));
+        }
+
+        /*
+         * The first 10 records have been overwritten, so reading from the start should give us the oldest record
+         * that's left.
+         */
+        let mut buffer = [KernelLogRecord::EMPTY; 4];
+        assert_eq!(log.read(0, &mut buffer), 4);
+        assert_eq!(buffer[0].sequence, 10);
+        assert_eq!(buffer[0].message(), Some("10"));
+        assert_eq!(buffer[3].sequence, 13);
+
+        assert_eq!(log.read(KERNEL_LOG_NUM_RECORDS as u64 + 8, &mut buffer), 2);
+        assert_eq!(buffer[1].message(), Some("521"));
+    }
+}
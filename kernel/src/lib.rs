@@ -17,7 +17,11 @@
 #[macro_use]
 extern crate alloc;
 
+pub mod backtrace;
+pub mod gdb;
 mod heap_allocator;
+pub mod klog;
+pub mod log_filter;
 pub mod memory;
 pub mod object;
 pub mod pci;
@@ -25,21 +29,36 @@ pub mod per_cpu;
 pub mod scheduler;
 pub mod syscall;
 
-use alloc::{boxed::Box, sync::Arc};
-use core::pin::Pin;
+use alloc::{
+    boxed::Box,
+    sync::{Arc, Weak},
+};
+use core::{mem, pin::Pin, ptr};
 use hal::{
     boot_info::LoadedImage,
-    memory::{FrameSize, PageTable, VirtualAddress},
+    interrupts::InterruptController,
+    memory::{FrameSize, PageTable, PhysicalAddress, VirtualAddress},
+    timer::Timer,
 };
 use heap_allocator::LockedHoleAllocator;
+use libpebble::syscall::{FaultReport, CHANNEL_MAX_NUM_HANDLES};
+use log::warn;
 use memory::{KernelStackAllocator, PhysicalMemoryManager};
-use object::{address_space::AddressSpace, memory_object::MemoryObject, task::Task, KernelObject};
-use pci::PciInfo;
+use object::{
+    address_space::AddressSpace,
+    channel::{ChannelEnd, Message},
+    event::Event,
+    interrupt::InterruptRoutingError,
+    memory_object::MemoryObject,
+    task::Task,
+    KernelObject,
+};
+use pci::{MsiMessage, PciInfo};
 use pci_types::ConfigRegionAccess as PciConfigRegionAccess;
 use pebble_util::InitGuard;
 use per_cpu::PerCpu;
 use scheduler::Scheduler;
-use spin::{Mutex, RwLock};
+use spin::{Mutex, MutexGuard, RwLock};
 
 #[cfg(not(test))]
 #[global_allocator]
@@ -49,13 +68,80 @@ pub static PHYSICAL_MEMORY_MANAGER: InitGuard<PhysicalMemoryManager> = InitGuard
 pub static FRAMEBUFFER: InitGuard<(libpebble::syscall::FramebufferInfo, Arc<MemoryObject>)> = InitGuard::uninit();
 pub static PCI_INFO: RwLock<Option<PciInfo>> = RwLock::new(None);
 pub static PCI_ACCESS: InitGuard<Option<Mutex<Box<dyn PciConfigRegionAccess>>>> = InitGuard::uninit();
+/// The kernel end of the channel that faults caused by userspace tasks are reported down, if a task has subscribed
+/// to them. This is only held weakly, so that it's dropped along with the subscribed task's handles when it exits,
+/// which lets another task subscribe.
+pub static FAULT_SUPERVISOR: Mutex<Option<Weak<ChannelEnd>>> = Mutex::new(None);
 
 pub trait Platform: Sized + 'static {
     type PageTableSize: FrameSize;
     type PageTable: PageTable<Self::PageTableSize> + Send;
     type PerCpu: PerCpu<Self>;
+    type InterruptController: InterruptController;
+    type Timer: Timer;
+
+    /// The end of the part of the address space usable by userspace. Userspace is free to map memory anywhere below
+    /// this address, but the kernel must never map user memory above it.
+    const USER_ADDRESS_SPACE_END: VirtualAddress;
+
+    /// A page of the kernel's address space that isn't used for anything else, which the kernel temporarily maps
+    /// device memory into when it needs to access it (e.g. to program the MSI-X table of a PCI device), as the
+    /// mapping of physical memory is cacheable. The kernel only uses it while holding the lock on `PCI_ACCESS`.
+    const DEVICE_WINDOW: VirtualAddress;
+
+    /// Get the kernel's page tables. These must be accessible outside of initialization, as kernel mappings (such
+    /// as the kernel stacks of tasks) are created and destroyed while the kernel is running.
+    fn kernel_page_table<'a>() -> MutexGuard<'a, Self::PageTable>;
+
+    /// Get the allocator used to allocate and free the kernel stacks of tasks.
+    fn kernel_stack_allocator<'a>() -> &'a KernelStackAllocator<Self>;
+
+    /// Get a virtual address through which the kernel can access the given physical address. Platforms are
+    /// expected to map all of physical memory into the kernel's address space to make this possible.
+    fn physical_to_virtual(address: PhysicalAddress) -> VirtualAddress;
+
+    /// Get the platform's interrupt controller, which the kernel uses to handle the interrupts it needs itself
+    /// (such as the timer's), and to send IPIs.
+    fn interrupt_controller<'a>() -> &'a Self::InterruptController;
+
+    /// Get the timer of the CPU this is called on, which also provides the platform's monotonic clock.
+    fn timer<'a>() -> &'a Self::Timer;
+
+    /// Get a number identifying the CPU this is called on. This is only used to tell CPUs apart (e.g. in the
+    /// kernel log), so it doesn't need to be contiguous with the IDs of the other CPUs.
+    fn cpu_id() -> u32;
+
+    /// Copy `length` bytes from `source` to `destination`, where one of them is in userspace (and has already
+    /// been validated). If accessing the userspace memory faults, the copy is abandoned and `Err(())` is returned,
+    /// instead of the fault being treated as a bug in the kernel.
+    unsafe fn copy_user(destination: *mut u8, source: *const u8, length: usize) -> Result<(), ()>;
+
+    /// Route the interrupt with the given GSI (global system interrupt) to the kernel, so that `INTERRUPT_SIGNAL` is
+    /// set on `event` each time it fires. If the interrupt is level-triggered, it must also be masked when it fires
+    /// (so it doesn't fire again before the device has been serviced), until it is acknowledged with
+    /// `acknowledge_interrupt`. Level-triggered interrupts can be routed to several events, which are all signalled
+    /// when it fires.
+    fn route_interrupt(gsi: u32, event: Arc<Event>) -> Result<(), InterruptRoutingError>;
+
+    /// Unmask an interrupt previously routed with `route_interrupt`, after it has been handled. If the interrupt is
+    /// shared, it is only unmasked once none of the events it was routed to still have `INTERRUPT_SIGNAL` set.
+    fn acknowledge_interrupt(gsi: u32);
 
-    fn kernel_page_table(&mut self) -> &mut Self::PageTable;
+    /// Stop signalling `event` when an interrupt previously routed to it with `route_interrupt` fires. Once no
+    /// events are left, the interrupt is masked, and can then be routed again.
+    fn unroute_interrupt(gsi: u32, event: &Arc<Event>);
+
+    /// Allocate a message that a PCI device can write to raise a message-signalled interrupt, so that
+    /// `INTERRUPT_SIGNAL` is set on `event` each time it's written.
+    fn route_msi(event: Arc<Event>) -> Result<MsiMessage, InterruptRoutingError>;
+
+    /// Free a message allocated with `route_msi`. The device must have stopped using it first.
+    fn unroute_msi(message: MsiMessage);
+
+    /// Idle the CPU until something happens that could allow a blocked task to run (generally, until the next
+    /// interrupt arrives). This is used by the scheduler when there are no tasks to run, and so interrupts must be
+    /// able to fire while idling, even if they're otherwise disabled.
+    fn idle();
 
     /// Get the per-CPU info for the current CPU. To make this safe, the per-CPU info must be installed before the
     /// `Platform` implementation is created.
@@ -66,12 +152,15 @@ pub trait Platform: Sized + 'static {
     ///
     /// `entry_point` is the address that should be jumped to in usermode when the task is run for the first time.
     /// `user_stack_top` is the virtual address that should be put into the stack pointer when the task is entered.
+    /// `task_argument` should be passed to the task as the first argument of its entry point, according to the
+    /// platform's calling convention.
     ///
     /// `kernel_stack_top` is the kernel stack that the new stack frames will be installed in, and must be mapped
     /// and writable when this is called. This method will update it as it puts stuff on the kernel stack.
     unsafe fn initialize_task_kernel_stack(
         kernel_stack_top: &mut VirtualAddress,
         task_entry_point: VirtualAddress,
+        task_argument: usize,
         user_stack_top: &mut VirtualAddress,
     );
 
@@ -80,31 +169,20 @@ pub trait Platform: Sized + 'static {
     unsafe fn context_switch(current_kernel_stack: *mut VirtualAddress, new_kernel_stack: VirtualAddress);
 
     /// Do the actual drop into usermode. This assumes that the task's page tables have already been installed,
-    /// and that an initial frame has been put into the task's kernel stack that this will use to enter userspace.
-    unsafe fn drop_into_userspace() -> !;
+    /// and that `kernel_stack_pointer` points to the initial frame installed by `initialize_task_kernel_stack`,
+    /// which this will use to enter userspace.
+    unsafe fn drop_into_userspace(kernel_stack_pointer: VirtualAddress) -> !;
 }
 
-pub fn load_task<P>(
-    scheduler: &mut Scheduler<P>,
-    image: &LoadedImage,
-    kernel_page_table: &mut P::PageTable,
-    allocator: &PhysicalMemoryManager,
-    kernel_stack_allocator: &mut KernelStackAllocator<P>,
-) where
+pub fn load_task<P>(scheduler: &mut Scheduler<P>, image: &LoadedImage, allocator: &PhysicalMemoryManager)
+where
     P: Platform,
 {
     use object::SENTINEL_KERNEL_ID;
 
-    let address_space = AddressSpace::new(SENTINEL_KERNEL_ID, kernel_page_table, allocator);
-    let task = Task::from_boot_info(
-        SENTINEL_KERNEL_ID,
-        address_space.clone(),
-        image,
-        allocator,
-        kernel_page_table,
-        kernel_stack_allocator,
-    )
-    .expect("Failed to load initial task");
+    let address_space = AddressSpace::new(SENTINEL_KERNEL_ID, &P::kernel_page_table(), allocator);
+    let task = Task::from_boot_info(SENTINEL_KERNEL_ID, address_space.clone(), image, allocator)
+        .expect("Failed to load initial task");
 
     for segment in image.segments() {
         let memory_object = MemoryObject::from_boot_info(task.id(), segment);
@@ -114,10 +192,45 @@ pub fn load_task<P>(
     scheduler.add_task(task);
 }
 
+/// Handle a fault caused by the running task while it was in userspace. The task is killed, and the fault is
+/// reported to the task subscribed to faults, if there is one. This should be called by the platform's exception
+/// handlers when they find that an exception was caused by userspace.
+pub fn handle_user_fault<P>(report: FaultReport) -> !
+where
+    P: Platform,
+{
+    /*
+     * We can't hold a reference to the task once we call `exit_running_task`, as we never return to drop it, so we
+     * make sure it's dropped (or moved into the report message) before then.
+     */
+    {
+        let task = P::per_cpu().scheduler().running_task.clone().expect("Fault with no running task");
+        warn!("Task {} faulted, and will be killed: {:x?}", task.name, report);
+
+        if let Some(supervisor) = FAULT_SUPERVISOR.lock().as_ref().and_then(Weak::upgrade) {
+            /*
+             * The message contains the report, followed by the name of the task, and transfers a handle to the
+             * task itself.
+             */
+            let mut bytes = vec![0; mem::size_of::<FaultReport>()];
+            unsafe {
+                ptr::write_unaligned(bytes.as_mut_ptr() as *mut FaultReport, report);
+            }
+            bytes.extend_from_slice(task.name.as_bytes());
+
+            let mut handle_objects = [None; CHANNEL_MAX_NUM_HANDLES];
+            handle_objects[0] = Some(task as Arc<dyn KernelObject>);
+            supervisor.add_message(Message { bytes, handle_objects });
+        }
+    }
+
+    P::per_cpu().scheduler().exit_running_task()
+}
+
 pub fn create_framebuffer(video_info: &hal::boot_info::VideoModeInfo) {
     use hal::{
         boot_info::PixelFormat as BootPixelFormat,
-        memory::{Flags, Size4KiB},
+        memory::{CacheType, Flags, Size4KiB},
     };
     use libpebble::syscall::{FramebufferInfo, PixelFormat};
 
@@ -133,7 +246,12 @@ pub fn create_framebuffer(video_info: &hal::boot_info::VideoModeInfo) {
         VIRTUAL_START,
         video_info.framebuffer_address,
         pebble_util::math::align_up(size_in_bytes, Size4KiB::SIZE),
-        Flags { writable: true, user_accessible: true, cached: false, ..Default::default() },
+        Flags {
+            writable: true,
+            user_accessible: true,
+            cache_type: CacheType::WriteCombining,
+            ..Default::default()
+        },
     );
 
     let info = FramebufferInfo {
@@ -0,0 +1,132 @@
+//! This module decides which log messages are enabled, using a filter passed to the loader on its command line.
+//! A filter is a comma-separated list of directives, each of which is either a level (e.g. `info`), which sets the
+//! default level, or a module path and a level (e.g. `kernel::scheduler=warn`), which sets the level of messages
+//! logged from that module and any modules inside it. If more than one module directive matches a message, the
+//! most specific one is used. The default level is `trace`, if a filter doesn't set it.
+//!
+//! Filters are also applied to tasks that log with `early_log` - the task's name is matched against the module
+//! directives, so `echo=warn` sets the level of the `echo` task.
+
+use core::str::FromStr;
+use hal::boot_info::{LogFilterString, MAX_LOG_FILTER_LENGTH};
+use log::{warn, LevelFilter};
+use pebble_util::InitGuard;
+
+pub static LOG_FILTER: InitGuard<LogFilter> = InitGuard::uninit();
+
+/// Each directive takes up at least two bytes of the filter (one for itself, and one for the comma after it), so
+/// this is the most a filter can have.
+const MAX_DIRECTIVES: usize = (MAX_LOG_FILTER_LENGTH + 1) / 2;
+
+/// Install the log filter passed by the loader. Until this is called, all messages are enabled.
+///
+/// The `log` crate's maximum level is left at `Trace`, and messages are only filtered by the logger (using
+/// `level_for`), because messages logged by tasks are logged at their own levels, with a target that isn't
+/// matched against the filter.
+pub fn init(string: LogFilterString) {
+    LOG_FILTER.initialize(LogFilter::new(string));
+}
+
+/// Get the most verbose level enabled for messages with the given target. This can be used before the filter is
+/// installed, in which case everything is enabled.
+pub fn level_for(target: &str) -> LevelFilter {
+    LOG_FILTER.try_get().map_or(LevelFilter::Trace, |filter| filter.level_for(target))
+}
+
+pub struct LogFilter {
+    string: LogFilterString,
+    /// The level set by the last directive without a module, or `Trace` if there isn't one.
+    default_level: LevelFilter,
+    /// The directives that set the level of a module, as the start and end of the module's path in `string`, and
+    /// the level.
+    module_directives: [(usize, usize, LevelFilter); MAX_DIRECTIVES],
+    num_module_directives: usize,
+}
+
+impl LogFilter {
+    /// Parse a filter. Invalid directives are ignored.
+    pub fn new(string: LogFilterString) -> LogFilter {
+        let mut filter = LogFilter {
+            string,
+            default_level: LevelFilter::Trace,
+            module_directives: [(0, 0, LevelFilter::Off); MAX_DIRECTIVES],
+            num_module_directives: 0,
+        };
+
+        let mut start = 0;
+        for directive in string.as_str().split(',') {
+            match parse_directive(directive) {
+                Some((Some(module), level)) => {
+                    filter.module_directives[filter.num_module_directives] = (start, start + module.len(), level);
+                    filter.num_module_directives += 1;
+                }
+                Some((None, level)) => filter.default_level = level,
+                None if directive.is_empty() => (),
+                None => warn!("Ignoring invalid log filter directive: '{}'", directive),
+            }
+            start += directive.len() + 1;
+        }
+
+        filter
+    }
+
+    /// Iterate over the directives that set the level of a module, as `(module, level)` pairs.
+    fn module_directives(&self) -> impl Iterator<Item = (&str, LevelFilter)> {
+        let string = self.string.as_str();
+        self.module_directives[0..self.num_module_directives]
+            .iter()
+            .map(move |&(start, end, level)| (&string[start..end], level))
+    }
+
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.module_directives()
+            .filter(|(module, _)| {
+                target == *module || (target.starts_with(module) && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default_level, |(_, level)| level)
+    }
+}
+
+fn parse_directive(directive: &str) -> Option<(Option<&str>, LevelFilter)> {
+    match directive.find('=') {
+        Some(index) => {
+            let module = &directive[0..index];
+            if module.is_empty() {
+                return None;
+            }
+            Some((Some(module), LevelFilter::from_str(&directive[(index + 1)..]).ok()?))
+        }
+        None => Some((None, LevelFilter::from_str(directive).ok()?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(string: &str) -> LogFilter {
+        LogFilter::new(LogFilterString::new(string).unwrap())
+    }
+
+    #[test]
+    fn test_level_for() {
+        let empty = filter("");
+        assert_eq!(empty.level_for("kernel::scheduler"), LevelFilter::Trace);
+
+        let filter = filter("info,kernel::scheduler=warn,kernel::scheduler::idle=off,echo=debug");
+        assert_eq!(filter.level_for("kernel"), LevelFilter::Info);
+        assert_eq!(filter.level_for("kernel::scheduler"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("kernel::scheduler::task"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("kernel::scheduler::idle"), LevelFilter::Off);
+        assert_eq!(filter.level_for("kernel::schedulers"), LevelFilter::Info);
+        assert_eq!(filter.level_for("echo"), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn test_invalid_directives() {
+        let filter = filter("warn,,kernel=verbose,=info,kernel::pci=ERROR");
+        assert_eq!(filter.level_for("kernel"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("kernel::pci"), LevelFilter::Error);
+    }
+}
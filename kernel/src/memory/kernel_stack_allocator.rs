@@ -1,4 +1,4 @@
-use super::{PhysicalMemoryManager, SlabAllocator, Stack};
+use super::{PhysicalMemoryManager, SlabAllocator, Stack, StackAllocationError};
 use crate::Platform;
 use core::marker::PhantomData;
 use hal::memory::VirtualAddress;
@@ -34,14 +34,20 @@ where
         initial_size: usize,
         physical_memory_manager: &PhysicalMemoryManager,
         kernel_page_table: &mut P::PageTable,
-    ) -> Option<Stack> {
+    ) -> Result<Stack, StackAllocationError> {
         use hal::memory::{Flags, PageTable};
 
-        let slot_bottom = self.kernel_stack_slots.lock().alloc()?;
+        let slot_bottom = self.kernel_stack_slots.lock().alloc().ok_or(StackAllocationError::NoSlots)?;
         let top = slot_bottom + self.slot_size - 1;
         let stack_bottom = top - initial_size + 1;
 
-        let physical_start = physical_memory_manager.alloc_bytes(initial_size);
+        let physical_start = match physical_memory_manager.try_alloc_bytes(initial_size) {
+            Some(physical_start) => physical_start,
+            None => {
+                self.kernel_stack_slots.lock().free(slot_bottom);
+                return Err(StackAllocationError::OutOfMemory);
+            }
+        };
         kernel_page_table
             .map_area(
                 stack_bottom,
@@ -52,6 +58,18 @@ where
             )
             .unwrap();
 
-        Some(Stack { top, slot_bottom, stack_bottom })
+        Ok(Stack { top, slot_bottom, stack_bottom })
+    }
+
+    /// Free a kernel stack allocated by `alloc_kernel_stack`. This unmaps the stack, frees the memory backing it,
+    /// and returns its slot to the allocator.
+    pub fn free_kernel_stack(
+        &self,
+        stack: Stack,
+        physical_memory_manager: &PhysicalMemoryManager,
+        kernel_page_table: &mut P::PageTable,
+    ) {
+        stack.unmap::<P>(kernel_page_table, physical_memory_manager);
+        self.kernel_stack_slots.lock().free(stack.slot_bottom);
     }
 }
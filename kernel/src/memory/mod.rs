@@ -5,10 +5,11 @@ mod slab_allocator;
 pub use kernel_stack_allocator::KernelStackAllocator;
 pub use slab_allocator::SlabAllocator;
 
+use crate::Platform;
 use buddy_allocator::BuddyAllocator;
 use core::ops::Range;
 use hal::{
-    boot_info::BootInfo,
+    boot_info::MemoryMap,
     memory::{Frame, FrameAllocator, FrameSize, PhysicalAddress, VirtualAddress},
 };
 use spin::Mutex;
@@ -18,10 +19,11 @@ pub struct PhysicalMemoryManager {
 }
 
 impl PhysicalMemoryManager {
-    pub fn new(boot_info: &BootInfo) -> PhysicalMemoryManager {
+    /// Create a `PhysicalMemoryManager` that manages the conventional memory in `memory_map`.
+    pub fn new(memory_map: &MemoryMap) -> PhysicalMemoryManager {
         let mut buddy_allocator = BuddyAllocator::new();
 
-        for entry in boot_info.memory_map.entries() {
+        for entry in memory_map.entries() {
             if entry.memory_type == hal::boot_info::MemoryType::Conventional {
                 buddy_allocator.add_range(entry.frame_range());
             }
@@ -37,6 +39,17 @@ impl PhysicalMemoryManager {
          */
         self.buddy.lock().allocate_n(num_bytes).expect("Failed to allocate physical memory!")
     }
+
+    /// Like `alloc_bytes`, but returns `None` if the allocation can't be satisfied, rather than panicking. This
+    /// should be used when the size of the allocation is controlled by userspace.
+    pub fn try_alloc_bytes(&self, num_bytes: usize) -> Option<PhysicalAddress> {
+        self.buddy.lock().allocate_n(num_bytes)
+    }
+
+    /// Free `num_bytes` of physical memory, starting at `start`, that was previously allocated with `alloc_bytes`.
+    pub fn free_bytes(&self, start: PhysicalAddress, num_bytes: usize) {
+        self.buddy.lock().free_n(start, num_bytes);
+    }
 }
 
 impl<S> FrameAllocator<S> for PhysicalMemoryManager
@@ -53,6 +66,15 @@ where
     }
 }
 
+/// The ways that allocating a `Stack` can fail.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StackAllocationError {
+    /// There are no more free slots to allocate the stack in.
+    NoSlots,
+    /// There isn't enough physical memory to back the stack.
+    OutOfMemory,
+}
+
 /// Represents a stack, either in kernel-space or user-space. Stacks are allocated in "slots" of fixed size, but
 /// only a subset of the slot may be mapped initially (to reduce physical memory usage). Stacks can't grow above
 /// the size of their slot.
@@ -62,3 +84,23 @@ pub struct Stack {
     pub slot_bottom: VirtualAddress,
     pub stack_bottom: VirtualAddress,
 }
+
+impl Stack {
+    /// Unmap the mapped part of this stack from `page_table`, and free the physical memory backing it. This assumes
+    /// that the stack is backed by a single allocation from `allocator`, which is true of stacks allocated by both
+    /// `KernelStackAllocator` and `AddressSpace`.
+    pub fn unmap<P>(&self, page_table: &mut P::PageTable, allocator: &PhysicalMemoryManager)
+    where
+        P: Platform,
+    {
+        use hal::memory::{Page, PageTable};
+
+        let mut physical_start = None;
+        for page in Page::<P::PageTableSize>::starts_with(self.stack_bottom)..Page::starts_with(self.top + 1) {
+            let frame = page_table.unmap(page, allocator).expect("Tried to unmap stack that isn't mapped");
+            physical_start.get_or_insert(frame.start);
+        }
+
+        allocator.free_bytes(physical_start.unwrap(), usize::from(self.top) + 1 - usize::from(self.stack_bottom));
+    }
+}
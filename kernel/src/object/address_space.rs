@@ -1,11 +1,11 @@
 use super::{alloc_kernel_object_id, memory_object::MemoryObject, KernelObject, KernelObjectId};
 use crate::{
-    memory::{PhysicalMemoryManager, SlabAllocator, Stack},
+    memory::{PhysicalMemoryManager, SlabAllocator, Stack, StackAllocationError},
     Platform,
 };
 use alloc::{sync::Arc, vec::Vec};
-use hal::memory::{mebibytes, Bytes, FrameAllocator, PageTable, VirtualAddress};
-use libpebble::syscall::MapMemoryObjectError;
+use hal::memory::{mebibytes, Bytes, Flags, FrameAllocator, PageTable, VirtualAddress};
+use libpebble::syscall::{MapMemoryObjectError, ProtectMemoryObjectError};
 use spin::Mutex;
 
 // TODO: we need some way of getting this from the platform I guess?
@@ -17,7 +17,17 @@ const USER_STACK_SLOT_SIZE: Bytes = mebibytes(2);
 #[derive(PartialEq, Eq, Debug)]
 pub enum State {
     NotActive,
-    Active,
+    /// The `AddressSpace` is active on the given number of CPUs. As the threads of a task share an `AddressSpace`,
+    /// it can be active on more than one CPU at the same time.
+    Active(usize),
+}
+
+/// A `MemoryObject` that's mapped into an `AddressSpace`.
+pub struct Mapping {
+    pub memory_object: Arc<MemoryObject>,
+    /// The flags the memory is currently mapped with. These start as the `MemoryObject`'s flags, but can be
+    /// changed with `AddressSpace::protect_memory_object`.
+    pub flags: Flags,
 }
 
 pub struct AddressSpace<P>
@@ -27,7 +37,7 @@ where
     pub id: KernelObjectId,
     pub owner: KernelObjectId,
     pub state: Mutex<State>,
-    pub memory_objects: Mutex<Vec<Arc<MemoryObject>>>,
+    pub mappings: Mutex<Vec<Mapping>>,
     page_table: Mutex<P::PageTable>,
     user_stack_allocator: Mutex<SlabAllocator>,
 }
@@ -44,7 +54,7 @@ where
             id: alloc_kernel_object_id(),
             owner,
             state: Mutex::new(State::NotActive),
-            memory_objects: Mutex::new(vec![]),
+            mappings: Mutex::new(vec![]),
             page_table: Mutex::new(P::PageTable::new_with_kernel_mapped(kernel_page_table, allocator)),
             user_stack_allocator: Mutex::new(SlabAllocator::new(
                 USER_STACK_BOTTOM,
@@ -74,20 +84,59 @@ where
                 // XXX: these are explicity enumerated to avoid a bug if variants are added to `PagingError`.
                 PagingError::AlreadyMapped => MapMemoryObjectError::RegionAlreadyMapped,
             })?;
-        self.memory_objects.lock().push(memory_object);
+        let flags = memory_object.flags;
+        self.mappings.lock().push(Mapping { memory_object, flags });
+        Ok(())
+    }
+
+    /// Change the flags that a `MemoryObject` that's mapped into this address space is mapped with. The memory can
+    /// only be made writable or executable if the `MemoryObject` itself is.
+    pub fn protect_memory_object(
+        &self,
+        memory_object: &Arc<MemoryObject>,
+        writable: bool,
+        executable: bool,
+        allocator: &PhysicalMemoryManager,
+    ) -> Result<(), ProtectMemoryObjectError> {
+        if (writable && !memory_object.flags.writable) || (executable && !memory_object.flags.executable) {
+            return Err(ProtectMemoryObjectError::ExceedsRights);
+        }
+
+        let mut mappings = self.mappings.lock();
+        let mapping = mappings
+            .iter_mut()
+            .find(|mapping| Arc::ptr_eq(&mapping.memory_object, memory_object))
+            .ok_or(ProtectMemoryObjectError::NotMapped)?;
+
+        mapping.flags = Flags { writable, executable, ..memory_object.flags };
+        self.page_table.lock().protect(
+            memory_object.virtual_address..(memory_object.virtual_address + memory_object.size),
+            mapping.flags,
+            allocator,
+        );
         Ok(())
     }
 
-    /// Try to allocate a slot for a user stack, and map `initial_size` bytes of it. Returns `None` if no more user
-    /// stacks can be allocated in this address space.
-    pub fn alloc_user_stack(&self, initial_size: usize, allocator: &PhysicalMemoryManager) -> Option<Stack> {
+    /// Try to allocate a slot for a user stack, and map `initial_size` bytes of it. Fails if no more user stacks
+    /// can be allocated in this address space, or if there isn't enough memory to back the stack.
+    pub fn alloc_user_stack(
+        &self,
+        initial_size: usize,
+        allocator: &PhysicalMemoryManager,
+    ) -> Result<Stack, StackAllocationError> {
         use hal::memory::Flags;
 
-        let slot_bottom = self.user_stack_allocator.lock().alloc()?;
+        let slot_bottom = self.user_stack_allocator.lock().alloc().ok_or(StackAllocationError::NoSlots)?;
         let top = slot_bottom + USER_STACK_SLOT_SIZE - 1;
         let stack_bottom = top - initial_size + 1;
 
-        let physical_start = allocator.alloc_bytes(initial_size);
+        let physical_start = match allocator.try_alloc_bytes(initial_size) {
+            Some(physical_start) => physical_start,
+            None => {
+                self.user_stack_allocator.lock().free(slot_bottom);
+                return Err(StackAllocationError::OutOfMemory);
+            }
+        };
         self.page_table
             .lock()
             .map_area(
@@ -99,18 +148,99 @@ where
             )
             .unwrap();
 
-        Some(Stack { top, slot_bottom, stack_bottom })
+        Ok(Stack { top, slot_bottom, stack_bottom })
+    }
+
+    /// Free a user stack allocated by `alloc_user_stack`, unmapping it and returning its slot to this address
+    /// space.
+    pub fn free_user_stack(&self, stack: Stack, allocator: &PhysicalMemoryManager) {
+        stack.unmap::<P>(&mut self.page_table.lock(), allocator);
+        self.user_stack_allocator.lock().free(stack.slot_bottom);
+    }
+
+    /// Check that the `length` bytes starting at `address` are all mapped into this address space, and are
+    /// accessible from userspace (and writable, if `write` is set). Userspace memory is either part of a mapped
+    /// `MemoryObject`, which is mapped with the flags of its `Mapping`, or part of a user stack, which is always
+    /// writable.
+    ///
+    /// This only checks the mappings - the caller must make sure the range lies within userspace first.
+    pub fn is_user_accessible(&self, address: VirtualAddress, length: usize, write: bool) -> bool {
+        use hal::memory::FrameSize;
+
+        let end = match address.checked_add(length) {
+            Some(end) => end,
+            None => return false,
+        };
+        let mappings = self.mappings.lock();
+
+        let mut current = address;
+        while current < end {
+            if let Some(mapping) = mappings.iter().find(|mapping| {
+                current >= mapping.memory_object.virtual_address
+                    && current < mapping.memory_object.virtual_address + mapping.memory_object.size
+            }) {
+                if !mapping.flags.user_accessible || (write && !mapping.flags.writable) {
+                    return false;
+                }
+                current = mapping.memory_object.virtual_address + mapping.memory_object.size;
+            } else if current >= USER_STACK_BOTTOM
+                && current <= USER_STACK_TOP
+                && self.page_table.lock().translate(current).is_some()
+            {
+                current = current.align_down(P::PageTableSize::SIZE) + P::PageTableSize::SIZE;
+            } else {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Call `f` with each run of memory that's mapped into the userspace part of this address space, as found by
+    /// walking its page tables. The kernel's mappings, which are in every address space, are skipped.
+    pub fn walk_user_mappings<F>(&self, mut f: F)
+    where
+        F: FnMut(hal::memory::Mapping),
+    {
+        self.page_table.lock().walk(|mapping| {
+            if mapping.virtual_start < P::USER_ADDRESS_SPACE_END {
+                f(mapping);
+            }
+        });
     }
 
     pub fn switch_to(&self) {
-        assert_eq!(*self.state.lock(), State::NotActive);
+        let mut state = self.state.lock();
         self.page_table.lock().switch_to();
-        *self.state.lock() = State::Active;
+        *state = match *state {
+            State::NotActive => State::Active(1),
+            State::Active(num_cpus) => State::Active(num_cpus + 1),
+        };
     }
 
     pub fn switch_from(&self) {
-        assert_eq!(*self.state.lock(), State::Active);
-        *self.state.lock() = State::NotActive;
+        let mut state = self.state.lock();
+        *state = match *state {
+            State::NotActive => panic!("Tried to switch away from an AddressSpace that isn't active!"),
+            State::Active(1) => State::NotActive,
+            State::Active(num_cpus) => State::Active(num_cpus - 1),
+        };
+    }
+}
+
+/*
+ * An `AddressSpace` is only dropped when no tasks hold onto it, and so it can't be active on any CPU. The user stacks
+ * have already been freed by the tasks that used them, so we free the page tables and then release the mappings,
+ * which frees the memory of any `MemoryObject`s that aren't mapped anywhere else.
+ */
+impl<P> Drop for AddressSpace<P>
+where
+    P: Platform,
+{
+    fn drop(&mut self) {
+        assert_eq!(*self.state.get_mut(), State::NotActive);
+        self.page_table.get_mut().free_tables(crate::PHYSICAL_MEMORY_MANAGER.get());
+        self.mappings.get_mut().clear();
     }
 }
 
@@ -4,6 +4,7 @@ use alloc::{
     sync::{Arc, Weak},
     vec::Vec,
 };
+use core::fmt;
 use libpebble::syscall::{GetMessageError, SendMessageError, CHANNEL_MAX_NUM_HANDLES};
 use log::warn;
 use spin::Mutex;
@@ -72,6 +73,11 @@ impl ChannelEnd {
         }
     }
 
+    /// Whether there are any messages waiting to be received from this `ChannelEnd`.
+    pub fn has_messages(&self) -> bool {
+        !self.messages.lock().is_empty()
+    }
+
     /// Try to "receive" a message from this `ChannelEnd`, potentially removing it from the queue. Note that this
     /// keeps a lock over the message queue while the passed function is called - if the handling of the message
     /// fails (for example, the buffer to put it into is too small), the passed function can return it with
@@ -98,6 +104,24 @@ impl KernelObject for ChannelEnd {
     }
 }
 
+/*
+ * `ChannelEnd`s are compared and printed using their IDs, so types that refer to them (such as `TaskBlock`) can
+ * derive these traits.
+ */
+impl PartialEq for ChannelEnd {
+    fn eq(&self, other: &ChannelEnd) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for ChannelEnd {}
+
+impl fmt::Debug for ChannelEnd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChannelEnd").field("id", &self.id).finish()
+    }
+}
+
 pub struct Message {
     pub bytes: Vec<u8>,
     /// The actual objects extracted from the handles transferred by a message. When a task receives this message,
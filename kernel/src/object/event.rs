@@ -0,0 +1,56 @@
+use super::{alloc_kernel_object_id, KernelObject, KernelObjectId};
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// An `Event` is a lightweight way of signalling that something has happened. Each `Event` has 32 signal bits,
+/// which can be set and cleared independently, and tasks can wait for any of a set of them to be signalled. This
+/// is much lighter than sending a message down a `Channel` when no data needs to be transferred.
+pub struct Event {
+    pub id: KernelObjectId,
+    pub owner: KernelObjectId,
+    signals: AtomicU32,
+}
+
+impl Event {
+    pub fn new(owner: KernelObjectId) -> Arc<Event> {
+        Arc::new(Event { id: alloc_kernel_object_id(), owner, signals: AtomicU32::new(0) })
+    }
+
+    /// Set the given signal bits. Bits that are already signalled are unaffected.
+    pub fn signal(&self, bits: u32) {
+        self.signals.fetch_or(bits, Ordering::SeqCst);
+    }
+
+    /// Clear the given signal bits.
+    pub fn clear(&self, bits: u32) {
+        self.signals.fetch_and(!bits, Ordering::SeqCst);
+    }
+
+    /// Get the signal bits that are currently set.
+    pub fn signals(&self) -> u32 {
+        self.signals.load(Ordering::SeqCst)
+    }
+}
+
+impl KernelObject for Event {
+    fn id(&self) -> KernelObjectId {
+        self.id
+    }
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Event) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Event {}
+
+impl fmt::Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Event").field("id", &self.id).field("signals", &self.signals()).finish()
+    }
+}
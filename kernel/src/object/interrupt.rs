@@ -0,0 +1,152 @@
+use super::{alloc_kernel_object_id, event::Event, KernelObject, KernelObjectId};
+use crate::{
+    pci::{self, MsiError, MsiMessage},
+    Platform,
+};
+use alloc::sync::Arc;
+use core::{fmt, marker::PhantomData};
+use pci_types::PciAddress;
+
+/// The signal bit of an `Interrupt`'s `Event` that is set when the interrupt fires.
+pub const INTERRUPT_SIGNAL: u32 = 1 << 0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterruptRoutingError {
+    /// The platform doesn't have an interrupt line with the given GSI.
+    InvalidGsi,
+    /// The interrupt has already been routed to another `Interrupt`, and can't be shared because it's
+    /// edge-triggered.
+    AlreadyRouted,
+    /// The platform has run out of vectors to deliver interrupts through.
+    NoFreeVectors,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PciInterruptError {
+    PlatformDoesNotSupportPci,
+    /// There is no PCI function at the given address.
+    InvalidDevice,
+    Msi(MsiError),
+    Routing(InterruptRoutingError),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterruptSource {
+    /// A platform interrupt line, identified by its GSI (global system interrupt).
+    Gsi(u32),
+    /// A message-signalled interrupt, raised by the PCI function at `address` using its MSI or MSI-X capability.
+    /// `index` is the index of the MSI-X table entry used.
+    Msi { address: PciAddress, index: u16, message: MsiMessage },
+}
+
+/// An `Interrupt` allows a task to handle a hardware interrupt, which is how userspace device drivers are notified
+/// by their devices. When the interrupt fires, the kernel masks it (if needed), and sets `INTERRUPT_SIGNAL` on the
+/// `Interrupt`'s `Event`, waking any tasks waiting on it. Once the driver has serviced the device, it acknowledges
+/// the interrupt, which clears the signal and unmasks it. Level-triggered interrupts can be shared by several
+/// `Interrupt`s, in which case the interrupt is only unmasked once all of them have acknowledged it.
+///
+/// The interrupt is unrouted when the `Interrupt` is dropped.
+pub struct Interrupt<P>
+where
+    P: Platform,
+{
+    pub id: KernelObjectId,
+    pub owner: KernelObjectId,
+    pub source: InterruptSource,
+    pub event: Arc<Event>,
+    _phantom: PhantomData<P>,
+}
+
+impl<P> Interrupt<P>
+where
+    P: Platform,
+{
+    pub fn from_gsi(owner: KernelObjectId, gsi: u32) -> Result<Arc<Interrupt<P>>, InterruptRoutingError> {
+        let event = Event::new(owner);
+        P::route_interrupt(gsi, event.clone())?;
+
+        Ok(Interrupt::new(owner, InterruptSource::Gsi(gsi), event))
+    }
+
+    /// Create an `Interrupt` that is raised by the PCI function at `address` using MSI or MSI-X. The kernel
+    /// allocates a message from the platform, and programs the function's capability to use it. For MSI-X, `index`
+    /// selects the entry of the function's MSI-X table to use.
+    pub fn from_pci_device(
+        owner: KernelObjectId,
+        address: PciAddress,
+        index: u16,
+    ) -> Result<Arc<Interrupt<P>>, PciInterruptError> {
+        let pci_info = crate::PCI_INFO.read();
+        let device = pci_info
+            .as_ref()
+            .ok_or(PciInterruptError::PlatformDoesNotSupportPci)?
+            .devices
+            .get(&address)
+            .ok_or(PciInterruptError::InvalidDevice)?;
+        let access = crate::PCI_ACCESS.get().as_ref().ok_or(PciInterruptError::PlatformDoesNotSupportPci)?.lock();
+
+        let event = Event::new(owner);
+        let message = P::route_msi(event.clone()).map_err(PciInterruptError::Routing)?;
+
+        if let Err(err) = pci::enable_msi::<P, _>(&**access, address, &device.capabilities, index, message) {
+            P::unroute_msi(message);
+            return Err(PciInterruptError::Msi(err));
+        }
+
+        Ok(Interrupt::new(owner, InterruptSource::Msi { address, index, message }, event))
+    }
+
+    fn new(owner: KernelObjectId, source: InterruptSource, event: Arc<Event>) -> Arc<Interrupt<P>> {
+        Arc::new(Interrupt { id: alloc_kernel_object_id(), owner, source, event, _phantom: PhantomData })
+    }
+
+    /// Acknowledge that the interrupt has been handled. This clears the signal, and unmasks the interrupt so it can
+    /// fire again.
+    pub fn acknowledge(&self) {
+        self.event.clear(INTERRUPT_SIGNAL);
+
+        /*
+         * Message-signalled interrupts are edge-triggered, and so are never masked when they fire.
+         */
+        if let InterruptSource::Gsi(gsi) = self.source {
+            P::acknowledge_interrupt(gsi);
+        }
+    }
+}
+
+impl<P> KernelObject for Interrupt<P>
+where
+    P: Platform,
+{
+    fn id(&self) -> KernelObjectId {
+        self.id
+    }
+}
+
+impl<P> Drop for Interrupt<P>
+where
+    P: Platform,
+{
+    fn drop(&mut self) {
+        match self.source {
+            InterruptSource::Gsi(gsi) => P::unroute_interrupt(gsi, &self.event),
+            InterruptSource::Msi { address, index, message } => {
+                let pci_info = crate::PCI_INFO.read();
+                let device = pci_info.as_ref().unwrap().devices.get(&address).unwrap();
+                let access = crate::PCI_ACCESS.get().as_ref().unwrap().lock();
+
+                pci::disable_msi::<P, _>(&**access, address, &device.capabilities, index);
+                P::unroute_msi(message);
+            }
+        }
+    }
+}
+
+impl<P> fmt::Debug for Interrupt<P>
+where
+    P: Platform,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Interrupt").field("id", &self.id).field("source", &self.source).finish()
+    }
+}
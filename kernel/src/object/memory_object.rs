@@ -2,8 +2,9 @@ use super::{alloc_kernel_object_id, KernelObject, KernelObjectId};
 use alloc::sync::Arc;
 use hal::{
     boot_info::Segment,
-    memory::{Flags, PhysicalAddress, VirtualAddress},
+    memory::{Flags, FrameSize, PhysicalAddress, Size4KiB, VirtualAddress},
 };
+use pebble_util::math::align_up;
 
 pub struct MemoryObject {
     pub id: KernelObjectId,
@@ -13,9 +14,14 @@ pub struct MemoryObject {
     /// Size of this MemoryObject in bytes.
     pub size: usize,
     pub flags: Flags,
+    /// Whether the physical memory backing this `MemoryObject` was allocated for it by `MemoryObject::allocate`,
+    /// in which case it's freed when the `MemoryObject` is dropped.
+    owns_memory: bool,
 }
 
 impl MemoryObject {
+    /// Create a `MemoryObject` that refers to memory it doesn't own, such as device memory. The memory isn't freed
+    /// when the `MemoryObject` is dropped.
     pub fn new(
         owner: KernelObjectId,
         virtual_address: VirtualAddress,
@@ -30,9 +36,36 @@ impl MemoryObject {
             physical_address,
             size,
             flags,
+            owns_memory: false,
         })
     }
 
+    /// Create a `MemoryObject` backed by newly-allocated physical memory, which is freed when the `MemoryObject`
+    /// is dropped. Returns `None` if `size` is zero, or if the memory can't be allocated. The memory isn't zeroed.
+    pub fn allocate(
+        owner: KernelObjectId,
+        virtual_address: VirtualAddress,
+        size: usize,
+        flags: Flags,
+    ) -> Option<Arc<MemoryObject>> {
+        if size == 0 {
+            return None;
+        }
+
+        let physical_address = crate::PHYSICAL_MEMORY_MANAGER.get().try_alloc_bytes(allocation_size(size))?;
+        Some(Arc::new(MemoryObject {
+            id: alloc_kernel_object_id(),
+            owner,
+            virtual_address,
+            physical_address,
+            size,
+            flags,
+            owns_memory: true,
+        }))
+    }
+
+    /// Create a `MemoryObject` for a segment of an image loaded by the bootloader. The bootloader allocates this
+    /// memory itself, so it's not owned by the `MemoryObject`.
     pub fn from_boot_info(owner: KernelObjectId, segment: &Segment) -> Arc<MemoryObject> {
         Arc::new(MemoryObject {
             id: alloc_kernel_object_id(),
@@ -41,12 +74,31 @@ impl MemoryObject {
             physical_address: segment.physical_address,
             size: segment.size,
             flags: segment.flags,
+            owns_memory: false,
         })
     }
 }
 
+/*
+ * Memory objects that don't own their memory, like the framebuffer, refer to memory that isn't managed by the
+ * physical memory manager, so we must leave it alone.
+ */
+impl Drop for MemoryObject {
+    fn drop(&mut self) {
+        if self.owns_memory {
+            crate::PHYSICAL_MEMORY_MANAGER.get().free_bytes(self.physical_address, allocation_size(self.size));
+        }
+    }
+}
+
 impl KernelObject for MemoryObject {
     fn id(&self) -> KernelObjectId {
         self.id
     }
 }
+
+/// The amount of physical memory `MemoryObject::allocate` allocates for a `MemoryObject` of `size` bytes. The
+/// physical memory manager can only allocate and free power-of-two numbers of frames.
+fn allocation_size(size: usize) -> usize {
+    align_up(size, Size4KiB::SIZE).next_power_of_two()
+}
@@ -1,5 +1,7 @@
 pub mod address_space;
 pub mod channel;
+pub mod event;
+pub mod interrupt;
 pub mod memory_object;
 pub mod task;
 
@@ -15,6 +17,12 @@ pub struct KernelObjectId(u64);
 /// used to mark things like the `owner` of a kernel object being the kernel itself.
 pub const SENTINEL_KERNEL_ID: KernelObjectId = KernelObjectId(0);
 
+impl From<KernelObjectId> for u64 {
+    fn from(id: KernelObjectId) -> u64 {
+        id.0
+    }
+}
+
 /// The next available `KernelObjectId`. It is shared between all the CPUs, and so is incremented atomically.
 static KERNEL_OBJECT_ID_COUNTER: AtomicU64 = AtomicU64::new(1);
 
@@ -1,25 +1,61 @@
-use super::{address_space::AddressSpace, alloc_kernel_object_id, KernelObject, KernelObjectId};
+use super::{
+    address_space::AddressSpace,
+    alloc_kernel_object_id,
+    channel::ChannelEnd,
+    event::Event,
+    memory_object::MemoryObject,
+    KernelObject,
+    KernelObjectId,
+};
 use crate::{
-    memory::{KernelStackAllocator, PhysicalMemoryManager, Stack},
+    memory::{PhysicalMemoryManager, Stack, StackAllocationError},
     Platform,
 };
 use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
 use core::{
     cell::UnsafeCell,
-    sync::atomic::{AtomicU32, Ordering},
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
 };
-use hal::memory::VirtualAddress;
+use hal::memory::{Flags, VirtualAddress};
 use libpebble::{caps::Capability, Handle};
 use spin::{Mutex, RwLock};
 
 #[derive(Clone, PartialEq, Eq, Debug)]
-pub enum TaskBlock {}
+pub enum TaskBlock {
+    /// The task is sleeping until the monotonic clock reaches the given time (in nanoseconds).
+    Sleep(u64),
+    /// The task is waiting for a message to arrive on the given `ChannelEnd`. If a deadline is given, the task is
+    /// also woken when the monotonic clock reaches it, even if no message has arrived.
+    WaitForMessage { channel: Arc<ChannelEnd>, deadline: Option<u64> },
+    /// The task is waiting for any of the signal bits in `mask` to be set on the given `Event`. If a deadline is
+    /// given, the task is also woken when the monotonic clock reaches it.
+    WaitForEvent { event: Arc<Event>, mask: u32, deadline: Option<u64> },
+}
+
+impl TaskBlock {
+    /// Whether the thing the task is waiting for has happened, and so whether the task can be run again. `now` is
+    /// the current value of the monotonic clock.
+    pub fn can_wake(&self, now: u64) -> bool {
+        match self {
+            TaskBlock::Sleep(deadline) => now >= *deadline,
+            TaskBlock::WaitForMessage { channel, deadline } => {
+                channel.has_messages() || deadline.map_or(false, |deadline| now >= deadline)
+            }
+            TaskBlock::WaitForEvent { event, mask, deadline } => {
+                (event.signals() & mask) != 0 || deadline.map_or(false, |deadline| now >= deadline)
+            }
+        }
+    }
+}
 
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum TaskState {
     Ready,
     Running,
     Blocked(TaskBlock),
+    /// The task has exited, or has been killed. It will never be scheduled again, and the resources it holds are
+    /// released when the last reference to it is dropped.
+    Exited,
 }
 
 #[derive(Debug)]
@@ -35,6 +71,38 @@ pub enum TaskCreationError {
     AddressSpaceFull,
     /// The kernel stack allocator has run out of slots - this means too many tasks have been started.
     NoKernelStackSlots,
+    /// The ELF image the task is being created from is malformed, or can't be loaded (e.g. because it has segments
+    /// that lie outside of userspace, or that overlap with each other).
+    InvalidElf,
+    /// The ELF image's loadable segments are too large to load, either because they're larger than the kernel
+    /// allows, or because there isn't enough physical memory.
+    ImageTooLarge,
+    /// The ELF image asks for capabilities that the task creating it isn't allowed to grant.
+    CapabilityNotAllowed,
+    /// There isn't enough physical memory for the task's stacks.
+    OutOfMemory,
+}
+
+/// The ways that creating a new thread of an existing task can fail. These are the parts of creating a task that
+/// don't depend on its image.
+#[derive(Debug)]
+pub enum ThreadCreationError {
+    /// The `AddressSpace` that the thread is being created in cannot contain any more threads.
+    AddressSpaceFull,
+    /// The kernel stack allocator has run out of slots - this means too many tasks have been started.
+    NoKernelStackSlots,
+    /// There isn't enough physical memory for the thread's stacks.
+    OutOfMemory,
+}
+
+impl From<ThreadCreationError> for TaskCreationError {
+    fn from(err: ThreadCreationError) -> Self {
+        match err {
+            ThreadCreationError::AddressSpaceFull => TaskCreationError::AddressSpaceFull,
+            ThreadCreationError::NoKernelStackSlots => TaskCreationError::NoKernelStackSlots,
+            ThreadCreationError::OutOfMemory => TaskCreationError::OutOfMemory,
+        }
+    }
 }
 
 pub struct Task<P>
@@ -53,8 +121,18 @@ where
     pub kernel_stack_pointer: UnsafeCell<VirtualAddress>,
     pub user_stack_pointer: UnsafeCell<VirtualAddress>,
 
-    pub handles: RwLock<BTreeMap<Handle, Arc<dyn KernelObject>>>,
+    /// The handles owned by this task. These are shared between all of the threads of a task.
+    pub handles: Arc<RwLock<BTreeMap<Handle, Arc<dyn KernelObject>>>>,
+    handle_state: Arc<HandleState>,
+}
+
+/// State that's shared between all of the threads of a task, along with their handles.
+struct HandleState {
     next_handle: AtomicU32,
+    /// The number of threads of the task that have been created and haven't exited yet. The task's handles are
+    /// released when this reaches zero. We can't use the strong count of `handles` for this, because exited
+    /// threads can be kept alive by references from elsewhere (e.g. from another task's handles).
+    live_threads: AtomicUsize,
 }
 
 /*
@@ -69,49 +147,293 @@ impl<P> Task<P>
 where
     P: Platform,
 {
-    pub fn from_boot_info(
+    pub fn new(
         owner: KernelObjectId,
         address_space: Arc<AddressSpace<P>>,
-        image: &hal::boot_info::LoadedImage,
+        name: String,
+        entry_point: VirtualAddress,
+        capabilities: Vec<Capability>,
         allocator: &PhysicalMemoryManager,
-        kernel_page_table: &mut P::PageTable,
-        kernel_stack_allocator: &mut KernelStackAllocator<P>,
     ) -> Result<Arc<Task<P>>, TaskCreationError> {
+        Task::new_with_handles(
+            owner,
+            address_space,
+            name,
+            entry_point,
+            0,
+            capabilities,
+            Arc::new(RwLock::new(BTreeMap::new())),
+            // XXX: 0 is a special handle value, so start at 1
+            Arc::new(HandleState { next_handle: AtomicU32::new(1), live_threads: AtomicUsize::new(0) }),
+            allocator,
+        )
+        .map_err(TaskCreationError::from)
+    }
+
+    /// Create a new thread of this task. The new thread shares this task's `AddressSpace`, capabilities, and
+    /// handles, and starts executing at `entry_point` with `argument` as its first argument.
+    pub fn spawn_thread(
+        &self,
+        entry_point: VirtualAddress,
+        argument: usize,
+        allocator: &PhysicalMemoryManager,
+    ) -> Result<Arc<Task<P>>, ThreadCreationError> {
+        Task::new_with_handles(
+            self.id,
+            self.address_space.clone(),
+            self.name.clone(),
+            entry_point,
+            argument,
+            self.capabilities.clone(),
+            self.handles.clone(),
+            self.handle_state.clone(),
+            allocator,
+        )
+    }
+
+    fn new_with_handles(
+        owner: KernelObjectId,
+        address_space: Arc<AddressSpace<P>>,
+        name: String,
+        entry_point: VirtualAddress,
+        argument: usize,
+        capabilities: Vec<Capability>,
+        handles: Arc<RwLock<BTreeMap<Handle, Arc<dyn KernelObject>>>>,
+        handle_state: Arc<HandleState>,
+        allocator: &PhysicalMemoryManager,
+    ) -> Result<Arc<Task<P>>, ThreadCreationError> {
         // TODO: better way of getting initial stack sizes
-        let user_stack =
-            address_space.alloc_user_stack(0x4000, allocator).ok_or(TaskCreationError::AddressSpaceFull)?;
-        let kernel_stack = kernel_stack_allocator
-            .alloc_kernel_stack(0x4000, allocator, kernel_page_table)
-            .ok_or(TaskCreationError::NoKernelStackSlots)?;
+        let user_stack = address_space.alloc_user_stack(0x4000, allocator).map_err(|err| match err {
+            StackAllocationError::NoSlots => ThreadCreationError::AddressSpaceFull,
+            StackAllocationError::OutOfMemory => ThreadCreationError::OutOfMemory,
+        })?;
+        let kernel_stack =
+            match P::kernel_stack_allocator().alloc_kernel_stack(0x4000, allocator, &mut P::kernel_page_table()) {
+                Ok(kernel_stack) => kernel_stack,
+                Err(err) => {
+                    address_space.free_user_stack(user_stack, allocator);
+                    return Err(match err {
+                        StackAllocationError::NoSlots => ThreadCreationError::NoKernelStackSlots,
+                        StackAllocationError::OutOfMemory => ThreadCreationError::OutOfMemory,
+                    });
+                }
+            };
 
         let mut kernel_stack_pointer = kernel_stack.top;
         let mut user_stack_pointer = user_stack.top;
         unsafe {
-            P::initialize_task_kernel_stack(&mut kernel_stack_pointer, image.entry_point, &mut user_stack_pointer);
+            P::initialize_task_kernel_stack(
+                &mut kernel_stack_pointer,
+                entry_point,
+                argument,
+                &mut user_stack_pointer,
+            );
         }
 
+        handle_state.live_threads.fetch_add(1, Ordering::Relaxed);
         Ok(Arc::new(Task {
             id: alloc_kernel_object_id(),
             owner,
-            name: String::from(image.name()),
+            name,
             address_space,
             state: Mutex::new(TaskState::Ready),
-            capabilities: decode_capabilities(&image.capability_stream)?,
+            capabilities,
             user_stack: Mutex::new(user_stack),
             kernel_stack: Mutex::new(kernel_stack),
             kernel_stack_pointer: UnsafeCell::new(kernel_stack_pointer),
             user_stack_pointer: UnsafeCell::new(user_stack_pointer),
-            handles: RwLock::new(BTreeMap::new()),
-            // XXX: 0 is a special handle value, so start at 1
-            next_handle: AtomicU32::new(1),
+            handles,
+            handle_state,
         }))
     }
 
+    pub fn from_boot_info(
+        owner: KernelObjectId,
+        address_space: Arc<AddressSpace<P>>,
+        image: &hal::boot_info::LoadedImage,
+        allocator: &PhysicalMemoryManager,
+    ) -> Result<Arc<Task<P>>, TaskCreationError> {
+        let capabilities = decode_capabilities(&image.capability_stream)?;
+        Task::new(owner, address_space, String::from(image.name()), image.entry_point, capabilities, allocator)
+    }
+
+    /// Create a new task, in a new `AddressSpace`, from an ELF image. The image's loadable segments are copied
+    /// into newly-allocated memory and mapped into the address space, and the task's capabilities are read from
+    /// the image's `PEBBLE` note entry (if it has one). The image can only ask for capabilities that are in
+    /// `allowed_capabilities`, so that a task can't create a task that's more privileged than itself.
+    pub fn from_elf(
+        owner: KernelObjectId,
+        name: String,
+        elf_bytes: &[u8],
+        allowed_capabilities: &[Capability],
+        allocator: &PhysicalMemoryManager,
+    ) -> Result<Arc<Task<P>>, TaskCreationError> {
+        use hal::memory::{mebibytes, FrameSize};
+        use mer::{
+            program::{ProgramHeader, SegmentType},
+            Elf,
+        };
+        use pebble_util::math::align_up;
+
+        const CAPABILITY_OWNER_STR: &[u8] = b"PEBBLE";
+        const CAPABILITY_ENTRY_TYPE: u32 = 0;
+        /// The maximum amount of memory the loadable segments of an image can take up in total. Images come from
+        /// userspace, so we need to stop them from asking for an unreasonable amount of memory.
+        const MAX_LOADED_SIZE: usize = mebibytes(64);
+
+        let elf = Elf::new(elf_bytes).map_err(|_| TaskCreationError::InvalidElf)?;
+
+        /*
+         * Check that all of the loadable segments make sense before we allocate any memory for them. Segments
+         * must be page-aligned, lie within the image, be mapped entirely within userspace, and not overlap with
+         * each other.
+         *
+         * The image could be mapped into a task that's still running, and so could change under us. We therefore
+         * only read each program header once, and only use the copies we've checked after this.
+         */
+        let mut capabilities = Vec::new();
+        let mut loaded_ranges: Vec<(usize, usize)> = Vec::new();
+        let mut load_segments: Vec<ProgramHeader> = Vec::new();
+        for segment in elf.segments() {
+            match segment.segment_type() {
+                SegmentType::Load if segment.mem_size > 0 => {
+                    let virtual_address = segment.virtual_address as usize;
+                    let end = align_up(segment.mem_size as usize, P::PageTableSize::SIZE)
+                        .checked_add(virtual_address)
+                        .ok_or(TaskCreationError::InvalidElf)?;
+
+                    if virtual_address % P::PageTableSize::SIZE != 0
+                        || segment.file_size > segment.mem_size
+                        || (segment.offset as usize)
+                            .checked_add(segment.file_size as usize)
+                            .map_or(true, |end| end > elf_bytes.len())
+                        || end > usize::from(P::USER_ADDRESS_SPACE_END)
+                        || loaded_ranges
+                            .iter()
+                            .any(|&(start, other_end)| virtual_address < other_end && start < end)
+                    {
+                        return Err(TaskCreationError::InvalidElf);
+                    }
+
+                    loaded_ranges.push((virtual_address, end));
+                    if loaded_ranges.iter().map(|(start, end)| end - start).sum::<usize>() > MAX_LOADED_SIZE {
+                        return Err(TaskCreationError::ImageTooLarge);
+                    }
+                    load_segments.push(segment);
+                }
+
+                SegmentType::Note => {
+                    if (segment.offset as usize)
+                        .checked_add(segment.file_size as usize)
+                        .map_or(true, |end| end > elf_bytes.len())
+                    {
+                        return Err(TaskCreationError::InvalidElf);
+                    }
+
+                    if let Some(entry) =
+                        segment.iterate_note_entries(&elf).ok_or(TaskCreationError::InvalidElf)?.find(|entry| {
+                            entry.entry_type == CAPABILITY_ENTRY_TYPE && entry.name == CAPABILITY_OWNER_STR
+                        })
+                    {
+                        capabilities = decode_capabilities(entry.desc)?;
+                    }
+                }
+
+                _ => (),
+            }
+        }
+
+        if capabilities.iter().any(|capability| !allowed_capabilities.contains(capability)) {
+            return Err(TaskCreationError::CapabilityNotAllowed);
+        }
+
+        let address_space = AddressSpace::new(owner, &P::kernel_page_table(), allocator);
+        let task = Task::new(
+            owner,
+            address_space.clone(),
+            name,
+            VirtualAddress::new(elf.entry_point()),
+            capabilities,
+            allocator,
+        )?;
+
+        /*
+         * Allocate memory for each segment, copy its data into it (zeroing the rest of it, which includes the
+         * remainder of the last page if the segment doesn't fill it), and map it into the new address space. If
+         * anything fails, dropping the task frees everything we've allocated for it so far.
+         */
+        for segment in &load_segments {
+            let size = align_up(segment.mem_size as usize, P::PageTableSize::SIZE);
+            let memory_object = MemoryObject::allocate(
+                task.id(),
+                VirtualAddress::new(segment.virtual_address as usize),
+                size,
+                Flags {
+                    writable: segment.is_writable(),
+                    executable: segment.is_executable(),
+                    user_accessible: true,
+                    ..Default::default()
+                },
+            )
+            .ok_or(TaskCreationError::ImageTooLarge)?;
+
+            /*
+             * We checked that the segment's data fits within its memory when we copied its header, so this can't
+             * underflow.
+             */
+            let data = segment.data(&elf);
+            unsafe {
+                let ptr: *mut u8 = P::physical_to_virtual(memory_object.physical_address).mut_ptr();
+                core::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+                core::ptr::write_bytes(ptr.add(data.len()), 0, size - data.len());
+            }
+
+            /*
+             * The segments can't overlap with each other, but they can still overlap with the task's user stack.
+             * If they do, we give up.
+             */
+            address_space
+                .map_memory_object(memory_object, allocator)
+                .map_err(|_| TaskCreationError::InvalidElf)?;
+        }
+
+        Ok(task)
+    }
+
     pub fn add_handle(&self, object: Arc<dyn KernelObject>) -> Handle {
-        let handle = Handle(self.next_handle.fetch_add(1, Ordering::Relaxed));
+        let handle = Handle(self.handle_state.next_handle.fetch_add(1, Ordering::Relaxed));
         self.handles.write().insert(handle, object);
         handle
     }
+
+    /// Record that this thread of the task has exited. When the last thread exits, the task's handles are released
+    /// straight away, rather than when the `Task` is dropped, so that the objects they refer to can be cleaned up
+    /// even if something else holds onto a reference to the task (including the task's own handles).
+    pub fn exit_thread(&self) {
+        if self.handle_state.live_threads.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.handles.write().clear();
+        }
+    }
+}
+
+/*
+ * The kernel stack of a task is freed when the `Task` is dropped, and so it's important that the last reference
+ * to a task is not dropped while we're still running on its kernel stack. The scheduler holds onto exited tasks
+ * until it has switched away from them to make sure this is the case.
+ */
+impl<P> Drop for Task<P>
+where
+    P: Platform,
+{
+    fn drop(&mut self) {
+        let allocator = crate::PHYSICAL_MEMORY_MANAGER.get();
+        self.address_space.free_user_stack(self.user_stack.get_mut().clone(), allocator);
+        P::kernel_stack_allocator().free_kernel_stack(
+            self.kernel_stack.get_mut().clone(),
+            allocator,
+            &mut P::kernel_page_table(),
+        );
+    }
 }
 
 impl<P> KernelObject for Task<P>
@@ -146,6 +468,11 @@ fn decode_capabilities(mut cap_stream: &[u8]) -> Result<Vec<Capability>, TaskCre
             CAP_SERVICE_PROVIDER => one_byte_cap!(Capability::ServiceProvider),
             CAP_SERVICE_USER => one_byte_cap!(Capability::ServiceUser),
             CAP_PCI_BUS_DRIVER => one_byte_cap!(Capability::PciBusDriver),
+            CAP_CREATE_TASK => one_byte_cap!(Capability::CreateTask),
+            CAP_HANDLE_INTERRUPTS => one_byte_cap!(Capability::HandleInterrupts),
+            CAP_SUPERVISOR => one_byte_cap!(Capability::Supervisor),
+            CAP_READ_KERNEL_LOG => one_byte_cap!(Capability::ReadKernelLog),
+            CAP_DUMP_ADDRESS_SPACE => one_byte_cap!(Capability::DumpAddressSpace),
 
             // We skip `0x00` as the first byte of a capability, as it is just used to pad the
             // stream and so has no meaning
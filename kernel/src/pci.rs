@@ -1,5 +1,18 @@
-use alloc::collections::BTreeMap;
-use pci_types::{BaseClass, DeviceId, DeviceRevision, Interface, PciAddress, SubClass, VendorId};
+use crate::Platform;
+use alloc::{collections::BTreeMap, vec::Vec};
+use bit_field::BitField;
+use core::ptr;
+use hal::memory::PhysicalAddress;
+use pci_types::{
+    BaseClass,
+    ConfigRegionAccess,
+    DeviceId,
+    DeviceRevision,
+    Interface,
+    PciAddress,
+    SubClass,
+    VendorId,
+};
 
 pub struct PciDevice {
     pub vendor_id: VendorId,
@@ -8,8 +21,299 @@ pub struct PciDevice {
     pub class: BaseClass,
     pub sub_class: SubClass,
     pub interface: Interface,
+    pub capabilities: Vec<PciCapability>,
+    /// The GSI (global system interrupt) that the function's legacy interrupt pin is routed to. This is `None` if
+    /// the function doesn't use an interrupt pin, or if the platform couldn't work out how it's routed.
+    pub gsi: Option<u32>,
 }
 
 pub struct PciInfo {
     pub devices: BTreeMap<PciAddress, PciDevice>,
 }
+
+/// A capability from a PCI function's capability list. `offset` is the offset of the capability's header within
+/// the function's configuration space.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PciCapability {
+    Msi {
+        offset: u16,
+    },
+    MsiX {
+        offset: u16,
+    },
+    /// A capability that the kernel doesn't use.
+    Other {
+        id: u8,
+        offset: u16,
+    },
+}
+
+const CAPABILITY_ID_MSI: u8 = 0x05;
+const CAPABILITY_ID_MSIX: u8 = 0x11;
+
+/// Walk the capability list of the PCI function at `address`.
+pub fn read_capabilities<A>(access: &A, address: PciAddress) -> Vec<PciCapability>
+where
+    A: ConfigRegionAccess + ?Sized,
+{
+    let mut capabilities = Vec::new();
+
+    /*
+     * Bit 4 of the Status register (the upper half of the dword at `0x04`) tells us if the function has a
+     * capability list.
+     */
+    if !unsafe { access.read(address, 0x04) }.get_bit(20) {
+        return capabilities;
+    }
+
+    /*
+     * The bottom two bits of each pointer are reserved, and must be masked off. We also stop after 48 entries (the
+     * number that can fit in the rest of the configuration space), so a malformed list can't loop forever.
+     */
+    let mut offset = unsafe { access.read(address, 0x34) }.get_bits(2..8) as u16 * 4;
+    while offset != 0 && capabilities.len() < 48 {
+        let header = unsafe { access.read(address, offset) };
+
+        capabilities.push(match header.get_bits(0..8) as u8 {
+            CAPABILITY_ID_MSI => PciCapability::Msi { offset },
+            CAPABILITY_ID_MSIX => PciCapability::MsiX { offset },
+            id => PciCapability::Other { id, offset },
+        });
+
+        offset = header.get_bits(10..16) as u16 * 4;
+    }
+
+    capabilities
+}
+
+/// The message a PCI device writes to raise a message-signalled interrupt. It's provided by the `Platform`, which
+/// decides where the write goes and what it means.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MsiError {
+    /// The function has neither an MSI nor an MSI-X capability, or its MSI-X table isn't somewhere we can use.
+    NotSupported,
+    /// The function does not have an MSI-X table entry with the given index. Functions that only support MSI only
+    /// have a single entry, with an index of `0`.
+    InvalidIndex,
+    /// The interrupt is already enabled.
+    AlreadyInUse,
+}
+
+/// Configure the PCI function at `address` to raise `message` as an interrupt. If the function supports MSI-X,
+/// `index` selects the entry of the MSI-X table to use. Otherwise, MSI is used, and only a single message is
+/// supported (so `index` must be `0`).
+pub fn enable_msi<P, A>(
+    access: &A,
+    address: PciAddress,
+    capabilities: &[PciCapability],
+    index: u16,
+    message: MsiMessage,
+) -> Result<(), MsiError>
+where
+    P: Platform,
+    A: ConfigRegionAccess + ?Sized,
+{
+    /*
+     * If a function supports both MSI-X and MSI, the specification recommends that we use MSI-X.
+     */
+    if let Some(offset) = msix_offset(capabilities) {
+        let entry_address = msix_table_entry(access, address, offset, index)?;
+
+        with_device_memory::<P, _, _>(entry_address, |entry| unsafe {
+            if !ptr::read_volatile(entry.add(3)).get_bit(0) {
+                return Err(MsiError::AlreadyInUse);
+            }
+
+            ptr::write_volatile(entry, message.address.get_bits(0..32) as u32);
+            ptr::write_volatile(entry.add(1), message.address.get_bits(32..64) as u32);
+            ptr::write_volatile(entry.add(2), message.data);
+            ptr::write_volatile(entry.add(3), 0);
+            Ok(())
+        })?;
+
+        unsafe {
+            /*
+             * MSI and MSI-X must never be enabled at the same time, so if the function also supports MSI, make sure
+             * it's disabled first.
+             */
+            if let Some(msi_offset) = msi_offset(capabilities) {
+                let mut msi_header = access.read(address, msi_offset);
+                msi_header.set_bit(16, false);
+                access.write(address, msi_offset, msi_header);
+            }
+
+            /*
+             * Enable MSI-X (bit 15 of the Message Control register), and make sure the whole function isn't masked
+             * (bit 14).
+             */
+            let mut header = access.read(address, offset);
+            header.set_bit(31, true);
+            header.set_bit(30, false);
+            access.write(address, offset, header);
+        }
+
+        Ok(())
+    } else if let Some(offset) = msi_offset(capabilities) {
+        if index != 0 {
+            return Err(MsiError::InvalidIndex);
+        }
+
+        unsafe {
+            let mut header = access.read(address, offset);
+            if header.get_bit(16) {
+                return Err(MsiError::AlreadyInUse);
+            }
+
+            /*
+             * The layout of the rest of the capability depends on whether the function supports 64-bit message
+             * addresses (bit 7 of the Message Control register).
+             */
+            let is_64_bit = header.get_bit(23);
+            access.write(address, offset + 0x4, message.address.get_bits(0..32) as u32);
+            if is_64_bit {
+                access.write(address, offset + 0x8, message.address.get_bits(32..64) as u32);
+                access.write(address, offset + 0xc, message.data);
+            } else {
+                access.write(address, offset + 0x8, message.data);
+            }
+
+            /*
+             * Only enable a single message (bits 4..7 of the Message Control register), and then enable MSI.
+             */
+            header.set_bits(20..23, 0);
+            header.set_bit(16, true);
+            access.write(address, offset, header);
+        }
+
+        Ok(())
+    } else {
+        Err(MsiError::NotSupported)
+    }
+}
+
+/// Stop the PCI function at `address` raising the interrupt enabled with `enable_msi`.
+pub fn disable_msi<P, A>(access: &A, address: PciAddress, capabilities: &[PciCapability], index: u16)
+where
+    P: Platform,
+    A: ConfigRegionAccess + ?Sized,
+{
+    if let Some(offset) = msix_offset(capabilities) {
+        /*
+         * Other entries of the table might still be in use, so we just mask this entry, and leave MSI-X enabled.
+         */
+        if let Ok(entry_address) = msix_table_entry(access, address, offset, index) {
+            with_device_memory::<P, _, _>(entry_address, |entry| unsafe {
+                ptr::write_volatile(entry.add(3), 1);
+            });
+        }
+    } else if let Some(offset) = msi_offset(capabilities) {
+        unsafe {
+            let mut header = access.read(address, offset);
+            header.set_bit(16, false);
+            access.write(address, offset, header);
+        }
+    }
+}
+
+fn msi_offset(capabilities: &[PciCapability]) -> Option<u16> {
+    capabilities.iter().find_map(|capability| match capability {
+        PciCapability::Msi { offset } => Some(*offset),
+        _ => None,
+    })
+}
+
+fn msix_offset(capabilities: &[PciCapability]) -> Option<u16> {
+    capabilities.iter().find_map(|capability| match capability {
+        PciCapability::MsiX { offset } => Some(*offset),
+        _ => None,
+    })
+}
+
+/// Get the physical address of the entry of a function's MSI-X table with the given index. Each entry is made up
+/// of four dwords: the lower and upper halves of the message address, the message data, and the vector control
+/// register.
+fn msix_table_entry<A>(
+    access: &A,
+    address: PciAddress,
+    offset: u16,
+    index: u16,
+) -> Result<PhysicalAddress, MsiError>
+where
+    A: ConfigRegionAccess + ?Sized,
+{
+    let table_size = unsafe { access.read(address, offset) }.get_bits(16..27) as u16 + 1;
+    if index >= table_size {
+        return Err(MsiError::InvalidIndex);
+    }
+
+    /*
+     * The table lives in the memory space of one of the function's BARs, given by the bottom three bits of the
+     * Table Offset register. There are only six BARs, so the values `6` and `7` are reserved.
+     */
+    let table_info = unsafe { access.read(address, offset + 0x4) };
+    let bar = table_info.get_bits(0..3) as u8;
+    if bar > 5 {
+        return Err(MsiError::NotSupported);
+    }
+    let bar_address = memory_bar_address(access, address, bar).ok_or(MsiError::NotSupported)?;
+    Ok(bar_address + (table_info & !0b111) as usize + usize::from(index) * 16)
+}
+
+/// Map the page of device memory that contains `address` into `Platform::DEVICE_WINDOW` as uncacheable, and call
+/// `f` with a pointer to `address` through it. The caller must hold the lock on `PCI_ACCESS`, which stops anything
+/// else using the window at the same time. `address` must be aligned to 4 bytes, so the dword it points to can't
+/// cross into the next page.
+fn with_device_memory<P, F, R>(address: PhysicalAddress, f: F) -> R
+where
+    P: Platform,
+    F: FnOnce(*mut u32) -> R,
+{
+    use hal::memory::{CacheType, Flags, Frame, FrameSize, Page, PageTable, Size4KiB};
+
+    let allocator = crate::PHYSICAL_MEMORY_MANAGER.get();
+    let page = Page::<Size4KiB>::starts_with(P::DEVICE_WINDOW);
+    P::kernel_page_table()
+        .map(
+            page,
+            Frame::contains(address),
+            Flags { writable: true, cache_type: CacheType::Uncacheable, ..Default::default() },
+            allocator,
+        )
+        .expect("Device window is already in use");
+
+    let result = f((P::DEVICE_WINDOW + usize::from(address) % Size4KiB::SIZE).mut_ptr() as *mut u32);
+
+    P::kernel_page_table().unmap(page, allocator);
+    result
+}
+
+/// Get the physical address of the memory space described by one of the BARs of a function. Returns `None` if the
+/// BAR describes I/O space instead of memory space.
+fn memory_bar_address<A>(access: &A, address: PciAddress, bar: u8) -> Option<PhysicalAddress>
+where
+    A: ConfigRegionAccess + ?Sized,
+{
+    let bar_offset = 0x10 + u16::from(bar) * 4;
+    let low = unsafe { access.read(address, bar_offset) };
+
+    if low.get_bit(0) {
+        return None;
+    }
+
+    /*
+     * Bits 1..3 give the type of the BAR. A value of `0b10` means it's 64 bits wide, and so also uses the next BAR
+     * for the upper half of the address.
+     */
+    let mut bar_address = (low & !0xf) as u64;
+    if low.get_bits(1..3) == 0b10 {
+        bar_address.set_bits(32..64, u64::from(unsafe { access.read(address, bar_offset + 4) }));
+    }
+
+    PhysicalAddress::new(bar_address as usize)
+}
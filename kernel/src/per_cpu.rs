@@ -7,7 +7,9 @@ where
     P: Platform,
 {
     fn scheduler(self: Pin<&mut Self>) -> Pin<&mut Scheduler<P>>;
-    fn set_kernel_stack_pointer(self: Pin<&mut Self>, stack_pointer: VirtualAddress);
+    /// Set the top of the running task's kernel stack (its highest byte, like `Stack::top`). This is where the
+    /// kernel's stack starts when the CPU enters it from userspace.
+    fn set_kernel_stack_pointer(self: Pin<&mut Self>, kernel_stack_top: VirtualAddress);
     fn get_user_stack_pointer(self: Pin<&mut Self>) -> VirtualAddress;
     fn set_user_stack_pointer(self: Pin<&mut Self>, stack_pointer: VirtualAddress);
 }
@@ -4,9 +4,18 @@ use crate::{
     Platform,
 };
 use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
-use hal::memory::VirtualAddress;
+use core::time::Duration;
+use hal::{
+    interrupts::{InterruptController, InterruptOrigin},
+    memory::VirtualAddress,
+    timer::Timer,
+};
 use log::trace;
 
+/// How often the timer ticks. This is how long a task can run before it's pre-empted (if another task is ready to
+/// run), and also how precisely sleeping tasks are woken.
+pub const TICK_PERIOD: Duration = Duration::from_millis(10);
+
 pub struct Scheduler<P>
 where
     P: Platform,
@@ -16,6 +25,9 @@ where
     /// Backed by a `VecDeque` so we can rotate objects in the queue efficiently.
     ready_queue: VecDeque<Arc<Task<P>>>,
     blocked_queue: Vec<Arc<Task<P>>>,
+    /// Tasks that have exited, but that can't be dropped yet because we could still be running on their kernel
+    /// stacks. These are dropped the next time we switch task.
+    exited_tasks: Vec<Arc<Task<P>>>,
 }
 
 impl<P> Scheduler<P>
@@ -23,7 +35,12 @@ where
     P: Platform,
 {
     pub fn new() -> Scheduler<P> {
-        Scheduler { running_task: None, ready_queue: VecDeque::new(), blocked_queue: Vec::new() }
+        Scheduler {
+            running_task: None,
+            ready_queue: VecDeque::new(),
+            blocked_queue: Vec::new(),
+            exited_tasks: Vec::new(),
+        }
     }
 
     pub fn add_task(&mut self, task: Arc<Task<P>>) {
@@ -32,6 +49,7 @@ where
             TaskState::Ready => self.ready_queue.push_back(task),
             TaskState::Blocked(_) => self.blocked_queue.push(task),
             TaskState::Running => panic!("Tried to schedule task that's already running!"),
+            TaskState::Exited => panic!("Tried to schedule task that has exited!"),
         }
     }
 
@@ -55,9 +73,9 @@ where
         self.running_task = Some(task.clone());
         task.address_space.switch_to();
         unsafe {
-            P::per_cpu().set_kernel_stack_pointer(*task.kernel_stack_pointer.get());
+            P::per_cpu().set_kernel_stack_pointer(task.kernel_stack.lock().top);
             P::per_cpu().set_user_stack_pointer(*task.user_stack_pointer.get());
-            P::drop_into_userspace()
+            P::drop_into_userspace(*task.kernel_stack_pointer.get())
         }
     }
 
@@ -70,55 +88,169 @@ where
     pub fn switch_to_next(&mut self, new_state: TaskState) {
         assert!(self.running_task.is_some());
 
+        /*
+         * We can't be running on the kernel stack of any of the exited tasks, so it's now safe to drop them.
+         */
+        self.exited_tasks.clear();
+
         /*
          * Select the next task to run.
          * NOTE: in the future, this could be more complex, e.g. by taking priority into account.
          */
-        if let Some(next_task) = self.choose_next() {
-            /*
-             * We're switching task! We sort out the internal scheduler state, and then ask the
-             * platform to perform the context switch for us!
-             * NOTE: This temporarily allows `running_task` to be `None`.
-             */
-            trace!("Switching to task: {}", next_task.name);
-            let old_task = self.running_task.take().unwrap();
-            assert_eq!(*old_task.state.lock(), TaskState::Running);
-            assert_eq!(*next_task.state.lock(), TaskState::Ready);
-
-            self.running_task = Some(next_task.clone());
-            *self.running_task.as_ref().unwrap().state.lock() = TaskState::Running;
-            match new_state {
+        self.wake_blocked_tasks();
+        let next_task = match self.choose_next() {
+            Some(next_task) => next_task,
+            None => match new_state {
                 TaskState::Running => panic!("Tried to switch away from a task to state of Running!"),
                 TaskState::Ready => {
-                    *old_task.state.lock() = TaskState::Ready;
-                    self.ready_queue.push_back(old_task.clone());
-                }
-                TaskState::Blocked(block) => {
-                    trace!("Blocking task: {}", old_task.name);
-                    *old_task.state.lock() = TaskState::Blocked(block);
-                    self.blocked_queue.push(old_task.clone());
+                    /*
+                     * There aren't any other schedulable tasks, so we just return to the current one (by doing
+                     * nothing here).
+                     */
+                    trace!("No more schedulable tasks. Returning to current one!");
+                    return;
                 }
+                TaskState::Blocked(ref block) => loop {
+                    /*
+                     * The current task wants to block, but there's nothing else to run. We idle until either its
+                     * block is released (in which case we return straight back to it), or until another task
+                     * becomes runnable.
+                     * TODO: this should catch up on any kernel bookkeeping while we're idle.
+                     */
+                    P::idle();
+
+                    if block.can_wake(P::timer().monotonic_time()) {
+                        return;
+                    }
+
+                    self.wake_blocked_tasks();
+                    if let Some(next_task) = self.choose_next() {
+                        break next_task;
+                    }
+                },
+                TaskState::Exited => loop {
+                    /*
+                     * The current task has exited, but there's nothing else to run yet. We can't return to it, so
+                     * we idle until a blocked task can be woken. Exited tasks aren't dropped until we've switched
+                     * away from them, so it's fine to keep running on its kernel stack until then.
+                     */
+                    P::idle();
+
+                    self.wake_blocked_tasks();
+                    if let Some(next_task) = self.choose_next() {
+                        break next_task;
+                    }
+                },
+            },
+        };
+
+        /*
+         * We're switching task! We sort out the internal scheduler state, and then ask the
+         * platform to perform the context switch for us!
+         * NOTE: This temporarily allows `running_task` to be `None`.
+         */
+        trace!("Switching to task: {}", next_task.name);
+        let old_task = self.running_task.take().unwrap();
+        assert_eq!(*old_task.state.lock(), TaskState::Running);
+        assert_eq!(*next_task.state.lock(), TaskState::Ready);
+
+        self.running_task = Some(next_task.clone());
+        *self.running_task.as_ref().unwrap().state.lock() = TaskState::Running;
+        match new_state {
+            TaskState::Running => panic!("Tried to switch away from a task to state of Running!"),
+            TaskState::Ready => {
+                *old_task.state.lock() = TaskState::Ready;
+                self.ready_queue.push_back(old_task.clone());
             }
+            TaskState::Blocked(block) => {
+                trace!("Blocking task: {}", old_task.name);
+                *old_task.state.lock() = TaskState::Blocked(block);
+                self.blocked_queue.push(old_task.clone());
+            }
+            TaskState::Exited => {
+                trace!("Task exited: {}", old_task.name);
+                *old_task.state.lock() = TaskState::Exited;
+                self.exited_tasks.push(old_task.clone());
+            }
+        }
 
+        /*
+         * Threads of the same task share an `AddressSpace`, so we don't need to switch if we're switching
+         * between them.
+         */
+        if !Arc::ptr_eq(&old_task.address_space, &next_task.address_space) {
             old_task.address_space.switch_from();
             next_task.address_space.switch_to();
+        }
 
-            let old_kernel_stack: *mut VirtualAddress = old_task.kernel_stack_pointer.get();
-            let new_kernel_stack = unsafe { *self.running_task.as_ref().unwrap().kernel_stack_pointer.get() };
-            let new_user_stack = unsafe { *self.running_task.as_ref().unwrap().user_stack_pointer.get() };
-            unsafe {
-                *old_task.user_stack_pointer.get() = P::per_cpu().get_user_stack_pointer();
-                P::per_cpu().set_kernel_stack_pointer(new_kernel_stack);
-                P::per_cpu().set_user_stack_pointer(new_user_stack);
-                P::context_switch(old_kernel_stack, new_kernel_stack);
+        /*
+         * The saved kernel stack pointer is only used to switch to the new task's context. Entries into the kernel
+         * from userspace always start at the top of the task's kernel stack, because the task can't be running in
+         * the kernel when it's in userspace.
+         */
+        let old_kernel_stack: *mut VirtualAddress = old_task.kernel_stack_pointer.get();
+        let new_kernel_stack = unsafe { *next_task.kernel_stack_pointer.get() };
+        let new_kernel_stack_top = next_task.kernel_stack.lock().top;
+        let new_user_stack = unsafe { *next_task.user_stack_pointer.get() };
+        unsafe {
+            *old_task.user_stack_pointer.get() = P::per_cpu().get_user_stack_pointer();
+        }
+
+        /*
+         * If the old task has exited, we'll never return here, so we need to drop our references to the
+         * tasks before we switch. The scheduler's queues keep both tasks alive.
+         */
+        drop(old_task);
+        drop(next_task);
+
+        unsafe {
+            P::per_cpu().set_kernel_stack_pointer(new_kernel_stack_top);
+            P::per_cpu().set_user_stack_pointer(new_user_stack);
+            P::context_switch(old_kernel_stack, new_kernel_stack);
+        }
+    }
+
+    /// Pre-empt the running task if another task is ready to run (including any that can be woken now), and
+    /// otherwise carry on running it.
+    pub fn preempt(&mut self) {
+        self.wake_blocked_tasks();
+        if !self.ready_queue.is_empty() {
+            self.switch_to_next(TaskState::Ready);
+        }
+    }
+
+    /// Remove the running task from the scheduler, and switch to the next ready task. If this was the last thread
+    /// of its task, the task's handles are released immediately. Its stacks and reference to its `AddressSpace`
+    /// are released when the last reference to it is dropped. This is used both when a task exits, and to kill a
+    /// task that has faulted.
+    pub fn exit_running_task(&mut self) -> ! {
+        /*
+         * XXX: we can't hold a reference to the task here, because we'll never return to drop it.
+         */
+        self.running_task.as_ref().unwrap().exit_thread();
+        self.switch_to_next(TaskState::Exited);
+        unreachable!()
+    }
+
+    /// Move any blocked tasks that are now able to run into the ready queue.
+    fn wake_blocked_tasks(&mut self) {
+        let now = P::timer().monotonic_time();
+        let mut i = 0;
+
+        while i < self.blocked_queue.len() {
+            let can_wake = match *self.blocked_queue[i].state.lock() {
+                TaskState::Blocked(ref block) => block.can_wake(now),
+                ref state => panic!("Task in blocked queue is in state: {:?}", state),
+            };
+
+            if can_wake {
+                let task = self.blocked_queue.remove(i);
+                trace!("Waking task: {}", task.name);
+                *task.state.lock() = TaskState::Ready;
+                self.ready_queue.push_back(task);
+            } else {
+                i += 1;
             }
-        } else {
-            /*
-             * There aren't any schedulable tasks. For now, we just return to the current one (by
-             * doing nothing here).
-             * TODO: this should catch up on any kernel bookkeeping, then idle to minimise power use.
-             */
-            trace!("No more schedulable tasks. Returning to current one!");
         }
     }
 
@@ -126,3 +258,32 @@ where
         self.ready_queue.pop_front()
     }
 }
+
+/// Start the timer ticking every `TICK_PERIOD` on the calling CPU, so that tasks are pre-empted (and sleeping tasks
+/// are woken) even if the running task never yields. The platform's interrupt controller and timer, and the CPU's
+/// per-CPU data, must have been initialised first.
+pub fn start_ticking<P>()
+where
+    P: Platform,
+{
+    let vector = P::timer().vector();
+    P::interrupt_controller().register_handler(vector, timer_tick::<P>).expect("Failed to register timer handler");
+    P::interrupt_controller().set_masked(vector, false);
+    P::timer().set_periodic(TICK_PERIOD);
+}
+
+fn timer_tick<P>(vector: u32, origin: InterruptOrigin)
+where
+    P: Platform,
+{
+    P::interrupt_controller().end_of_interrupt(vector);
+
+    /*
+     * The kernel can't be pre-empted, so we only switch task if we've interrupted userspace. If we've interrupted
+     * the kernel, the task is pre-empted by a later tick instead (and if the kernel was idling, the scheduler
+     * checks for tasks to wake when the interrupt wakes it up).
+     */
+    if origin == InterruptOrigin::Userspace {
+        P::per_cpu().scheduler().preempt();
+    }
+}
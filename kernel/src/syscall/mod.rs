@@ -1,41 +1,60 @@
 mod validation;
 
 use crate::{
+    klog,
+    log_filter,
     object::{
         address_space::AddressSpace,
         channel::{ChannelEnd, Message},
+        event::Event,
+        interrupt::{Interrupt, InterruptRoutingError, PciInterruptError, INTERRUPT_SIGNAL},
         memory_object::MemoryObject,
-        task::{Task, TaskState},
+        task::{Task, TaskBlock, TaskCreationError, TaskState, ThreadCreationError},
         KernelObject,
     },
     per_cpu::PerCpu,
     Platform,
 };
-use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
 use bit_field::BitField;
 use core::convert::TryFrom;
-use hal::memory::{Flags, VirtualAddress};
+use hal::{
+    memory::{Flags, VirtualAddress},
+    timer::Timer,
+};
 use libpebble::{
     caps::Capability,
     syscall::{
         self,
         result::{handle_to_syscall_repr, status_to_syscall_repr, status_with_payload_to_syscall_repr},
+        CreateInterruptError,
         CreateMemoryObjectError,
+        CreatePciInterruptError,
+        CreateTaskError,
+        DumpAddressSpaceError,
         EarlyLogError,
+        EventError,
         FramebufferInfo,
         GetFramebufferError,
         GetMessageError,
+        InterruptError,
+        KernelLogRecord,
         MapMemoryObjectError,
         PciGetInfoError,
+        ProtectMemoryObjectError,
+        ReadKernelLogError,
         RegisterServiceError,
         SendMessageError,
+        SpawnThreadError,
+        SubscribeToFaultsError,
         SubscribeToServiceError,
+        WaitForMessageError,
         CHANNEL_MAX_NUM_HANDLES,
     },
     Handle,
     ZERO_HANDLE,
 };
-use log::{info, trace, warn};
+use log::{info, log, warn, Level};
 use spin::Mutex;
 use validation::{UserPointer, UserSlice, UserString};
 
@@ -55,17 +74,35 @@ where
 
     match number {
         syscall::SYSCALL_YIELD => yield_syscall::<P>(),
-        syscall::SYSCALL_EARLY_LOG => status_to_syscall_repr(early_log(task, a, b)),
+        syscall::SYSCALL_EARLY_LOG => status_to_syscall_repr(early_log(task, a, b, c)),
         syscall::SYSCALL_GET_FRAMEBUFFER => handle_to_syscall_repr(get_framebuffer(task, a)),
         syscall::SYSCALL_CREATE_MEMORY_OBJECT => handle_to_syscall_repr(create_memory_object(task, a, b, c)),
         syscall::SYSCALL_MAP_MEMORY_OBJECT => status_to_syscall_repr(map_memory_object(task, a, b, c)),
         syscall::SYSCALL_CREATE_CHANNEL => todo!(),
         syscall::SYSCALL_SEND_MESSAGE => status_to_syscall_repr(send_message(task, a, b, c, d, e)),
         syscall::SYSCALL_GET_MESSAGE => status_with_payload_to_syscall_repr(get_message(task, a, b, c, d, e)),
-        syscall::SYSCALL_WAIT_FOR_MESSAGE => todo!(),
+        syscall::SYSCALL_WAIT_FOR_MESSAGE => status_to_syscall_repr(wait_for_message(task, a, b)),
         syscall::SYSCALL_REGISTER_SERVICE => handle_to_syscall_repr(register_service(task, a, b)),
         syscall::SYSCALL_SUBSCRIBE_TO_SERVICE => handle_to_syscall_repr(subscribe_to_service(task, a, b)),
         syscall::SYSCALL_PCI_GET_INFO => status_with_payload_to_syscall_repr(pci_get_info(task, a, b)),
+        syscall::SYSCALL_EXIT_TASK => exit_task::<P>(task, a),
+        syscall::SYSCALL_CREATE_TASK => handle_to_syscall_repr(create_task(task, a, b, c, d)),
+        syscall::SYSCALL_SPAWN_THREAD => handle_to_syscall_repr(spawn_thread(task, a, b)),
+        syscall::SYSCALL_GET_TIME => P::timer().monotonic_time() as usize,
+        syscall::SYSCALL_SLEEP_UNTIL => sleep_until::<P>(a),
+        syscall::SYSCALL_CREATE_EVENT => handle_to_syscall_repr(create_event(task)),
+        syscall::SYSCALL_SIGNAL_EVENT => status_to_syscall_repr(signal_event(task, a, b)),
+        syscall::SYSCALL_CLEAR_EVENT => status_to_syscall_repr(clear_event(task, a, b)),
+        syscall::SYSCALL_WAIT_FOR_EVENT => status_with_payload_to_syscall_repr(wait_for_event(task, a, b, c)),
+        syscall::SYSCALL_CREATE_INTERRUPT => handle_to_syscall_repr(create_interrupt(task, a)),
+        syscall::SYSCALL_WAIT_FOR_INTERRUPT => status_to_syscall_repr(wait_for_interrupt(task, a, b)),
+        syscall::SYSCALL_ACK_INTERRUPT => status_to_syscall_repr(ack_interrupt(task, a)),
+        syscall::SYSCALL_CREATE_PCI_INTERRUPT => handle_to_syscall_repr(create_pci_interrupt(task, a, b)),
+        syscall::SYSCALL_SUBSCRIBE_TO_FAULTS => handle_to_syscall_repr(subscribe_to_faults(task)),
+        syscall::SYSCALL_READ_KERNEL_LOG => status_with_payload_to_syscall_repr(read_kernel_log(task, a, b, c)),
+        syscall::SYSCALL_GET_LOG_LEVEL => log_filter::level_for(&task.name) as usize,
+        syscall::SYSCALL_PROTECT_MEMORY_OBJECT => status_to_syscall_repr(protect_memory_object(task, a, b, c)),
+        syscall::SYSCALL_DUMP_ADDRESS_SPACE => status_to_syscall_repr(dump_address_space(task, a)),
 
         _ => {
             warn!("Process made system call with invalid syscall number: {}", number);
@@ -83,7 +120,20 @@ where
     0
 }
 
-fn early_log<P>(task: &Arc<Task<P>>, str_length: usize, str_address: usize) -> Result<(), EarlyLogError>
+fn exit_task<P>(task: &Arc<Task<P>>, status: usize) -> !
+where
+    P: Platform,
+{
+    info!("Task {} exited with status {}", task.name, status);
+    P::per_cpu().scheduler().exit_running_task()
+}
+
+fn early_log<P>(
+    task: &Arc<Task<P>>,
+    str_length: usize,
+    str_address: usize,
+    level: usize,
+) -> Result<(), EarlyLogError>
 where
     P: Platform,
 {
@@ -99,10 +149,20 @@ where
 
     // Check the message is valid UTF-8
     let message = UserString::new(str_address as *mut u8, str_length)
-        .validate()
+        .read(task)
         .map_err(|_| EarlyLogError::MessageNotValidUtf8)?;
 
-    trace!("Early log message from {}: {}", task.name, message);
+    let level = match level {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        5 => Level::Trace,
+        _ => return Err(EarlyLogError::InvalidLevel),
+    };
+
+    klog::record(level, task.id(), P::timer().monotonic_time(), P::cpu_id(), format_args!("{}", message));
+    log!(target: klog::TASK_LOG_TARGET, level, "Early log message from {}: {}", task.name, message);
     Ok(())
 }
 
@@ -121,7 +181,7 @@ where
     let handle = task.add_handle(memory_object.clone());
 
     UserPointer::new(info_address as *mut FramebufferInfo, true)
-        .write(*info)
+        .write(task, *info)
         .map_err(|()| GetFramebufferError::InfoAddressIsInvalid)?;
 
     Ok(handle)
@@ -139,16 +199,13 @@ where
     let writable = flags.get_bit(0);
     let executable = flags.get_bit(1);
 
-    // TODO: do something more sensible with this when we have a concept of physical memory "ownership"
-    let physical_start = crate::PHYSICAL_MEMORY_MANAGER.get().alloc_bytes(size);
-
-    let memory_object = MemoryObject::new(
+    let memory_object = MemoryObject::allocate(
         task.id(),
         VirtualAddress::new(virtual_address),
-        physical_start,
         size,
         Flags { writable, executable, user_accessible: true, ..Default::default() },
-    );
+    )
+    .ok_or(CreateMemoryObjectError::InvalidSize)?;
 
     Ok(task.add_handle(memory_object))
 }
@@ -202,13 +259,63 @@ where
     if address_ptr != 0x0 {
         let mut address_ptr = UserPointer::new(address_ptr as *mut VirtualAddress, true);
         address_ptr
-            .write(memory_object.virtual_address)
+            .write(task, memory_object.virtual_address)
             .map_err(|()| MapMemoryObjectError::AddressPointerInvalid)?;
     }
 
     Ok(())
 }
 
+fn protect_memory_object<P>(
+    task: &Arc<Task<P>>,
+    memory_object_handle: usize,
+    address_space_handle: usize,
+    flags: usize,
+) -> Result<(), ProtectMemoryObjectError>
+where
+    P: Platform,
+{
+    let memory_object_handle =
+        Handle::try_from(memory_object_handle).map_err(|_| ProtectMemoryObjectError::InvalidHandle)?;
+    let address_space_handle =
+        Handle::try_from(address_space_handle).map_err(|_| ProtectMemoryObjectError::InvalidHandle)?;
+
+    if flags & !0b11 != 0 {
+        return Err(ProtectMemoryObjectError::InvalidFlags);
+    }
+    let writable = flags.get_bit(0);
+    let executable = flags.get_bit(1);
+
+    let memory_object = task
+        .handles
+        .read()
+        .get(&memory_object_handle)
+        .ok_or(ProtectMemoryObjectError::InvalidHandle)?
+        .clone()
+        .downcast_arc::<MemoryObject>()
+        .ok()
+        .ok_or(ProtectMemoryObjectError::NotAMemoryObject)?;
+
+    if address_space_handle == ZERO_HANDLE {
+        task.address_space.protect_memory_object(
+            &memory_object,
+            writable,
+            executable,
+            &crate::PHYSICAL_MEMORY_MANAGER.get(),
+        )
+    } else {
+        task.handles
+            .read()
+            .get(&address_space_handle)
+            .ok_or(ProtectMemoryObjectError::InvalidHandle)?
+            .clone()
+            .downcast_arc::<AddressSpace<P>>()
+            .ok()
+            .ok_or(ProtectMemoryObjectError::NotAnAddressSpace)?
+            .protect_memory_object(&memory_object, writable, executable, &crate::PHYSICAL_MEMORY_MANAGER.get())
+    }
+}
+
 fn send_message<P>(
     task: &Arc<Task<P>>,
     channel_handle: usize,
@@ -231,17 +338,17 @@ where
 
     let channel_handle = Handle::try_from(channel_handle).map_err(|_| SendMessageError::InvalidChannelHandle)?;
     let bytes = if num_bytes == 0 {
-        &[]
+        Vec::new()
     } else {
         UserSlice::new(byte_address as *mut u8, num_bytes)
-            .validate_read()
+            .read(task)
             .map_err(|()| SendMessageError::BytesAddressInvalid)?
     };
     let handles = if num_handles == 0 {
-        &[]
+        Vec::new()
     } else {
         UserSlice::new(handles_address as *mut Handle, num_handles)
-            .validate_read()
+            .read(task)
             .map_err(|()| SendMessageError::HandlesAddressInvalid)?
     };
     let handle_objects = {
@@ -268,7 +375,7 @@ where
         .downcast_arc::<ChannelEnd>()
         .ok()
         .ok_or(SendMessageError::NotAChannel)?
-        .send(Message { bytes: bytes.to_vec(), handle_objects })
+        .send(Message { bytes, handle_objects })
 }
 
 fn get_message<P>(
@@ -304,24 +411,32 @@ where
             return Err((message, GetMessageError::HandlesBufferTooSmall));
         }
 
+        /*
+         * We make sure both buffers can be written to before we write anything, so we don't consume the message
+         * (or issue handles for the objects it transfers) if we're going to fail.
+         */
+        let mut byte_buffer = UserSlice::new(bytes_address as *mut u8, message.bytes.len());
+        let mut handles_buffer = UserSlice::new(handles_address as *mut Handle, num_handles);
+        if bytes_len > 0 && bytes_address != 0x0 && byte_buffer.validate_write(task).is_err() {
+            return Err((message, GetMessageError::BytesAddressInvalid));
+        }
+        if handles_len > 0 && handles_address != 0x0 && handles_buffer.validate_write(task).is_err() {
+            return Err((message, GetMessageError::HandlesAddressInvalid));
+        }
+
+        /*
+         * If userspace unmaps the buffers after we've validated them, the message is still consumed.
+         */
         if bytes_len > 0 && bytes_address != 0x0 {
-            let byte_buffer = match UserSlice::new(bytes_address as *mut u8, message.bytes.len()).validate_write()
-            {
-                Ok(buffer) => buffer,
-                Err(()) => return Err((message, GetMessageError::BytesAddressInvalid)),
-            };
-            byte_buffer.copy_from_slice(&message.bytes);
+            let _ = byte_buffer.write(task, &message.bytes);
         }
 
         if handles_len > 0 && handles_address != 0x0 {
-            let handles_buffer = match UserSlice::new(handles_address as *mut Handle, num_handles).validate_write()
-            {
-                Ok(buffer) => buffer,
-                Err(()) => return Err((message, GetMessageError::HandlesAddressInvalid)),
-            };
-            for i in 0..num_handles {
-                handles_buffer[i] = task.add_handle(message.handle_objects[i].as_ref().unwrap().clone());
-            }
+            let handles: Vec<Handle> = message.handle_objects[0..num_handles]
+                .iter()
+                .map(|object| task.add_handle(object.as_ref().unwrap().clone()))
+                .collect();
+            let _ = handles_buffer.write(task, &handles);
         }
 
         let mut status = 0;
@@ -331,6 +446,243 @@ where
     })
 }
 
+fn wait_for_message<P>(
+    task: &Arc<Task<P>>,
+    channel_handle: usize,
+    deadline: usize,
+) -> Result<(), WaitForMessageError>
+where
+    P: Platform,
+{
+    let channel_handle =
+        Handle::try_from(channel_handle).map_err(|_| WaitForMessageError::InvalidChannelHandle)?;
+
+    let channel = task
+        .handles
+        .read()
+        .get(&channel_handle)
+        .ok_or(WaitForMessageError::InvalidChannelHandle)?
+        .clone()
+        .downcast_arc::<ChannelEnd>()
+        .ok()
+        .ok_or(WaitForMessageError::NotAChannel)?;
+
+    /*
+     * A deadline of `usize::MAX` is used to wait for a message without a timeout.
+     */
+    let deadline = if deadline == usize::MAX { None } else { Some(deadline as u64) };
+
+    if !channel.has_messages() && deadline.map_or(true, |deadline| P::timer().monotonic_time() < deadline) {
+        P::per_cpu()
+            .scheduler()
+            .switch_to_next(TaskState::Blocked(TaskBlock::WaitForMessage { channel: channel.clone(), deadline }));
+    }
+
+    if channel.has_messages() {
+        Ok(())
+    } else {
+        Err(WaitForMessageError::TimedOut)
+    }
+}
+
+fn sleep_until<P>(deadline: usize) -> usize
+where
+    P: Platform,
+{
+    let deadline = deadline as u64;
+
+    if P::timer().monotonic_time() < deadline {
+        P::per_cpu().scheduler().switch_to_next(TaskState::Blocked(TaskBlock::Sleep(deadline)));
+    }
+
+    0
+}
+
+fn create_event<P>(task: &Arc<Task<P>>) -> Result<Handle, EventError>
+where
+    P: Platform,
+{
+    Ok(task.add_handle(Event::new(task.id())))
+}
+
+fn signal_event<P>(task: &Arc<Task<P>>, event_handle: usize, bits: usize) -> Result<(), EventError>
+where
+    P: Platform,
+{
+    get_event(task, event_handle)?.signal(bits as u32);
+    Ok(())
+}
+
+fn clear_event<P>(task: &Arc<Task<P>>, event_handle: usize, bits: usize) -> Result<(), EventError>
+where
+    P: Platform,
+{
+    get_event(task, event_handle)?.clear(bits as u32);
+    Ok(())
+}
+
+fn wait_for_event<P>(
+    task: &Arc<Task<P>>,
+    event_handle: usize,
+    mask: usize,
+    deadline: usize,
+) -> Result<usize, EventError>
+where
+    P: Platform,
+{
+    let event = get_event(task, event_handle)?;
+    let mask = mask as u32;
+
+    /*
+     * A deadline of `usize::MAX` is used to wait for the event without a timeout.
+     */
+    let deadline = if deadline == usize::MAX { None } else { Some(deadline as u64) };
+
+    if (event.signals() & mask) == 0 && deadline.map_or(true, |deadline| P::timer().monotonic_time() < deadline) {
+        P::per_cpu().scheduler().switch_to_next(TaskState::Blocked(TaskBlock::WaitForEvent {
+            event: event.clone(),
+            mask,
+            deadline,
+        }));
+    }
+
+    let signals = event.signals() & mask;
+    if signals == 0 {
+        return Err(EventError::TimedOut);
+    }
+
+    let mut status = 0;
+    status.set_bits(32..64, signals as usize);
+    Ok(status)
+}
+
+fn get_event<P>(task: &Arc<Task<P>>, event_handle: usize) -> Result<Arc<Event>, EventError>
+where
+    P: Platform,
+{
+    let event_handle = Handle::try_from(event_handle).map_err(|_| EventError::InvalidHandle)?;
+
+    task.handles
+        .read()
+        .get(&event_handle)
+        .ok_or(EventError::InvalidHandle)?
+        .clone()
+        .downcast_arc::<Event>()
+        .ok()
+        .ok_or(EventError::NotAnEvent)
+}
+
+fn create_interrupt<P>(task: &Arc<Task<P>>, gsi: usize) -> Result<Handle, CreateInterruptError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(&Capability::HandleInterrupts) {
+        return Err(CreateInterruptError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let gsi = u32::try_from(gsi).map_err(|_| CreateInterruptError::InvalidGsi)?;
+    let interrupt = Interrupt::<P>::from_gsi(task.id(), gsi).map_err(|err| match err {
+        InterruptRoutingError::InvalidGsi => CreateInterruptError::InvalidGsi,
+        InterruptRoutingError::AlreadyRouted => CreateInterruptError::AlreadyInUse,
+        InterruptRoutingError::NoFreeVectors => CreateInterruptError::NoFreeVectors,
+    })?;
+
+    info!("Task {} is now handling interrupt with GSI {}", task.name, gsi);
+    Ok(task.add_handle(interrupt))
+}
+
+fn create_pci_interrupt<P>(
+    task: &Arc<Task<P>>,
+    address: usize,
+    index: usize,
+) -> Result<Handle, CreatePciInterruptError>
+where
+    P: Platform,
+{
+    use crate::pci::MsiError;
+    use libpebble::syscall::pci::pci_address_from_syscall_repr;
+
+    /*
+     * Creating an interrupt reprograms the device, so only the PCI bus driver, which decides which driver each
+     * device is handed to, can do it. It can then pass the `Interrupt` on to the device's driver.
+     */
+    if !task.capabilities.contains(&Capability::HandleInterrupts)
+        || !task.capabilities.contains(&Capability::PciBusDriver)
+    {
+        return Err(CreatePciInterruptError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let address = pci_address_from_syscall_repr(address);
+    let index = u16::try_from(index).map_err(|_| CreatePciInterruptError::InvalidIndex)?;
+    let interrupt = Interrupt::<P>::from_pci_device(task.id(), address, index).map_err(|err| match err {
+        PciInterruptError::PlatformDoesNotSupportPci => CreatePciInterruptError::PlatformDoesNotSupportPci,
+        PciInterruptError::InvalidDevice => CreatePciInterruptError::InvalidDevice,
+        PciInterruptError::Msi(MsiError::NotSupported) => CreatePciInterruptError::MsiNotSupported,
+        PciInterruptError::Msi(MsiError::InvalidIndex) => CreatePciInterruptError::InvalidIndex,
+        PciInterruptError::Msi(MsiError::AlreadyInUse) => CreatePciInterruptError::AlreadyInUse,
+        PciInterruptError::Routing(_) => CreatePciInterruptError::NoFreeVectors,
+    })?;
+
+    info!("Task {} is now handling MSI {} of PCI device {}", task.name, index, address);
+    Ok(task.add_handle(interrupt))
+}
+
+fn wait_for_interrupt<P>(
+    task: &Arc<Task<P>>,
+    interrupt_handle: usize,
+    deadline: usize,
+) -> Result<(), InterruptError>
+where
+    P: Platform,
+{
+    let interrupt = get_interrupt(task, interrupt_handle)?;
+
+    /*
+     * A deadline of `usize::MAX` is used to wait for the interrupt without a timeout.
+     */
+    let deadline = if deadline == usize::MAX { None } else { Some(deadline as u64) };
+
+    if (interrupt.event.signals() & INTERRUPT_SIGNAL) == 0
+        && deadline.map_or(true, |deadline| P::timer().monotonic_time() < deadline)
+    {
+        P::per_cpu().scheduler().switch_to_next(TaskState::Blocked(TaskBlock::WaitForEvent {
+            event: interrupt.event.clone(),
+            mask: INTERRUPT_SIGNAL,
+            deadline,
+        }));
+    }
+
+    if (interrupt.event.signals() & INTERRUPT_SIGNAL) == 0 {
+        return Err(InterruptError::TimedOut);
+    }
+
+    Ok(())
+}
+
+fn ack_interrupt<P>(task: &Arc<Task<P>>, interrupt_handle: usize) -> Result<(), InterruptError>
+where
+    P: Platform,
+{
+    get_interrupt(task, interrupt_handle)?.acknowledge();
+    Ok(())
+}
+
+fn get_interrupt<P>(task: &Arc<Task<P>>, interrupt_handle: usize) -> Result<Arc<Interrupt<P>>, InterruptError>
+where
+    P: Platform,
+{
+    let interrupt_handle = Handle::try_from(interrupt_handle).map_err(|_| InterruptError::InvalidHandle)?;
+
+    task.handles
+        .read()
+        .get(&interrupt_handle)
+        .ok_or(InterruptError::InvalidHandle)?
+        .clone()
+        .downcast_arc::<Interrupt<P>>()
+        .ok()
+        .ok_or(InterruptError::NotAnInterrupt)
+}
+
 fn register_service<P>(
     task: &Arc<Task<P>>,
     name_length: usize,
@@ -352,12 +704,12 @@ where
     }
 
     let service_name = UserString::new(name_ptr as *mut u8, name_length)
-        .validate()
+        .read(task)
         .map_err(|()| RegisterServiceError::NamePointerNotValid)?;
 
     info!("Task {} has registered a service called {}", task.name, service_name);
     let channel = ChannelEnd::new_kernel_channel(task.id());
-    SERVICE_MAP.lock().insert(task.name.clone() + "." + service_name, channel.clone());
+    SERVICE_MAP.lock().insert(task.name.clone() + "." + &service_name, channel.clone());
 
     Ok(task.add_handle(channel))
 }
@@ -383,10 +735,10 @@ where
     }
 
     let service_name = UserString::new(name_ptr as *mut u8, name_length)
-        .validate()
+        .read(task)
         .map_err(|()| SubscribeToServiceError::NamePointerNotValid)?;
 
-    if let Some(register_channel) = SERVICE_MAP.lock().get(service_name) {
+    if let Some(register_channel) = SERVICE_MAP.lock().get(&service_name) {
         // Create new channel to allow the two tasks to communicate
         let (provider_end, user_end) = ChannelEnd::new_channel(task.id());
 
@@ -405,6 +757,86 @@ where
     }
 }
 
+fn dump_address_space<P>(task: &Arc<Task<P>>, address_space_handle: usize) -> Result<(), DumpAddressSpaceError>
+where
+    P: Platform,
+{
+    /*
+     * The dump includes the physical addresses that memory is mapped to, so only trusted tasks can make it.
+     */
+    if !task.capabilities.contains(&Capability::DumpAddressSpace) {
+        return Err(DumpAddressSpaceError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let address_space_handle =
+        Handle::try_from(address_space_handle).map_err(|_| DumpAddressSpaceError::InvalidHandle)?;
+
+    let address_space = if address_space_handle == ZERO_HANDLE {
+        task.address_space.clone()
+    } else {
+        task.handles
+            .read()
+            .get(&address_space_handle)
+            .ok_or(DumpAddressSpaceError::InvalidHandle)?
+            .clone()
+            .downcast_arc::<AddressSpace<P>>()
+            .ok()
+            .ok_or(DumpAddressSpaceError::NotAnAddressSpace)?
+    };
+
+    info!("Memory mapped into address space {:?} (dumped by task {}):", address_space.id, task.name);
+    address_space.walk_user_mappings(|mapping| info!("    {}", mapping));
+    Ok(())
+}
+
+fn subscribe_to_faults<P>(task: &Arc<Task<P>>) -> Result<Handle, SubscribeToFaultsError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(&Capability::Supervisor) {
+        return Err(SubscribeToFaultsError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let mut supervisor = crate::FAULT_SUPERVISOR.lock();
+    if supervisor.as_ref().map_or(false, |supervisor| supervisor.strong_count() > 0) {
+        return Err(SubscribeToFaultsError::AlreadySubscribed);
+    }
+
+    info!("Task {} has subscribed to faults", task.name);
+    let channel = ChannelEnd::new_kernel_channel(task.id());
+    *supervisor = Some(Arc::downgrade(&channel));
+
+    Ok(task.add_handle(channel))
+}
+
+fn read_kernel_log<P>(
+    task: &Arc<Task<P>>,
+    first_sequence: usize,
+    buffer_address: usize,
+    buffer_length: usize,
+) -> Result<usize, ReadKernelLogError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(&Capability::ReadKernelLog) {
+        return Err(ReadKernelLogError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let mut buffer = UserSlice::new(buffer_address as *mut KernelLogRecord, buffer_length);
+    buffer.validate_write(task).map_err(|()| ReadKernelLogError::BufferPointerInvalid)?;
+
+    /*
+     * We read the records into the kernel first, so we don't hold the log's lock while we copy them to userspace.
+     */
+    let mut records = vec![KernelLogRecord::EMPTY; buffer_length.min(klog::KERNEL_LOG_NUM_RECORDS)];
+    let num_records = klog::read(first_sequence as u64, &mut records);
+    buffer.write(task, &records[0..num_records]).map_err(|()| ReadKernelLogError::BufferPointerInvalid)?;
+
+    let mut status = 0;
+    status.set_bits(16..48, num_records);
+    Ok(status)
+}
+
 fn pci_get_info<P>(
     task: &Arc<Task<P>>,
     buffer_address: usize,
@@ -428,12 +860,10 @@ where
                 return Err(PciGetInfoError::BufferNotLargeEnough(num_descriptors as u32));
             }
 
-            let descriptor_buffer = UserSlice::new(buffer_address as *mut PciDeviceInfo, buffer_size)
-                .validate_write()
-                .map_err(|()| PciGetInfoError::BufferPointerInvalid)?;
-
-            for (i, (&address, device)) in pci_info.devices.iter().enumerate() {
-                descriptor_buffer[i] = libpebble::syscall::PciDeviceInfo {
+            let descriptors: Vec<PciDeviceInfo> = pci_info
+                .devices
+                .iter()
+                .map(|(&address, device)| PciDeviceInfo {
                     address,
                     vendor_id: device.vendor_id,
                     device_id: device.device_id,
@@ -441,8 +871,13 @@ where
                     class: device.class,
                     sub_class: device.sub_class,
                     interface: device.interface,
-                };
-            }
+                    has_gsi: device.gsi.is_some() as u8,
+                    gsi: device.gsi.unwrap_or(0),
+                })
+                .collect();
+            UserSlice::new(buffer_address as *mut PciDeviceInfo, buffer_size)
+                .write(task, &descriptors)
+                .map_err(|()| PciGetInfoError::BufferPointerInvalid)?;
 
             let mut status = 0;
             status.set_bits(16..48, num_descriptors);
@@ -454,3 +889,119 @@ where
         Err(PciGetInfoError::PlatformDoesNotSupportPci)
     }
 }
+
+fn create_task<P>(
+    task: &Arc<Task<P>>,
+    elf_handle: usize,
+    name_length: usize,
+    name_ptr: usize,
+    bootstrap_channel_ptr: usize,
+) -> Result<Handle, CreateTaskError>
+where
+    P: Platform,
+{
+    use libpebble::syscall::{BOOTSTRAP_CHANNEL_HANDLE, TASK_NAME_MAX_LENGTH};
+
+    // Check that the task has the `CreateTask` capability
+    if !task.capabilities.contains(&Capability::CreateTask) {
+        return Err(CreateTaskError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    // Check that the name is not too short or long
+    if name_length == 0 || name_length > TASK_NAME_MAX_LENGTH {
+        return Err(CreateTaskError::NameLengthNotValid);
+    }
+
+    let name = UserString::new(name_ptr as *mut u8, name_length)
+        .read(task)
+        .map_err(|()| CreateTaskError::NamePointerNotValid)?;
+
+    /*
+     * Check that we'll be able to tell the caller about its end of the bootstrap channel before we create
+     * anything, so we don't leave it holding a handle it doesn't know about.
+     */
+    let mut bootstrap_channel_ptr = UserPointer::new(bootstrap_channel_ptr as *mut Handle, true);
+    bootstrap_channel_ptr.validate_write(task).map_err(|()| CreateTaskError::BootstrapChannelAddressInvalid)?;
+
+    let elf_handle = Handle::try_from(elf_handle).map_err(|_| CreateTaskError::InvalidHandle)?;
+    let elf_memory_object = task
+        .handles
+        .read()
+        .get(&elf_handle)
+        .ok_or(CreateTaskError::InvalidHandle)?
+        .clone()
+        .downcast_arc::<MemoryObject>()
+        .ok()
+        .ok_or(CreateTaskError::NotAMemoryObject)?;
+
+    /*
+     * `MemoryObject`s are physically contiguous, so we can access the image through the kernel's mapping of
+     * physical memory, whether or not it's mapped into the calling task's address space. The new task's
+     * capabilities are taken from the image, but it can't be given any that the calling task doesn't have.
+     */
+    let elf_bytes = unsafe {
+        core::slice::from_raw_parts(
+            P::physical_to_virtual(elf_memory_object.physical_address).ptr(),
+            elf_memory_object.size,
+        )
+    };
+    let new_task =
+        Task::from_elf(task.id(), name, elf_bytes, &task.capabilities, &crate::PHYSICAL_MEMORY_MANAGER.get())
+            .map_err(|err| match err {
+                TaskCreationError::InvalidName | TaskCreationError::NameTooLong => {
+                    CreateTaskError::NameLengthNotValid
+                }
+                TaskCreationError::InvalidCapabilityEncoding => CreateTaskError::InvalidCapabilityEncoding,
+                TaskCreationError::AddressSpaceFull | TaskCreationError::NoKernelStackSlots => {
+                    CreateTaskError::TooManyTasks
+                }
+                TaskCreationError::InvalidElf => CreateTaskError::InvalidElf,
+                TaskCreationError::ImageTooLarge => CreateTaskError::ImageTooLarge,
+                TaskCreationError::CapabilityNotAllowed => CreateTaskError::CapabilityNotAllowed,
+                TaskCreationError::OutOfMemory => CreateTaskError::OutOfMemory,
+            })?;
+
+    /*
+     * Create the bootstrap channel. The new task's end is the first handle it's issued, so it always knows where
+     * to find it.
+     */
+    let (our_end, their_end) = ChannelEnd::new_channel(task.id());
+    assert_eq!(new_task.add_handle(their_end), BOOTSTRAP_CHANNEL_HANDLE);
+    let our_end = task.add_handle(our_end);
+    if bootstrap_channel_ptr.write(task, our_end).is_err() {
+        /*
+         * This can only happen if the caller has changed its mappings since we checked the pointer. The new task
+         * hasn't been scheduled yet, so we can still throw it away.
+         */
+        task.handles.write().remove(&our_end);
+        return Err(CreateTaskError::BootstrapChannelAddressInvalid);
+    }
+
+    info!("Task {} created a new task called {}", task.name, new_task.name);
+    P::per_cpu().scheduler().add_task(new_task.clone());
+    Ok(task.add_handle(new_task))
+}
+
+fn spawn_thread<P>(task: &Arc<Task<P>>, entry_point: usize, argument: usize) -> Result<Handle, SpawnThreadError>
+where
+    P: Platform,
+{
+    /*
+     * We need to make sure the entry point is in userspace, as we'd otherwise enter usermode at an address in the
+     * kernel (or at a non-canonical address, which faults in the kernel on some processors).
+     */
+    if entry_point >= usize::from(P::USER_ADDRESS_SPACE_END) {
+        return Err(SpawnThreadError::InvalidEntryPoint);
+    }
+
+    let thread = task
+        .spawn_thread(VirtualAddress::new(entry_point), argument, &crate::PHYSICAL_MEMORY_MANAGER.get())
+        .map_err(|err| match err {
+            ThreadCreationError::AddressSpaceFull => SpawnThreadError::AddressSpaceFull,
+            ThreadCreationError::NoKernelStackSlots => SpawnThreadError::TooManyThreads,
+            ThreadCreationError::OutOfMemory => SpawnThreadError::OutOfMemory,
+        })?;
+
+    P::per_cpu().scheduler().add_task(thread.clone());
+    Ok(task.add_handle(thread))
+}
@@ -3,80 +3,168 @@
 //! address from userspace, we should make sure it's mapped (so we don't page-fault) and an address
 //! that userspace could ordinarily access itself (otherwise, we could leak information to a
 //! userspace task that it shouldn't be able to access).
+//!
+//! Memory is validated against the calling task's `AddressSpace`, and then copied into or out of the kernel with
+//! `Platform::copy_user`. Userspace can still unmap memory from another thread after it's been validated, so
+//! this copy can fail too - we never hand out references to userspace memory.
 
-use core::{marker::PhantomData, ptr, slice, str};
+use crate::{object::task::Task, Platform};
+use alloc::{string::String, vec::Vec};
+use core::mem;
+use hal::memory::VirtualAddress;
 
+/// Check that `task` can access the `length` bytes at `address` from userspace, and that `address` is aligned to
+/// `alignment`. If `write` is set, the memory must also be writable.
+fn validate<P>(task: &Task<P>, address: usize, length: usize, alignment: usize, write: bool) -> Result<(), ()>
+where
+    P: Platform,
+{
+    if length == 0 {
+        return Ok(());
+    }
+    if address % alignment != 0 {
+        return Err(());
+    }
+
+    /*
+     * Creating a `VirtualAddress` canonicalises it, which could turn an invalid address into a valid kernel one,
+     * so we check that both ends of the range are canonical userspace addresses first.
+     */
+    let last = address.checked_add(length - 1).ok_or(())?;
+    let is_user_address = |address: usize| {
+        let virtual_address = VirtualAddress::new(address);
+        usize::from(virtual_address) == address && virtual_address < P::USER_ADDRESS_SPACE_END
+    };
+    if !is_user_address(address) || !is_user_address(last) {
+        return Err(());
+    }
+
+    if task.address_space.is_user_accessible(VirtualAddress::new(address), length, write) {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// Represents a pointer to a `T` in userspace. `T` should be a plain-old-data type - when a `T` is read from
+/// userspace, any bit pattern must be a valid `T`.
 pub struct UserPointer<T> {
     ptr: *mut T,
     can_write: bool,
 }
 
-impl<T> UserPointer<T> {
+impl<T> UserPointer<T>
+where
+    T: Copy,
+{
     pub fn new(ptr: *mut T, needs_write: bool) -> UserPointer<T> {
-        // TODO: validate that this is a valid pointer:
-        //  - the address is canonical
-        //  - the address is in user-space
-        //  - the address is actually mapped for a size of `T`
-        //  - the address is correctly aligned for `T`
-        //  - if we're writing, that the mapping is writable
         UserPointer { ptr, can_write: needs_write }
     }
 
-    pub fn read(&self) -> Result<T, ()> {
-        Ok(unsafe { ptr::read_volatile(self.ptr) })
+    pub fn read<P>(&self, task: &Task<P>) -> Result<T, ()>
+    where
+        P: Platform,
+    {
+        validate(task, self.ptr as usize, mem::size_of::<T>(), mem::align_of::<T>(), false)?;
+
+        let mut value = mem::MaybeUninit::<T>::uninit();
+        unsafe {
+            P::copy_user(value.as_mut_ptr() as *mut u8, self.ptr as *const u8, mem::size_of::<T>())?;
+            Ok(value.assume_init())
+        }
     }
 
-    pub fn write(&mut self, value: T) -> Result<(), ()> {
+    /// Check that the pointer can be written through, without writing anything. Like `UserSlice::validate_write`,
+    /// this is useful when something must be done before the write that can't be undone if it fails.
+    pub fn validate_write<P>(&self, task: &Task<P>) -> Result<(), ()>
+    where
+        P: Platform,
+    {
         if !self.can_write {
             return Err(());
         }
 
-        /*
-         * This has two subtleties:
-         *    - Using `write_volatile` instead of `write` makes sure the compiler doesn't think it can elide the
-         *      write, as the data is read and written to from both the kernel and userspace.
-         *    - Using `ptr::write_volatile(x, ...)` instead of `*x = ...` makes sure we don't attempt to drop
-         *      the existing value, which could read uninitialized memory.
-         */
-        unsafe { ptr::write_volatile(self.ptr, value) }
-        Ok(())
+        validate(task, self.ptr as usize, mem::size_of::<T>(), mem::align_of::<T>(), true)
+    }
+
+    pub fn write<P>(&mut self, task: &Task<P>, value: T) -> Result<(), ()>
+    where
+        P: Platform,
+    {
+        self.validate_write(task)?;
+        unsafe { P::copy_user(self.ptr as *mut u8, &value as *const T as *const u8, mem::size_of::<T>()) }
     }
 }
 
-/// Represents a slice of `T`s in userspace.
-pub struct UserSlice<'a, T> {
+/// Represents a slice of `T`s in userspace. Like `UserPointer`, `T` should be a plain-old-data type.
+pub struct UserSlice<T> {
     ptr: *mut T,
     length: usize,
-    _phantom: PhantomData<&'a ()>,
 }
 
-impl<'a, T> UserSlice<'a, T> {
-    pub fn new(ptr: *mut T, length: usize) -> UserSlice<'a, T> {
-        UserSlice { ptr, length, _phantom: PhantomData }
+impl<T> UserSlice<T>
+where
+    T: Copy,
+{
+    pub fn new(ptr: *mut T, length: usize) -> UserSlice<T> {
+        UserSlice { ptr, length }
+    }
+
+    /// Copy the contents of the slice into the kernel.
+    pub fn read<P>(&self, task: &Task<P>) -> Result<Vec<T>, ()>
+    where
+        P: Platform,
+    {
+        let size = mem::size_of::<T>().checked_mul(self.length).ok_or(())?;
+        validate(task, self.ptr as usize, size, mem::align_of::<T>(), false)?;
+
+        let mut values = Vec::with_capacity(self.length);
+        unsafe {
+            P::copy_user(values.as_mut_ptr() as *mut u8, self.ptr as *const u8, size)?;
+            values.set_len(self.length);
+        }
+        Ok(values)
     }
 
-    pub fn validate_read(&self) -> Result<&'a [T], ()> {
-        // TODO: validate access is valid
-        Ok(unsafe { slice::from_raw_parts(self.ptr, self.length) })
+    /// Check that the whole slice can be written to, without writing anything to it. This is useful when
+    /// something must be done before writing to the slice that can't be undone if the write fails - it's still
+    /// possible for `write` to fail afterwards, but only if userspace changes its mappings in the meantime.
+    pub fn validate_write<P>(&self, task: &Task<P>) -> Result<(), ()>
+    where
+        P: Platform,
+    {
+        let size = mem::size_of::<T>().checked_mul(self.length).ok_or(())?;
+        validate(task, self.ptr as usize, size, mem::align_of::<T>(), true)
     }
 
-    /// Validate this slice for a write, BUT DOES NOT ACTUALLY WRITE ANYTHING INTO IT. You must write into the
-    /// returned mutable reference, generally using either `copy_from_slice` if `T: Copy`, or `clone_from_slice`
-    /// otherwise.
-    pub fn validate_write(&mut self) -> Result<&'a mut [T], ()> {
-        // TODO: validate access is valid
-        Ok(unsafe { slice::from_raw_parts_mut(self.ptr, self.length) })
+    /// Copy `values` into the start of the slice. Fails if there are more `values` than will fit in the slice.
+    pub fn write<P>(&mut self, task: &Task<P>, values: &[T]) -> Result<(), ()>
+    where
+        P: Platform,
+    {
+        if values.len() > self.length {
+            return Err(());
+        }
+
+        let size = mem::size_of::<T>() * values.len();
+        validate(task, self.ptr as usize, size, mem::align_of::<T>(), true)?;
+        unsafe { P::copy_user(self.ptr as *mut u8, values.as_ptr() as *const u8, size) }
     }
 }
 
-pub struct UserString<'a>(UserSlice<'a, u8>);
+/// Represents a UTF-8 string in userspace.
+pub struct UserString(UserSlice<u8>);
 
-impl<'a> UserString<'a> {
-    pub fn new(ptr: *mut u8, length: usize) -> UserString<'a> {
+impl UserString {
+    pub fn new(ptr: *mut u8, length: usize) -> UserString {
         UserString(UserSlice::new(ptr, length))
     }
 
-    pub fn validate(&self) -> Result<&'a str, ()> {
-        str::from_utf8(self.0.validate_read()?).map_err(|_| ())
+    /// Copy the string into the kernel. Fails if the memory can't be read, or if the string is not valid UTF-8.
+    pub fn read<P>(&self, task: &Task<P>) -> Result<String, ()>
+    where
+        P: Platform,
+    {
+        String::from_utf8(self.0.read(task)?).map_err(|_| ())
     }
 }
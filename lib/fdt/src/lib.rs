@@ -0,0 +1,280 @@
+//! A parser for Flattened Device Trees (FDTs, also called Device Tree Blobs), which describe the hardware of
+//! platforms that can't discover it themselves (and don't use ACPI). It doesn't allocate, so it can be used by the
+//! kernel before it has a heap.
+//!
+//! The format is described by the [Devicetree Specification](https://www.devicetree.org/specifications/). All
+//! values in the blob are big-endian.
+
+#![no_std]
+
+mod node;
+
+pub use node::{Node, Property, Region};
+
+use core::{convert::TryInto, slice, str};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+
+/// The version of the format we parse. Version 17 added the size of the structure block to the header, which we
+/// rely on to find the end of the block, and everything we're likely to be given is version 17. Newer versions must
+/// stay backwards-compatible with it, so we can parse any blob whose last compatible version is 17 or older.
+const VERSION: u32 = 17;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// The maximum depth of the tree we support. We need to keep track of a node's ancestors while we walk the tree
+/// (for example, to find its parent), and we do so without allocating.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FdtError {
+    /// The blob is too short to contain the header, or the size it says it is.
+    TooShort,
+    /// The magic number at the start of the blob (should be `0xd00dfeed`) is incorrect.
+    IncorrectMagic,
+    /// The blob is in a version of the format that we can't parse.
+    IncompatibleVersion(u32),
+    /// One of the blocks the header describes doesn't fit within the blob.
+    InvalidBlock,
+    /// The structure block doesn't start with a root node.
+    NoRootNode,
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    bytes: &'a [u8],
+    structure: &'a [u8],
+    strings: &'a [u8],
+    memory_reservations: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Fdt<'a>, FdtError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(FdtError::TooShort);
+        }
+
+        let header_field = |index: usize| be_u32(bytes, index * 4).unwrap();
+        if header_field(0) != FDT_MAGIC {
+            return Err(FdtError::IncorrectMagic);
+        }
+
+        let total_size = header_field(1) as usize;
+        if bytes.len() < total_size {
+            return Err(FdtError::TooShort);
+        }
+        let bytes = &bytes[0..total_size];
+
+        /*
+         * The header contains the version of the blob, and the oldest version it's backwards-compatible with.
+         */
+        let version = header_field(5);
+        if version < VERSION {
+            return Err(FdtError::IncompatibleVersion(version));
+        }
+        let last_compatible_version = header_field(6);
+        if last_compatible_version > VERSION {
+            return Err(FdtError::IncompatibleVersion(last_compatible_version));
+        }
+
+        let block = |offset: u32, size: usize| {
+            let offset = offset as usize;
+            let end = offset.checked_add(size).ok_or(FdtError::InvalidBlock)?;
+            bytes.get(offset..end).ok_or(FdtError::InvalidBlock)
+        };
+        let structure = block(header_field(2), header_field(9) as usize)?;
+        let strings = block(header_field(3), header_field(8) as usize)?;
+        /*
+         * The memory reservation block doesn't have a size - it's terminated by an empty entry - so we let it run
+         * to the end of the blob, and stop at the terminator when we iterate over it.
+         */
+        let memory_reservations = block(header_field(4), total_size.saturating_sub(header_field(4) as usize))?;
+
+        /*
+         * Check that there's a root node, so that `root` can't fail.
+         */
+        let fdt = Fdt { bytes, structure, strings, memory_reservations };
+        if NodeIter::new(fdt).next().is_none() {
+            return Err(FdtError::NoRootNode);
+        }
+
+        Ok(fdt)
+    }
+
+    /// Create an `Fdt` from the blob at `address`, which is usually passed to the kernel by the firmware or
+    /// bootloader. The size of the blob is read from its header.
+    ///
+    /// ### Safety
+    /// `address` must point to a valid device tree blob, which must remain valid for `'a`.
+    pub unsafe fn from_ptr(address: *const u8) -> Result<Fdt<'a>, FdtError> {
+        let header = slice::from_raw_parts(address, HEADER_SIZE);
+        if be_u32(header, 0).unwrap() != FDT_MAGIC {
+            return Err(FdtError::IncorrectMagic);
+        }
+
+        let total_size = be_u32(header, 4).unwrap() as usize;
+        Fdt::new(slice::from_raw_parts(address, total_size))
+    }
+
+    /// The size of the whole blob, in bytes.
+    pub fn total_size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// The ID of the CPU that is booting, for blobs version 2 and newer.
+    pub fn boot_cpu_id(&self) -> u32 {
+        be_u32(self.bytes, 28).unwrap()
+    }
+
+    /// Iterate over the areas of physical memory that the blob says must not be used. These are given as `(start,
+    /// size)` pairs. Note that this doesn't include areas described by the `/reserved-memory` node.
+    pub fn memory_reservations(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        self.memory_reservations
+            .chunks_exact(16)
+            .map(|entry| (be_u64(entry, 0).unwrap(), be_u64(entry, 8).unwrap()))
+            .take_while(|&(address, size)| address != 0 || size != 0)
+    }
+
+    pub fn root(&self) -> Node<'a> {
+        self.all_nodes().next().expect("Device tree does not have a root node")
+    }
+
+    /// Iterate over every node in the tree, in the order they appear in the blob (so each node comes after its
+    /// parent).
+    pub fn all_nodes(&self) -> impl Iterator<Item = Node<'a>> {
+        NodeIter::new(*self)
+    }
+
+    /// Find the node at `path`, which should be absolute (e.g. `/soc/serial@7e201000`). Components of the path
+    /// that don't include a unit address (the part after the `@`) match any node with that name, and the first one
+    /// is returned.
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>> {
+        let mut node = self.root();
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            node = node.children().find(|child| {
+                child.name() == component
+                    || (!component.contains('@') && child.name().split('@').next() == Some(component))
+            })?;
+        }
+        Some(node)
+    }
+
+    /// Find the first node that is compatible with any of `compatible`.
+    pub fn find_compatible(&self, compatible: &[&str]) -> Option<Node<'a>> {
+        self.all_nodes().find(|node| node.compatible().any(|entry| compatible.contains(&entry)))
+    }
+
+    /// Find the first node with the given `phandle`, which is how properties of one node refer to another (e.g. to
+    /// say which interrupt controller it's connected to).
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        self.all_nodes()
+            .find(|node| node.property("phandle").and_then(|property| property.as_u32()) == Some(phandle))
+    }
+
+    /// The `/chosen` node, which contains parameters chosen by the firmware or bootloader, such as the kernel's
+    /// command line.
+    pub fn chosen(&self) -> Option<Node<'a>> {
+        self.find_node("/chosen")
+    }
+
+    /// Iterate over the `/memory` nodes, which describe the physical memory of the platform.
+    pub fn memory_nodes(&self) -> impl Iterator<Item = Node<'a>> {
+        self.root()
+            .children()
+            .filter(|node| node.property("device_type").and_then(|property| property.as_str()) == Some("memory"))
+    }
+
+    /// Get the string at `offset` within the strings block.
+    fn string_at(&self, offset: usize) -> Option<&'a str> {
+        read_str(self.strings.get(offset..)?)
+    }
+}
+
+/// Walks the structure block, producing each node in turn. It keeps track of the ancestors of the node it's at, so
+/// it can tell each node where its parent is.
+struct NodeIter<'a> {
+    fdt: Fdt<'a>,
+    cursor: usize,
+    /// The offsets of the nodes we're currently inside.
+    stack: [usize; MAX_DEPTH],
+    depth: usize,
+    finished: bool,
+}
+
+impl<'a> NodeIter<'a> {
+    fn new(fdt: Fdt<'a>) -> NodeIter<'a> {
+        NodeIter { fdt, cursor: 0, stack: [0; MAX_DEPTH], depth: 0, finished: false }
+    }
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let structure = self.fdt.structure;
+
+        while !self.finished {
+            let token = match be_u32(structure, self.cursor) {
+                Some(token) => token,
+                None => break,
+            };
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let offset = self.cursor;
+                    let name = read_str(&structure[(offset + 4)..])?;
+                    self.cursor = align_up(offset + 4 + name.len() + 1, 4);
+
+                    if self.depth == MAX_DEPTH {
+                        self.finished = true;
+                        return None;
+                    }
+                    let parent = if self.depth == 0 { None } else { Some(self.stack[self.depth - 1]) };
+                    self.stack[self.depth] = offset;
+                    self.depth += 1;
+
+                    return Some(Node::new(self.fdt, offset, name, self.cursor, parent));
+                }
+                FDT_END_NODE => {
+                    self.cursor += 4;
+                    self.depth = self.depth.saturating_sub(1);
+                }
+                FDT_PROP => {
+                    let length = be_u32(structure, self.cursor + 4)? as usize;
+                    self.cursor = align_up(self.cursor + 12 + length, 4);
+                }
+                FDT_NOP => self.cursor += 4,
+                FDT_END => self.finished = true,
+                /*
+                 * An unknown token means the blob is malformed, and we can't tell how to skip over it.
+                 */
+                _ => self.finished = true,
+            }
+        }
+
+        None
+    }
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(offset..(offset + 4))?.try_into().unwrap()))
+}
+
+fn be_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(offset..(offset + 8))?.try_into().unwrap()))
+}
+
+/// Read a null-terminated string from the start of `bytes`.
+fn read_str(bytes: &[u8]) -> Option<&str> {
+    let length = bytes.iter().position(|&byte| byte == 0)?;
+    str::from_utf8(&bytes[0..length]).ok()
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
@@ -0,0 +1,242 @@
+use crate::{align_up, be_u32, be_u64, read_str, Fdt, FDT_NOP, FDT_PROP};
+
+/// The values of `#address-cells` and `#size-cells` to use if a node doesn't specify them.
+const DEFAULT_ADDRESS_CELLS: usize = 2;
+const DEFAULT_SIZE_CELLS: usize = 1;
+
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    /// The offset of the node's `FDT_BEGIN_NODE` token within the structure block. This uniquely identifies the
+    /// node.
+    offset: usize,
+    name: &'a str,
+    /// The offset of the first token after the node's name, which is where its properties start.
+    properties_offset: usize,
+    /// The offset of the node's parent, or `None` if this is the root node.
+    parent: Option<usize>,
+}
+
+impl<'a> Node<'a> {
+    pub(crate) fn new(
+        fdt: Fdt<'a>,
+        offset: usize,
+        name: &'a str,
+        properties_offset: usize,
+        parent: Option<usize>,
+    ) -> Node<'a> {
+        Node { fdt, offset, name, properties_offset, parent }
+    }
+
+    /// The name of the node, including its unit address (e.g. `serial@7e201000`). The name of the root node is
+    /// empty.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.parent.is_none()
+    }
+
+    pub fn parent(&self) -> Option<Node<'a>> {
+        let parent = self.parent?;
+        self.fdt.all_nodes().find(|node| node.offset == parent)
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> {
+        let offset = self.offset;
+        self.fdt.all_nodes().filter(move |node| node.parent == Some(offset))
+    }
+
+    pub fn properties(&self) -> PropertyIter<'a> {
+        PropertyIter { fdt: self.fdt, cursor: self.properties_offset }
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property<'a>> {
+        self.properties().find(|property| property.name == name)
+    }
+
+    /// Iterate over the entries of the node's `compatible` property, which are ordered from most to least specific.
+    pub fn compatible(&self) -> impl Iterator<Item = &'a str> {
+        self.property("compatible").into_iter().flat_map(|property| property.as_str_list())
+    }
+
+    /// Whether the device described by this node is usable. Nodes without a `status` property are.
+    pub fn is_enabled(&self) -> bool {
+        match self.property("status").and_then(|property| property.as_str()) {
+            Some(status) => status == "okay" || status == "ok",
+            None => true,
+        }
+    }
+
+    /// The number of cells used to encode addresses in the `reg` properties of this node's children.
+    pub fn address_cells(&self) -> usize {
+        self.property("#address-cells")
+            .and_then(|property| property.as_u32())
+            .map_or(DEFAULT_ADDRESS_CELLS, |cells| cells as usize)
+    }
+
+    /// The number of cells used to encode sizes in the `reg` properties of this node's children.
+    pub fn size_cells(&self) -> usize {
+        self.property("#size-cells")
+            .and_then(|property| property.as_u32())
+            .map_or(DEFAULT_SIZE_CELLS, |cells| cells as usize)
+    }
+
+    /// Iterate over the regions of the node's `reg` property. The addresses are in the address space of the node's
+    /// parent bus - use `translate_address` (or `mmio_regions`) to turn them into physical addresses.
+    pub fn reg(&self) -> impl Iterator<Item = Region> + 'a {
+        let (address_cells, size_cells) = match self.parent() {
+            Some(parent) => (parent.address_cells(), parent.size_cells()),
+            None => (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS),
+        };
+        let value = self.property("reg").map_or(&[][..], |property| property.value);
+
+        value.chunks_exact((address_cells + size_cells) * 4).map(move |entry| Region {
+            address: read_cells(entry, 0, address_cells),
+            size: if size_cells == 0 { None } else { Some(read_cells(entry, address_cells, size_cells)) },
+        })
+    }
+
+    /// Like `reg`, but with the addresses translated into the physical address space. Regions that can't be
+    /// translated (for example, because they're on a bus that isn't memory-mapped) are skipped.
+    pub fn mmio_regions(&self) -> impl Iterator<Item = Region> + 'a {
+        let node = *self;
+        self.reg().filter_map(move |region| {
+            Some(Region { address: node.translate_address(region.address)?, size: region.size })
+        })
+    }
+
+    /// Translate an address on this node's parent bus (such as one from its `reg` property) into a physical
+    /// address, using the `ranges` properties of its ancestors. Returns `None` if the address isn't visible to the
+    /// CPU.
+    pub fn translate_address(&self, mut address: u64) -> Option<u64> {
+        let mut bus = self.parent()?;
+
+        while let Some(bus_parent) = bus.parent() {
+            /*
+             * A bus without a `ranges` property isn't memory-mapped, while one with an empty `ranges` property
+             * uses the same address space as its parent.
+             */
+            let ranges = bus.property("ranges")?.value;
+            if !ranges.is_empty() {
+                let child_cells = bus.address_cells();
+                let parent_cells = bus_parent.address_cells();
+                let size_cells = bus.size_cells();
+
+                address =
+                    ranges.chunks_exact((child_cells + parent_cells + size_cells) * 4).find_map(|entry| {
+                        let child = read_cells(entry, 0, child_cells);
+                        let parent = read_cells(entry, child_cells, parent_cells);
+                        let size = read_cells(entry, child_cells + parent_cells, size_cells);
+
+                        if address >= child && address - child < size {
+                            Some(parent + (address - child))
+                        } else {
+                            None
+                        }
+                    })?;
+            }
+
+            bus = bus_parent;
+        }
+
+        Some(address)
+    }
+}
+
+/// A region from a `reg` property. `size` is `None` if the bus doesn't use sizes (`#size-cells` is `0`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Region {
+    pub address: u64,
+    pub size: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> Property<'a> {
+    pub fn as_u32(&self) -> Option<u32> {
+        if self.value.len() == 4 {
+            be_u32(self.value, 0)
+        } else {
+            None
+        }
+    }
+
+    /// Read the property as a `u64`. Some properties that hold a `u64` are encoded as a single cell if the value
+    /// fits, so this accepts either.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => be_u32(self.value, 0).map(u64::from),
+            8 => be_u64(self.value, 0),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        read_str(self.value)
+    }
+
+    /// Iterate over the strings of a property that holds a list of null-terminated strings (such as
+    /// `compatible`).
+    pub fn as_str_list(&self) -> impl Iterator<Item = &'a str> {
+        let value = match self.value.split_last() {
+            Some((0, value)) => value,
+            _ => self.value,
+        };
+        value
+            .split(|&byte| byte == 0)
+            .filter(|string| !string.is_empty())
+            .filter_map(|string| core::str::from_utf8(string).ok())
+    }
+
+    /// Iterate over the property's value as a list of cells (`u32`s).
+    pub fn cells(&self) -> impl Iterator<Item = u32> + 'a {
+        self.value.chunks_exact(4).map(|cell| be_u32(cell, 0).unwrap())
+    }
+}
+
+pub struct PropertyIter<'a> {
+    fdt: Fdt<'a>,
+    cursor: usize,
+}
+
+impl<'a> Iterator for PropertyIter<'a> {
+    type Item = Property<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let structure = self.fdt.structure;
+
+        loop {
+            match be_u32(structure, self.cursor)? {
+                FDT_NOP => self.cursor += 4,
+                FDT_PROP => {
+                    let length = be_u32(structure, self.cursor + 4)? as usize;
+                    let name_offset = be_u32(structure, self.cursor + 8)? as usize;
+                    let value_start = self.cursor + 12;
+                    let value = structure.get(value_start..(value_start + length))?;
+                    self.cursor = align_up(value_start + length, 4);
+
+                    return Some(Property { name: self.fdt.string_at(name_offset)?, value });
+                }
+                /*
+                 * Properties must come before a node's children, so anything else means we've reached the end of
+                 * them.
+                 */
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Read a value that is encoded as `cells` cells, starting at cell `start`. Values of more than two cells are
+/// truncated to their lower 64 bits - the extra cells are used by buses like PCI to encode flags, rather than
+/// address bits.
+fn read_cells(bytes: &[u8], start: usize, cells: usize) -> u64 {
+    let skip = cells.saturating_sub(2);
+    (skip..cells).fold(0, |value, cell| (value << 32) | be_u32(bytes, (start + cell) * 4).unwrap_or(0) as u64)
+}
@@ -0,0 +1,88 @@
+use fdt::{Fdt, FdtError, Region};
+
+/*
+ * This is the device tree the Raspberry Pi 4 firmware passes to the kernel, before the firmware fills in the
+ * memory node etc.
+ */
+const RPI4_DTB: &[u8] = include_bytes!("../../../bundled/rpi4/bcm2711-rpi-4-b.dtb");
+
+#[test]
+fn test_header() {
+    let fdt = Fdt::new(RPI4_DTB).unwrap();
+    assert_eq!(fdt.total_size(), RPI4_DTB.len());
+    assert_eq!(fdt.memory_reservations().collect::<Vec<_>>(), vec![(0x0, 0x1000)]);
+
+    assert!(Fdt::new(&RPI4_DTB[0..20]).is_err());
+    assert!(Fdt::new(&RPI4_DTB[0..1000]).is_err());
+    assert!(Fdt::new(&[0u8; 64]).is_err());
+
+    /*
+     * Patch the header's `version` (word 5), `last_comp_version` (word 6), and `size_dt_struct` (word 9) fields.
+     */
+    let with_header_field = |index: usize, value: u32| {
+        let mut blob = RPI4_DTB.to_vec();
+        blob[(index * 4)..(index * 4 + 4)].copy_from_slice(&value.to_be_bytes());
+        blob
+    };
+    assert_eq!(Fdt::new(&with_header_field(5, 16)).err(), Some(FdtError::IncompatibleVersion(16)));
+    assert_eq!(Fdt::new(&with_header_field(6, 18)).err(), Some(FdtError::IncompatibleVersion(18)));
+    assert!(Fdt::new(&with_header_field(5, 18)).is_ok());
+    assert_eq!(Fdt::new(&with_header_field(9, 0)).err(), Some(FdtError::NoRootNode));
+}
+
+#[test]
+fn test_nodes() {
+    let fdt = Fdt::new(RPI4_DTB).unwrap();
+    let root = fdt.root();
+    assert!(root.is_root());
+    assert_eq!(root.name(), "");
+    assert_eq!(root.compatible().collect::<Vec<_>>(), vec!["raspberrypi,4-model-b", "brcm,bcm2711"]);
+    assert_eq!(root.address_cells(), 2);
+    assert_eq!(root.size_cells(), 1);
+
+    assert!(fdt.chosen().is_some());
+    assert!(fdt.find_node("/soc/nonexistent").is_none());
+
+    let uart = fdt.find_node("/soc/serial@7e201000").unwrap();
+    assert_eq!(uart.name(), "serial@7e201000");
+    assert_eq!(uart.parent().unwrap().name(), "soc");
+    assert!(uart.compatible().any(|compatible| compatible == "arm,pl011"));
+    assert_eq!(
+        fdt.find_node("/aliases").unwrap().property("serial0").unwrap().as_str(),
+        Some("/soc/serial@7e215040")
+    );
+
+    assert_eq!(fdt.find_node("/cpus/cpu").unwrap().parent().unwrap().name(), "cpus");
+    assert_eq!(fdt.root().children().filter(|node| node.name() == "soc").count(), 1);
+}
+
+#[test]
+fn test_address_translation() {
+    let fdt = Fdt::new(RPI4_DTB).unwrap();
+
+    /*
+     * Peripherals on the `soc` bus are at `0x7e000000` from the VideoCore's point of view, which is mapped to
+     * `0xfe000000` for the ARM cores.
+     */
+    let uart = fdt.find_compatible(&["arm,pl011"]).unwrap();
+    assert_eq!(uart.reg().next(), Some(Region { address: 0x7e20_1000, size: Some(0x200) }));
+    assert_eq!(uart.mmio_regions().next(), Some(Region { address: 0xfe20_1000, size: Some(0x200) }));
+
+    let gic = fdt.find_compatible(&["arm,gic-400"]).unwrap();
+    assert_eq!(
+        gic.mmio_regions().map(|region| region.address).collect::<Vec<_>>(),
+        vec![0xff84_1000, 0xff84_2000, 0xff84_4000, 0xff84_6000]
+    );
+    assert_eq!(gic.property("interrupts").unwrap().cells().collect::<Vec<_>>(), vec![1, 9, 0xf04]);
+
+    /*
+     * The firmware fills in the memory node, so it's empty in the blob on disk.
+     */
+    let memory = fdt.memory_nodes().next().unwrap();
+    assert_eq!(memory.name(), "memory@0");
+    assert_eq!(memory.reg().collect::<Vec<_>>(), vec![Region { address: 0, size: Some(0) }]);
+
+    let pcie = fdt.find_compatible(&["brcm,bcm2711-pcie"]).unwrap();
+    assert_eq!(pcie.property("bus-range").unwrap().cells().collect::<Vec<_>>(), vec![0, 1]);
+    assert_eq!(fdt.find_phandle(pcie.property("phandle").unwrap().as_u32().unwrap()).unwrap().name(), pcie.name());
+}
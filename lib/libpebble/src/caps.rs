@@ -5,6 +5,11 @@ pub enum Capability {
     ServiceProvider,
     ServiceUser,
     PciBusDriver,
+    CreateTask,
+    HandleInterrupts,
+    Supervisor,
+    ReadKernelLog,
+    DumpAddressSpace,
 }
 
 pub const CAP_PADDING: u8 = 0x00;
@@ -13,6 +18,11 @@ pub const CAP_EARLY_LOGGING: u8 = 0x02;
 pub const CAP_SERVICE_PROVIDER: u8 = 0x03;
 pub const CAP_SERVICE_USER: u8 = 0x04;
 pub const CAP_PCI_BUS_DRIVER: u8 = 0x05;
+pub const CAP_CREATE_TASK: u8 = 0x06;
+pub const CAP_HANDLE_INTERRUPTS: u8 = 0x07;
+pub const CAP_SUPERVISOR: u8 = 0x08;
+pub const CAP_READ_KERNEL_LOG: u8 = 0x09;
+pub const CAP_DUMP_ADDRESS_SPACE: u8 = 0x0a;
 
 /// `N` must be a multiple of 4, and padded with zeros, so the whole descriptor is aligned to a
 /// 4-byte boundary.
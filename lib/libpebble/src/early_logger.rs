@@ -1,9 +1,24 @@
 use alloc::string::String;
 use core::fmt::Write;
-use log::{Log, Metadata, Record};
+use log::{LevelFilter, Log, Metadata, Record};
 
 pub struct EarlyLogger;
 
+impl EarlyLogger {
+    /// Ask the kernel which messages this task should log, as set by the log filter passed to the kernel. This
+    /// should be passed to `log::set_max_level` when the logger is installed.
+    pub fn max_level() -> LevelFilter {
+        match crate::syscall::get_log_level() {
+            0 => LevelFilter::Off,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            4 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+}
+
 impl Log for EarlyLogger {
     fn enabled(&self, _metadata: &Metadata) -> bool {
         true
@@ -13,7 +28,7 @@ impl Log for EarlyLogger {
         if self.enabled(record.metadata()) {
             let mut s = String::new();
             write!(s, "{}", record.args()).unwrap();
-            crate::syscall::early_log(&s).unwrap();
+            crate::syscall::early_log(&s, record.level() as usize).unwrap();
         }
     }
 
@@ -0,0 +1,78 @@
+use super::{
+    raw,
+    result::{define_error_type, handle_from_syscall_repr},
+    SYSCALL_SUBSCRIBE_TO_FAULTS,
+};
+use crate::Handle;
+use core::{mem, ptr, str};
+
+define_error_type!(SubscribeToFaultsError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    /// Another task is already subscribed to faults.
+    AlreadySubscribed => 2,
+});
+
+/// The type of exception a task caused. Some of these are specific to an architecture.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum FaultKind {
+    InvalidOpcode = 0,
+    GeneralProtectionFault = 1,
+    PageFault = 2,
+    DivideError = 3,
+    Debug = 4,
+    Overflow = 5,
+    BoundRangeExceeded = 6,
+    DeviceNotAvailable = 7,
+    InvalidTss = 8,
+    SegmentNotPresent = 9,
+    StackSegmentFault = 10,
+    X87FloatingPoint = 11,
+    AlignmentCheck = 12,
+    SimdFloatingPoint = 13,
+    Virtualization = 14,
+}
+
+/// Describes a fault caused by a userspace task. When a task faults, the kernel kills it, and sends a message to
+/// the task subscribed to faults (if there is one). The message contains a `FaultReport`, followed by the name of
+/// the faulting task, and transfers a handle to the faulting `Task`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct FaultReport {
+    pub kind: FaultKind,
+    /// The error code pushed by the exception, or `0` if it doesn't have one.
+    pub error_code: u64,
+    /// The address of the instruction that caused the fault.
+    pub instruction_pointer: u64,
+    /// For page faults, the address that was being accessed. For other faults, this is `0`.
+    pub address: u64,
+}
+
+impl FaultReport {
+    /// Parse the bytes of a message sent by the kernel into a `FaultReport` and the name of the faulting task.
+    /// Returns `None` if the message is not a valid fault report.
+    pub fn from_bytes(bytes: &[u8]) -> Option<(FaultReport, &str)> {
+        if bytes.len() < mem::size_of::<FaultReport>() {
+            return None;
+        }
+
+        /*
+         * The kernel only ever sends valid `FaultKind`s, but we check anyway, as the message could have come from
+         * anywhere.
+         */
+        let kind = u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if kind > FaultKind::Virtualization as u32 {
+            return None;
+        }
+
+        let report = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const FaultReport) };
+        let name = str::from_utf8(&bytes[mem::size_of::<FaultReport>()..]).ok()?;
+        Some((report, name))
+    }
+}
+
+/// Subscribe to faults caused by userspace tasks. Returns a handle to a channel end, down which the kernel sends a
+/// message each time a task faults. Only one task can be subscribed at a time.
+pub fn subscribe_to_faults() -> Result<Handle, SubscribeToFaultsError> {
+    handle_from_syscall_repr(unsafe { raw::syscall0(SYSCALL_SUBSCRIBE_TO_FAULTS) })
+}
@@ -0,0 +1,71 @@
+use super::{
+    raw,
+    result::{define_error_type, status_from_syscall_repr},
+    SYSCALL_READ_KERNEL_LOG,
+};
+use bit_field::BitField;
+use core::str;
+
+/// The maximum length of the message of a `KernelLogRecord`, in bytes. Longer messages are truncated.
+pub const KERNEL_LOG_MESSAGE_MAX_LENGTH: usize = 128;
+
+/// A single message in the kernel log. Each record is given a sequence number when it's logged, which increases by
+/// one for each record - this can be used to read the log incrementally, and to detect records that were
+/// overwritten before they could be read.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct KernelLogRecord {
+    pub sequence: u64,
+    /// The value of the monotonic clock (see `get_time`) when the record was logged. Records logged before the
+    /// clock was initialized have a timestamp of `0`.
+    pub timestamp: u64,
+    /// The ID of the task that logged the record (using `early_log`), or `0` if it was logged by the kernel itself.
+    pub source: u64,
+    /// The CPU the record was logged on.
+    pub cpu: u32,
+    /// The level of the record, from `1` (error) to `5` (trace).
+    pub level: u16,
+    pub message_length: u16,
+    pub message: [u8; KERNEL_LOG_MESSAGE_MAX_LENGTH],
+}
+
+impl KernelLogRecord {
+    /// A record with every field zeroed. This is useful for creating buffers to read records into.
+    pub const EMPTY: KernelLogRecord = KernelLogRecord {
+        sequence: 0,
+        timestamp: 0,
+        source: 0,
+        cpu: 0,
+        level: 0,
+        message_length: 0,
+        message: [0; KERNEL_LOG_MESSAGE_MAX_LENGTH],
+    };
+
+    pub fn message(&self) -> Option<&str> {
+        str::from_utf8(self.message.get(0..(self.message_length as usize))?).ok()
+    }
+}
+
+define_error_type!(ReadKernelLogError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    BufferPointerInvalid => 2,
+});
+
+/// Read records from the kernel log into `buffer`, starting with the record with sequence number `first_sequence`.
+/// If that record has already been overwritten, reading starts with the oldest record the kernel still has. Returns
+/// the part of `buffer` that was filled, which is empty if there are no new records.
+///
+/// The kernel keeps the records logged during boot until the log wraps around, so reading from sequence `0` replays
+/// the kernel's boot messages.
+pub fn read_kernel_log(
+    first_sequence: u64,
+    buffer: &mut [KernelLogRecord],
+) -> Result<&mut [KernelLogRecord], ReadKernelLogError> {
+    let result = unsafe {
+        raw::syscall3(SYSCALL_READ_KERNEL_LOG, first_sequence as usize, buffer.as_mut_ptr() as usize, buffer.len())
+    };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+
+    let num_records = result.get_bits(16..48);
+    Ok(&mut buffer[0..num_records])
+}
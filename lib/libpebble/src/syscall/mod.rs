@@ -1,8 +1,12 @@
+pub mod fault;
 pub mod get_framebuffer;
+pub mod kernel_log;
 pub mod pci;
 pub mod result;
 
+pub use fault::{subscribe_to_faults, FaultKind, FaultReport, SubscribeToFaultsError};
 pub use get_framebuffer::{get_framebuffer, FramebufferInfo, GetFramebufferError, PixelFormat};
+pub use kernel_log::{read_kernel_log, KernelLogRecord, ReadKernelLogError, KERNEL_LOG_MESSAGE_MAX_LENGTH};
 #[cfg(feature = "can_alloc")]
 pub use pci::pci_get_info_vec;
 pub use pci::{pci_get_info, pci_get_info_slice, PciDeviceInfo, PciGetInfoError};
@@ -11,13 +15,17 @@ cfg_if::cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
         pub mod raw_x86_64;
         pub use raw_x86_64 as raw;
+    } else if #[cfg(target_arch = "aarch64")] {
+        pub mod raw_aarch64;
+        pub use raw_aarch64 as raw;
     } else {
         compile_error!("libpebble does not support this target architecture!");
     }
 }
 
-use crate::Handle;
+use crate::{Handle, ZERO_HANDLE};
 use bit_field::BitField;
+use pci_types::PciAddress;
 use result::{define_error_type, handle_from_syscall_repr, status_from_syscall_repr};
 
 pub const SYSCALL_YIELD: usize = 0;
@@ -32,6 +40,24 @@ pub const SYSCALL_WAIT_FOR_MESSAGE: usize = 8;
 pub const SYSCALL_REGISTER_SERVICE: usize = 9;
 pub const SYSCALL_SUBSCRIBE_TO_SERVICE: usize = 10;
 pub const SYSCALL_PCI_GET_INFO: usize = 11;
+pub const SYSCALL_EXIT_TASK: usize = 12;
+pub const SYSCALL_CREATE_TASK: usize = 13;
+pub const SYSCALL_SPAWN_THREAD: usize = 14;
+pub const SYSCALL_GET_TIME: usize = 15;
+pub const SYSCALL_SLEEP_UNTIL: usize = 16;
+pub const SYSCALL_CREATE_EVENT: usize = 17;
+pub const SYSCALL_SIGNAL_EVENT: usize = 18;
+pub const SYSCALL_CLEAR_EVENT: usize = 19;
+pub const SYSCALL_WAIT_FOR_EVENT: usize = 20;
+pub const SYSCALL_CREATE_INTERRUPT: usize = 21;
+pub const SYSCALL_WAIT_FOR_INTERRUPT: usize = 22;
+pub const SYSCALL_ACK_INTERRUPT: usize = 23;
+pub const SYSCALL_CREATE_PCI_INTERRUPT: usize = 24;
+pub const SYSCALL_SUBSCRIBE_TO_FAULTS: usize = 25;
+pub const SYSCALL_READ_KERNEL_LOG: usize = 26;
+pub const SYSCALL_GET_LOG_LEVEL: usize = 27;
+pub const SYSCALL_PROTECT_MEMORY_OBJECT: usize = 28;
+pub const SYSCALL_DUMP_ADDRESS_SPACE: usize = 29;
 
 pub fn yield_to_kernel() {
     unsafe {
@@ -39,18 +65,36 @@ pub fn yield_to_kernel() {
     }
 }
 
+/// Exit the current task, with the given status. The task's handles are closed, and its resources are freed by
+/// the kernel. This never returns.
+pub fn exit_task(status: usize) -> ! {
+    unsafe {
+        raw::syscall1(SYSCALL_EXIT_TASK, status);
+    }
+    unreachable!()
+}
+
 define_error_type!(EarlyLogError {
     MessageTooLong => 1,
     MessageNotValidUtf8 => 2,
     TaskDoesNotHaveCorrectCapability => 3,
+    InvalidLevel => 4,
 });
 
-pub fn early_log(message: &str) -> Result<(), EarlyLogError> {
+/// Log a message through the kernel. `level` is numbered like the levels returned by `get_log_level`, from `1`
+/// (error) to `5` (trace).
+pub fn early_log(message: &str, level: usize) -> Result<(), EarlyLogError> {
     status_from_syscall_repr(unsafe {
-        raw::syscall2(SYSCALL_EARLY_LOG, message.len(), message as *const str as *const u8 as usize)
+        raw::syscall3(SYSCALL_EARLY_LOG, message.len(), message as *const str as *const u8 as usize, level)
     })
 }
 
+/// Get the most verbose level of log message the current task should log, as set by the log filter passed to the
+/// kernel. Levels are numbered from `0` (logging is off) to `5` (trace).
+pub fn get_log_level() -> usize {
+    unsafe { raw::syscall0(SYSCALL_GET_LOG_LEVEL) }
+}
+
 define_error_type!(CreateMemoryObjectError {
     InvalidVirtualAddress => 1,
     InvalidFlags => 2,
@@ -95,6 +139,45 @@ pub unsafe fn map_memory_object(
     })
 }
 
+define_error_type!(ProtectMemoryObjectError {
+    InvalidHandle => 1,
+    NotAMemoryObject => 2,
+    NotAnAddressSpace => 3,
+    InvalidFlags => 4,
+    ExceedsRights => 5,
+    NotMapped => 6,
+});
+
+/// Change whether a MemoryObject that's mapped into an AddressSpace is writable and executable. It can only be made
+/// writable or executable if it was created that way. The zero handle can be passed as `address_space` to use the
+/// calling task's AddressSpace.
+pub fn protect_memory_object(
+    memory_object: Handle,
+    address_space: Handle,
+    writable: bool,
+    executable: bool,
+) -> Result<(), ProtectMemoryObjectError> {
+    let mut flags = 0usize;
+    flags.set_bit(0, writable);
+    flags.set_bit(1, executable);
+
+    status_from_syscall_repr(unsafe {
+        raw::syscall3(SYSCALL_PROTECT_MEMORY_OBJECT, memory_object.0 as usize, address_space.0 as usize, flags)
+    })
+}
+
+define_error_type!(DumpAddressSpaceError {
+    InvalidHandle => 1,
+    NotAnAddressSpace => 2,
+    TaskDoesNotHaveCorrectCapability => 3,
+});
+
+/// Log the memory mapped into the userspace part of an AddressSpace to the kernel log, for debugging. The zero
+/// handle can be passed as `address_space` to dump the calling task's AddressSpace.
+pub fn dump_address_space(address_space: Handle) -> Result<(), DumpAddressSpaceError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_DUMP_ADDRESS_SPACE, address_space.0 as usize) })
+}
+
 pub const CHANNEL_MAX_NUM_BYTES: usize = 4096;
 pub const CHANNEL_MAX_NUM_HANDLES: usize = 4;
 
@@ -162,6 +245,26 @@ pub fn get_message<'b, 'h>(
     Ok((&mut byte_buffer[0..valid_bytes_len], &mut handle_buffer[0..valid_handles_len]))
 }
 
+define_error_type!(WaitForMessageError {
+    InvalidChannelHandle => 1,
+    NotAChannel => 2,
+    /// The deadline was reached before a message arrived.
+    TimedOut => 3,
+});
+
+/// Block until a message is available on the given `Channel` end, or until the monotonic clock (as returned by
+/// `get_time`) reaches `deadline`, if one is given. This doesn't receive the message - use `get_message` to do
+/// that.
+pub fn wait_for_message(channel: Handle, deadline: Option<u64>) -> Result<(), WaitForMessageError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(
+            SYSCALL_WAIT_FOR_MESSAGE,
+            channel.0 as usize,
+            deadline.map_or(usize::MAX, |deadline| deadline as usize),
+        )
+    })
+}
+
 pub const SERVICE_NAME_MAX_LENGTH: usize = 256;
 
 define_error_type!(RegisterServiceError {
@@ -190,3 +293,186 @@ pub fn subscribe_to_service(name: &str) -> Result<Handle, SubscribeToServiceErro
         raw::syscall2(SYSCALL_SUBSCRIBE_TO_SERVICE, name.len(), name.as_ptr() as usize)
     })
 }
+
+pub const TASK_NAME_MAX_LENGTH: usize = 32;
+
+/// The handle to a task's end of its bootstrap channel, if it was created by another task using `create_task`.
+/// This is always the first handle issued to the new task.
+pub const BOOTSTRAP_CHANNEL_HANDLE: Handle = Handle(1);
+
+define_error_type!(CreateTaskError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    /// Name must be greater than `0` bytes, and not greater than `32` bytes.
+    NameLengthNotValid => 2,
+    NamePointerNotValid => 3,
+    InvalidHandle => 4,
+    NotAMemoryObject => 5,
+    /// The image is not a valid ELF, or could not be loaded.
+    InvalidElf => 6,
+    /// The image's `PEBBLE` note contains an invalid capability encoding.
+    InvalidCapabilityEncoding => 7,
+    /// The kernel can't create any more tasks.
+    TooManyTasks => 8,
+    BootstrapChannelAddressInvalid => 9,
+    /// The image's loadable segments are too large to load.
+    ImageTooLarge => 10,
+    /// The image asks for a capability that the calling task doesn't have.
+    CapabilityNotAllowed => 11,
+    /// There isn't enough physical memory to create the task.
+    OutOfMemory => 12,
+});
+
+/// Create a new task from the ELF image held in a `MemoryObject`, and schedule it. Returns a handle to the new
+/// `Task`, and a handle to our end of a channel that can be used to communicate with the new task (the new task
+/// can find its end at `BOOTSTRAP_CHANNEL_HANDLE`).
+pub fn create_task(elf: Handle, name: &str) -> Result<(Handle, Handle), CreateTaskError> {
+    let mut bootstrap_channel = ZERO_HANDLE;
+    let task = handle_from_syscall_repr(unsafe {
+        raw::syscall4(
+            SYSCALL_CREATE_TASK,
+            elf.0 as usize,
+            name.len(),
+            name.as_ptr() as usize,
+            &mut bootstrap_channel as *mut Handle as usize,
+        )
+    })?;
+
+    Ok((task, bootstrap_channel))
+}
+
+define_error_type!(SpawnThreadError {
+    /// The entry point is not a valid userspace address.
+    InvalidEntryPoint => 1,
+    /// The kernel can't create any more threads, because too many tasks have been created.
+    TooManyThreads => 2,
+    /// The task's `AddressSpace` has run out of space for more user stacks.
+    AddressSpaceFull => 3,
+    /// There isn't enough physical memory for the new thread's stacks.
+    OutOfMemory => 4,
+});
+
+/// Spawn a new thread of the current task, which shares its `AddressSpace` and handles. The thread starts
+/// executing `entry`, which is passed `argument`. Returns a handle to the new thread's `Task`.
+pub fn spawn_thread(entry: extern "C" fn(usize) -> !, argument: usize) -> Result<Handle, SpawnThreadError> {
+    handle_from_syscall_repr(unsafe { raw::syscall2(SYSCALL_SPAWN_THREAD, entry as usize, argument) })
+}
+
+/// Get the current value of the kernel's monotonic clock, in nanoseconds. The clock starts at an arbitrary point
+/// during boot, and never goes backwards.
+pub fn get_time() -> u64 {
+    unsafe { raw::syscall0(SYSCALL_GET_TIME) as u64 }
+}
+
+/// Block the current task until the monotonic clock (as returned by `get_time`) reaches `deadline`.
+pub fn sleep_until(deadline: u64) {
+    unsafe {
+        raw::syscall1(SYSCALL_SLEEP_UNTIL, deadline as usize);
+    }
+}
+
+define_error_type!(EventError {
+    InvalidHandle => 1,
+    NotAnEvent => 2,
+    /// Only returned by `wait_for_event`, if the deadline was reached before any of the waited-on bits were
+    /// signalled.
+    TimedOut => 3,
+});
+
+/// Create a new `Event`, with none of its signal bits set.
+pub fn create_event() -> Handle {
+    handle_from_syscall_repr::<EventError>(unsafe { raw::syscall0(SYSCALL_CREATE_EVENT) })
+        .expect("Failed to create Event")
+}
+
+/// Set the given signal bits of an `Event`, waking any tasks waiting on them.
+pub fn signal_event(event: Handle, bits: u32) -> Result<(), EventError> {
+    status_from_syscall_repr(unsafe { raw::syscall2(SYSCALL_SIGNAL_EVENT, event.0 as usize, bits as usize) })
+}
+
+/// Clear the given signal bits of an `Event`.
+pub fn clear_event(event: Handle, bits: u32) -> Result<(), EventError> {
+    status_from_syscall_repr(unsafe { raw::syscall2(SYSCALL_CLEAR_EVENT, event.0 as usize, bits as usize) })
+}
+
+/// Block until any of the signal bits in `mask` are set on an `Event`, or until the monotonic clock (as returned
+/// by `get_time`) reaches `deadline`, if one is given. Returns the bits of `mask` that are signalled. This does
+/// not clear the signalled bits.
+pub fn wait_for_event(event: Handle, mask: u32, deadline: Option<u64>) -> Result<u32, EventError> {
+    let result = unsafe {
+        raw::syscall3(
+            SYSCALL_WAIT_FOR_EVENT,
+            event.0 as usize,
+            mask as usize,
+            deadline.map_or(usize::MAX, |deadline| deadline as usize),
+        )
+    };
+    status_from_syscall_repr(result.get_bits(0..32))?;
+    Ok(result.get_bits(32..64) as u32)
+}
+
+define_error_type!(CreateInterruptError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    /// The platform does not have an interrupt with the given GSI.
+    InvalidGsi => 2,
+    /// The interrupt is edge-triggered, and is already being handled by another `Interrupt`.
+    AlreadyInUse => 3,
+    /// The kernel has run out of resources to deliver interrupts with.
+    NoFreeVectors => 4,
+});
+
+/// Create an `Interrupt` to handle the hardware interrupt with the given GSI (global system interrupt). After the
+/// interrupt has fired, it must be acknowledged with `ack_interrupt` before it can fire again.
+pub fn create_interrupt(gsi: u32) -> Result<Handle, CreateInterruptError> {
+    handle_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_CREATE_INTERRUPT, gsi as usize) })
+}
+
+define_error_type!(CreatePciInterruptError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    PlatformDoesNotSupportPci => 2,
+    /// There is no PCI function at the given address.
+    InvalidDevice => 3,
+    /// The function supports neither MSI nor MSI-X, or its MSI-X table isn't somewhere the kernel can use.
+    MsiNotSupported => 4,
+    /// The function does not have an MSI-X table entry with the given index. Functions that only support MSI only
+    /// have a single entry, with an index of `0`.
+    InvalidIndex => 5,
+    /// The interrupt is already enabled, and so is probably being handled by another `Interrupt`.
+    AlreadyInUse => 6,
+    /// The kernel has run out of resources to deliver interrupts with.
+    NoFreeVectors => 7,
+});
+
+/// Create an `Interrupt` that is raised by the PCI function at `address` using a message-signalled interrupt. If
+/// the function supports MSI-X, `index` selects the entry of its MSI-X table to use. Otherwise, MSI is used, and
+/// `index` must be `0`. Like other `Interrupt`s, the interrupt must be acknowledged with `ack_interrupt` once it
+/// has been handled.
+pub fn create_pci_interrupt(address: PciAddress, index: u16) -> Result<Handle, CreatePciInterruptError> {
+    handle_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_CREATE_PCI_INTERRUPT, pci::pci_address_to_syscall_repr(address), index as usize)
+    })
+}
+
+define_error_type!(InterruptError {
+    InvalidHandle => 1,
+    NotAnInterrupt => 2,
+    /// Only returned by `wait_for_interrupt`, if the deadline was reached before the interrupt fired.
+    TimedOut => 3,
+});
+
+/// Block until an `Interrupt` fires, or until the monotonic clock (as returned by `get_time`) reaches `deadline`,
+/// if one is given. Returns immediately if the interrupt has fired but has not yet been acknowledged.
+pub fn wait_for_interrupt(interrupt: Handle, deadline: Option<u64>) -> Result<(), InterruptError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(
+            SYSCALL_WAIT_FOR_INTERRUPT,
+            interrupt.0 as usize,
+            deadline.map_or(usize::MAX, |deadline| deadline as usize),
+        )
+    })
+}
+
+/// Acknowledge that an `Interrupt` has been handled (e.g. that the device that raised it has been serviced), so
+/// that it can fire again.
+pub fn ack_interrupt(interrupt: Handle) -> Result<(), InterruptError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_ACK_INTERRUPT, interrupt.0 as usize) })
+}
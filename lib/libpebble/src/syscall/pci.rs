@@ -3,7 +3,7 @@ use bit_field::BitField;
 use core::convert::TryFrom;
 use pci_types::{BaseClass, DeviceId, DeviceRevision, Interface, PciAddress, SubClass, VendorId};
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default)]
 #[repr(C)]
 pub struct PciDeviceInfo {
     pub address: PciAddress,
@@ -21,6 +21,23 @@ pub struct PciDeviceInfo {
     /// The lower byte of the class-code. This may indicate a specific register-level programming interface of the
     /// device.
     pub interface: Interface,
+    /// Whether the device's legacy interrupt pin is routed to a GSI. This is `1` if it is, in which case `gsi` is
+    /// valid, and `0` otherwise. `Option<u32>` doesn't have a stable layout, so can't be used here.
+    pub has_gsi: u8,
+    /// The GSI (global system interrupt) that the device's legacy interrupt pin is routed to, if `has_gsi` is set.
+    /// This can be passed to `create_interrupt` to handle the device's interrupts.
+    pub gsi: u32,
+}
+
+impl PciDeviceInfo {
+    /// Get the GSI that the device's legacy interrupt pin is routed to, if it has one.
+    pub fn legacy_gsi(&self) -> Option<u32> {
+        if self.has_gsi != 0 {
+            Some(self.gsi)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -84,6 +101,25 @@ pub fn pci_get_info_slice(buffer: &mut [PciDeviceInfo]) -> Result<&mut [PciDevic
     }
 }
 
+/// Encode a `PciAddress` so it can be passed to the kernel in a single register.
+pub fn pci_address_to_syscall_repr(address: PciAddress) -> usize {
+    let mut repr = 0;
+    repr.set_bits(0..3, address.function() as usize);
+    repr.set_bits(3..8, address.device() as usize);
+    repr.set_bits(8..16, address.bus() as usize);
+    repr.set_bits(16..32, address.segment() as usize);
+    repr
+}
+
+pub fn pci_address_from_syscall_repr(repr: usize) -> PciAddress {
+    PciAddress::new(
+        repr.get_bits(16..32) as u16,
+        repr.get_bits(8..16) as u8,
+        repr.get_bits(3..8) as u8,
+        repr.get_bits(0..3) as u8,
+    )
+}
+
 #[cfg(feature = "can_alloc")]
 pub fn pci_get_info_vec() -> Result<alloc::vec::Vec<PciDeviceInfo>, PciGetInfoError> {
     use alloc::vec::Vec;
@@ -0,0 +1,83 @@
+//! System calls are made with `svc #0`. The number of the system call is passed in `x0`, and its arguments in `x1`
+//! through `x5`. The result is returned in `x0`, and the kernel preserves all of the other registers.
+
+#[inline(never)]
+pub unsafe fn syscall0(number: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("svc #0",
+            inlateout("x0") number => result,
+        );
+    }
+    result
+}
+
+#[inline(never)]
+pub unsafe fn syscall1(number: usize, a: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("svc #0",
+            inlateout("x0") number => result,
+            in("x1") a,
+        );
+    }
+    result
+}
+
+#[inline(never)]
+pub unsafe fn syscall2(number: usize, a: usize, b: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("svc #0",
+            inlateout("x0") number => result,
+            in("x1") a,
+            in("x2") b,
+        );
+    }
+    result
+}
+
+#[inline(never)]
+pub unsafe fn syscall3(number: usize, a: usize, b: usize, c: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("svc #0",
+            inlateout("x0") number => result,
+            in("x1") a,
+            in("x2") b,
+            in("x3") c,
+        );
+    }
+    result
+}
+
+#[inline(never)]
+pub unsafe fn syscall4(number: usize, a: usize, b: usize, c: usize, d: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("svc #0",
+            inlateout("x0") number => result,
+            in("x1") a,
+            in("x2") b,
+            in("x3") c,
+            in("x4") d,
+        );
+    }
+    result
+}
+
+#[inline(never)]
+pub unsafe fn syscall5(number: usize, a: usize, b: usize, c: usize, d: usize, e: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("svc #0",
+            inlateout("x0") number => result,
+            in("x1") a,
+            in("x2") b,
+            in("x3") c,
+            in("x4") d,
+            in("x5") e,
+        );
+    }
+    result
+}
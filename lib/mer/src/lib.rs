@@ -12,7 +12,7 @@ use crate::{
     section::{SectionHeader, SectionType},
     symbol::Symbol,
 };
-use core::{marker::PhantomData, mem, str};
+use core::{marker::PhantomData, mem, ops::Range, str};
 use scroll::{ctx::TryFromCtx, Pread};
 
 /// An ELF binary
@@ -33,15 +33,36 @@ impl Elf<'_> {
         let header = bytes.pread::<Header>(0).map_err(|_| ElfError::MalformedHeader)?;
         header.validate()?;
 
+        /*
+         * Check that the section and program header tables lie within the file, and that their entries are big
+         * enough to hold a header. This is what makes it safe for `sections` and `segments` to index `bytes`.
+         */
+        table_range::<SectionHeader>(
+            bytes,
+            header.section_header_offset,
+            header.section_header_entry_size,
+            header.number_of_section_headers,
+        )
+        .ok_or(ElfError::SectionOutOfBounds)?;
+        table_range::<ProgramHeader>(
+            bytes,
+            header.program_header_offset,
+            header.program_header_entry_size,
+            header.number_of_program_headers,
+        )
+        .ok_or(ElfError::SegmentOutOfBounds)?;
+
         let mut elf = Elf { bytes, header, symbol_table: None };
 
-        elf.sections().map(|section| section.validate()).collect::<Result<_, ElfError>>()?;
-        elf.segments().map(|segment| segment.validate()).collect::<Result<_, ElfError>>()?;
+        elf.sections().map(|section| section.validate(bytes)).collect::<Result<_, ElfError>>()?;
+        elf.segments().map(|segment| segment.validate(bytes)).collect::<Result<_, ElfError>>()?;
 
         // Cache the symbol table, if there is one
         elf.symbol_table = match elf.sections().find(|section| section.name(&elf) == Some(".symtab")) {
             Some(symbol_table) => {
-                if symbol_table.section_type() != SectionType::SymTab {
+                if symbol_table.section_type() != SectionType::SymTab
+                    || symbol_table.entry_size < mem::size_of::<Symbol>() as u64
+                {
                     return Err(ElfError::InvalidSymbolTable);
                 }
 
@@ -56,24 +77,35 @@ impl Elf<'_> {
 
     /// Create a `SectionIter` that iterates over this ELF's section header.
     pub fn sections(&self) -> EntryIter<SectionHeader> {
-        let start = self.header.section_header_offset as usize;
-        let end = start
-            + self.header.section_header_entry_size as usize * self.header.number_of_section_headers as usize;
+        /*
+         * The range of the table was checked when the `Elf` was created.
+         */
+        let range = table_range::<SectionHeader>(
+            self.bytes,
+            self.header.section_header_offset,
+            self.header.section_header_entry_size,
+            self.header.number_of_section_headers,
+        )
+        .unwrap();
 
         EntryIter::new(
-            &self.bytes[start..end],
+            &self.bytes[range],
             self.header.number_of_section_headers as u64,
             self.header.section_header_entry_size as u64,
         )
     }
 
     pub fn segments(&self) -> EntryIter<ProgramHeader> {
-        let start = self.header.program_header_offset as usize;
-        let end = start
-            + self.header.program_header_entry_size as usize * self.header.number_of_program_headers as usize;
+        let range = table_range::<ProgramHeader>(
+            self.bytes,
+            self.header.program_header_offset,
+            self.header.program_header_entry_size,
+            self.header.number_of_program_headers,
+        )
+        .unwrap();
 
         EntryIter::new(
-            &self.bytes[start..end],
+            &self.bytes[range],
             self.header.number_of_program_headers as u64,
             self.header.program_header_entry_size as u64,
         )
@@ -116,6 +148,9 @@ pub enum ElfError {
      * Errors that can be produced parsing section headers.
      */
     SectionInvalidType,
+    /// The section header table, or the data of a section, doesn't lie within the file (or its
+    /// entries are too small to hold a section header).
+    SectionOutOfBounds,
     /// The `.symtab` section is not actually a symbol table.
     InvalidSymbolTable,
 
@@ -123,6 +158,9 @@ pub enum ElfError {
      * Errors that can be produced parsing program headers.
      */
     SegmentInvalidType,
+    /// The program header table, or the data of a segment, doesn't lie within the file (or its
+    /// entries are too small to hold a program header).
+    SegmentOutOfBounds,
 }
 
 pub struct EntryIter<'a, T: TryFromCtx<'a, scroll::Endian, Error = scroll::Error, Size = usize>> {
@@ -169,6 +207,28 @@ where
     }
 }
 
+/// Get the range of `bytes` covered by a table of `count` entries of `entry_size` bytes, starting
+/// at `offset`. Returns `None` if the table doesn't lie within `bytes`, or if its entries are too
+/// small to hold a `T`.
+fn table_range<T>(bytes: &[u8], offset: u64, entry_size: u16, count: u16) -> Option<Range<usize>> {
+    if count > 0 && (entry_size as usize) < mem::size_of::<T>() {
+        return None;
+    }
+
+    data_range(bytes, offset, (entry_size as u64).checked_mul(count as u64)?)
+}
+
+/// Get the range of `bytes` covered by `size` bytes starting at `offset`, if they lie within
+/// `bytes`.
+pub(crate) fn data_range(bytes: &[u8], offset: u64, size: u64) -> Option<Range<usize>> {
+    let end = offset.checked_add(size)?;
+    if end > bytes.len() as u64 {
+        return None;
+    }
+
+    Some((offset as usize)..(end as usize))
+}
+
 /// Utility function to extract a null-terminated, UTF-8 `&str` from string tables, symbol tables
 /// etc.
 pub(crate) fn from_utf8_null_terminated(bytes: &[u8]) -> Result<&str, str::Utf8Error> {
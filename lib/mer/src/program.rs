@@ -35,12 +35,13 @@ pub struct ProgramHeader {
 }
 
 impl ProgramHeader {
-    pub(crate) fn validate(&self) -> Result<(), ElfError> {
+    pub(crate) fn validate(&self, bytes: &[u8]) -> Result<(), ElfError> {
         match self.segment_type {
             0..=6 | 0x60000000..=0x7fffffff => Ok(()),
             _ => Err(ElfError::SegmentInvalidType),
         }?;
 
+        crate::data_range(bytes, self.offset, self.file_size).ok_or(ElfError::SegmentOutOfBounds)?;
         Ok(())
     }
 
@@ -61,7 +62,10 @@ impl ProgramHeader {
     }
 
     pub fn data<'a>(&self, elf: &'a Elf) -> &'a [u8] {
-        &elf.bytes[(self.offset as usize)..((self.offset + self.file_size) as usize)]
+        /*
+         * Segments read from the ELF were checked to lie within it when the `Elf` was created.
+         */
+        &elf.bytes[crate::data_range(elf.bytes, self.offset, self.file_size).expect("Segment is out of bounds")]
     }
 
     pub fn is_executable(&self) -> bool {
@@ -76,13 +76,16 @@ pub struct SectionHeader {
 }
 
 impl SectionHeader {
-    pub(crate) fn validate(&self) -> Result<(), ElfError> {
+    pub(crate) fn validate(&self, bytes: &[u8]) -> Result<(), ElfError> {
         match self.section_type {
             0..=11 | 0x60000000..=0x7fffffff => Ok(()),
             _ => Err(ElfError::SectionInvalidType),
         }?;
 
-        Ok(())
+        match self.section_type() {
+            SectionType::Null | SectionType::NoBits => Ok(()),
+            _ => crate::data_range(bytes, self.offset, self.size).map(|_| ()).ok_or(ElfError::SectionOutOfBounds),
+        }
     }
 
     pub fn section_type(&self) -> SectionType {
@@ -112,7 +115,7 @@ impl SectionHeader {
         }
 
         let string_table = elf.sections().nth(elf.header.string_table_index as usize)?;
-        crate::from_utf8_null_terminated(&string_table.data(elf)?[(self.name as usize)..]).ok()
+        crate::from_utf8_null_terminated(string_table.data(elf)?.get((self.name as usize)..)?).ok()
     }
 
     /// Get this section's data, as a byte slice. Returns `None` if the image isn't represented in
@@ -123,7 +126,7 @@ impl SectionHeader {
             _ => (),
         }
 
-        Some(&elf.bytes[(self.offset as usize)..((self.offset + self.size) as usize)])
+        crate::data_range(elf.bytes, self.offset, self.size).map(|range| &elf.bytes[range])
     }
 
     /// Whether this section contains writable data
@@ -1,6 +1,7 @@
 use scroll_derive::Pread;
 use bit_field::BitField;
-use crate::{Elf, section::SectionType};
+use crate::{Elf, EntryIter, section::SectionType};
+use core::mem;
 
 pub enum SymbolBinding {
     /// Only visible inside the object file that defines it.
@@ -70,6 +71,16 @@ impl Symbol {
         }
     }
 
+    /// Get the name of this symbol from a string table that has been separated from the rest of the ELF. This is
+    /// useful alongside `iterate_symbol_table`.
+    pub fn name_in<'a>(&self, string_table: &'a [u8]) -> Option<&'a str> {
+        if self.name == 0 {
+            return None;
+        }
+
+        crate::from_utf8_null_terminated(string_table.get((self.name as usize)..)?).ok()
+    }
+
     pub fn name<'a>(&self, elf: &'a Elf) -> Option<&'a str> {
         if self.name == 0 {
             return None;
@@ -86,14 +97,21 @@ impl Symbol {
                  * NOTE: the `link` field of the symbol table contains the index of the string
                  * table that contains the names of the symbols.
                  */
-                let string_table = elf.sections().nth(symbol_table.link as usize).unwrap();
+                let string_table = elf.sections().nth(symbol_table.link as usize)?;
 
                 if string_table.section_type() != SectionType::StrTab {
                     return None;
                 }
 
-                crate::from_utf8_null_terminated(&string_table.data(elf)?[(self.name as usize)..]).ok()
+                crate::from_utf8_null_terminated(string_table.data(elf)?.get((self.name as usize)..)?).ok()
             }
         }
     }
 }
+
+/// Iterate over the symbols of a symbol table that has been separated from the rest of the ELF (for example, if only
+/// the contents of the `.symtab` section have been kept around). `bytes` should be the contents of the section.
+pub fn iterate_symbol_table(bytes: &[u8]) -> EntryIter<Symbol> {
+    let entry_size = mem::size_of::<Symbol>();
+    EntryIter::new(bytes, (bytes.len() / entry_size) as u64, entry_size as u64)
+}
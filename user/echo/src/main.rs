@@ -21,7 +21,7 @@ static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    syscall::early_log("Hello, World!").unwrap();
+    syscall::early_log("Hello, World!", log::Level::Info as usize).unwrap();
     // Initialise the heap
     const HEAP_START: usize = 0x600000000;
     const HEAP_SIZE: usize = 0x4000;
@@ -32,7 +32,7 @@ pub extern "C" fn _start() -> ! {
     }
 
     log::set_logger(&EarlyLogger).unwrap();
-    log::set_max_level(log::LevelFilter::Trace);
+    log::set_max_level(EarlyLogger::max_level());
     info!("Echo running!");
 
     let echo_service_channel = syscall::register_service("echo").unwrap();
@@ -75,7 +75,7 @@ pub extern "C" fn _start() -> ! {
 #[panic_handler]
 pub fn handle_panic(info: &PanicInfo) -> ! {
     log::error!("PANIC: {}", info);
-    loop {}
+    syscall::exit_task(1)
 }
 
 #[alloc_error_handler]
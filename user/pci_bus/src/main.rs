@@ -22,7 +22,7 @@ static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    syscall::early_log("Hello from pci_bus!!").unwrap();
+    syscall::early_log("Hello from pci_bus!!", log::Level::Info as usize).unwrap();
     // Initialise the heap
     const HEAP_START: usize = 0x600000000;
     const HEAP_SIZE: usize = 0x4000;
@@ -33,7 +33,7 @@ pub extern "C" fn _start() -> ! {
     }
 
     log::set_logger(&EarlyLogger).unwrap();
-    log::set_max_level(log::LevelFilter::Trace);
+    log::set_max_level(EarlyLogger::max_level());
     info!("PCI bus driver is running!");
 
     let platform_bus_channel = syscall::subscribe_to_service("platform_bus.bus_driver").unwrap();
@@ -60,6 +60,9 @@ pub extern "C" fn _start() -> ! {
             properties.insert("pci.device_id".to_string(), Property::Integer(descriptor.device_id as u64));
             properties.insert("pci.class".to_string(), Property::Integer(descriptor.class as u64));
             properties.insert("pci.sub_class".to_string(), Property::Integer(descriptor.sub_class as u64));
+            if let Some(gsi) = descriptor.legacy_gsi() {
+                properties.insert("pci.gsi".to_string(), Property::Integer(gsi as u64));
+            }
 
             let mut bytes = Vec::new();
             ptah::to_wire(&BusDriverMessage::RegisterDevice(name, Device::new(properties)), &mut bytes).unwrap();
@@ -75,7 +78,7 @@ pub extern "C" fn _start() -> ! {
 #[panic_handler]
 pub fn handle_panic(info: &PanicInfo) -> ! {
     log::error!("PANIC: {}", info);
-    loop {}
+    syscall::exit_task(1)
 }
 
 #[alloc_error_handler]
@@ -32,7 +32,7 @@ struct DeviceEntry {
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    syscall::early_log("Hello from platform_bus!").unwrap();
+    syscall::early_log("Hello from platform_bus!", log::Level::Info as usize).unwrap();
     // Initialise the heap
     const HEAP_START: usize = 0x600000000;
     const HEAP_SIZE: usize = 0x4000;
@@ -43,7 +43,7 @@ pub extern "C" fn _start() -> ! {
     }
 
     log::set_logger(&EarlyLogger).unwrap();
-    log::set_max_level(log::LevelFilter::Trace);
+    log::set_max_level(EarlyLogger::max_level());
     info!("Platform-bus is running!");
 
     let bus_driver_service_channel = syscall::register_service("bus_driver").unwrap();
@@ -101,7 +101,7 @@ pub extern "C" fn _start() -> ! {
 #[panic_handler]
 pub fn handle_panic(info: &PanicInfo) -> ! {
     log::error!("PANIC: {}", info);
-    loop {}
+    syscall::exit_task(1)
 }
 
 #[alloc_error_handler]
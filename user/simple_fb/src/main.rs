@@ -31,7 +31,7 @@ static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    syscall::early_log("Hello from FB").unwrap();
+    syscall::early_log("Hello from FB", log::Level::Info as usize).unwrap();
     // Initialise the heap
     const HEAP_START: usize = 0x600000000;
     const HEAP_SIZE: usize = 0x4000;
@@ -42,7 +42,7 @@ pub extern "C" fn _start() -> ! {
     }
 
     log::set_logger(&EarlyLogger).unwrap();
-    log::set_max_level(log::LevelFilter::Trace);
+    log::set_max_level(EarlyLogger::max_level());
     info!("Simple framebuffer driver is running!");
 
     /*
@@ -121,7 +121,7 @@ fn make_framebuffer() -> Framebuffer<Bgr32> {
 #[panic_handler]
 pub fn handle_panic(info: &PanicInfo) -> ! {
     log::error!("PANIC: {}", info);
-    loop {}
+    syscall::exit_task(1)
 }
 
 #[alloc_error_handler]